@@ -0,0 +1,76 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use r6n::{
+    block::{BlockKey, Timestamp},
+    message::{BufferPool, ResultMessage},
+};
+
+// counts allocations, to compare the ways of encoding
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations per call of `f`
+fn allocations(mut f: impl FnMut()) -> f64 {
+    const RUNS: usize = 1000;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..RUNS {
+        f();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / RUNS as f64
+}
+
+fn encoding(c: &mut Criterion) {
+    let key = BlockKey::from([1; 64]);
+    let block = [0x55; 1024];
+    let expiration = Timestamp::FOREVER;
+
+    let encode = || ResultMessage::encode(13, expiration, key, &block).unwrap();
+    let mut buf = vec![0; u16::MAX as usize];
+    let mut encode_into = || ResultMessage::encode_into(13, expiration, key, &block, &mut buf);
+    let mut pool = BufferPool::default();
+    let mut pooled = || {
+        let message = pool
+            .encode(|buf| ResultMessage::encode_into(13, expiration, key, &block, buf))
+            .unwrap();
+        pool.recycle(black_box(message));
+    };
+
+    println!("allocations per message:");
+    println!(
+        "  encode       {}",
+        allocations(|| drop(black_box(encode())))
+    );
+    println!(
+        "  encode_into  {}",
+        allocations(|| {
+            black_box(encode_into().unwrap());
+        })
+    );
+    println!("  pooled       {}", allocations(&mut pooled));
+
+    c.bench_function("encode 1k result", |b| b.iter(encode));
+    c.bench_function("encode_into 1k result", |b| b.iter(&mut encode_into));
+    c.bench_function("pooled 1k result", |b| b.iter(&mut pooled));
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
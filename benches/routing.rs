@@ -0,0 +1,66 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use r6n::{block::BlockKey, log2_xor_dist, xor, Peer, PeerId, RoutingTable, RoutingTableConfig};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn peers(n: usize) -> Vec<Peer> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..n).map(|_| Peer::from_bytes(rng.gen())).collect()
+}
+
+fn table(peers: &[Peer]) -> RoutingTable {
+    // large buckets, to simulate the churn of a big table
+    let config = RoutingTableConfig { bucket_size: 4096 };
+    let mut table = RoutingTable::new(PeerId::from_bytes([0; 64]), config);
+    for &peer in peers {
+        let _ = table.insert(peer);
+    }
+    table
+}
+
+fn routing(c: &mut Criterion) {
+    let peers = peers(20_000);
+
+    c.bench_function("insert 20k", |b| b.iter(|| table(black_box(&peers))));
+
+    c.bench_function("insert+remove 20k", |b| {
+        b.iter_batched(
+            || table(&peers),
+            |mut table| {
+                for peer in &peers {
+                    table.remove(peer);
+                }
+                table
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let table = table(&peers);
+    let key = BlockKey::from([0x55; 64]);
+    c.bench_function("closest_peers 20k", |b| {
+        b.iter(|| table.closest_peers(black_box(&key), 8).len())
+    });
+}
+
+fn distance(c: &mut Criterion) {
+    let ids: Vec<PeerId> = peers(1024).iter().map(Peer::id).collect();
+    let host = PeerId::from_bytes([0x55; 64]);
+
+    c.bench_function("log2_xor_dist 1k", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| log2_xor_dist(black_box(&host), id))
+                .max()
+        })
+    });
+    c.bench_function("xor 1k", |b| {
+        b.iter(|| {
+            ids.iter()
+                .map(|id| xor(black_box(host.as_bytes()), id.as_bytes()))
+                .min()
+        })
+    });
+}
+
+criterion_group!(benches, routing, distance);
+criterion_main!(benches);
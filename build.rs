@@ -0,0 +1,53 @@
+use std::{env, fmt::Write, fs, path::Path};
+
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha512};
+
+/// Seed for the identities in `testing::identities`. Changing it changes
+/// every fixture.
+const SEED: &[u8] = b"r6n testing identities";
+const COUNT: u32 = 64;
+
+fn main() {
+    println!("cargo::rerun-if-changed=build.rs");
+
+    let mut out = String::new();
+    writeln!(out, "pub static IDENTITIES: [Fixture; {COUNT}] = [").unwrap();
+
+    let mut host = None;
+    for i in 0..COUNT {
+        let seed: [u8; 64] = Sha512::new()
+            .chain_update(SEED)
+            .chain_update(i.to_be_bytes())
+            .finalize()
+            .into();
+        let secret: [u8; 32] = seed[..32].try_into().unwrap();
+        let public = SigningKey::from_bytes(&secret).verifying_key().to_bytes();
+        let id: [u8; 64] = Sha512::digest(public).into();
+        let host = *host.get_or_insert(id);
+
+        writeln!(
+            out,
+            "    Fixture {{ secret: {secret:?}, public: {public:?}, id: {id:?}, bucket: {} }},",
+            log2_xor_dist(&host, &id)
+        )
+        .unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("identities.rs");
+    fs::write(dest, out).unwrap();
+}
+
+// same as crate::log2_xor_dist
+fn log2_xor_dist(x: &[u8; 64], y: &[u8; 64]) -> u16 {
+    let mut dist = 0;
+    for i in 0..64 {
+        let clz = (x[i] ^ y[i]).leading_zeros() as u16;
+        dist += clz;
+        if clz < 8 {
+            break;
+        }
+    }
+    512 - dist
+}
@@ -0,0 +1,229 @@
+#ifndef R6N_H
+#define R6N_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Send `data` to `peer`
+ */
+#define R6N_ACTION_SEND 1
+
+/**
+ * Connect to `peer` at the address in `data`, a UTF-8 string without a NUL
+ */
+#define R6N_ACTION_CONNECT 2
+
+/**
+ * Prefer keeping the connection to `peer`
+ */
+#define R6N_ACTION_HOLD 3
+
+/**
+ * Undo a hold on `peer`
+ */
+#define R6N_ACTION_DROP 4
+
+/**
+ * A block found by a GET
+ */
+#define R6N_EVENT_RESULT 1
+
+/**
+ * The GET timed out, and won't produce any more results
+ */
+#define R6N_EVENT_EXPIRED 2
+
+/**
+ * A DHT node owned by C code
+ */
+typedef struct R6nNode R6nNode;
+
+/**
+ * Something for the application's transport to do.
+ *
+ * `data` points into the node and stays valid until the next
+ * `r6n_node_poll_action` or `r6n_node_free`.
+ */
+typedef struct R6nAction {
+  uint32_t kind;
+  uint8_t peer[32];
+  const uint8_t *data;
+  size_t len;
+} R6nAction;
+
+/**
+ * Something that happened to a GET.
+ *
+ * `data` points into the node and stays valid until the next
+ * `r6n_node_poll_event` or `r6n_node_free`. For expired queries it's null.
+ */
+typedef struct R6nEvent {
+  uint32_t kind;
+  uint64_t query;
+  uint32_t block_type;
+  /**
+   * Microseconds since the UNIX epoch
+   */
+  uint64_t expiration;
+  const uint8_t *data;
+  size_t len;
+} R6nEvent;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a node with the Ed25519 secret key at `secret`, or a fresh one if
+ * it's null, assuming a network of `network_size` peers until
+ * `r6n_node_set_network_size` says otherwise. Free it with `r6n_node_free`.
+ *
+ * # Safety
+ *
+ * `secret` must be null or point to 32 bytes.
+ */
+struct R6nNode *r6n_node_new(const uint8_t *secret, uint64_t network_size);
+
+/**
+ * # Safety
+ *
+ * `node` must be null or from `r6n_node_new`, and not used afterwards.
+ */
+void r6n_node_free(struct R6nNode *node);
+
+/**
+ * Write the node's public key to `out`.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `out` point to 32 writable bytes.
+ */
+void r6n_node_peer(const struct R6nNode *node, uint8_t *out);
+
+/**
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new`.
+ */
+void r6n_node_set_network_size(struct R6nNode *node, uint64_t network_size);
+
+/**
+ * The transport connected to `peer`. `constrained` peers, eg phones, are
+ * talked to but not routed through.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `peer` point to 32 bytes.
+ */
+void r6n_node_peer_connected(struct R6nNode *node, const uint8_t *peer, bool constrained);
+
+/**
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `peer` point to 32 bytes.
+ */
+void r6n_node_peer_disconnected(struct R6nNode *node, const uint8_t *peer);
+
+/**
+ * We're reachable at `addr`, eg `ip+udp://192.0.2.1:2086`. Returns -1 if
+ * it isn't UTF-8.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `addr` a NUL terminated string.
+ */
+int32_t r6n_node_address_added(struct R6nNode *node, const char *addr);
+
+/**
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `addr` a NUL terminated string.
+ */
+int32_t r6n_node_address_deleted(struct R6nNode *node, const char *addr);
+
+/**
+ * Process a message received from `peer`.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new`, `peer` point to 32 bytes and `data`
+ * to `len`.
+ */
+void r6n_node_receive(struct R6nNode *node, const uint8_t *peer, const uint8_t *data, size_t len);
+
+/**
+ * Run due maintenance, returning how many microseconds until it should be
+ * called again.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new`.
+ */
+uint64_t r6n_node_tick(struct R6nNode *node);
+
+/**
+ * Take the next thing for the transport to do, returning false once there
+ * are none left.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `out` writable.
+ */
+bool r6n_node_poll_action(struct R6nNode *node, struct R6nAction *out);
+
+/**
+ * Start looking for blocks of `block_type` under the 64 byte `key`,
+ * returning the id their events will have.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `key` point to 64 bytes.
+ */
+uint64_t r6n_node_get(struct R6nNode *node, const uint8_t *key, uint32_t block_type);
+
+/**
+ * Stop a GET. Its events stop too.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new`.
+ */
+void r6n_node_cancel(struct R6nNode *node, uint64_t query);
+
+/**
+ * Take the next event of a GET, returning false once there are none left.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new` and `out` writable.
+ */
+bool r6n_node_poll_event(struct R6nNode *node, struct R6nEvent *out);
+
+/**
+ * Store `data` as a block of `block_type` under the 64 byte `key` for
+ * `lifetime_secs`, returning how many peers it was sent to, or -1 if it's
+ * too big to send.
+ *
+ * # Safety
+ *
+ * `node` must be from `r6n_node_new`, `key` point to 64 bytes and `data`
+ * to `len`.
+ */
+int64_t r6n_node_put(struct R6nNode *node,
+                     const uint8_t *key,
+                     uint32_t block_type,
+                     const uint8_t *data,
+                     size_t len,
+                     uint64_t lifetime_secs);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* R6N_H */
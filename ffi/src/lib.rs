@@ -0,0 +1,4 @@
+//! The C interface to r6n, see [`r6n::ffi`]. Its declarations are in
+//! `include/r6n.h`.
+
+pub use r6n::ffi::*;
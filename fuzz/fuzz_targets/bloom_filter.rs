@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::bloom::BloomFilter;
+
+fuzz_target!(|input: (BloomFilter<Vec<u8>>, [u8; 64])| {
+    let (mut bloom, key) = input;
+    bloom.insert(&key);
+    assert!(bloom.test(&key));
+    // a filter read back from its bytes has the same members
+    let again = BloomFilter::from_with_k(bloom.as_bytes().to_vec(), bloom.k()).unwrap();
+    assert!(again.test(&key));
+});
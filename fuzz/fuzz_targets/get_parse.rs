@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::message::GetMessage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(get) = GetMessage::parse(data) {
+        assert!(get.result_filter().len() + get.xquery().len() <= data.len());
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::message::{GetMessage, GetMessageOwned};
+
+// a GET that starts here encodes back to the same bytes from its fields
+fuzz_target!(|get: GetMessageOwned| {
+    let parsed = GetMessage::parse(get.as_bytes()).unwrap();
+    let again = GetMessage::encode(
+        parsed.block_type(),
+        *parsed.flags(),
+        parsed.replication_level(),
+        parsed.peer_bloom_filter().clone(),
+        *parsed.query_hash(),
+        parsed.result_filter(),
+        parsed.xquery(),
+    )
+    .unwrap();
+    assert_eq!(again.as_bytes(), get.as_bytes());
+});
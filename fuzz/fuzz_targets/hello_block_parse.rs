@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::{
+    block::{BlockKey, BlockOperation, HelloBlock},
+    message::Hello,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(hello) = HelloBlock::parse(data) {
+        hello.addresses().for_each(drop);
+        // the rest of the input doubles as a result filter
+        let mut filter = data.to_vec();
+        hello.filter_result(&BlockKey::from([0; 64]), &mut filter, &[]);
+    }
+    if let Ok(hello) = Hello::parse(data) {
+        hello.addresses().for_each(drop);
+    }
+});
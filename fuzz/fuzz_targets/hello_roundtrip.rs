@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::block::{HelloBlock, HelloBlockOwned};
+
+// a signed HELLO verifies, and encodes back to the same bytes
+fuzz_target!(|hello: HelloBlockOwned| {
+    let parsed = HelloBlock::parse(hello.as_bytes()).unwrap();
+    let again = HelloBlock::encode(
+        &parsed.peer(),
+        parsed.signature(),
+        parsed.expiration(),
+        parsed.raw_addresses(),
+    );
+    assert_eq!(again, hello.as_bytes());
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::message::{dissect::explain, PutMessage};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(put) = PutMessage::parse(data) {
+        assert!(put.block().len() <= data.len());
+    }
+    if let Ok(dissect) = explain(data) {
+        let _ = dissect.to_string();
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::message::{PutMessage, PutMessageOwned};
+
+// a PUT that starts here encodes back to the same bytes from its fields
+fuzz_target!(|put: PutMessageOwned| {
+    let parsed = PutMessage::parse(put.as_bytes()).unwrap();
+    let again = PutMessage::encode(
+        parsed.block_type(),
+        *parsed.flags(),
+        parsed.replication_level(),
+        parsed.expiration(),
+        parsed.peer_bloom_filter().clone(),
+        *parsed.block_key(),
+        parsed.last_hop_signature(),
+        parsed.block(),
+    )
+    .unwrap();
+    assert_eq!(again.as_bytes(), put.as_bytes());
+    assert!(parsed.put_path().is_empty());
+});
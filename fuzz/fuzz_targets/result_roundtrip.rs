@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use r6n::message::{ResultMessage, ResultMessageOwned};
+
+// a RESULT that starts here encodes back to the same bytes from its fields
+fuzz_target!(|result: ResultMessageOwned| {
+    let parsed = ResultMessage::parse(result.as_bytes()).unwrap();
+    let again = ResultMessage::encode(
+        parsed.block_type(),
+        parsed.expiration(),
+        *parsed.query_hash(),
+        parsed.block(),
+    )
+    .unwrap();
+    assert_eq!(again.as_bytes(), result.as_bytes());
+});
@@ -0,0 +1,452 @@
+//! Keeping our own HELLO fresh.
+//!
+//! With an identity, the node signs its own HELLO from the addresses the
+//! underlay reports. A new one is signed a while before the last expires,
+//! and soon after the addresses change. Both wait a random part of
+//! [`jitter`](LocalHelloConfig::jitter), so that a burst of address
+//! changes makes one HELLO, and restarted peers don't all re-sign at once.
+//!
+//! The addresses themselves are kept in an [`AddressBook`], which orders
+//! them the way peers should try them. Only the first few go in the HELLO,
+//! up to [`max_addresses`](LocalHelloConfig::max_addresses) and
+//! [`max_address_bytes`](LocalHelloConfig::max_address_bytes), as a HELLO
+//! has to fit in a message and peers won't try dozens of addresses anyway.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use rand::{Rng, RngCore};
+
+use crate::{
+    block::Timestamp,
+    gossip::SignedHello,
+    identity::LocalPeer,
+    underlay::{AddressSchemes, SchemePreference},
+};
+
+/// How widely an address can be reached from, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reachability {
+    Public,
+    /// not an IP address, eg `memory://1`
+    Other,
+    /// a private or unique local network
+    Private,
+    LinkLocal,
+    /// the loopback or unspecified address
+    Loopback,
+}
+
+impl Reachability {
+    /// Classify an address by the IP after its scheme, eg
+    /// `ip+udp://192.168.1.2:2086` is [`Private`](Self::Private).
+    pub fn of(addr: &str) -> Self {
+        let rest = addr.split_once("://").map_or(addr, |(_, rest)| rest);
+        let ip = match rest.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => match rest.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => return Self::Other,
+            },
+        };
+        match ip.to_canonical() {
+            ip if ip.is_loopback() || ip.is_unspecified() => Self::Loopback,
+            IpAddr::V4(ip) if ip.is_link_local() => Self::LinkLocal,
+            IpAddr::V4(ip) if ip.is_private() => Self::Private,
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => Self::LinkLocal,
+            IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 => Self::Private,
+            _ => Self::Public,
+        }
+    }
+}
+
+/// Our own addresses, as the underlay reports them. Repeated reports of an
+/// address are ignored, as are addresses with schemes we don't
+/// [support](Self::set_schemes). They are listed by
+/// [`Reachability`], then by [scheme preference](Self::set_preference),
+/// then in the order they were added.
+pub struct AddressBook<A> {
+    addresses: Vec<A>,
+    /// if set, only addresses valid for these are kept
+    schemes: Option<AddressSchemes>,
+    preference: SchemePreference,
+}
+
+impl<A: Clone + PartialEq + fmt::Display> AddressBook<A> {
+    pub fn new() -> Self {
+        Self {
+            addresses: Vec::new(),
+            schemes: None,
+            preference: SchemePreference::default(),
+        }
+    }
+
+    /// Returns whether the address is new and supported.
+    pub fn add(&mut self, addr: A) -> bool {
+        let supported = match &self.schemes {
+            Some(schemes) => schemes.is_valid(&addr.to_string()),
+            None => true,
+        };
+        if !supported || self.addresses.contains(&addr) {
+            return false;
+        }
+        self.addresses.push(addr);
+        self.sort();
+        true
+    }
+
+    /// Returns whether we had the address.
+    pub fn remove(&mut self, addr: &A) -> bool {
+        let before = self.addresses.len();
+        self.addresses.retain(|a| a != addr);
+        self.addresses.len() != before
+    }
+
+    /// Drop addresses that aren't valid for these schemes, now and as
+    /// they are added. Returns whether any were dropped.
+    pub fn set_schemes(&mut self, schemes: AddressSchemes) -> bool {
+        let before = self.addresses.len();
+        self.addresses.retain(|a| schemes.is_valid(&a.to_string()));
+        self.schemes = Some(schemes);
+        self.addresses.len() != before
+    }
+
+    pub fn preference(&self) -> &SchemePreference {
+        &self.preference
+    }
+
+    /// Returns whether the order changed.
+    pub fn set_preference(&mut self, preference: SchemePreference) -> bool {
+        self.preference = preference;
+        let before = self.addresses.clone();
+        self.sort();
+        self.addresses != before
+    }
+
+    /// Best first, see [`AddressBook`]
+    pub fn addresses(&self) -> &[A] {
+        &self.addresses
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    fn sort(&mut self) {
+        let preference = &self.preference;
+        self.addresses.sort_by_cached_key(|addr| {
+            let addr = addr.to_string();
+            (Reachability::of(&addr), preference.rank(&addr))
+        });
+    }
+}
+
+impl<A: Clone + PartialEq + fmt::Display> Default for AddressBook<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: fmt::Debug> fmt::Debug for AddressBook<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AddressBook")
+            .field("addresses", &self.addresses)
+            .field("schemes", &self.schemes)
+            .field("preference", &self.preference)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct LocalHelloConfig {
+    /// How long each HELLO is valid for
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub lifetime: Duration,
+    /// How long before it expires a HELLO is replaced
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub refresh_before: Duration,
+    /// The most signing is put off by, see the [module docs](self)
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub jitter: Duration,
+    /// The most addresses advertised
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub max_addresses: Option<usize>,
+    /// The most bytes of addresses advertised, counting each one's NUL
+    pub max_address_bytes: usize,
+}
+
+impl Default for LocalHelloConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(12 * 60 * 60),
+            refresh_before: Duration::from_secs(60 * 60),
+            jitter: Duration::from_secs(5),
+            max_addresses: Some(8),
+            max_address_bytes: 1024,
+        }
+    }
+}
+
+impl LocalHelloConfig {
+    /// The addresses to advertise, the first that fit within the limits,
+    /// and how many were left out.
+    pub fn select<'a>(&self, addrs: impl IntoIterator<Item = &'a str>) -> (Vec<&'a str>, usize) {
+        let max = self.max_addresses.unwrap_or(usize::MAX);
+        let mut bytes = 0;
+        let mut omitted = 0;
+        let mut selected = Vec::new();
+        for addr in addrs {
+            if selected.len() < max && bytes + addr.len() < self.max_address_bytes {
+                bytes += addr.len() + 1;
+                selected.push(addr);
+            } else {
+                omitted += 1;
+            }
+        }
+        (selected, omitted)
+    }
+}
+
+/// When to sign our next HELLO. See the [module docs](self).
+#[derive(Debug)]
+pub struct LocalHello {
+    config: LocalHelloConfig,
+    next: Option<Duration>,
+    /// cleared when the application advertises a HELLO of its own
+    automatic: bool,
+    /// addresses left out of the last HELLO
+    omitted: usize,
+}
+
+impl LocalHello {
+    pub fn new(config: LocalHelloConfig) -> Self {
+        Self {
+            config,
+            next: None,
+            automatic: true,
+            omitted: 0,
+        }
+    }
+
+    pub fn config(&self) -> &LocalHelloConfig {
+        &self.config
+    }
+
+    /// The next HELLO is signed on the old schedule, and with the new
+    /// lifetime.
+    pub fn set_config(&mut self, config: LocalHelloConfig) {
+        self.config = config;
+    }
+
+    pub fn is_automatic(&self) -> bool {
+        self.automatic
+    }
+
+    /// Stop signing HELLOs, eg as the application advertises its own, or
+    /// start again with one signed right away.
+    pub fn set_automatic(&mut self, automatic: bool, now: Duration) {
+        self.automatic = automatic;
+        self.next = automatic.then_some(now);
+    }
+
+    /// Sign a new HELLO soon, as the addresses changed.
+    pub fn addresses_changed(&mut self, now: Duration, rng: &mut dyn RngCore) {
+        let at = now.saturating_add(self.jitter(rng));
+        self.next = Some(self.next.map_or(at, |next| next.min(at)));
+    }
+
+    /// Put off the next HELLO until the addresses change, eg as there's
+    /// nothing to sign it with yet.
+    pub fn cancel(&mut self) {
+        self.next = None;
+    }
+
+    /// When a new HELLO is due, if one is
+    pub fn next_due(&self) -> Option<Duration> {
+        self.next.filter(|_| self.automatic)
+    }
+
+    /// How many addresses were left out of the last HELLO, as they were
+    /// over the limits
+    pub fn omitted(&self) -> usize {
+        self.omitted
+    }
+
+    /// Sign a HELLO advertising the first of `addrs` that fit, if one is
+    /// due at `now`, which is `timestamp` on the wall clock.
+    pub fn poll<'a>(
+        &mut self,
+        identity: &LocalPeer,
+        addrs: impl IntoIterator<Item = &'a str>,
+        now: Duration,
+        timestamp: Timestamp,
+        rng: &mut dyn RngCore,
+    ) -> Option<SignedHello> {
+        if self.next_due().is_none_or(|next| next > now) {
+            return None;
+        }
+        let expiration = timestamp.saturating_add(self.config.lifetime);
+        let refresh = self
+            .config
+            .lifetime
+            .saturating_sub(self.config.refresh_before);
+        self.next = Some(now.saturating_add(refresh.saturating_sub(self.jitter(rng))));
+        let (addrs, omitted) = self.config.select(addrs);
+        self.omitted = omitted;
+        Some(identity.sign_hello(expiration, addrs))
+    }
+
+    // in whole milliseconds, which every clock can wait for exactly
+    fn jitter(&self, rng: &mut dyn RngCore) -> Duration {
+        let max = self.config.jitter.as_millis().min(u64::MAX.into()) as u64;
+        Duration::from_millis(rng.gen_range(0..=max))
+    }
+}
+
+impl Default for LocalHello {
+    fn default() -> Self {
+        Self::new(LocalHelloConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::Timestamp,
+        identity::LocalPeer,
+        testing::identities,
+        underlay::{AddressSchemes, SchemePreference},
+    };
+
+    use super::{AddressBook, LocalHello, LocalHelloConfig, Reachability};
+
+    #[test]
+    fn reachability() {
+        for (addr, reachability) in [
+            ("ip+udp://1.1.1.1:2086", Reachability::Public),
+            ("quic://[2001:db8::1]:443", Reachability::Public),
+            ("memory://1", Reachability::Other),
+            ("ip+tcp://10.0.0.1:2086", Reachability::Private),
+            ("ip+tcp://[fd00::1]:2086", Reachability::Private),
+            ("ip+udp://169.254.0.1:2086", Reachability::LinkLocal),
+            ("ip+udp://[fe80::1]:2086", Reachability::LinkLocal),
+            ("ip+udp://127.0.0.1:2086", Reachability::Loopback),
+            ("ip+udp://[::ffff:127.0.0.1]:2086", Reachability::Loopback),
+        ] {
+            assert_eq!(Reachability::of(addr), reachability, "{addr}");
+        }
+    }
+
+    #[test]
+    fn select() {
+        let mut config = LocalHelloConfig {
+            max_addresses: Some(2),
+            ..Default::default()
+        };
+        let addrs = ["memory://1", "memory://22", "memory://333"];
+        assert_eq!(config.select(addrs), (vec!["memory://1", "memory://22"], 1));
+        config.max_addresses = None;
+        // each address counts its NUL
+        config.max_address_bytes = 23;
+        assert_eq!(config.select(addrs), (vec!["memory://1", "memory://22"], 1));
+        config.max_address_bytes = 22;
+        assert_eq!(config.select(addrs), (vec!["memory://1"], 2));
+        // a shorter address later on still fits
+        config.max_address_bytes = 11;
+        assert_eq!(
+            config.select(["memory://22", "memory://1"]),
+            (vec!["memory://1"], 1)
+        );
+    }
+
+    #[test]
+    fn address_book() {
+        let mut book = AddressBook::new();
+        let add = |book: &mut AddressBook<String>, addr: &str| book.add(addr.to_owned());
+        assert!(add(&mut book, "ip+udp://127.0.0.1:1"));
+        assert!(add(&mut book, "ip+udp://[fe80::1]:1"));
+        assert!(add(&mut book, "ip+udp://1.1.1.1:1"));
+        assert!(add(&mut book, "ip+tcp://1.1.1.1:1"));
+        assert!(add(&mut book, "quic://1.1.1.1:1"));
+        assert!(!add(&mut book, "quic://1.1.1.1:1"));
+        assert_eq!(
+            book.addresses(),
+            [
+                "ip+udp://1.1.1.1:1",
+                "ip+tcp://1.1.1.1:1",
+                "quic://1.1.1.1:1",
+                "ip+udp://[fe80::1]:1",
+                "ip+udp://127.0.0.1:1",
+            ]
+        );
+
+        assert!(book.set_preference(SchemePreference::new(["quic", "ip+tcp", "ip+udp"])));
+        assert_eq!(
+            book.addresses()[..3],
+            [
+                "quic://1.1.1.1:1",
+                "ip+tcp://1.1.1.1:1",
+                "ip+udp://1.1.1.1:1"
+            ]
+        );
+        assert!(!book.set_preference(book.preference().clone()));
+
+        assert!(book.remove(&"ip+udp://127.0.0.1:1".to_owned()));
+        assert!(!book.remove(&"ip+udp://127.0.0.1:1".to_owned()));
+        assert!(!book.set_schemes(AddressSchemes::builtin()));
+        assert!(!add(&mut book, "memory://1"));
+        assert_eq!(book.len(), 4);
+    }
+
+    #[test]
+    fn schedule() {
+        let identity = LocalPeer::new(identities::host().signing_key());
+        let mut rng = StdRng::seed_from_u64(0);
+        let secs = Duration::from_secs;
+        let mut local = LocalHello::new(LocalHelloConfig {
+            lifetime: secs(100),
+            refresh_before: secs(10),
+            jitter: secs(5),
+            ..Default::default()
+        });
+        let mut poll = |local: &mut LocalHello, now| {
+            let timestamp = Timestamp::from_micros(0).saturating_add(now);
+            local.poll(&identity, ["memory://0"], now, timestamp, &mut rng)
+        };
+        assert_eq!(local.next_due(), None);
+
+        local.addresses_changed(secs(10), &mut StdRng::seed_from_u64(1));
+        let due = local.next_due().unwrap();
+        assert!(due >= secs(10) && due <= secs(15));
+        assert!(poll(&mut local, due - Duration::from_nanos(1)).is_none());
+        let hello = poll(&mut local, due).unwrap();
+        assert_eq!(
+            hello.expiration(),
+            Timestamp::from_micros(0).saturating_add(due + secs(100))
+        );
+        // re-signed before it expires
+        let next = local.next_due().unwrap();
+        assert!(next >= due + secs(85) && next <= due + secs(90));
+
+        local.set_automatic(false, secs(0));
+        assert!(poll(&mut local, secs(1000)).is_none());
+        local.set_automatic(true, secs(1000));
+        assert!(poll(&mut local, secs(1000)).is_some());
+    }
+}
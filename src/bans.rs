@@ -0,0 +1,220 @@
+//! Banning peers that misbehave.
+//!
+//! Each peer's offences are counted: messages that don't parse, signatures
+//! that don't check out and messages dropped by the rate limiter. A peer
+//! that reaches the limit for any of them is banned for a while, which
+//! means its messages are ignored and it isn't routed to. Counts survive
+//! reconnecting, and start over once a ban ends.
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::Peer;
+
+/// What a peer can be banned for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offence {
+    /// A message that doesn't parse
+    Malformed,
+    /// A HELLO, or a block, with a bad signature
+    InvalidSignature,
+    /// A message dropped for going over the rate limits
+    RateLimited,
+}
+
+/// How many of each offence get a peer banned. `None`, or `"unlimited"`
+/// with serde, never does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct BanConfig {
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub malformed: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub invalid_signatures: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub rate_limited: Option<u32>,
+    /// How long bans last
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub duration: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            malformed: Some(10),
+            invalid_signatures: Some(3),
+            rate_limited: Some(1000),
+            duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl BanConfig {
+    /// Never ban anyone
+    pub fn disabled() -> Self {
+        Self {
+            malformed: None,
+            invalid_signatures: None,
+            rate_limited: None,
+            ..Self::default()
+        }
+    }
+
+    fn limit(&self, offence: Offence) -> Option<u32> {
+        match offence {
+            Offence::Malformed => self.malformed,
+            Offence::InvalidSignature => self.invalid_signatures,
+            Offence::RateLimited => self.rate_limited,
+        }
+    }
+}
+
+/// A peer's offences since it was last banned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Offences {
+    pub malformed: u32,
+    pub invalid_signatures: u32,
+    pub rate_limited: u32,
+}
+
+impl Offences {
+    fn count(&mut self, offence: Offence) -> &mut u32 {
+        match offence {
+            Offence::Malformed => &mut self.malformed,
+            Offence::InvalidSignature => &mut self.invalid_signatures,
+            Offence::RateLimited => &mut self.rate_limited,
+        }
+    }
+}
+
+/// Offences and bans. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct BanList {
+    config: BanConfig,
+    offences: HashMap<Peer, Offences>,
+    /// when each ban ends
+    bans: HashMap<Peer, Duration>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            offences: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &BanConfig {
+        &self.config
+    }
+
+    /// Bans already made keep their end.
+    pub fn set_config(&mut self, config: BanConfig) {
+        self.config = config;
+    }
+
+    /// Count an offence by `peer`. Returns whether this got it banned.
+    pub fn record(&mut self, peer: Peer, offence: Offence, now: Duration) -> bool {
+        if self.is_banned(&peer, now) {
+            return false;
+        }
+        let count = self.offences.entry(peer).or_default().count(offence);
+        *count = count.saturating_add(1);
+        match self.config.limit(offence) {
+            Some(limit) if *count >= limit => {
+                self.ban(peer, now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ban a peer for the configured [`duration`](BanConfig::duration)
+    pub fn ban(&mut self, peer: Peer, now: Duration) {
+        self.offences.remove(&peer);
+        self.bans
+            .insert(peer, now.saturating_add(self.config.duration));
+    }
+
+    /// Lift a ban, and forget the peer's offences. Returns whether it was
+    /// banned.
+    pub fn unban(&mut self, peer: &Peer) -> bool {
+        self.offences.remove(peer);
+        self.bans.remove(peer).is_some()
+    }
+
+    /// Lift every ban, and forget every offence
+    pub fn clear(&mut self) {
+        self.offences.clear();
+        self.bans.clear();
+    }
+
+    pub fn is_banned(&self, peer: &Peer, now: Duration) -> bool {
+        self.bans.get(peer).is_some_and(|&until| until > now)
+    }
+
+    /// The banned peers, and when their bans end
+    pub fn banned(&self, now: Duration) -> impl Iterator<Item = (Peer, Duration)> + '_ {
+        self.bans
+            .iter()
+            .filter(move |(_, &until)| until > now)
+            .map(|(&peer, &until)| (peer, until))
+    }
+
+    pub fn offences(&self, peer: &Peer) -> Offences {
+        self.offences.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Forget bans that have ended
+    pub fn remove_expired(&mut self, now: Duration) {
+        self.bans.retain(|_, &mut until| until > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::identities;
+
+    use super::{BanConfig, BanList, Offence, Offences};
+
+    #[test]
+    fn bans() {
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let mut bans = BanList::new(BanConfig {
+            invalid_signatures: Some(2),
+            duration: Duration::from_secs(10),
+            ..BanConfig::disabled()
+        });
+        let at = Duration::from_secs;
+
+        for _ in 0..100 {
+            assert!(!bans.record(a, Offence::Malformed, at(0)));
+        }
+        assert!(!bans.record(a, Offence::InvalidSignature, at(0)));
+        let offences = Offences {
+            malformed: 100,
+            invalid_signatures: 1,
+            rate_limited: 0,
+        };
+        assert_eq!(bans.offences(&a), offences);
+        assert!(bans.record(a, Offence::InvalidSignature, at(1)));
+        assert!(bans.is_banned(&a, at(10)));
+        assert!(!bans.is_banned(&b, at(10)));
+        assert_eq!(bans.banned(at(10)).collect::<Vec<_>>(), [(a, at(11))]);
+        assert_eq!(bans.offences(&a), Offences::default());
+
+        // bans end by themselves, or can be lifted
+        assert!(!bans.is_banned(&a, at(11)));
+        bans.ban(b, at(11));
+        assert!(bans.unban(&b));
+        assert!(!bans.is_banned(&b, at(11)));
+        bans.remove_expired(at(11));
+        assert_eq!(bans.banned(at(0)).count(), 0);
+    }
+}
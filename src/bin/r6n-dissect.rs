@@ -0,0 +1,87 @@
+//! Print a breakdown of R5N messages, see [`r6n::message::dissect`].
+//!
+//! ```text
+//! r6n-dissect [--sender PEER] [--receiver PEER] [HEX | --file PATH]...
+//! ```
+//!
+//! Messages are given as hex, or as files of raw bytes. Without either, stdin
+//! is read, as hex if it is and raw bytes otherwise. Several messages can be
+//! given back to back. Knowing the sender and receiver lets more signatures
+//! be checked.
+
+use std::{
+    fs,
+    io::{self, Read},
+    process::ExitCode,
+};
+
+use r6n::{encoding::hex_decode, message::dissect, Peer};
+
+const USAGE: &str = "usage: r6n-dissect [--sender PEER] [--receiver PEER] [HEX | --file PATH]...";
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut sender = None;
+    let mut receiver = None;
+    let mut inputs = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--sender" => sender = Some(peer(&value()?)?),
+            "--receiver" => receiver = Some(peer(&value()?)?),
+            "--file" => {
+                let path = value()?;
+                inputs.push(fs::read(&path).map_err(|e| format!("reading {path}: {e}"))?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ => inputs.push(hex(&arg).ok_or(format!("not hex: {arg}"))?),
+        }
+    }
+    if inputs.is_empty() {
+        let mut stdin = vec![];
+        io::stdin()
+            .read_to_end(&mut stdin)
+            .map_err(|e| format!("reading stdin: {e}"))?;
+        let text = std::str::from_utf8(&stdin).ok();
+        inputs.push(text.and_then(hex).unwrap_or(stdin));
+    }
+
+    for input in &inputs {
+        let mut rest = &input[..];
+        while !rest.is_empty() {
+            let mut explained = dissect::explain(rest).map_err(|e| e.to_string())?;
+            if let Some(sender) = sender {
+                explained = explained.sender(sender);
+            }
+            if let Some(receiver) = receiver {
+                explained = explained.receiver(receiver);
+            }
+            println!("{explained}");
+            // a header claiming 0 bytes fails to parse, so this moves on
+            rest = &rest[explained.size()..];
+        }
+    }
+    Ok(())
+}
+
+fn peer(s: &str) -> Result<Peer, String> {
+    s.parse().map_err(|_| format!("not a peer: {s}"))
+}
+
+/// Hex, ignoring whitespace
+fn hex(s: &str) -> Option<Vec<u8>> {
+    let s: String = s.split_whitespace().collect();
+    let mut bytes = vec![0; s.len() / 2];
+    hex_decode(&s, &mut bytes).ok()?;
+    Some(bytes)
+}
@@ -0,0 +1,135 @@
+//! Manage identities and HELLOs, eg to debug bootstrapping.
+//!
+//! ```text
+//! r6n-hello generate PATH
+//! r6n-hello sign PATH [--lifetime SECS] [--block] ADDR...
+//! r6n-hello decode URI | HEX | --file PATH
+//! ```
+//!
+//! `generate` saves a new identity and prints its peer ID. `sign` prints a
+//! HELLO for the identity at `PATH` advertising `ADDR`s, as a URI or with
+//! `--block` as a hex HELLO block. `decode` checks and prints a HELLO given as
+//! a URI, a hex block, or a file holding a raw block.
+
+use std::{fs, path::Path, process::ExitCode, time::Duration};
+
+use r6n::{
+    block::{HelloBlock, Timestamp},
+    encoding::{hex_decode, hex_encode},
+    gossip::SignedHello,
+    identity::LocalPeer,
+    time::{Clock, SystemClock},
+};
+
+const USAGE: &str = "usage:
+    r6n-hello generate PATH
+    r6n-hello sign PATH [--lifetime SECS] [--block] ADDR...
+    r6n-hello decode URI | HEX | --file PATH";
+
+/// As long as GNUnet's HELLOs last
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["generate", path] => generate(Path::new(path)),
+        ["sign", path, rest @ ..] => sign(Path::new(path), rest),
+        ["decode", "--file", path] => fs::read(path)
+            .map_err(|e| format!("reading {path}: {e}"))
+            .and_then(|block| decode_block(&block)),
+        ["decode", hello] if hello.starts_with("gnunet://") => hello
+            .parse()
+            .map(|hello| print_hello(&hello))
+            .map_err(|e| e.to_string()),
+        ["decode", hex] => {
+            let mut block = vec![0; hex.len() / 2];
+            hex_decode(hex, &mut block)
+                .map_err(|_| "neither a HELLO URI nor hex".to_owned())
+                .and_then(|()| decode_block(&block))
+        }
+        _ => Err(USAGE.to_owned()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn generate(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()));
+    }
+    let identity = LocalPeer::generate();
+    identity
+        .save(path)
+        .map_err(|e| format!("saving {}: {e}", path.display()))?;
+    println!("{}", identity.peer());
+    Ok(())
+}
+
+fn sign(path: &Path, mut args: &[&str]) -> Result<(), String> {
+    let identity = LocalPeer::load(path).map_err(|e| format!("loading {}: {e}", path.display()))?;
+    let mut lifetime = DEFAULT_LIFETIME;
+    let mut block = false;
+    let mut addrs = vec![];
+    while let [arg, rest @ ..] = args {
+        args = rest;
+        match *arg {
+            "--lifetime" => {
+                let [secs, rest @ ..] = args else {
+                    return Err("--lifetime needs a value".to_owned());
+                };
+                args = rest;
+                let secs = secs.parse().map_err(|_| format!("bad lifetime {secs}"))?;
+                lifetime = Duration::from_secs(secs);
+            }
+            "--block" => block = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            addr => addrs.push(addr),
+        }
+    }
+
+    let expiration = SystemClock::new().timestamp().saturating_add(lifetime);
+    let hello = identity.sign_hello(expiration, addrs);
+    hello.to_message().map_err(|e| e.to_string())?;
+    if block {
+        println!("{}", hex_encode(&hello.to_block()));
+    } else {
+        println!("{}", hello.to_uri());
+    }
+    Ok(())
+}
+
+fn decode_block(block: &[u8]) -> Result<(), String> {
+    let block = HelloBlock::parse(block).map_err(|e| e.to_string())?;
+    print_hello(&SignedHello::from_block(&block));
+    Ok(())
+}
+
+fn print_hello(hello: &SignedHello) {
+    println!("peer:       {}", hello.peer());
+    println!("expiration: {}", describe(hello.expiration()));
+    for addr in hello.addresses() {
+        println!("address:    {addr}");
+    }
+    println!("uri:        {}", hello.to_uri());
+}
+
+/// An expiration relative to now, as that's what matters when bootstrapping
+fn describe(expiration: Timestamp) -> String {
+    if expiration == Timestamp::FOREVER {
+        return "never".to_owned();
+    }
+    let now = SystemClock::new().timestamp().as_micros();
+    let at = expiration.as_micros();
+    let secs = |micros: u64| Duration::from_micros(micros).as_secs();
+    if at < now {
+        format!("{at} (expired {}s ago)", secs(now - at))
+    } else {
+        format!("{at} (in {}s)", secs(at - now))
+    }
+}
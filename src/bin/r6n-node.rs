@@ -0,0 +1,284 @@
+//! Run a DHT node.
+//!
+//! ```text
+//! r6n-node [--config PATH] [--identity PATH] [--state DIR]
+//!          [--listen ip+udp://ADDR | ip+tcp://ADDR] [--advertise ADDR]...
+//!          [HELLO URI]...
+//! ```
+//!
+//! The node connects to the peers in the given HELLO URIs, and prints its own
+//! on startup. Without an identity file it runs with a throwaway key, and
+//! without a state dir it starts from scratch each time. Settings not in the
+//! config file can be given in the environment, see
+//! [`DhtConfig::from_toml`](r6n::config::DhtConfig::from_toml).
+//!
+//! Commands are read from stdin, one per line:
+//!
+//! - `get <word> <type>`: print the blocks found under the SHA-512 of `word`
+//! - `put <word> <type> <data>`: store `data` under the SHA-512 of `word`
+//! - `hello`: print our HELLO URI
+//! - `stats`, `dump`: print what the node has done, or its whole state
+//! - `quit`: save the state and stop, as does closing stdin
+
+use std::{
+    io::{self, BufRead},
+    net::SocketAddr,
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
+
+use r6n::{
+    block::BlockKey,
+    client::Dht,
+    config::{DhtBuilder, DhtConfig},
+    gossip::SignedHello,
+    identity::LocalPeer,
+    query::GetOptions,
+    state::NodeState,
+    time::{Clock, SystemClock},
+    underlay::{
+        tcp::{TcpAddress, TcpConfig, TcpUnderlay},
+        udp::{UdpAddress, UdpConfig, UdpUnderlay},
+        Underlay, UnderlaySignal,
+    },
+};
+use sha2::{Digest, Sha512};
+use tokio::sync::mpsc;
+
+const USAGE: &str = "usage: r6n-node [--config PATH] [--identity PATH] [--state DIR] \
+    [--listen ip+udp://ADDR | ip+tcp://ADDR] [--advertise ADDR]... [HELLO URI]...";
+
+/// How long our HELLO is valid for. It isn't renewed, so a node running for
+/// longer than this stops being advertised.
+const HELLO_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+enum Listen {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(UdpAddress(addr)) = s.parse() {
+            Ok(Self::Udp(addr))
+        } else if let Ok(TcpAddress(addr)) = s.parse() {
+            Ok(Self::Tcp(addr))
+        } else {
+            Err(format!("can't listen on {s}"))
+        }
+    }
+}
+
+struct Args {
+    config: Option<PathBuf>,
+    identity: Option<PathBuf>,
+    state: Option<PathBuf>,
+    listen: Listen,
+    advertise: Vec<String>,
+    bootstrap: Vec<SignedHello>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self {
+            config: None,
+            identity: None,
+            state: None,
+            listen: Listen::Udp(([0, 0, 0, 0], 2086).into()),
+            advertise: vec![],
+            bootstrap: vec![],
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--config" => parsed.config = Some(value()?.into()),
+                "--identity" => parsed.identity = Some(value()?.into()),
+                "--state" => parsed.state = Some(value()?.into()),
+                "--listen" => parsed.listen = value()?.parse()?,
+                "--advertise" => parsed.advertise.push(value()?),
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ => {
+                    let hello = arg.parse().map_err(|e| format!("{e}: {arg}"))?;
+                    parsed.bootstrap.push(hello);
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match start(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn start(args: Args) -> Result<(), String> {
+    let config = match &args.config {
+        Some(path) => DhtConfig::from_toml(path),
+        None => DhtConfig::from_toml_str("", std::env::vars()),
+    }
+    .map_err(|e| format!("loading config: {e}"))?;
+    let identity = match &args.identity {
+        Some(path) => LocalPeer::load_or_generate(path),
+        None => Ok(LocalPeer::generate()),
+    }
+    .map_err(|e| format!("loading identity: {e}"))?;
+    let state = match &args.state {
+        Some(dir) => NodeState::load(dir).map_err(|e| format!("loading state: {e}"))?,
+        None => NodeState::default(),
+    };
+
+    let peer = identity.peer();
+    match args.listen {
+        Listen::Udp(addr) => {
+            let (underlay, signals) = UdpUnderlay::bind(peer, addr, UdpConfig::default())
+                .map_err(|e| format!("binding {addr}: {e}"))?;
+            let local = underlay
+                .local_addr()
+                .map_err(|e| e.to_string())?
+                .to_string();
+            serve(args, config, identity, state, underlay, signals, local).await
+        }
+        Listen::Tcp(addr) => {
+            let (underlay, signals) = TcpUnderlay::bind(peer, addr, TcpConfig::default())
+                .await
+                .map_err(|e| format!("binding {addr}: {e}"))?;
+            let local = underlay.local_addr().to_string();
+            serve(args, config, identity, state, underlay, signals, local).await
+        }
+    }
+}
+
+async fn serve<U>(
+    args: Args,
+    config: DhtConfig,
+    identity: LocalPeer,
+    state: NodeState,
+    underlay: U,
+    signals: mpsc::UnboundedReceiver<UnderlaySignal<U>>,
+    local: String,
+) -> Result<(), String>
+where
+    U: Underlay + Send + 'static,
+    U::Address: Send + FromStr,
+{
+    let mut advertise = args.advertise;
+    if advertise.is_empty() {
+        advertise.push(local);
+    }
+    let expiration = SystemClock::new()
+        .timestamp()
+        .saturating_add(HELLO_LIFETIME);
+    let hello = identity.sign_hello(expiration, advertise.iter().map(String::as_str));
+
+    let get_options = config.get;
+    let put_options = config.put;
+    let mut node = DhtBuilder::new(underlay)
+        .identity(identity)
+        .config(config)
+        .state(state)
+        .build()
+        .map_err(|e| format!("invalid config: {e}"))?;
+    node.set_local_hello(hello.clone());
+
+    let mut dht = Dht::spawn(node, signals);
+    dht.set_state_dir(args.state);
+    dht.bootstrap(args.bootstrap).map_err(|e| e.to_string())?;
+    println!("{}", hello.to_uri());
+
+    // stdin is read on its own thread, since tokio's needs a feature we
+    // don't otherwise use
+    let (lines_tx, mut lines) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(line) = lines.recv().await {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["get", word, block_type] => match block_type.parse() {
+                Ok(block_type) => get(&dht, word, block_type, get_options).await,
+                Err(_) => Err(format!("bad block type {block_type}")),
+            },
+            ["put", word, block_type, ..] => match block_type.parse() {
+                Ok(block_type) => {
+                    // the data is everything after the type, spaces and all
+                    let data = line.trim().splitn(4, char::is_whitespace).nth(3);
+                    let data = data.unwrap_or_default().trim_start();
+                    let key = key_for(word);
+                    let sent = dht.put(block_type, key, data.into(), put_options).await;
+                    sent.map(|n| println!("sent to {n} peers"))
+                        .map_err(|e| e.to_string())
+                }
+                Err(_) => Err(format!("bad block type {block_type}")),
+            },
+            ["hello"] => {
+                println!("{}", hello.to_uri());
+                Ok(())
+            }
+            ["stats"] => {
+                println!("{:#?}", dht.stats());
+                Ok(())
+            }
+            ["dump"] => dht
+                .debug_dump()
+                .await
+                .map(|dump| println!("{dump:#?}"))
+                .map_err(|e| e.to_string()),
+            ["quit"] => break,
+            _ => Err(format!("unknown command {line:?}")),
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+        }
+    }
+
+    dht.shutdown()
+        .await
+        .map_err(|e| format!("shutting down: {e}"))
+}
+
+async fn get(dht: &Dht, word: &str, block_type: u32, options: GetOptions) -> Result<(), String> {
+    let mut results = dht
+        .get(key_for(word), block_type, options)
+        .await
+        .map_err(|e| e.to_string())?;
+    let word = word.to_owned();
+    // results keep coming until the query times out, so print them as they
+    // do without holding up other commands
+    tokio::spawn(async move {
+        while let Some(block) = results.next().await {
+            println!(
+                "{word}: {}",
+                String::from_utf8_lossy(&block.data).escape_debug()
+            );
+        }
+    });
+    Ok(())
+}
+
+fn key_for(word: &str) -> BlockKey {
+    BlockKey::from(<[u8; 64]>::from(Sha512::digest(word)))
+}
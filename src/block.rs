@@ -1,9 +1,18 @@
+use alloc::{sync::Arc, vec, vec::Vec};
+
 use curve25519_dalek::edwards::CompressedEdwardsY;
-use ed25519_dalek::{ed25519::SignatureBytes, Signature, Verifier, VerifyingKey};
+use ed25519_dalek::{
+    ed25519::SignatureBytes, Signature, Signer, SigningKey, Verifier, VerifyingKey,
+};
 use sha2::{Digest, Sha512};
 use zerocopy::{big_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
 
-use crate::{bloom::BloomFilter, xor, Peer};
+use crate::{
+    bloom::BloomFilter,
+    error::{CryptoError, DhtError, ParseError},
+    limits::{MAX_HELLO_SIZE, MAX_RESULT_FILTER_SIZE},
+    xor, Peer,
+};
 
 pub enum FilterResult {
     /// Block is a valid result, and there may be more.
@@ -23,7 +32,30 @@ pub enum FilterResult {
     Irrelevant,
 }
 
-trait BlockOperation {
+/// The extended query of a block type, which narrows down the blocks a GET
+/// wants beyond the key, eg to records of some type. On the wire it is the
+/// opaque bytes at the end of a GET.
+pub trait XQuery: Sized {
+    fn encode(&self) -> Vec<u8>;
+    /// `None` if the bytes aren't a query of this type, in which case the
+    /// GET is malformed
+    fn decode(b: &[u8]) -> Option<Self>;
+}
+
+/// For block types without an extended query, where it must be empty
+impl XQuery for () {
+    fn encode(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        b.is_empty().then_some(())
+    }
+}
+
+pub trait BlockOperation {
+    type XQuery: XQuery;
+
     /// is used to evaluate the request for a block as part of GetMessage processing. Here, the block payload is unkown, but if possible the XQuery and Key SHOULD be verified
     fn validate_block_query(key: &BlockKey, x_query: &[u8]) -> bool;
     /// is used to synthesize the block key from the block payload as part of PutMessage and ResultMessage processing. The special return value of NONE implies that this block type does not permit deriving the key from the block. A Key may be returned for a block that is ill-formed
@@ -36,19 +68,98 @@ trait BlockOperation {
     fn filter_result(&self, key: &BlockKey, rf: &mut [u8], x_query: &[u8]) -> FilterResult;
 }
 
-#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[repr(C)]
-pub struct BlockKey([u8; 64]);
+pub struct BlockKey(pub(crate) [u8; 64]);
+
+impl BlockKey {
+    /// An abbreviated form for logs
+    pub fn short(&self) -> crate::encoding::Short<'_> {
+        crate::encoding::Short(&self.0)
+    }
+}
+
+impl From<[u8; 64]> for BlockKey {
+    fn from(value: [u8; 64]) -> Self {
+        Self(value)
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for BlockKey {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(Self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for BlockKey {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        crate::encoding::serialize_key(&self.0, s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BlockKey {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        crate::encoding::deserialize_key(d).map(Self)
+    }
+}
 
 /// https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-8.2
 pub struct HelloBlock<'a> {
+    bytes: &'a [u8],
     header: &'a HelloBlockHeader,
     addrs: Addrs<'a>,
 }
 
+/// A [`HelloBlock`] that owns its bytes, eg to queue it or hand it to
+/// another task
+#[derive(Clone, Debug)]
+pub struct HelloBlockOwned {
+    bytes: Arc<[u8]>,
+}
+
+impl HelloBlockOwned {
+    /// Borrow the block, without checking the signature again
+    pub fn get(&self) -> HelloBlock<'_> {
+        HelloBlock::split(&self.bytes).expect("parsed before")
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A HELLO signed by an arbitrary key, advertising arbitrary addresses
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for HelloBlockOwned {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let key = SigningKey::from_bytes(&u.arbitrary()?);
+        let expiration = u.arbitrary()?;
+        let addrs: Vec<&str> = u.arbitrary()?;
+        // addresses are separated by 0 bytes, so can't contain any
+        if addrs.iter().any(|a| a.contains('\0')) {
+            return Err(arbitrary::Error::IncorrectFormat);
+        }
+        let addrs = encode_addresses(addrs);
+        if size_of::<HelloBlockHeader>() + addrs.len() > MAX_HELLO_SIZE {
+            return Err(arbitrary::Error::IncorrectFormat);
+        }
+        let signature = HelloBlockSignaturePayload::new(expiration, &addrs).sign(&key);
+        let peer = key.verifying_key().into();
+        let block = HelloBlock::encode(&peer, &signature, expiration, &addrs);
+        Ok(HelloBlockOwned {
+            bytes: block.into(),
+        })
+    }
+}
+
 impl BlockOperation for HelloBlock<'_> {
+    type XQuery = ();
+
     fn validate_block_query(_key: &BlockKey, x_query: &[u8]) -> bool {
-        x_query.is_empty()
+        Self::XQuery::decode(x_query).is_some()
     }
 
     fn derive_block_key(&self) -> Option<BlockKey> {
@@ -57,26 +168,16 @@ impl BlockOperation for HelloBlock<'_> {
     }
 
     fn validate_block_store_request(&self) -> bool {
-        let Some(pk): Option<VerifyingKey> = self.header.peer_public_key.try_into().ok() else {
-            return false;
-        };
-
-        let sig = HelloBlockSignaturePayload {
-            size: big_endian::U32::new(80),
-            purpose: big_endian::U32::new(7),
-            expiration: self.header.expiration,
-            hash_addrs: Sha512::digest(self.addrs.0).into(),
-        };
-        let expected_sig = Signature::from_bytes(&self.header.signature);
-
-        pk.verify(sig.as_bytes(), &expected_sig).is_ok()
+        HelloBlockSignaturePayload::new(self.header.expiration, self.addrs.0.as_bytes())
+            .verify(&self.header.peer_public_key.into(), &self.header.signature)
     }
 
     type Mutator = u32;
     fn setup_result_filter(&self, filter_size: u32, mutator: Self::Mutator) -> Vec<u8> {
-        const MAX_BYTES: u32 = 1 << 15;
-        let e = filter_size.next_power_of_two();
-        let b = e * 16 / 4;
+        // the largest power of two that leaves room for the mutator
+        const MAX_BYTES: u32 = MAX_RESULT_FILTER_SIZE as u32 / 2;
+        let e = filter_size.min(MAX_BYTES).next_power_of_two();
+        let b = (e * 4).min(MAX_BYTES);
 
         let mut result_filter = vec![0u8; b as usize + 4];
         result_filter[..4].copy_from_slice(&mutator.to_be_bytes()[..]);
@@ -115,26 +216,99 @@ pub struct HelloBlockHeader {
 }
 
 impl<'a> HelloBlock<'a> {
-    pub fn parse(mut b: &'a [u8]) -> Option<Self> {
-        let header = HelloBlockHeader::ref_from_prefix(b)?;
-        b = b.get(size_of_val(header)..)?;
+    /// `GNUNET_BLOCK_TYPE_DHT_HELLO`
+    pub const BLOCK_TYPE: u32 = 7;
 
-        let sig = HelloBlockSignaturePayload {
-            size: big_endian::U32::new(80),
-            purpose: big_endian::U32::new(7),
-            expiration: header.expiration,
-            hash_addrs: Sha512::digest(b).into(),
-        };
-        let pk: VerifyingKey = header.peer_public_key.try_into().ok()?;
-        let expected_sig = Signature::from_bytes(&header.signature);
-        pk.verify(sig.as_bytes(), &expected_sig).ok()?;
+    /// Parse a HELLO block, checking its signature
+    pub fn parse(b: &'a [u8]) -> Result<Self, DhtError> {
+        let block = Self::split(b)?;
+        let peer = block.peer();
+        if VerifyingKey::from_bytes(peer.as_bytes()).is_err() {
+            return Err(CryptoError::PublicKey.into());
+        }
+        let payload = HelloBlockSignaturePayload::new(block.expiration(), block.raw_addresses());
+        if !payload.verify(&peer, block.signature()) {
+            return Err(CryptoError::Signature.into());
+        }
+        Ok(block)
+    }
 
-        let s = std::str::from_utf8(b).ok()?;
-        Some(Self {
+    /// Parse without checking the signature
+    fn split(b: &'a [u8]) -> Result<Self, ParseError> {
+        const WHAT: &str = "HELLO block";
+        if b.len() > MAX_HELLO_SIZE {
+            return Err(ParseError::TooLarge {
+                what: WHAT,
+                field: "block",
+                len: b.len(),
+                max: MAX_HELLO_SIZE,
+            });
+        }
+        let header = HelloBlockHeader::ref_from_prefix(b).ok_or(ParseError::TooShort {
+            what: WHAT,
+            len: b.len(),
+        })?;
+        let offset = size_of_val(header);
+        let s = core::str::from_utf8(&b[offset..]).map_err(|e| ParseError::Addresses {
+            what: WHAT,
+            offset: offset + e.valid_up_to(),
+        })?;
+        Ok(Self {
+            bytes: b,
             header,
             addrs: Addrs(s),
         })
     }
+
+    pub fn to_owned(&self) -> HelloBlockOwned {
+        HelloBlockOwned {
+            bytes: self.bytes.into(),
+        }
+    }
+
+    /// The whole block
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Encode a HELLO block. `addrs` is the address list as encoded by
+    /// [`encode_addresses`].
+    pub fn encode(
+        peer: &Peer,
+        signature: &SignatureBytes,
+        expiration: Timestamp,
+        addrs: &[u8],
+    ) -> Vec<u8> {
+        let header = HelloBlockHeader {
+            peer_public_key: PublicKey(*peer.as_bytes()),
+            signature: *signature,
+            expiration,
+        };
+        let mut block = header.as_bytes().to_vec();
+        block.extend_from_slice(addrs);
+        block
+    }
+
+    pub fn peer(&self) -> Peer {
+        self.header.peer_public_key.into()
+    }
+
+    pub fn signature(&self) -> &'a SignatureBytes {
+        &self.header.signature
+    }
+
+    pub fn expiration(&self) -> Timestamp {
+        self.header.expiration
+    }
+
+    pub fn addresses(&self) -> Addrs<'a> {
+        self.addrs.clone()
+    }
+
+    /// The addresses as they were encoded and signed
+    pub fn raw_addresses(&self) -> &'a [u8] {
+        self.addrs.0.as_bytes()
+    }
 }
 
 #[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
@@ -146,8 +320,92 @@ pub struct HelloBlockSignaturePayload {
     hash_addrs: [u8; 64],
 }
 
+impl HelloBlockSignaturePayload {
+    /// What a peer signs to advertise `addrs` until `expiration`
+    pub fn new(expiration: Timestamp, addrs: &[u8]) -> Self {
+        Self {
+            size: big_endian::U32::new(80),
+            purpose: big_endian::U32::new(7),
+            expiration,
+            hash_addrs: Sha512::digest(addrs).into(),
+        }
+    }
+
+    pub fn sign(&self, key: &SigningKey) -> SignatureBytes {
+        key.sign(self.as_bytes()).to_bytes()
+    }
+
+    pub fn verify(&self, peer: &Peer, signature: &SignatureBytes) -> bool {
+        let Ok(pk) = VerifyingKey::from_bytes(peer.as_bytes()) else {
+            return false;
+        };
+        pk.verify(self.as_bytes(), &Signature::from_bytes(signature))
+            .is_ok()
+    }
+}
+
+/// What a peer signs when it forwards a block with path recording, so that
+/// the next hop can add it to the path.
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub struct HopSignaturePayload {
+    size: big_endian::U32,
+    purpose: big_endian::U32,
+    expiration: Timestamp,
+    hash_block: [u8; 64],
+    pred: [u8; 32],
+    succ: [u8; 32],
+}
+
+impl HopSignaturePayload {
+    /// A hop forwarding `block` from `pred` to `succ`
+    pub fn new(expiration: Timestamp, block: &[u8], pred: &Peer, succ: &Peer) -> Self {
+        Self {
+            size: big_endian::U32::new(144),
+            // GNUNET_SIGNATURE_PURPOSE_DHT_HOP
+            purpose: big_endian::U32::new(45),
+            expiration,
+            hash_block: Sha512::digest(block).into(),
+            pred: *pred.as_bytes(),
+            succ: *succ.as_bytes(),
+        }
+    }
+
+    pub fn sign(&self, key: &SigningKey) -> SignatureBytes {
+        key.sign(self.as_bytes()).to_bytes()
+    }
+
+    pub fn verify(&self, peer: &Peer, signature: &SignatureBytes) -> bool {
+        let Ok(pk) = VerifyingKey::from_bytes(peer.as_bytes()) else {
+            return false;
+        };
+        pk.verify(self.as_bytes(), &Signature::from_bytes(signature))
+            .is_ok()
+    }
+}
+
+/// The addresses in a HELLO. Use
+/// [`AddressSchemes::is_valid`](crate::underlay::AddressSchemes::is_valid) to
+/// skip those we don't support.
+#[derive(Clone)]
 pub struct Addrs<'a>(&'a str);
 
+impl<'a> Addrs<'a> {
+    pub(crate) fn new(s: &'a str) -> Self {
+        Self(s)
+    }
+}
+
+/// Encode addresses for a HELLO, each terminated by a 0 byte.
+pub fn encode_addresses<'a>(addrs: impl IntoIterator<Item = &'a str>) -> Vec<u8> {
+    let mut out = vec![];
+    for addr in addrs {
+        out.extend_from_slice(addr.as_bytes());
+        out.push(0);
+    }
+    out
+}
+
 impl<'a> Iterator for Addrs<'a> {
     type Item = &'a str;
 
@@ -162,6 +420,77 @@ impl<'a> Iterator for Addrs<'a> {
 #[repr(transparent)]
 pub struct Timestamp(big_endian::U64);
 
+impl Timestamp {
+    /// A timestamp that never expires
+    pub const FOREVER: Self = Self(big_endian::U64::MAX_VALUE);
+
+    /// Microseconds since the UNIX epoch
+    pub fn from_micros(micros: u64) -> Self {
+        Self(big_endian::U64::new(micros))
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Whether this expiration time has passed at `now`
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        *self != Self::FOREVER && self.as_micros() < now.as_micros()
+    }
+
+    /// This time plus `d`, or [`FOREVER`](Self::FOREVER) if that overflows
+    pub fn saturating_add(&self, d: core::time::Duration) -> Self {
+        let micros = u64::try_from(d.as_micros()).unwrap_or(u64::MAX);
+        Self::from_micros(self.as_micros().saturating_add(micros))
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_micros() == other.as_micros()
+    }
+}
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.as_micros().cmp(&other.as_micros())
+    }
+}
+
+/// As microseconds since the UNIX epoch
+#[cfg(feature = "serde")]
+impl serde::Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(self.as_micros())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        u64::deserialize(d).map(Self::from_micros)
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Timestamp {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(Self::from_micros)
+    }
+}
+
+impl core::fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("Timestamp").field(&self.as_micros()).finish()
+    }
+}
+
 #[derive(FromZeroes, FromBytes, AsBytes, Unaligned, Clone, Copy)]
 #[repr(transparent)]
 pub struct PublicKey([u8; 32]);
@@ -184,3 +513,27 @@ impl TryFrom<PublicKey> for ed25519_dalek::VerifyingKey {
         ed25519_dalek::VerifyingKey::from_bytes(&value.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::identities;
+
+    use super::{BlockKey, BlockOperation, HelloBlock, XQuery};
+
+    #[test]
+    fn empty_xquery() {
+        assert!(().encode().is_empty());
+        assert_eq!(<()>::decode(&[]), Some(()));
+        assert_eq!(<()>::decode(&[0]), None);
+    }
+
+    #[test]
+    fn hello_block_query() {
+        let key = BlockKey::from(identities::peers()[0].id);
+        assert!(HelloBlock::validate_block_query(&key, &[]));
+        assert!(!HelloBlock::validate_block_query(&key, b"records"));
+        // any key can name a HELLO
+        let key = BlockKey::from([0; 64]);
+        assert!(HelloBlock::validate_block_query(&key, &[]));
+    }
+}
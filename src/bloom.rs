@@ -1,6 +1,10 @@
-use zerocopy::{big_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
+use alloc::{vec, vec::Vec};
 
-#[derive(FromBytes, FromZeroes, AsBytes, Unaligned)]
+use zerocopy::{big_endian, little_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::{Peer, PeerId};
+
+#[derive(FromBytes, FromZeroes, AsBytes, Unaligned, Clone)]
 #[repr(C)]
 pub struct PeerBloomFilter {
     bits: [u8; 128],
@@ -12,6 +16,15 @@ impl Default for PeerBloomFilter {
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for PeerBloomFilter {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(|bits| Self { bits })
+    }
+}
+
+// the peer bloom filter is part of the wire format, so it always uses all
+// 16 probes per key.
 impl PeerBloomFilter {
     pub fn get_ref(&self) -> BloomFilter<&[u8; 128]> {
         BloomFilter::from(&self.bits).unwrap()
@@ -19,97 +32,168 @@ impl PeerBloomFilter {
     pub fn get_mut(&mut self) -> BloomFilter<&mut [u8; 128]> {
         BloomFilter::from(&mut self.bits).unwrap()
     }
+
+    pub fn insert_peer(&mut self, peer: &Peer) {
+        self.insert_peer_id(&peer.id());
+    }
+    pub fn contains_peer(&self, peer: &Peer) -> bool {
+        self.contains_peer_id(&peer.id())
+    }
+
+    pub fn insert_peer_id(&mut self, id: &PeerId) {
+        self.get_mut().insert(&id.0);
+    }
+    pub fn contains_peer_id(&self, id: &PeerId) -> bool {
+        self.get_ref().test(&id.0)
+    }
 }
 
+/// The maximum number of probes per key. A 512-bit key only has 16 `u32`
+/// chunks to use as bit indices.
+pub const MAX_K: usize = 16;
+
+#[derive(Debug)]
 pub struct BloomFilter<B> {
     byte_mask: usize,
+    /// number of u32 chunks of the key used as bit indices
+    k: usize,
     bytes: B,
 }
 
 impl BloomFilter<Vec<u8>> {
     pub fn new(bits: u32) -> Option<Self> {
+        Self::with_k(bits, MAX_K)
+    }
+
+    /// Create a filter that only sets `k` bits per key. Smaller filters
+    /// saturate quickly when every key sets all 16 bits.
+    pub fn with_k(bits: u32, k: usize) -> Option<Self> {
         if !bits.is_power_of_two() || bits < 8 {
             return None;
         }
         let bytes = usize::try_from(bits / 8).ok()?;
-        let byte_mask = bytes - 1;
-        Some(Self {
-            byte_mask,
-            bytes: vec![0; bytes],
-        })
+        Self::from_with_k(vec![0; bytes], k)
+    }
+}
+
+/// A filter of 8 bytes to 64 KiB, with any number of probes
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for BloomFilter<Vec<u8>> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let len = 8 << u.int_in_range(0..=13)?;
+        let k = u.int_in_range(1..=MAX_K)?;
+        let bytes = u.bytes(len)?.to_vec();
+        Ok(Self::from_with_k(bytes, k).expect("a power of two"))
     }
 }
 
 impl<B: AsRef<[u8]>> BloomFilter<B> {
     pub fn from(bytes: B) -> Option<Self> {
+        Self::from_with_k(bytes, MAX_K)
+    }
+
+    pub fn from_with_k(bytes: B, k: usize) -> Option<Self> {
+        if k == 0 || k > MAX_K {
+            return None;
+        }
         let b = bytes.as_ref().len();
         if !b.is_power_of_two() {
             return None;
         }
         let byte_mask = b - 1;
-        Some(Self { byte_mask, bytes })
+        Some(Self {
+            byte_mask,
+            k,
+            bytes,
+        })
+    }
+
+    /// The number of bits set per key
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_ref()
     }
 }
 
 impl<B: AsRef<[u8]>> BloomFilter<B> {
     /// return true if id is in the filter
     pub fn test(&self, key: &[u8; 64]) -> bool {
-        bf_test_inner(self.bytes.as_ref(), self.byte_mask, key)
+        bf_test_inner(self.bytes.as_ref(), self.byte_mask, self.k, key)
     }
 }
 
 impl<B: AsMut<[u8]>> BloomFilter<B> {
     pub fn insert(&mut self, key: &[u8; 64]) {
-        bf_insert_inner(self.bytes.as_mut(), self.byte_mask, key)
+        bf_insert_inner(self.bytes.as_mut(), self.byte_mask, self.k, key)
     }
 }
 
-fn bf_test_inner(bytes: &[u8], mask: usize, key: &[u8; 64]) -> bool {
-    let keys = Keys::ref_from(key).unwrap();
+fn bf_test_inner(bytes: &[u8], mask: usize, k: usize, key: &[u8; 64]) -> bool {
+    let keys = &Keys::ref_from(key).unwrap().0[..k];
+
+    // filters are a power of two in size, so anything at least 8 bytes
+    // splits evenly into words.
+    if let Some(words) = little_endian::U64::slice_from(bytes) {
+        let bit_mask = bit_mask(mask);
+        return keys.iter().all(|k| {
+            let bit = k.get() as usize & bit_mask;
+            words[bit >> 6].get() >> (bit & 63) & 1 == 1
+        });
+    }
 
-    let mut out = true;
-    for k in &keys.0 {
+    assert!(mask < bytes.len());
+    keys.iter().all(|k| {
         let k = k.get();
         let bit = k & 0x7;
-        let byte = (k >> 3) as usize;
+        // byte <= mask, therefore byte < bytes.len()
+        let byte = (k >> 3) as usize & mask;
+        bytes[byte] >> bit & 1 == 1
+    })
+}
 
-        assert!(mask < bytes.len());
+fn bf_insert_inner(bytes: &mut [u8], mask: usize, k: usize, key: &[u8; 64]) {
+    let keys = &Keys::ref_from(key).unwrap().0[..k];
 
-        let byte = byte & mask;
-        // byte <= mask, therefore byte < bytes.len()
-        if bytes[byte] >> bit & 1 == 0 {
-            out &= false;
+    if bytes.len() >= 8 {
+        let words = little_endian::U64::mut_slice_from(bytes).unwrap();
+        let bit_mask = bit_mask(mask);
+        for k in keys {
+            let bit = k.get() as usize & bit_mask;
+            let word = &mut words[bit >> 6];
+            word.set(word.get() | 1 << (bit & 63));
         }
+        return;
     }
 
-    out
-}
-
-fn bf_insert_inner(bytes: &mut [u8], mask: usize, key: &[u8; 64]) {
-    let keys = Keys::ref_from(key).unwrap();
-
-    for k in &keys.0 {
+    assert!(mask < bytes.len());
+    for k in keys {
         let k = k.get();
         let bit = k & 0x7;
-        let byte = (k >> 3) as usize;
-
-        assert!(mask < bytes.len());
-
-        let byte = byte & mask;
         // byte <= mask, therefore byte < bytes.len()
+        let byte = (k >> 3) as usize & mask;
         bytes[byte] |= 1 << bit;
     }
 }
 
+/// Bit `i` of the filter is bit `i % 8` of byte `i / 8`. Loading 8 bytes as a
+/// little endian u64 keeps that same numbering, so bit `i` is bit `i % 64` of
+/// word `i / 64`.
+fn bit_mask(byte_mask: usize) -> usize {
+    byte_mask << 3 | 0x7
+}
+
 #[derive(FromBytes, FromZeroes)]
-struct Keys([big_endian::U32; 16]);
+struct Keys([big_endian::U32; MAX_K]);
 
 #[cfg(test)]
 mod tests {
     use curve25519_dalek::edwards::CompressedEdwardsY;
 
     use crate::{
-        bloom::{BloomFilter, PeerBloomFilter},
+        bloom::{BloomFilter, PeerBloomFilter, MAX_K},
         Peer,
     };
 
@@ -177,4 +261,70 @@ mod tests {
         assert!(bloom.test(&peer2.0));
         assert!(bloom.test(&peer3.0));
     }
+
+    #[test]
+    fn with_k() {
+        assert!(BloomFilter::with_k(128, 0).is_none());
+        assert!(BloomFilter::with_k(128, MAX_K + 1).is_none());
+
+        let mut bloom = BloomFilter::with_k(128, 4).unwrap();
+        assert_eq!(bloom.k(), 4);
+
+        let peer1 = Peer(CompressedEdwardsY([1; 32])).id();
+        let peer2 = Peer(CompressedEdwardsY([2; 32])).id();
+
+        bloom.insert(&peer1.0);
+        assert!(bloom.test(&peer1.0));
+        assert!(!bloom.test(&peer2.0));
+
+        // only the first 4 probes were set
+        let set: u32 = bloom.bytes.iter().map(|b| b.count_ones()).sum();
+        assert!(set <= 4);
+    }
+
+    #[test]
+    fn peer_filter_uses_all_probes() {
+        let mut bloom = PeerBloomFilter::default();
+        assert_eq!(bloom.get_ref().k(), MAX_K);
+        assert_eq!(bloom.get_mut().k(), MAX_K);
+    }
+
+    #[test]
+    fn words_match_bytes() {
+        // reference implementation, one byte at a time
+        fn insert(bytes: &mut [u8], key: &[u8; 64]) {
+            for k in key.chunks_exact(4) {
+                let k = u32::from_be_bytes(k.try_into().unwrap()) as usize;
+                bytes[(k >> 3) % bytes.len()] |= 1 << (k & 7);
+            }
+        }
+
+        for size in [8, 16, 128, 1024] {
+            let mut expected = vec![0; size];
+            let mut bloom = BloomFilter::from(vec![0; size]).unwrap();
+            for i in 0..8 {
+                let peer = Peer(CompressedEdwardsY([i; 32])).id();
+                insert(&mut expected, &peer.0);
+                bloom.insert(&peer.0);
+                assert!(bloom.test(&peer.0));
+            }
+            assert_eq!(bloom.bytes, expected);
+        }
+    }
+
+    #[test]
+    fn peers() {
+        let mut bloom = PeerBloomFilter::default();
+
+        let peer1 = Peer(CompressedEdwardsY([1; 32]));
+        let peer2 = Peer(CompressedEdwardsY([2; 32]));
+
+        bloom.insert_peer(&peer1);
+        assert!(bloom.contains_peer(&peer1));
+        assert!(bloom.contains_peer_id(&peer1.id()));
+        assert!(!bloom.contains_peer(&peer2));
+
+        bloom.insert_peer_id(&peer2.id());
+        assert!(bloom.contains_peer(&peer2));
+    }
 }
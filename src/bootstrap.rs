@@ -0,0 +1,289 @@
+//! Joining a network for the first time.
+//!
+//! A fresh node doesn't know any peers, so it needs HELLOs from somewhere
+//! else, eg configuration or a hostlist server. [`Bootstrap`] keeps trying
+//! to connect to them, backing off exponentially, until each is connected
+//! or runs out of attempts.
+//!
+//! Hostlists are a stream of HELLO blocks, each framed by a message header
+//! like GNUnet's hostlist daemon serves them. With the `hostlist` feature
+//! they can be downloaded with [`fetch_hostlist`].
+
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::{
+    block::{HelloBlock, Timestamp},
+    gossip::SignedHello,
+    hellos::HelloCache,
+    message::MessageHeader,
+    underlay::{Underlay, UnderlaySignal},
+    Peer,
+};
+
+/// The message type framing each HELLO in a hostlist
+pub const HOSTLIST_ENTRY_TYPE: u16 = 17;
+
+/// Parse a hostlist, skipping HELLOs that are invalid or expired at `now`.
+pub fn parse_hostlist(mut body: &[u8], now: Timestamp) -> Vec<SignedHello> {
+    let mut hellos = vec![];
+    while let Some(header) = MessageHeader::ref_from_prefix(body) {
+        let size = header.message_size() as usize;
+        let Some(entry) = body.get(size_of::<MessageHeader>()..size) else {
+            break;
+        };
+        if let Ok(block) = HelloBlock::parse(entry) {
+            let hello = SignedHello::from_block(&block);
+            if !hello.is_expired(now) {
+                hellos.push(hello);
+            }
+        }
+        body = &body[size..];
+    }
+    hellos
+}
+
+/// Encode HELLOs as a hostlist, eg to serve one. HELLOs too big for a
+/// message are left out.
+pub fn encode_hostlist<'a>(hellos: impl IntoIterator<Item = &'a SignedHello>) -> Vec<u8> {
+    let mut body = vec![];
+    for hello in hellos {
+        let block = hello.to_block();
+        let size = size_of::<MessageHeader>() + block.len();
+        if let Some(header) = MessageHeader::new(size, HOSTLIST_ENTRY_TYPE) {
+            body.extend_from_slice(header.as_bytes());
+            body.extend_from_slice(&block);
+        }
+    }
+    body
+}
+
+/// Download a hostlist over HTTP or HTTPS. This blocks, so in async code it
+/// should run on a blocking thread.
+#[cfg(feature = "hostlist")]
+pub fn fetch_hostlist(url: &str) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    // hostlists are a few kilobytes, anything much bigger is a mistake
+    const MAX_SIZE: u64 = 1 << 20;
+
+    let response = ureq::get(url).call().map_err(std::io::Error::other)?;
+    let mut body = vec![];
+    response
+        .into_reader()
+        .take(MAX_SIZE)
+        .read_to_end(&mut body)?;
+    Ok(body)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootstrapConfig {
+    /// The wait after the first failed attempt
+    pub initial_backoff: Duration,
+    /// The wait between attempts doubles up to this
+    pub max_backoff: Duration,
+    /// Peers are given up on after this many attempts
+    pub max_attempts: u32,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(600),
+            max_attempts: 10,
+        }
+    }
+}
+
+/// Peers to connect to until they are connected.
+///
+/// Feed it the underlay's signals with [`handle_signal`](Self::handle_signal)
+/// and call [`poll`](Self::poll) when it is due. Times are durations since
+/// the node's epoch, as with [`Clock::now`](crate::time::Clock::now).
+pub struct Bootstrap {
+    config: BootstrapConfig,
+    candidates: HashMap<Peer, Candidate>,
+}
+
+struct Candidate {
+    /// of the HELLO the addresses are from, so that newer ones replace them
+    expiration: Timestamp,
+    addresses: Vec<String>,
+    attempts: u32,
+    next: Duration,
+}
+
+impl Bootstrap {
+    pub fn new(config: BootstrapConfig) -> Self {
+        Self {
+            config,
+            candidates: HashMap::new(),
+        }
+    }
+
+    /// Start connecting to this peer on the next poll. A newer HELLO for a
+    /// peer that is already a candidate replaces the old one.
+    pub fn add(&mut self, hello: SignedHello, now: Duration) {
+        let addresses = hello.addresses().map(str::to_owned).collect();
+        self.add_addresses(*hello.peer(), hello.expiration(), addresses, now);
+    }
+
+    /// Start connecting to a peer from its transport HELLO, eg from an
+    /// older peer's hostlist. Addresses whose transport we have no
+    /// equivalent for are left out. A signed HELLO for the same peer
+    /// replaces it if it is newer.
+    #[cfg(feature = "legacy-hello")]
+    pub fn add_legacy(&mut self, hello: &crate::legacy::LegacyHello, now: Duration) {
+        let addresses = hello.addresses().collect();
+        self.add_addresses(*hello.peer(), hello.expiration(), addresses, now);
+    }
+
+    fn add_addresses(
+        &mut self,
+        peer: Peer,
+        expiration: Timestamp,
+        addresses: Vec<String>,
+        now: Duration,
+    ) {
+        match self.candidates.get_mut(&peer) {
+            Some(c) if c.expiration < expiration => {
+                c.expiration = expiration;
+                c.addresses = addresses;
+            }
+            Some(_) => {}
+            None => {
+                let candidate = Candidate {
+                    expiration,
+                    addresses,
+                    attempts: 0,
+                    next: now,
+                };
+                self.candidates.insert(peer, candidate);
+            }
+        }
+    }
+
+    /// Reconnect to every peer with a cached HELLO, eg one
+    /// [restored](crate::DhtNode::restore) from before a restart.
+    pub fn add_cached(&mut self, cache: &HelloCache, now: Duration) {
+        for hello in cache.iter() {
+            self.add(hello.clone(), now);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Stop trying peers once they are connected.
+    pub fn handle_signal<U: Underlay>(&mut self, signal: &UnderlaySignal<U>) {
+        if let UnderlaySignal::PeerConnected(peer, _) = signal {
+            self.candidates.remove(peer);
+        }
+    }
+
+    /// Try to connect to every peer that is due, each time using the next
+    /// address in its HELLO that the underlay understands. Returns when it
+    /// should be polled next, if there are peers left to try.
+    pub fn poll<U>(&mut self, underlay: &U, now: Duration) -> Option<Duration>
+    where
+        U: Underlay,
+        U::Address: FromStr,
+    {
+        let config = self.config;
+        self.candidates.retain(|&peer, c| {
+            if c.next > now {
+                return true;
+            }
+            let addrs: Vec<U::Address> =
+                c.addresses.iter().filter_map(|a| a.parse().ok()).collect();
+            if addrs.is_empty() {
+                return false;
+            }
+
+            let addr = addrs[c.attempts as usize % addrs.len()].clone();
+            // a successful start doesn't mean it will connect, so the next
+            // attempt is scheduled regardless
+            let _ = underlay.try_connect(peer, addr);
+            let backoff = config.initial_backoff * 2u32.saturating_pow(c.attempts);
+            c.attempts += 1;
+            c.next = now + backoff.min(config.max_backoff);
+            c.attempts < config.max_attempts
+        });
+        self.candidates.values().map(|c| c.next).min()
+    }
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Self::new(BootstrapConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::Timestamp,
+        gossip::SignedHello,
+        testing::identities,
+        underlay::{memory::MemoryNetwork, UnderlaySignal},
+    };
+
+    use super::{encode_hostlist, parse_hostlist, Bootstrap, BootstrapConfig};
+
+    #[test]
+    fn hostlist() {
+        let at = Timestamp::from_micros;
+        let hellos: Vec<SignedHello> = identities::peers()[..3]
+            .iter()
+            .enumerate()
+            .map(|(i, f)| SignedHello::sign(&f.signing_key(), at(100 * i as u64), ["memory://1"]))
+            .collect();
+        let mut body = encode_hostlist(&hellos);
+        // a bad entry is skipped, and a truncated one ends the list
+        body.splice(0..0, [0, 6, 0, 17, 1, 2]);
+        body.extend_from_slice(&[0, 200, 0, 17]);
+
+        assert_eq!(parse_hostlist(&body, at(50)), hellos[1..]);
+    }
+
+    #[test]
+    fn backoff() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let (ua, rxa) = network.join(a.peer());
+        let (_ub, _rxb) = network.join(b.peer());
+        let secs = Duration::from_secs;
+        let mut bootstrap = Bootstrap::new(BootstrapConfig {
+            max_attempts: 3,
+            ..Default::default()
+        });
+
+        // b isn't at the first address, so it takes a second attempt
+        let hello = SignedHello::sign(
+            &b.signing_key(),
+            Timestamp::FOREVER,
+            ["memory://0", "ip+udp://127.0.0.1:1", "memory://1"],
+        );
+        bootstrap.add(hello, secs(0));
+        assert_eq!(bootstrap.poll(&ua, secs(0)), Some(secs(1)));
+        assert!(!rxa
+            .try_iter()
+            .any(|s| matches!(s, UnderlaySignal::PeerConnected(..))));
+        assert_eq!(bootstrap.poll(&ua, secs(0)), Some(secs(1)));
+
+        assert_eq!(bootstrap.poll(&ua, secs(1)), Some(secs(3)));
+        for signal in rxa.try_iter() {
+            bootstrap.handle_signal(&signal);
+        }
+        assert!(bootstrap.is_empty());
+    }
+}
@@ -0,0 +1,753 @@
+//! An async API over a [`DhtNode`] running on tokio.
+//!
+//! [`Dht::spawn`] moves the node into a task that feeds it the underlay's
+//! signals and runs its maintenance, and the returned handle can be cloned
+//! to get and put blocks from anywhere. [`Dht::shutdown`] stops it cleanly.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+
+use crate::{
+    block::{BlockKey, BlockOperation, Timestamp, XQuery},
+    bootstrap::Bootstrap,
+    config::{ConfigError, ConfigUpdate},
+    debug::DebugDump,
+    gossip::SignedHello,
+    maintenance::Budget,
+    metrics::{Metrics, Stats},
+    node::{PutError, PutOptions},
+    query::{GetOptions, QueryEvent, QueryId},
+    time::{TimerDriver, TokioTimer},
+    underlay::{Underlay, UnderlaySignal},
+    DhtNode, Distance,
+};
+
+/// A block found by a GET.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The key the block is stored under, for block types that say. With
+    /// [`find_approximate`](GetOptions::find_approximate), it can differ
+    /// from the key asked for.
+    pub key: Option<BlockKey>,
+    pub block_type: u32,
+    pub expiration: Timestamp,
+    pub data: Vec<u8>,
+}
+
+/// How many results a [`GetStream`] buffers unless configured otherwise
+const DEFAULT_RESULT_BUFFER: usize = 16;
+
+/// How long shutting down waits for queued messages to be sent
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Command {
+    Get {
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        /// for watches
+        repeat: Option<Duration>,
+        results: mpsc::Sender<Block>,
+        started: oneshot::Sender<QueryId>,
+    },
+    Exclude(QueryId, Vec<u8>),
+    Cancel(QueryId),
+    Put {
+        block_type: u32,
+        key: BlockKey,
+        block: Vec<u8>,
+        options: PutOptions,
+        done: oneshot::Sender<Result<usize, PutError>>,
+    },
+    Bootstrap(Vec<SignedHello>),
+    DebugDump(oneshot::Sender<DebugDump>),
+    UpdateConfig(Box<ConfigUpdate>, oneshot::Sender<Result<(), ConfigError>>),
+    Shutdown {
+        state_dir: Option<PathBuf>,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// A handle to a running node. The node stops once every handle is dropped,
+/// when the underlay's signal channel closes, or on
+/// [`shutdown`](Self::shutdown).
+#[derive(Clone)]
+pub struct Dht {
+    commands: mpsc::UnboundedSender<Command>,
+    result_buffer: usize,
+    state_dir: Option<PathBuf>,
+    metrics: Arc<Metrics>,
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the node has stopped")
+}
+
+impl Dht {
+    /// Run the node on the current tokio runtime.
+    pub fn spawn<U>(node: DhtNode<U>, signals: mpsc::UnboundedReceiver<UnderlaySignal<U>>) -> Self
+    where
+        U: Underlay + Send + 'static,
+        U::Address: Send + FromStr,
+    {
+        let timer = TokioTimer::new(node.clock().clone());
+        Self::spawn_with_timer(node, signals, timer)
+    }
+
+    /// Like [`spawn`](Self::spawn), but waiting for the node's timers with
+    /// `timer`, eg a [`ManualTimer`](crate::time::ManualTimer) to run it in
+    /// virtual time. The node should be on the timer's
+    /// [clock](TimerDriver::clock).
+    pub fn spawn_with_timer<U, T>(
+        node: DhtNode<U>,
+        signals: mpsc::UnboundedReceiver<UnderlaySignal<U>>,
+        timer: T,
+    ) -> Self
+    where
+        U: Underlay + Send + 'static,
+        U::Address: Send + FromStr,
+        T: TimerDriver,
+    {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let metrics = node.metrics().clone();
+        tokio::spawn(run(node, signals, rx, timer));
+        Self {
+            commands,
+            result_buffer: DEFAULT_RESULT_BUFFER,
+            state_dir: None,
+            metrics,
+        }
+    }
+
+    /// What the node has done so far.
+    pub fn stats(&self) -> Stats {
+        self.metrics.snapshot()
+    }
+
+    /// How many results each [`GetStream`] buffers before its query is
+    /// paused. At least 1.
+    pub fn set_result_buffer(&mut self, size: usize) {
+        self.result_buffer = size.max(1);
+    }
+
+    /// Where [`shutdown`](Self::shutdown) saves the node's
+    /// [state](crate::state), if anywhere.
+    pub fn set_state_dir(&mut self, dir: Option<PathBuf>) {
+        self.state_dir = dir;
+    }
+
+    /// Look for blocks under `key`. Results arrive until the query times
+    /// out, or the stream is dropped. While the stream is full, the query
+    /// isn't sent to any more peers.
+    pub async fn get(
+        &self,
+        key: BlockKey,
+        block_type: u32,
+        options: GetOptions,
+    ) -> io::Result<GetStream> {
+        self.query(key, block_type, Vec::new(), options, None).await
+    }
+
+    /// Like [`get`](Self::get), but only for blocks that match `xquery`,
+    /// as block type `B` defines it.
+    pub async fn get_with_xquery<B: BlockOperation>(
+        &self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: &B::XQuery,
+        options: GetOptions,
+    ) -> io::Result<GetStream> {
+        self.query(key, block_type, xquery.encode(), options, None)
+            .await
+    }
+
+    /// Keep looking for new blocks under `key`, sending the GET again every
+    /// `interval`, until the stream is dropped or the query cancelled. Each
+    /// block arrives once, however often it is found.
+    pub async fn watch(
+        &self,
+        key: BlockKey,
+        block_type: u32,
+        options: GetOptions,
+        interval: Duration,
+    ) -> io::Result<GetStream> {
+        self.query(key, block_type, Vec::new(), options, Some(interval))
+            .await
+    }
+
+    /// Like [`watch`](Self::watch), but only for blocks that match
+    /// `xquery`, as block type `B` defines it.
+    pub async fn watch_with_xquery<B: BlockOperation>(
+        &self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: &B::XQuery,
+        options: GetOptions,
+        interval: Duration,
+    ) -> io::Result<GetStream> {
+        let xquery = xquery.encode();
+        self.query(key, block_type, xquery, options, Some(interval))
+            .await
+    }
+
+    async fn query(
+        &self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        repeat: Option<Duration>,
+    ) -> io::Result<GetStream> {
+        let (results, rx) = mpsc::channel(self.result_buffer);
+        let (started, id) = oneshot::channel();
+        let command = Command::Get {
+            key,
+            block_type,
+            xquery,
+            options,
+            repeat,
+            results,
+            started,
+        };
+        self.commands.send(command).map_err(|_| stopped())?;
+        let handle = QueryHandle {
+            id: id.await.map_err(|_| stopped())?,
+            commands: self.commands.clone(),
+        };
+        Ok(GetStream {
+            results: rx,
+            handle,
+        })
+    }
+
+    /// Store a block in the network, returning how many peers it was sent
+    /// to.
+    pub async fn put(
+        &self,
+        block_type: u32,
+        key: BlockKey,
+        block: Vec<u8>,
+        options: PutOptions,
+    ) -> io::Result<usize> {
+        let (done, rx) = oneshot::channel();
+        let command = Command::Put {
+            block_type,
+            key,
+            block,
+            options,
+            done,
+        };
+        self.commands.send(command).map_err(|_| stopped())?;
+        rx.await
+            .map_err(|_| stopped())?
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Connect to these peers, retrying with backoff until they are
+    /// connected, as with [`Bootstrap`].
+    pub fn bootstrap(&self, hellos: impl IntoIterator<Item = SignedHello>) -> io::Result<()> {
+        let hellos = hellos.into_iter().collect();
+        self.commands
+            .send(Command::Bootstrap(hellos))
+            .map_err(|_| stopped())
+    }
+
+    /// Report the node's state, see [`DhtNode::debug_dump`].
+    pub async fn debug_dump(&self) -> io::Result<DebugDump> {
+        let (done, rx) = oneshot::channel();
+        self.commands
+            .send(Command::DebugDump(done))
+            .map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())
+    }
+
+    /// Change some of the running node's settings, see
+    /// [`DhtNode::update_config`].
+    pub async fn update_config(&self, update: ConfigUpdate) -> io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        let command = Command::UpdateConfig(Box::new(update), done);
+        self.commands.send(command).map_err(|_| stopped())?;
+        rx.await
+            .map_err(|_| stopped())?
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Stop the node: its maintenance stops, its holds on peers are
+    /// released, and its state is saved to the
+    /// [state dir](Self::set_state_dir). Resolves once the messages it had
+    /// queued are sent, or fails if they aren't within a few seconds. Every
+    /// handle's GETs end, and later calls fail.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        let command = Command::Shutdown {
+            state_dir: self.state_dir.clone(),
+            done,
+        };
+        self.commands.send(command).map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+}
+
+/// Controls a running GET.
+#[derive(Clone)]
+pub struct QueryHandle {
+    id: QueryId,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl QueryHandle {
+    pub fn id(&self) -> QueryId {
+        self.id
+    }
+
+    /// Tell peers we already have this block, so it isn't sent to us.
+    pub fn exclude(&self, block: Vec<u8>) {
+        let _ = self.commands.send(Command::Exclude(self.id, block));
+    }
+
+    /// Stop the query. The stream ends after the results it already has.
+    pub fn cancel(&self) {
+        let _ = self.commands.send(Command::Cancel(self.id));
+    }
+}
+
+/// The results of a GET, each block once.
+pub struct GetStream {
+    results: mpsc::Receiver<Block>,
+    handle: QueryHandle,
+}
+
+impl GetStream {
+    /// The next new result, or `None` once the query is over.
+    pub async fn next(&mut self) -> Option<Block> {
+        self.results.recv().await
+    }
+
+    pub fn handle(&self) -> &QueryHandle {
+        &self.handle
+    }
+}
+
+impl futures_core::Stream for GetStream {
+    type Item = Block;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Block>> {
+        self.results.poll_recv(cx)
+    }
+}
+
+/// Where the results of a GET go
+struct Subscription {
+    results: mpsc::Sender<Block>,
+    /// results that didn't fit in the stream
+    pending: VecDeque<Block>,
+    /// for approximate queries, pending results are ordered by distance
+    /// from this
+    approximate: Option<BlockKey>,
+    /// the query is over, so this goes once the pending results are sent
+    finished: bool,
+    /// for room in the stream
+    waiting: bool,
+}
+
+type Ready = (QueryId, mpsc::OwnedPermit<Block>);
+
+impl Subscription {
+    fn push(&mut self, block: Block) {
+        let Some(key) = self.approximate else {
+            self.pending.push_back(block);
+            return;
+        };
+        // blocks whose key we don't know go last
+        let rank = |b: &Block| {
+            let distance = b.key.map(|k| Distance::between(&k.0, &key.0));
+            (distance.is_none(), distance)
+        };
+        let new = rank(&block);
+        let at = self.pending.iter().position(|b| rank(b) > new);
+        self.pending.insert(at.unwrap_or(self.pending.len()), block);
+    }
+
+    /// Send as many pending results as fit. When some don't, pause the
+    /// query and wait for room, which arrives on `ready`. Returns whether
+    /// the subscription is done with.
+    fn flush<U: Underlay>(
+        &mut self,
+        id: QueryId,
+        node: &mut DhtNode<U>,
+        ready: &mpsc::UnboundedSender<Ready>,
+    ) -> bool {
+        while let Some(block) = self.pending.pop_front() {
+            match self.results.try_send(block) {
+                Ok(()) => {}
+                Err(TrySendError::Full(block)) => {
+                    self.pending.push_front(block);
+                    node.queries_mut().set_paused(id, true);
+                    self.waiting = true;
+                    let (results, ready) = (self.results.clone(), ready.clone());
+                    tokio::spawn(async move {
+                        if let Ok(permit) = results.reserve_owned().await {
+                            let _ = ready.send((id, permit));
+                        }
+                    });
+                    return false;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    node.queries_mut().cancel(id);
+                    return true;
+                }
+            }
+        }
+        node.queries_mut().set_paused(id, false);
+        self.finished
+    }
+}
+
+async fn run<U, T>(
+    mut node: DhtNode<U>,
+    mut signals: mpsc::UnboundedReceiver<UnderlaySignal<U>>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    timer: T,
+) where
+    U: Underlay,
+    U::Address: FromStr,
+    T: TimerDriver,
+{
+    let mut bootstrap = Bootstrap::default();
+    // peers from a restored state are reconnected to like bootstrap peers
+    bootstrap.add_cached(node.hellos(), node.now());
+    let mut subscriptions: HashMap<QueryId, Subscription> = HashMap::new();
+    let (ready_tx, mut ready) = mpsc::unbounded_channel::<Ready>();
+    let mut shutdown = None;
+    loop {
+        let mut next_due = node.tick(Budget::unlimited()).next_due;
+        for hello in node.take_discovered() {
+            bootstrap.add(hello, node.now());
+        }
+        node.connect_neighbours();
+        if let Some(at) = bootstrap.poll(node.underlay(), node.now()) {
+            next_due = next_due.min(at);
+        }
+        let sleep = timer.sleep_until(next_due);
+        tokio::select! {
+            signal = signals.recv() => match signal {
+                Some(signal) => {
+                    bootstrap.handle_signal(&signal);
+                    node.handle_signal(signal);
+                }
+                None => break,
+            },
+            command = commands.recv() => match command {
+                Some(Command::Get { key, block_type, xquery, options, repeat, results, started }) => {
+                    let id = match repeat {
+                        Some(interval) => node.watch(key, block_type, xquery, options, interval),
+                        None => node.get(key, block_type, xquery, options),
+                    };
+                    let _ = started.send(id);
+                    let subscription = Subscription {
+                        results,
+                        pending: VecDeque::new(),
+                        approximate: options.find_approximate.then_some(key),
+                        finished: false,
+                        waiting: false,
+                    };
+                    subscriptions.insert(id, subscription);
+                }
+                Some(Command::Exclude(id, block)) => {
+                    node.queries_mut().exclude(id, &block);
+                }
+                Some(Command::Cancel(id)) => {
+                    node.queries_mut().cancel(id);
+                    if let Some(s) = subscriptions.get_mut(&id) {
+                        s.finished = true;
+                    }
+                }
+                Some(Command::Put { block_type, key, block, options, done }) => {
+                    let _ = done.send(node.put(block_type, key, &block, &options));
+                }
+                Some(Command::Bootstrap(hellos)) => {
+                    for hello in hellos {
+                        bootstrap.add(hello, node.now());
+                    }
+                }
+                Some(Command::DebugDump(done)) => {
+                    let _ = done.send(node.debug_dump());
+                }
+                Some(Command::UpdateConfig(update, done)) => {
+                    let _ = done.send(node.update_config(&update));
+                }
+                Some(Command::Shutdown { state_dir, done }) => {
+                    shutdown = Some((state_dir, done));
+                    break;
+                }
+                None => break,
+            },
+            Some((id, permit)) = ready.recv() => {
+                if let Some(s) = subscriptions.get_mut(&id) {
+                    s.waiting = false;
+                    if let Some(block) = s.pending.pop_front() {
+                        permit.send(block);
+                    }
+                }
+            }
+            _ = sleep => {}
+        }
+
+        while let Some(event) = node.next_query_event() {
+            match event {
+                QueryEvent::Result {
+                    id,
+                    key,
+                    block_type,
+                    expiration,
+                    block,
+                } => {
+                    if let Some(s) = subscriptions.get_mut(&id) {
+                        s.push(Block {
+                            key,
+                            block_type,
+                            expiration,
+                            data: block,
+                        });
+                    }
+                }
+                QueryEvent::Expired(id) => {
+                    if let Some(s) = subscriptions.get_mut(&id) {
+                        s.finished = true;
+                    }
+                }
+            }
+        }
+        subscriptions.retain(|&id, s| {
+            if s.results.is_closed() {
+                node.queries_mut().cancel(id);
+                return false;
+            }
+            // nothing fits until the stream has room again
+            s.waiting || !s.flush(id, &mut node, &ready_tx)
+        });
+    }
+
+    node.shutdown();
+    let Some((state_dir, done)) = shutdown else {
+        return;
+    };
+    let saved = match state_dir {
+        Some(dir) => node.state().save(&dir),
+        None => Ok(()),
+    };
+    let deadline = node.now() + DRAIN_TIMEOUT;
+    let drained = tokio::select! {
+        () = drain(&mut node, &timer) => Ok(()),
+        () = timer.sleep_until(deadline) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "messages were left unsent",
+        )),
+    };
+    let _ = done.send(saved.and(drained));
+}
+
+async fn drain<U: Underlay>(node: &mut DhtNode<U>, timer: &impl TimerDriver) {
+    loop {
+        let next_due = node.tick(Budget::unlimited()).next_due;
+        if node.is_drained() {
+            return;
+        }
+        timer.sleep_until(next_due).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        gossip::SignedHello,
+        message::{GetMessageHeader, PutMessageHeader, ResultMessage},
+        node::PutOptions,
+        query::{GetOptions, QueryConfig},
+        state::NodeState,
+        testing::identities,
+        time::{Clock, ManualTimer, TimerDriver},
+        underlay::{
+            udp::{UdpConfig, UdpUnderlay},
+            Underlay, UnderlaySignal,
+        },
+        DhtNode,
+    };
+
+    use super::{Block, Dht, Subscription};
+
+    async fn recv(rx: &mut UnboundedReceiver<UnderlaySignal<UdpUnderlay>>, message_type: u16) {
+        loop {
+            if let UnderlaySignal::Receive(_, message) = rx.recv().await.unwrap() {
+                if message.header().unwrap().message_type() == message_type {
+                    return;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn get_and_put() {
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let (ua, rxa) = UdpUnderlay::bind(a.peer(), localhost, UdpConfig::default()).unwrap();
+        let (ub, mut rxb) = UdpUnderlay::bind(b.peer(), localhost, UdpConfig::default()).unwrap();
+        let addr_b = ub.local_addr().unwrap().to_string();
+        let mut node = DhtNode::new(a.peer_id(), ua);
+        // the query may go out before a is connected to b
+        node.queries_mut().set_config(QueryConfig {
+            retry_interval: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let mut dht = Dht::spawn(node, rxa);
+        dht.set_result_buffer(1);
+        let hello = SignedHello::sign(&b.signing_key(), Timestamp::FOREVER, [addr_b.as_str()]);
+        dht.bootstrap([hello]).unwrap();
+
+        // b is the only peer a can send to
+        let key = BlockKey::from([3; 64]);
+        let mut results = dht.get(key, 13, GetOptions::default()).await.unwrap();
+        recv(&mut rxb, GetMessageHeader::MESSAGE_TYPE).await;
+        results.handle().exclude(b"known".to_vec());
+        // more results than the stream has room for, which are held back
+        // until it is read
+        for data in [&b"known"[..], b"one", b"two", b"one", b"three"] {
+            let result = ResultMessage::encode(13, Timestamp::FOREVER, key, data).unwrap();
+            ub.send(a.peer(), result).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for data in [&b"one"[..], b"two", b"three"] {
+            let block = tokio::time::timeout(Duration::from_secs(5), results.next())
+                .await
+                .unwrap();
+            let expected = Block {
+                key: None,
+                block_type: 13,
+                expiration: Timestamp::FOREVER,
+                data: data.to_vec(),
+            };
+            assert_eq!(block, Some(expected));
+        }
+        results.handle().cancel();
+        assert_eq!(results.next().await, None);
+
+        let sent = dht.put(13, key, b"stored".to_vec(), PutOptions::default());
+        assert_eq!(sent.await.unwrap(), 1);
+        let record_route = PutOptions {
+            record_route: true,
+            ..Default::default()
+        };
+        assert!(dht.put(13, key, vec![], record_route).await.is_err());
+        recv(&mut rxb, PutMessageHeader::MESSAGE_TYPE).await;
+        assert_eq!(dht.stats().sent.put, 1);
+        let dump = dht.debug_dump().await.unwrap();
+        assert_eq!(dump.stats.sent.put, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let a = &identities::peers()[0];
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let (ua, rxa) = UdpUnderlay::bind(a.peer(), localhost, UdpConfig::default()).unwrap();
+        let mut dht = Dht::spawn(DhtNode::new(a.peer_id(), ua), rxa);
+        let dir = std::env::temp_dir().join(format!("r6n-shutdown-{}", std::process::id()));
+        dht.set_state_dir(Some(dir.clone()));
+
+        let key = BlockKey::from([3; 64]);
+        let options = PutOptions {
+            demultiplex: true,
+            ..Default::default()
+        };
+        dht.put(13, key, b"kept".to_vec(), options).await.unwrap();
+        let mut results = dht.get(key, 13, GetOptions::default()).await.unwrap();
+        dht.shutdown().await.unwrap();
+        assert_eq!(results.next().await, None);
+        assert!(dht.put(13, key, vec![], options).await.is_err());
+
+        let (ub, _signals) = UdpUnderlay::bind(a.peer(), localhost, UdpConfig::default()).unwrap();
+        let mut node = DhtNode::new(a.peer_id(), ub);
+        node.restore(&NodeState::load(&dir).unwrap());
+        assert_eq!(node.datacache().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn virtual_time() {
+        let a = &identities::peers()[0];
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let (ua, rxa) = UdpUnderlay::bind(a.peer(), localhost, UdpConfig::default()).unwrap();
+        let timer = ManualTimer::default();
+        let node = DhtNode::with_clock(a.peer_id(), ua, timer.clock());
+        let dht = Dht::spawn_with_timer(node, rxa, timer.clone());
+
+        // the minute until the query times out passes without waiting for it
+        let key = BlockKey::from([3; 64]);
+        let mut results = dht.get(key, 13, GetOptions::default()).await.unwrap();
+        let forward = tokio::spawn({
+            let timer = timer.clone();
+            async move {
+                loop {
+                    tokio::task::yield_now().await;
+                    timer.fast_forward();
+                }
+            }
+        });
+        let next = tokio::time::timeout(Duration::from_secs(5), results.next()).await;
+        forward.abort();
+        assert_eq!(next.unwrap(), None);
+        assert!(timer.mock_clock().now() >= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn approximate_order() {
+        let (results, _rx) = mpsc::channel(1);
+        let mut subscription = Subscription {
+            results,
+            pending: VecDeque::new(),
+            approximate: Some(BlockKey::from([0; 64])),
+            finished: false,
+            waiting: false,
+        };
+        let block = |distance: Option<u8>| {
+            let key = distance.map(|d| {
+                let mut key = [0; 64];
+                key[63] = d;
+                BlockKey::from(key)
+            });
+            Block {
+                key,
+                block_type: 13,
+                expiration: Timestamp::FOREVER,
+                data: vec![],
+            }
+        };
+        for distance in [Some(4), None, Some(1), Some(2)] {
+            subscription.push(block(distance));
+        }
+        let order: Vec<_> = subscription
+            .pending
+            .iter()
+            .map(|b| b.key.map(|k| k.0[63]))
+            .collect();
+        assert_eq!(order, [Some(1), Some(2), Some(4), None]);
+    }
+}
@@ -0,0 +1,489 @@
+//! Everything a node can be configured with, in one place.
+//!
+//! [`DhtBuilder`] builds a [`DhtNode`] from a [`DhtConfig`], an underlay
+//! and optionally an identity, clock and saved state, checking that the
+//! settings make sense together. Each part can still be reconfigured on the
+//! node afterwards with its own `set_*_config`.
+//!
+//! With the `config` feature, a [`DhtConfig`] can be loaded from a TOML file
+//! with [`DhtConfig::from_toml`], and overridden by environment variables.
+//! With just `serde`, the config structs can be (de)serialized in any format,
+//! with durations as seconds and `"unlimited"` for missing rate limits.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use rand::RngCore;
+
+use crate::{
+    advertise::LocalHelloConfig,
+    bans::BanConfig,
+    datacache::DataCacheConfig,
+    dedup::DedupConfig,
+    gossip::GossipConfig,
+    identity::LocalPeer,
+    message::max_block_size,
+    neighbours::NeighbourConfig,
+    node::{PutOptions, DEFAULT_MAINTENANCE_INTERVAL},
+    nse::NseConfig,
+    outbound::OutboundConfig,
+    quality::QualityConfig,
+    query::{GetOptions, QueryConfig},
+    ratelimit::RateLimitConfig,
+    refresh::RefreshConfig,
+    relay::RelayConfig,
+    republish::{MigrationConfig, RepublishConfig},
+    routing::MAXIMUM_REPLICATION_LEVEL,
+    state::NodeState,
+    time::Clock,
+    underlay::Underlay,
+    DhtNode, PeerId, RoutingTableConfig,
+};
+
+#[cfg(feature = "serde")]
+mod fields;
+#[cfg(feature = "config")]
+mod file;
+
+#[cfg(feature = "serde")]
+pub(crate) use fields::{or_unlimited, secs};
+#[cfg(feature = "config")]
+pub use file::LoadError;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct DhtConfig {
+    pub routing: RoutingTableConfig,
+    /// How often garbage collection runs. Gossip, bucket refreshes and
+    /// republishing have their own intervals.
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub maintenance_interval: Duration,
+    pub gossip: GossipConfig,
+    pub local_hello: LocalHelloConfig,
+    pub query: QueryConfig,
+    pub rate_limits: RateLimitConfig,
+    pub bans: BanConfig,
+    pub get_cache: DedupConfig,
+    pub put_cache: DedupConfig,
+    pub datacache: DataCacheConfig,
+    pub republish: RepublishConfig,
+    pub refresh: RefreshConfig,
+    pub neighbours: NeighbourConfig,
+    pub quality: QualityConfig,
+    pub migration: MigrationConfig,
+    pub relay: RelayConfig,
+    pub outbound: OutboundConfig,
+    /// Estimate the network size ourselves, falling back to
+    /// [`NseConfig::fallback`] until there's a sample. If `None`, the
+    /// underlay's estimate is used.
+    pub nse: Option<NseConfig>,
+    /// The options GETs start from
+    pub get: GetOptions,
+    /// The options PUTs start from
+    pub put: PutOptions,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            routing: RoutingTableConfig::default(),
+            maintenance_interval: DEFAULT_MAINTENANCE_INTERVAL,
+            gossip: GossipConfig::default(),
+            local_hello: LocalHelloConfig::default(),
+            query: QueryConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            bans: BanConfig::default(),
+            get_cache: DedupConfig::default(),
+            put_cache: DedupConfig::default(),
+            datacache: DataCacheConfig::default(),
+            republish: RepublishConfig::default(),
+            refresh: RefreshConfig::default(),
+            neighbours: NeighbourConfig::default(),
+            quality: QualityConfig::default(),
+            migration: MigrationConfig::default(),
+            relay: RelayConfig::default(),
+            outbound: OutboundConfig::default(),
+            nse: None,
+            get: GetOptions::default(),
+            put: PutOptions::default(),
+        }
+    }
+}
+
+impl DhtConfig {
+    /// Check the settings make sense together. `identity` is whether the
+    /// node will have one.
+    pub fn validate(&self, identity: bool) -> Result<(), ConfigError> {
+        if self.routing.bucket_size == 0 {
+            return Err(ConfigError::BucketSize);
+        }
+        let levels = [
+            self.get.replication_level,
+            self.put.replication_level,
+            self.refresh.replication_level,
+        ];
+        if levels
+            .iter()
+            .any(|&l| l == 0 || l > MAXIMUM_REPLICATION_LEVEL)
+        {
+            return Err(ConfigError::ReplicationLevel);
+        }
+        let intervals = [
+            self.maintenance_interval,
+            self.gossip.interval,
+            self.republish.interval,
+            self.refresh.interval,
+        ];
+        if intervals.contains(&Duration::ZERO) {
+            return Err(ConfigError::ZeroInterval);
+        }
+        if self.local_hello.lifetime <= self.local_hello.refresh_before {
+            return Err(ConfigError::HelloLifetime);
+        }
+        if self.put.record_route && !identity {
+            return Err(ConfigError::NoIdentity);
+        }
+        if max_block_size(self.outbound.mtu) == 0 {
+            return Err(ConfigError::Mtu);
+        }
+        if !(self.quality.floor > 0.0 && self.quality.floor <= 1.0) {
+            return Err(ConfigError::WeightFloor);
+        }
+        Ok(())
+    }
+}
+
+/// Changes to a running node's config, for
+/// [`DhtNode::update_config`](crate::DhtNode::update_config). Parts left
+/// `None` stay as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfigUpdate {
+    /// Can't change, the routing table is built around it
+    pub host: Option<PeerId>,
+    /// Can't change, buckets aren't resized while running
+    pub routing: Option<RoutingTableConfig>,
+    pub maintenance_interval: Option<Duration>,
+    pub gossip: Option<GossipConfig>,
+    pub local_hello: Option<LocalHelloConfig>,
+    pub query: Option<QueryConfig>,
+    pub rate_limits: Option<RateLimitConfig>,
+    /// Bans already made keep their end
+    pub bans: Option<BanConfig>,
+    pub get_cache: Option<DedupConfig>,
+    pub put_cache: Option<DedupConfig>,
+    pub datacache: Option<DataCacheConfig>,
+    pub republish: Option<RepublishConfig>,
+    pub refresh: Option<RefreshConfig>,
+    pub neighbours: Option<NeighbourConfig>,
+    pub quality: Option<QualityConfig>,
+    pub migration: Option<MigrationConfig>,
+    pub relay: Option<RelayConfig>,
+    pub outbound: Option<OutboundConfig>,
+    /// Start estimating the network size ourselves, or change how. There's
+    /// no going back to the underlay's estimate.
+    pub nse: Option<NseConfig>,
+    pub get: Option<GetOptions>,
+    pub put: Option<PutOptions>,
+}
+
+impl ConfigUpdate {
+    /// Apply the changes to the `config` of a node running as `host`.
+    pub fn apply(&self, host: &PeerId, config: &mut DhtConfig) -> Result<(), ConfigError> {
+        if self.host.is_some_and(|h| h != *host) {
+            return Err(ConfigError::Immutable("host"));
+        }
+        if self.routing.is_some_and(|r| r != config.routing) {
+            return Err(ConfigError::Immutable("routing"));
+        }
+        fn update<T: Copy>(field: &mut T, new: &Option<T>) {
+            if let Some(new) = new {
+                *field = *new;
+            }
+        }
+        update(&mut config.maintenance_interval, &self.maintenance_interval);
+        update(&mut config.gossip, &self.gossip);
+        update(&mut config.local_hello, &self.local_hello);
+        update(&mut config.query, &self.query);
+        update(&mut config.rate_limits, &self.rate_limits);
+        update(&mut config.bans, &self.bans);
+        update(&mut config.get_cache, &self.get_cache);
+        update(&mut config.put_cache, &self.put_cache);
+        update(&mut config.datacache, &self.datacache);
+        update(&mut config.republish, &self.republish);
+        update(&mut config.refresh, &self.refresh);
+        update(&mut config.neighbours, &self.neighbours);
+        update(&mut config.quality, &self.quality);
+        update(&mut config.migration, &self.migration);
+        update(&mut config.relay, &self.relay);
+        update(&mut config.outbound, &self.outbound);
+        if let Some(nse) = self.nse {
+            config.nse = Some(nse);
+        }
+        update(&mut config.get, &self.get);
+        update(&mut config.put, &self.put);
+        Ok(())
+    }
+}
+
+/// Why a [`DhtConfig`] was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Neither a host nor an identity was given
+    NoHost,
+    /// The identity isn't the host's
+    WrongIdentity,
+    /// Buckets must fit at least one peer
+    BucketSize,
+    /// A default replication level is 0, or above
+    /// [`MAXIMUM_REPLICATION_LEVEL`]
+    ReplicationLevel,
+    /// A maintenance interval is zero, which would run it constantly
+    ZeroInterval,
+    /// Our HELLO would be replaced as soon as it's signed
+    HelloLifetime,
+    /// PUTs record their route by default, which needs an identity
+    NoIdentity,
+    /// The MTU is too small for any block to be PUT
+    Mtu,
+    /// Peers could be weighted out of random routing altogether, or above
+    /// the best
+    WeightFloor,
+    /// The setting can't be changed while the node runs
+    Immutable(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NoHost => f.write_str("no host or identity"),
+            ConfigError::WrongIdentity => f.write_str("not the host's identity"),
+            ConfigError::BucketSize => f.write_str("bucket size is 0"),
+            ConfigError::ReplicationLevel => f.write_str("replication level out of range"),
+            ConfigError::ZeroInterval => f.write_str("maintenance interval is 0"),
+            ConfigError::HelloLifetime => f.write_str("HELLO lifetime is within its refresh"),
+            ConfigError::NoIdentity => f.write_str("recording routes needs an identity"),
+            ConfigError::Mtu => f.write_str("MTU too small"),
+            ConfigError::WeightFloor => f.write_str("weight floor not above 0 and at most 1"),
+            ConfigError::Immutable(field) => write!(f, "{field} can't be changed while running"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builds a [`DhtNode`]. See the [module docs](self).
+pub struct DhtBuilder<U> {
+    underlay: U,
+    host: Option<PeerId>,
+    identity: Option<LocalPeer>,
+    clock: Option<Arc<dyn Clock>>,
+    rng: Option<Box<dyn RngCore + Send>>,
+    config: DhtConfig,
+    state: Option<NodeState>,
+}
+
+impl<U: Underlay> DhtBuilder<U> {
+    pub fn new(underlay: U) -> Self {
+        Self {
+            underlay,
+            host: None,
+            identity: None,
+            clock: None,
+            rng: None,
+            config: DhtConfig::default(),
+            state: None,
+        }
+    }
+
+    /// The peer the node runs as. Not needed if an
+    /// [identity](Self::identity) is given.
+    pub fn host(mut self, host: PeerId) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// The key the node signs with
+    pub fn identity(mut self, identity: LocalPeer) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// The clock the node runs on. The system's by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Where the node's random choices come from. Seeded from the OS by
+    /// default.
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    pub fn config(mut self, config: DhtConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The largest message the node sends, see [`OutboundConfig::mtu`].
+    /// Set after [`config`](Self::config), as that replaces it.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.config.outbound.mtu = mtu;
+        self
+    }
+
+    /// Restore the datacache and HELLO cache from a saved state, see
+    /// [`DhtNode::restore`].
+    pub fn state(mut self, state: NodeState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn build(self) -> Result<DhtNode<U>, ConfigError> {
+        let host = match (&self.identity, self.host) {
+            (Some(identity), Some(host)) if identity.peer_id() != host => {
+                return Err(ConfigError::WrongIdentity)
+            }
+            (Some(identity), _) => identity.peer_id(),
+            (None, Some(host)) => host,
+            (None, None) => return Err(ConfigError::NoHost),
+        };
+        let config = self.config;
+        config.validate(self.identity.is_some())?;
+
+        let mut node = match self.clock {
+            Some(clock) => DhtNode::with_clock(host, self.underlay, clock),
+            None => DhtNode::new(host, self.underlay),
+        }
+        .with_routing_config(config.routing);
+        node.apply_config(&config);
+        if let Some(identity) = self.identity {
+            node.set_identity(identity);
+        }
+        if let Some(rng) = self.rng {
+            node.set_rng(rng);
+        }
+        if let Some(state) = &self.state {
+            node.restore(state);
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        datacache::DataCacheConfig, gossip::GossipConfig, identity::LocalPeer, node::PutOptions,
+        testing::identities, underlay::memory::MemoryNetwork, RoutingTableConfig,
+    };
+
+    use super::{ConfigError, ConfigUpdate, DhtBuilder, DhtConfig};
+
+    #[test]
+    fn build() {
+        let host = identities::host();
+        let network = MemoryNetwork::new();
+        let underlay = || network.join(host.peer()).0;
+        let identity = LocalPeer::new(host.signing_key());
+
+        let recording = DhtConfig {
+            routing: RoutingTableConfig { bucket_size: 4 },
+            put: PutOptions {
+                record_route: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let node = DhtBuilder::new(underlay())
+            .identity(identity.clone())
+            .config(recording)
+            .build()
+            .unwrap();
+        assert_eq!(node.routing_table().config().bucket_size, 4);
+        assert!(node.default_put_options().record_route);
+
+        let build = |builder: DhtBuilder<_>| builder.build().err();
+        let other = identities::peers()[0].peer_id();
+        assert_eq!(
+            build(DhtBuilder::new(underlay())),
+            Some(ConfigError::NoHost)
+        );
+        let mismatched = DhtBuilder::new(underlay()).host(other).identity(identity);
+        assert_eq!(build(mismatched), Some(ConfigError::WrongIdentity));
+        let anonymous = DhtBuilder::new(underlay()).host(other).config(recording);
+        assert_eq!(build(anonymous), Some(ConfigError::NoIdentity));
+        let spinning = DhtConfig {
+            maintenance_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let spinning = DhtBuilder::new(underlay()).host(other).config(spinning);
+        assert_eq!(build(spinning), Some(ConfigError::ZeroInterval));
+    }
+
+    #[test]
+    fn update() {
+        let host = identities::host().peer_id();
+        let network = MemoryNetwork::new();
+        let underlay = network.join(identities::host().peer()).0;
+        let mut node = DhtBuilder::new(underlay).host(host).build().unwrap();
+
+        let gossip = GossipConfig {
+            interval: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let update = ConfigUpdate {
+            gossip: Some(gossip),
+            datacache: Some(DataCacheConfig { capacity: 1024 }),
+            ..Default::default()
+        };
+        node.update_config(&update).unwrap();
+        let config = node.config();
+        assert_eq!(config.gossip.interval, Duration::from_secs(10));
+        assert_eq!(config.datacache.capacity, 1024);
+
+        // nothing changes when part of an update is rejected
+        let resize = ConfigUpdate {
+            routing: Some(RoutingTableConfig { bucket_size: 16 }),
+            datacache: Some(DataCacheConfig { capacity: 0 }),
+            ..Default::default()
+        };
+        let rejected = node.update_config(&resize);
+        assert_eq!(rejected, Err(ConfigError::Immutable("routing")));
+        let other = ConfigUpdate {
+            host: Some(identities::peers()[0].peer_id()),
+            ..Default::default()
+        };
+        assert_eq!(
+            node.update_config(&other),
+            Err(ConfigError::Immutable("host"))
+        );
+        let unchanged = ConfigUpdate {
+            routing: Some(RoutingTableConfig::default()),
+            ..Default::default()
+        };
+        node.update_config(&unchanged).unwrap();
+        assert_eq!(node.config().datacache.capacity, 1024);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde() {
+        let mut config = DhtConfig::default();
+        config.gossip.interval = Duration::from_millis(500);
+        config.rate_limits.put = None;
+        let json = serde_json::to_value(config).unwrap();
+        assert_eq!(json["gossip"]["interval"], 0.5);
+        assert_eq!(json["rate_limits"]["put"], "unlimited");
+
+        let parsed: DhtConfig = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(parsed.gossip.interval, config.gossip.interval);
+        assert_eq!(parsed.rate_limits, config.rate_limits);
+        assert_eq!(serde_json::to_value(parsed).unwrap(), json);
+    }
+}
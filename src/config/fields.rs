@@ -0,0 +1,67 @@
+//! How config fields are written, for `#[serde(with = "...")]`.
+
+/// A duration in seconds, whole or not
+pub(crate) mod secs {
+    use std::time::Duration;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(d.as_secs_f64())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(d)?;
+        Duration::try_from_secs_f64(secs).map_err(de::Error::custom)
+    }
+
+    pub(crate) fn serialize_opt<S: Serializer>(
+        d: &Option<Duration>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => s.serialize_some(&d.as_secs_f64()),
+            None => s.serialize_none(),
+        }
+    }
+}
+
+/// A limit, or `"unlimited"` for `None`
+pub(crate) mod or_unlimited {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Unlimited {
+        Unlimited,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Limit<T> {
+        Unlimited(Unlimited),
+        Limited(T),
+    }
+
+    pub(crate) fn serialize<S, T>(limit: &Option<T>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        match limit {
+            None => Limit::<&T>::Unlimited(Unlimited::Unlimited).serialize(s),
+            Some(limit) => Limit::Limited(limit).serialize(s),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        match Limit::deserialize(d)? {
+            Limit::Unlimited(_) => Ok(None),
+            Limit::Limited(limit) => Ok(Some(limit)),
+        }
+    }
+}
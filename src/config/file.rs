@@ -0,0 +1,163 @@
+//! Loading a [`DhtConfig`] from TOML.
+
+use std::{fmt, fs, io, path::Path};
+
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use super::DhtConfig;
+
+/// Environment variables with this prefix override the file
+const ENV_PREFIX: &str = "R6N_";
+
+impl DhtConfig {
+    /// Load the config from a TOML file, with overrides from the
+    /// environment.
+    ///
+    /// Every field is optional, and falls back to its default. Durations
+    /// are in seconds, and rate limits can be `"unlimited"`:
+    ///
+    /// ```toml
+    /// maintenance_interval = 30
+    ///
+    /// [routing]
+    /// bucket_size = 16
+    ///
+    /// [rate_limits]
+    /// get = { per_second = 100, burst = 200 }
+    /// put = "unlimited"
+    /// ```
+    ///
+    /// Environment variables starting with `R6N_` override the file. The
+    /// rest of the name is the path to the field, with `__` between tables,
+    /// eg `R6N_ROUTING__BUCKET_SIZE=16`. Values are TOML, or else a string.
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let toml = fs::read_to_string(path).map_err(LoadError::Io)?;
+        Self::from_toml_str(&toml, std::env::vars())
+    }
+
+    /// Parse a TOML config like [`from_toml`](Self::from_toml), with
+    /// overrides from `env`, which is usually [`std::env::vars`]. Variables without the `R6N_` prefix are ignored.
+    pub fn from_toml_str(
+        toml: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, LoadError> {
+        let mut table: Table = toml.parse().map_err(LoadError::Toml)?;
+        for (name, value) in env {
+            let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let path = path.to_lowercase();
+            if !set(&mut table, &path, parse_value(&value)) {
+                return Err(LoadError::Env(name));
+            }
+        }
+        Self::deserialize(table).map_err(LoadError::Toml)
+    }
+}
+
+/// Set the field at `path`, creating the tables on the way. Fails if one of
+/// them is already something else.
+fn set(table: &mut Table, path: &str, value: Value) -> bool {
+    let (tables, field) = match path.rsplit_once("__") {
+        Some((tables, field)) => (Some(tables), field),
+        None => (None, path),
+    };
+    let mut table = table;
+    for name in tables.into_iter().flat_map(|t| t.split("__")) {
+        let next = table
+            .entry(name)
+            .or_insert_with(|| Value::Table(Table::new()));
+        let Value::Table(next) = next else {
+            return false;
+        };
+        table = next;
+    }
+    table.insert(field.to_owned(), value);
+    true
+}
+
+fn parse_value(value: &str) -> Value {
+    let parsed = format!("v = {value}").parse::<Table>();
+    match parsed.ok().and_then(|mut t| t.remove("v")) {
+        Some(value) => value,
+        None => Value::String(value.to_owned()),
+    }
+}
+
+/// Why a config couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    /// The environment variable sets a field inside something that isn't a
+    /// table
+    Env(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "reading config: {e}"),
+            LoadError::Toml(e) => write!(f, "parsing config: {e}"),
+            LoadError::Env(name) => write!(f, "{name} doesn't name a config field"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Toml(e) => Some(e),
+            LoadError::Env(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::ratelimit::Rate;
+
+    use super::{DhtConfig, LoadError};
+
+    #[test]
+    fn from_toml() {
+        let toml = r#"
+            maintenance_interval = 30
+
+            [routing]
+            bucket_size = 16
+
+            [rate_limits]
+            get = { per_second = 100, burst = 200 }
+            put = "unlimited"
+
+            [gossip]
+            interval = 0.5
+        "#;
+        let env = [
+            ("R6N_ROUTING__BUCKET_SIZE", "4"),
+            ("R6N_NSE__FALLBACK", "50"),
+            ("HOME", "/root"),
+        ]
+        .map(|(k, v)| (k.to_owned(), v.to_owned()));
+        let config = DhtConfig::from_toml_str(toml, env).unwrap();
+        assert_eq!(config.maintenance_interval, Duration::from_secs(30));
+        assert_eq!(config.gossip.interval, Duration::from_millis(500));
+        assert_eq!(config.routing.bucket_size, 4);
+        assert_eq!(config.rate_limits.get, Some(Rate::new(100.0, 200.0)));
+        assert_eq!(config.rate_limits.put, None);
+        assert!(config.rate_limits.hello.is_some());
+        assert_eq!(config.nse.unwrap().fallback, 50);
+        assert_eq!(config.nse.unwrap().smoothing, 0.25);
+
+        let typo = DhtConfig::from_toml_str("[routing]\nbucket_sise = 4", []);
+        assert!(matches!(typo, Err(LoadError::Toml(_))));
+        let env = [("R6N_MAINTENANCE_INTERVAL__SECS".to_owned(), "1".to_owned())];
+        let nested = DhtConfig::from_toml_str("maintenance_interval = 30", env);
+        assert!(matches!(nested, Err(LoadError::Env(_))));
+    }
+}
@@ -0,0 +1,336 @@
+//! Blocks stored here for other peers to find.
+//!
+//! PUTs are stored by the peers closest to their key, and by every peer along
+//! the way if they have the demultiplex flag set. GETs that reach those peers
+//! are answered from here.
+
+use std::{collections::HashMap, mem::size_of};
+
+use zerocopy::{big_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::{
+    block::{BlockKey, Timestamp},
+    error::StoreError,
+    query::BLOCK_TYPE_ANY,
+    Distance,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct DataCacheConfig {
+    /// How many bytes of blocks are stored at most. When full, blocks that
+    /// expire sooner make way for ones that expire later.
+    pub capacity: usize,
+}
+
+impl Default for DataCacheConfig {
+    fn default() -> Self {
+        Self { capacity: 16 << 20 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredBlock {
+    pub block_type: u32,
+    pub expiration: Timestamp,
+    pub block: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct DataCache {
+    config: DataCacheConfig,
+    blocks: HashMap<BlockKey, Vec<StoredBlock>>,
+    // total length of the stored blocks
+    bytes: usize,
+}
+
+impl DataCache {
+    pub fn new(config: DataCacheConfig) -> Self {
+        Self {
+            config,
+            blocks: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &DataCacheConfig {
+        &self.config
+    }
+
+    /// Blocks that no longer fit are dropped, soonest to expire first.
+    pub fn set_config(&mut self, config: DataCacheConfig) {
+        self.config = config;
+        while self.bytes > self.config.capacity {
+            let Some((key, i)) = self.soonest(Timestamp::FOREVER) else {
+                break;
+            };
+            self.remove(key, i);
+        }
+    }
+
+    /// Store a block. Storing a block that is already here keeps the later
+    /// expiration. Expired blocks, and blocks there's no room for, aren't
+    /// stored.
+    pub fn insert(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        expiration: Timestamp,
+        block: &[u8],
+        now: Timestamp,
+    ) -> Result<(), StoreError> {
+        if expiration.is_expired(now) {
+            return Err(StoreError::Expired);
+        }
+        let existing = self.blocks.get_mut(&key).and_then(|blocks| {
+            blocks
+                .iter_mut()
+                .find(|b| b.block_type == block_type && b.block == block)
+        });
+        if let Some(existing) = existing {
+            existing.expiration = existing.expiration.max(expiration);
+            return Ok(());
+        }
+        while self.bytes + block.len() > self.config.capacity {
+            let Some((key, i)) = self.soonest(expiration) else {
+                return Err(StoreError::Full);
+            };
+            self.remove(key, i);
+        }
+        self.bytes += block.len();
+        self.blocks.entry(key).or_default().push(StoredBlock {
+            block_type,
+            expiration,
+            block: block.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// The blocks stored under `key`. [`BLOCK_TYPE_ANY`] matches every type.
+    pub fn get(&self, key: &BlockKey, block_type: u32) -> impl Iterator<Item = &StoredBlock> {
+        self.blocks
+            .get(key)
+            .into_iter()
+            .flatten()
+            .filter(move |b| block_type == BLOCK_TYPE_ANY || b.block_type == block_type)
+    }
+
+    /// The blocks stored under every key, closest to `key` first, for
+    /// approximate GETs.
+    pub fn closest(&self, key: &BlockKey, block_type: u32) -> Vec<(&BlockKey, &StoredBlock)> {
+        let mut blocks: Vec<_> = self
+            .blocks
+            .iter()
+            .flat_map(|(k, blocks)| blocks.iter().map(move |b| (k, b)))
+            .filter(|(_, b)| block_type == BLOCK_TYPE_ANY || b.block_type == block_type)
+            .collect();
+        blocks.sort_by_cached_key(|(k, _)| Distance::between(&k.0, &key.0));
+        blocks
+    }
+
+    pub fn remove_expired(&mut self, now: Timestamp) {
+        let mut freed = 0;
+        self.blocks.retain(|_, blocks| {
+            blocks.retain(|b| {
+                let expired = b.expiration.is_expired(now);
+                if expired {
+                    freed += b.block.len();
+                }
+                !expired
+            });
+            !blocks.is_empty()
+        });
+        self.bytes -= freed;
+    }
+
+    /// The keys blocks are stored under
+    pub fn keys(&self) -> impl Iterator<Item = &BlockKey> {
+        self.blocks.keys()
+    }
+
+    /// The number of blocks stored
+    pub fn len(&self) -> usize {
+        self.blocks.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The total length of the blocks stored
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Serialize the stored blocks so that they can be
+    /// [`restore`](Self::restore)d after a restart.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            count: big_endian::U32::new(self.len() as u32),
+        };
+        let entries = self.len() * size_of::<SnapshotEntry>();
+        let mut out = Vec::with_capacity(size_of::<SnapshotHeader>() + entries + self.bytes);
+        out.extend_from_slice(header.as_bytes());
+        for (key, blocks) in &self.blocks {
+            for b in blocks {
+                let entry = SnapshotEntry {
+                    key: *key,
+                    block_type: big_endian::U32::new(b.block_type),
+                    expiration: b.expiration,
+                    len: big_endian::U32::new(b.block.len() as u32),
+                };
+                out.extend_from_slice(entry.as_bytes());
+                out.extend_from_slice(&b.block);
+            }
+        }
+        out
+    }
+
+    /// Store the blocks in a [`snapshot`](Self::snapshot), returning how
+    /// many were stored. Blocks that expired since, or don't fit, are
+    /// skipped like any other [`insert`](Self::insert). Nothing is stored
+    /// from a snapshot that doesn't parse.
+    pub fn restore(&mut self, snapshot: &[u8], now: Timestamp) -> Result<usize, StoreError> {
+        let header = SnapshotHeader::ref_from_prefix(snapshot).ok_or(StoreError::Snapshot)?;
+        if header.magic != SNAPSHOT_MAGIC {
+            return Err(StoreError::Snapshot);
+        }
+        let mut rest = &snapshot[size_of::<SnapshotHeader>()..];
+        let mut entries = Vec::new();
+        for _ in 0..header.count.get() {
+            let entry = SnapshotEntry::ref_from_prefix(rest).ok_or(StoreError::Snapshot)?;
+            rest = &rest[size_of::<SnapshotEntry>()..];
+            let block = rest
+                .get(..entry.len.get() as usize)
+                .ok_or(StoreError::Snapshot)?;
+            rest = &rest[block.len()..];
+            entries.push((entry, block));
+        }
+        if !rest.is_empty() {
+            return Err(StoreError::Snapshot);
+        }
+        let stored = entries.into_iter().map(|(entry, block)| {
+            let block_type = entry.block_type.get();
+            self.insert(entry.key, block_type, entry.expiration, block, now)
+        });
+        Ok(stored.filter(Result::is_ok).count())
+    }
+
+    /// The block that expires soonest, if it expires before `before`
+    fn soonest(&self, before: Timestamp) -> Option<(BlockKey, usize)> {
+        self.blocks
+            .iter()
+            .flat_map(|(key, blocks)| blocks.iter().enumerate().map(move |(i, b)| (key, i, b)))
+            .filter(|(_, _, b)| b.expiration < before)
+            .min_by_key(|(_, _, b)| b.expiration)
+            .map(|(key, i, _)| (*key, i))
+    }
+
+    fn remove(&mut self, key: BlockKey, i: usize) {
+        let Some(blocks) = self.blocks.get_mut(&key) else {
+            return;
+        };
+        self.bytes -= blocks.swap_remove(i).block.len();
+        if blocks.is_empty() {
+            self.blocks.remove(&key);
+        }
+    }
+}
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"r6dc";
+
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct SnapshotHeader {
+    magic: [u8; 4],
+    count: big_endian::U32,
+}
+
+/// followed by `len` bytes of block
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct SnapshotEntry {
+    key: BlockKey,
+    block_type: big_endian::U32,
+    expiration: Timestamp,
+    len: big_endian::U32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{BlockKey, Timestamp},
+        query::BLOCK_TYPE_ANY,
+    };
+
+    use super::{DataCache, DataCacheConfig, StoreError};
+
+    #[test]
+    fn store() {
+        let mut cache = DataCache::new(DataCacheConfig { capacity: 8 });
+        let key = BlockKey::from([1; 64]);
+        let at = Timestamp::from_micros;
+
+        assert!(cache.insert(key, 13, at(10), b"abcd", at(0)).is_ok());
+        assert!(cache.insert(key, 13, at(20), b"abcd", at(0)).is_ok());
+        assert!(cache.insert(key, 7, at(30), b"efgh", at(0)).is_ok());
+        let late = cache.insert(key, 13, at(5), b"late", at(6));
+        assert_eq!(late, Err(StoreError::Expired));
+        assert_eq!((cache.len(), cache.bytes()), (2, 8));
+        assert_eq!(cache.get(&key, 13).next().unwrap().expiration, at(20));
+        assert_eq!(cache.get(&key, BLOCK_TYPE_ANY).count(), 2);
+        assert_eq!(cache.get(&BlockKey::from([2; 64]), 13).count(), 0);
+
+        // when full, the block expiring soonest makes way
+        let other = BlockKey::from([2; 64]);
+        let full = cache.insert(other, 13, at(15), b"ijkl", at(0));
+        assert_eq!(full, Err(StoreError::Full));
+        assert!(cache.insert(other, 13, at(25), b"ijkl", at(0)).is_ok());
+        assert_eq!(cache.get(&key, 13).count(), 0);
+        assert_eq!(cache.len(), 2);
+
+        cache.remove_expired(at(26));
+        assert_eq!((cache.len(), cache.bytes()), (1, 4));
+        cache.set_config(DataCacheConfig { capacity: 0 });
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn closest() {
+        let mut cache = DataCache::default();
+        let now = Timestamp::from_micros(0);
+        for (i, block) in [(0b0100, b"far"), (0b0001, b"one"), (0b0010, b"two")] {
+            let mut key = [0; 64];
+            key[63] = i;
+            let _ = cache.insert(BlockKey::from(key), 13, Timestamp::FOREVER, block, now);
+        }
+        let closest = cache.closest(&BlockKey::from([0; 64]), 13);
+        let blocks: Vec<_> = closest.into_iter().map(|(_, b)| &b.block[..]).collect();
+        assert_eq!(blocks, [b"one", b"two", b"far"]);
+        assert!(cache.closest(&BlockKey::from([0; 64]), 7).is_empty());
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut cache = DataCache::default();
+        let at = Timestamp::from_micros;
+        let _ = cache.insert(BlockKey::from([1; 64]), 13, at(10), b"soon", at(0));
+        let _ = cache.insert(BlockKey::from([1; 64]), 7, at(30), b"later", at(0));
+        let _ = cache.insert(BlockKey::from([2; 64]), 13, at(30), b"", at(0));
+        let snapshot = cache.snapshot();
+
+        let mut restored = DataCache::default();
+        assert_eq!(restored.restore(&snapshot, at(20)), Ok(2));
+        assert_eq!((restored.len(), restored.bytes()), (2, 5));
+        assert_eq!(restored.get(&BlockKey::from([1; 64]), 7).count(), 1);
+        assert_eq!(
+            restored.restore(&snapshot[..snapshot.len() - 1], at(0)),
+            Err(StoreError::Snapshot)
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! A report of a node's state, for working out why lookups fail.
+//!
+//! [`DhtNode::debug_dump`] takes one. With the `serde` feature, the report
+//! can be serialized, eg to JSON for a debug endpoint. Keys and peers are
+//! base32, and times are seconds.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    encoding::base32_encode, metrics::Stats, query::BLOCK_TYPE_ANY, underlay::Underlay,
+    BucketIndex, DhtNode, RoutingTableConfig,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DebugDump {
+    pub host: String,
+    pub network_size: u64,
+    pub routing: RoutingDump,
+    pub queries: Vec<QueryDump>,
+    pub datacache: DataCacheDump,
+    pub hellos: Vec<HelloDump>,
+    pub stats: Stats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RoutingDump {
+    pub config: RoutingTableConfig,
+    /// As grown with the network size estimate
+    pub bucket_size: usize,
+    /// The non-empty buckets, furthest first
+    pub buckets: Vec<BucketDump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BucketDump {
+    /// log2 XOR distance from the host
+    pub dist: BucketIndex,
+    pub peers: Vec<RouteDump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RouteDump {
+    pub peer: String,
+    /// How long the peer has been connected
+    pub age: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QueryDump {
+    pub key: String,
+    pub block_type: u32,
+    pub replication_level: u16,
+    /// How long ago it started
+    pub age: f64,
+    pub results: usize,
+    pub paused: bool,
+    pub watch: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DataCacheDump {
+    pub blocks: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+    /// How many blocks of each type are stored
+    pub block_types: BTreeMap<u32, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HelloDump {
+    pub peer: String,
+    /// Microseconds since the Unix epoch
+    pub expiration: u64,
+    pub addresses: Vec<String>,
+}
+
+impl<U: Underlay> DhtNode<U> {
+    /// Report everything that decides how lookups go. See the
+    /// [module docs](self).
+    pub fn debug_dump(&self) -> DebugDump {
+        let now = self.now();
+        let table = self.routing_table();
+        let mut buckets: BTreeMap<BucketIndex, Vec<RouteDump>> = BTreeMap::new();
+        for route in table.iter() {
+            buckets.entry(route.bucket()).or_default().push(RouteDump {
+                peer: route.peer().to_string(),
+                age: route.age().as_secs_f64(),
+            });
+        }
+        let buckets = buckets
+            .into_iter()
+            .rev()
+            .map(|(dist, peers)| BucketDump { dist, peers })
+            .collect();
+
+        let mut queries: Vec<_> = self.queries().iter().collect();
+        queries.sort_by_key(|(id, _)| *id);
+        let queries = queries
+            .into_iter()
+            .map(|(_, q)| QueryDump {
+                key: base32_encode(&q.key().0),
+                block_type: q.block_type(),
+                replication_level: q.options().replication_level,
+                age: now.saturating_sub(q.started()).as_secs_f64(),
+                results: q.results(),
+                paused: q.is_paused(),
+                watch: q.is_watch(),
+            })
+            .collect();
+
+        let datacache = self.datacache();
+        let mut block_types = BTreeMap::new();
+        for key in datacache.keys() {
+            for block in datacache.get(key, BLOCK_TYPE_ANY) {
+                *block_types.entry(block.block_type).or_default() += 1;
+            }
+        }
+
+        let mut hellos: Vec<_> = self
+            .gossip()
+            .hellos()
+            .iter()
+            .map(|h| HelloDump {
+                peer: h.peer().to_string(),
+                expiration: h.expiration().as_micros(),
+                addresses: h.addresses().map(str::to_owned).collect(),
+            })
+            .collect();
+        hellos.sort_by(|a, b| a.peer.cmp(&b.peer));
+
+        DebugDump {
+            host: table.host().to_string(),
+            network_size: self.network_size(),
+            routing: RoutingDump {
+                config: *table.config(),
+                bucket_size: table.bucket_size(),
+                buckets,
+            },
+            queries,
+            datacache: DataCacheDump {
+                blocks: datacache.len(),
+                bytes: datacache.bytes(),
+                capacity: datacache.config().capacity,
+                block_types,
+            },
+            hellos,
+            stats: self.metrics().snapshot(),
+        }
+    }
+}
+
+/// `MessageCounts::by_block_type` as a list, since its keys aren't strings
+#[cfg(feature = "serde")]
+pub(crate) fn block_type_counts<S: serde::Serializer>(
+    counts: &BTreeMap<(crate::monitor::MessageKind, u32), u64>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(serde::Serialize)]
+    struct Count {
+        kind: &'static str,
+        block_type: u32,
+        count: u64,
+    }
+
+    s.collect_seq(counts.iter().map(|(&(kind, block_type), &count)| Count {
+        kind: kind.as_str(),
+        block_type,
+        count,
+    }))
+}
+
+/// `Stats::dropped_by_policy` as a list, for the same reason
+#[cfg(feature = "serde")]
+pub(crate) fn policy_drop_counts<S: serde::Serializer>(
+    counts: &BTreeMap<(&'static str, u32), u64>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(serde::Serialize)]
+    struct Count {
+        reason: &'static str,
+        block_type: u32,
+        count: u64,
+    }
+
+    s.collect_seq(counts.iter().map(|(&(reason, block_type), &count)| Count {
+        reason,
+        block_type,
+        count,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::BlockKey,
+        node::PutOptions,
+        query::GetOptions,
+        testing::identities,
+        underlay::{memory::MemoryNetwork, UnderlaySignal},
+        BucketIndex, DhtNode,
+    };
+
+    #[test]
+    fn dump() {
+        let host = identities::host();
+        let network = MemoryNetwork::new();
+        let (underlay, _signals) = network.join(host.peer());
+        let mut node = DhtNode::new(host.peer_id(), underlay);
+        for f in identities::in_bucket(512).take(2) {
+            node.handle_signal(UnderlaySignal::PeerConnected(f.peer(), Default::default()));
+        }
+        let key = BlockKey::from([1; 64]);
+        node.get(key, 13, Vec::new(), GetOptions::default());
+        let options = PutOptions {
+            demultiplex: true,
+            ..Default::default()
+        };
+        node.put(13, key, b"block", &options).unwrap();
+
+        let dump = node.debug_dump();
+        assert_eq!(dump.host, host.peer_id().to_string());
+        assert_eq!(dump.routing.buckets.len(), 1);
+        assert_eq!(dump.routing.buckets[0].dist, BucketIndex::FURTHEST);
+        assert_eq!(dump.routing.buckets[0].peers.len(), 2);
+        assert_eq!(dump.queries.len(), 1);
+        assert_eq!(dump.queries[0].block_type, 13);
+        assert_eq!(dump.datacache.block_types[&13], 1);
+        assert_eq!(dump.stats.routing_table, [(BucketIndex::FURTHEST, 2)]);
+    }
+}
@@ -0,0 +1,244 @@
+//! Recognising GETs and PUTs we have already seen.
+//!
+//! The same GET often reaches us along several paths. Handling each copy
+//! would forward and answer it again, so GETs are remembered for a while by
+//! a fingerprint of their query hash, block type, extended query and result
+//! filter. A copy with a different result filter is a retry that wants new
+//! results, so it isn't a duplicate.
+//!
+//! PUTs of the same block come in storms when several peers replicate it at
+//! once, so they are remembered by their key and block.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha512};
+
+use crate::message::{GetMessage, PutMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct DedupConfig {
+    /// How long a message is remembered
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub window: Duration,
+    /// How many messages are remembered at most. The oldest are forgotten
+    /// first.
+    pub capacity: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            capacity: 4096,
+        }
+    }
+}
+
+type Fingerprint = [u8; 16];
+
+fn get_fingerprint(get: &GetMessage<'_>) -> Fingerprint {
+    let mut hasher = Sha512::new();
+    hasher.update(get.query_hash().0);
+    hasher.update(get.block_type().to_be_bytes());
+    // lengths keep the variable parts from running into each other
+    hasher.update((get.xquery().len() as u64).to_be_bytes());
+    hasher.update(get.xquery());
+    hasher.update(get.result_filter());
+    truncate(hasher.finalize().into())
+}
+
+fn put_fingerprint(put: &PutMessage<'_>) -> Fingerprint {
+    let mut hasher = Sha512::new();
+    hasher.update(put.block_key().0);
+    hasher.update(put.block_type().to_be_bytes());
+    hasher.update(put.block());
+    truncate(hasher.finalize().into())
+}
+
+// 128 bits is plenty to tell recent messages apart
+fn truncate(hash: [u8; 64]) -> Fingerprint {
+    hash[..16].try_into().unwrap()
+}
+
+/// Recently seen GETs. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct GetCache(Recent);
+
+impl GetCache {
+    pub fn new(config: DedupConfig) -> Self {
+        Self(Recent::new(config))
+    }
+
+    pub fn config(&self) -> &DedupConfig {
+        &self.0.config
+    }
+
+    pub fn set_config(&mut self, config: DedupConfig) {
+        self.0.config = config;
+    }
+
+    /// Remember a GET, returning whether it is new, ie not seen within the
+    /// window.
+    pub fn insert(&mut self, get: &GetMessage<'_>, now: Duration) -> bool {
+        self.0.insert(get_fingerprint(get), now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.order.is_empty()
+    }
+}
+
+/// Recently seen PUTs. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct PutCache(Recent);
+
+impl PutCache {
+    pub fn new(config: DedupConfig) -> Self {
+        Self(Recent::new(config))
+    }
+
+    pub fn config(&self) -> &DedupConfig {
+        &self.0.config
+    }
+
+    pub fn set_config(&mut self, config: DedupConfig) {
+        self.0.config = config;
+    }
+
+    /// Remember a PUT, returning whether it is new, ie the block wasn't PUT
+    /// under the same key within the window.
+    pub fn insert(&mut self, put: &PutMessage<'_>, now: Duration) -> bool {
+        self.0.insert(put_fingerprint(put), now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.order.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recent {
+    config: DedupConfig,
+    seen: HashSet<Fingerprint>,
+    // oldest first
+    order: VecDeque<(Fingerprint, Duration)>,
+}
+
+impl Recent {
+    fn new(config: DedupConfig) -> Self {
+        Self {
+            config,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, fingerprint: Fingerprint, now: Duration) -> bool {
+        self.expire(now);
+        if self.seen.contains(&fingerprint) {
+            return false;
+        }
+        if self.config.capacity == 0 {
+            return true;
+        }
+        while self.order.len() >= self.config.capacity {
+            self.pop();
+        }
+        self.seen.insert(fingerprint);
+        self.order.push_back((fingerprint, now));
+        true
+    }
+
+    fn expire(&mut self, now: Duration) {
+        while let Some(&(_, seen)) = self.order.front() {
+            if now.saturating_sub(seen) < self.config.window {
+                break;
+            }
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((fingerprint, _)) = self.order.pop_front() {
+            self.seen.remove(&fingerprint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::BlockKey,
+        block::Timestamp,
+        bloom::PeerBloomFilter,
+        message::{Flags, GetMessage, PutMessage},
+    };
+
+    use super::{DedupConfig, GetCache, PutCache};
+
+    #[test]
+    fn duplicates() {
+        let get = |key: u8, result_filter: &[u8]| {
+            GetMessage::encode(
+                13,
+                Flags::default(),
+                5,
+                PeerBloomFilter::default(),
+                BlockKey::from([key; 64]),
+                result_filter,
+                b"",
+            )
+            .unwrap()
+        };
+        let [a, b, retry] = [get(1, b""), get(2, b""), get(1, &[1; 8])];
+        let [a, b, retry] = [&a, &b, &retry].map(|m| GetMessage::parse(m.as_bytes()).unwrap());
+        let mut cache = GetCache::new(DedupConfig {
+            window: Duration::from_secs(10),
+            capacity: 2,
+        });
+        let at = Duration::from_secs;
+
+        assert!(cache.insert(&a, at(0)));
+        assert!(!cache.insert(&a, at(5)));
+        assert!(cache.insert(&retry, at(5)));
+        // the window has passed
+        assert!(cache.insert(&a, at(10)));
+        assert_eq!(cache.len(), 2);
+        // a full cache forgets the oldest
+        assert!(cache.insert(&b, at(11)));
+        assert!(cache.insert(&retry, at(11)));
+        assert!(cache.insert(&a, at(11)));
+        assert!(!cache.insert(&retry, at(11)));
+
+        let put = |block: &[u8]| {
+            let key = BlockKey::from([1; 64]);
+            let bloom = PeerBloomFilter::default();
+            let flags = Flags::default();
+            PutMessage::encode(13, flags, 5, Timestamp::FOREVER, bloom, key, None, block).unwrap()
+        };
+        let [x, y] = [put(b"x"), put(b"y")];
+        let [x, y] = [&x, &y].map(|m| PutMessage::parse(m.as_bytes()).unwrap());
+        let mut cache = PutCache::default();
+        assert!(cache.insert(&x, at(0)));
+        assert!(cache.insert(&y, at(0)));
+        assert!(!cache.insert(&x, at(1)));
+    }
+}
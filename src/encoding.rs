@@ -0,0 +1,278 @@
+//! Text encodings for keys, compatible with GNUnet's tooling.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+/// GNUnet uses Crockford's base32 alphabet for keys and hashes
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The length of `len` bytes when encoded with [`base32_encode`]
+pub const fn base32_len(len: usize) -> usize {
+    (len * 8).div_ceil(5)
+}
+
+/// Encode bytes as Crockford base32, most significant bit first. The final
+/// character is padded with zero bits, as in `GNUNET_STRINGS_data_to_string`.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(base32_len(data.len()));
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for &b in data {
+        bits = bits << 8 | b as u32;
+        nbits += 8;
+        while nbits >= 5 {
+            nbits -= 5;
+            out.push(ALPHABET[(bits >> nbits) as usize & 31] as char);
+        }
+    }
+    if nbits > 0 {
+        out.push(ALPHABET[(bits << (5 - nbits)) as usize & 31] as char);
+    }
+    out
+}
+
+/// The first 8 base32 characters of a key, like GNUnet's `GNUNET_i2s`.
+/// Enough to tell peers apart in logs without printing whole keys.
+#[derive(Clone, Copy)]
+pub struct Short<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Display for Short<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base32_encode(&self.0[..self.0.len().min(5)]))
+    }
+}
+
+impl fmt::Debug for Short<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Decode Crockford base32 into exactly `out.len()` bytes. Decoding is case
+/// insensitive and accepts Crockford's aliases (`O` for `0`, `I` and `L` for
+/// `1`, and GNUnet's `U` for `V`). The padding bits of the final character
+/// must be zero, so each key has one encoding, up to case and aliases.
+pub fn base32_decode(s: &str, out: &mut [u8]) -> Result<(), ParseKeyError> {
+    if s.len() != base32_len(out.len()) {
+        return Err(ParseKeyError);
+    }
+
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    let mut out = out.iter_mut();
+    for c in s.bytes() {
+        bits = bits << 5 | base32_value(c).ok_or(ParseKeyError)? as u32;
+        nbits += 5;
+        if nbits >= 8 {
+            nbits -= 8;
+            // the length check makes sure there's space
+            *out.next().ok_or(ParseKeyError)? = (bits >> nbits) as u8;
+        }
+    }
+    if bits & ((1 << nbits) - 1) != 0 {
+        return Err(ParseKeyError);
+    }
+    Ok(())
+}
+
+fn base32_value(c: u8) -> Option<u8> {
+    let c = match c.to_ascii_uppercase() {
+        b'O' => b'0',
+        b'I' | b'L' => b'1',
+        b'U' => b'V',
+        c => c,
+    };
+    ALPHABET.iter().position(|&a| a == c).map(|i| i as u8)
+}
+
+/// Encode bytes as lowercase hex
+pub fn hex_encode(data: &[u8]) -> String {
+    use fmt::Write;
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(out, "{b:02x}").unwrap();
+    }
+    out
+}
+
+/// Percent-encode everything but URI unreserved characters, eg for a query
+/// string.
+pub fn percent_encode(s: &str) -> String {
+    use fmt::Write;
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            write!(out, "%{b:02X}").unwrap();
+        }
+    }
+    out
+}
+
+/// Undo [`percent_encode`]. `None` if an escape is invalid, or it isn't
+/// UTF-8 after decoding.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        out.push(hex_value(bytes.next()?)? << 4 | hex_value(bytes.next()?)?);
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Decode hex, in either case, into exactly `out.len()` bytes
+pub fn hex_decode(s: &str, out: &mut [u8]) -> Result<(), ParseKeyError> {
+    if s.len() != out.len() * 2 {
+        return Err(ParseKeyError);
+    }
+    for (o, pair) in out.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        let [hi, lo] = [pair[0], pair[1]].map(hex_value);
+        *o = hi
+            .zip(lo)
+            .map(|(hi, lo)| hi << 4 | lo)
+            .ok_or(ParseKeyError)?;
+    }
+    Ok(())
+}
+
+/// Only hex digits, unlike `from_str_radix`, which also takes a sign
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// A key could not be parsed from a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseKeyError;
+
+impl fmt::Display for ParseKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid key encoding, expected base32 or hex")
+    }
+}
+
+impl core::error::Error for ParseKeyError {}
+
+/// Keys are base32 in human-readable formats like JSON, and raw bytes in
+/// compact ones
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_key<S: serde::Serializer>(key: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    if s.is_human_readable() {
+        s.serialize_str(&base32_encode(key))
+    } else {
+        s.serialize_bytes(key)
+    }
+}
+
+/// Undo [`serialize_key`]. Strings can also be hex.
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_key<'de, D, const N: usize>(d: D) -> Result<[u8; N], D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::{self, SeqAccess, Visitor};
+
+    struct KeyVisitor<const N: usize>;
+
+    impl<'de, const N: usize> Visitor<'de> for KeyVisitor<N> {
+        type Value = [u8; N];
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a {N} byte key")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            crate::decode_key(s).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<Self::Value, E> {
+            b.try_into().map_err(|_| E::invalid_length(b.len(), &self))
+        }
+
+        // for formats without a bytes type
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut key = [0; N];
+            for (i, b) in key.iter_mut().enumerate() {
+                *b = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            }
+            Ok(key)
+        }
+    }
+
+    if d.is_human_readable() {
+        d.deserialize_str(KeyVisitor)
+    } else {
+        d.deserialize_bytes(KeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base32_decode, base32_encode, base32_len, hex_decode, hex_encode, percent_decode,
+        percent_encode, Short,
+    };
+
+    #[test]
+    fn base32() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(&[0xff]), "ZW");
+        assert_eq!(base32_encode(b"hello"), "D1JPRV3F");
+        assert_eq!(base32_len(32), 52);
+        assert_eq!(base32_len(64), 103);
+
+        let data: Vec<u8> = (0..64).collect();
+        let s = base32_encode(&data);
+        assert_eq!(s.len(), 103);
+        let mut out = [0; 64];
+        base32_decode(&s, &mut out).unwrap();
+        assert_eq!(out[..], data[..]);
+        base32_decode(&s.to_lowercase(), &mut out).unwrap();
+        assert_eq!(out[..], data[..]);
+
+        let mut out = [0; 5];
+        base32_decode("d1jprv3f", &mut out).unwrap();
+        assert_eq!(&out, b"hello");
+        assert!(base32_decode("D1JPRV3", &mut out).is_err());
+        assert!(base32_decode("D1JPRV3!", &mut out).is_err());
+        // "ZW" is 0xff with two zero bits after it, which must stay zero
+        let mut out = [0; 1];
+        base32_decode("ZW", &mut out).unwrap();
+        assert!(base32_decode("ZX", &mut out).is_err());
+        assert!(base32_decode("ZZ", &mut out).is_err());
+
+        assert_eq!(Short(b"hello world").to_string(), "D1JPRV3F");
+        assert_eq!(Short(&[0xff]).to_string(), "ZW");
+    }
+
+    #[test]
+    fn percent() {
+        let encoded = percent_encode("127.0.0.1:2086/a b");
+        assert_eq!(encoded, "127.0.0.1%3A2086%2Fa%20b");
+        assert_eq!(percent_decode(&encoded).unwrap(), "127.0.0.1:2086/a b");
+        assert_eq!(percent_decode("%e2%9c%93").unwrap(), "\u{2713}");
+        assert_eq!(percent_decode("%3"), None);
+        assert_eq!(percent_decode("%ff"), None);
+        assert_eq!(percent_decode("%+f"), None);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_encode(&[0, 0xab, 0x10]), "00ab10");
+        let mut out = [0; 3];
+        hex_decode("00AB10", &mut out).unwrap();
+        assert_eq!(out, [0, 0xab, 0x10]);
+        assert!(hex_decode("00ab1", &mut out).is_err());
+        assert!(hex_decode("00ab1g", &mut out).is_err());
+        // from_str_radix would take these as +1 and +f
+        assert!(hex_decode("+1ab10", &mut out).is_err());
+        assert!(hex_decode("00+f10", &mut out).is_err());
+        assert!(hex_decode("00\u{e9}10", &mut out).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! What can go wrong. Each area has its own error, and [`DhtError`] holds
+//! any of them for callers that don't care which.
+
+/// Why a message or block didn't parse. `what` names it, eg `"PUT"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    /// Shorter than its fixed size header
+    #[error("{what}: {len} bytes is too short for the header")]
+    TooShort { what: &'static str, len: usize },
+    #[error("{what}: unexpected message type {found}")]
+    WrongType { what: &'static str, found: u16 },
+    #[error("{what}: unsupported version {found}")]
+    Version { what: &'static str, found: u16 },
+    /// The header's size is more than there is, or less than the header
+    #[error("{what}: message size {size} doesn't fit in {len} bytes")]
+    Size {
+        what: &'static str,
+        size: u16,
+        len: usize,
+    },
+    /// A field runs past the end of the message. `offset` is from the
+    /// start of the message, and `remaining` is what's left of it there.
+    #[error("{what}: {field} of {len} bytes at offset {offset} exceeds the remaining {remaining}")]
+    Overrun {
+        what: &'static str,
+        field: &'static str,
+        len: usize,
+        offset: usize,
+        remaining: usize,
+    },
+    /// Addresses that aren't UTF-8 from `offset`
+    #[error("{what}: addresses aren't UTF-8 at offset {offset}")]
+    Addresses { what: &'static str, offset: usize },
+    #[error("{what}: header says {expected} addresses but there are {found}")]
+    AddressCount {
+        what: &'static str,
+        expected: u16,
+        found: usize,
+    },
+    /// A field is larger than [`limits`](crate::limits) allows
+    #[error("{what}: {field} of {len} bytes is over the limit of {max}")]
+    TooLarge {
+        what: &'static str,
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+}
+
+/// Why a message couldn't be built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum EncodeError {
+    /// Messages are at most
+    /// [`MAX_MESSAGE_SIZE`](crate::limits::MAX_MESSAGE_SIZE) bytes
+    #[error("{what}: {size} bytes is too large for a message")]
+    TooLarge { what: &'static str, size: usize },
+    /// A field is larger than [`limits`](crate::limits) allows
+    #[error("{what}: {field} of {len} bytes is over the limit of {max}")]
+    FieldTooLarge {
+        what: &'static str,
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// The buffer given to `encode_into` is only `len` bytes
+    #[error("{what}: {size} byte message doesn't fit in {len} bytes")]
+    BufferTooSmall {
+        what: &'static str,
+        size: usize,
+        len: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("invalid signature")]
+    Signature,
+    /// Not a point on the curve
+    #[error("invalid public key")]
+    PublicKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RoutingError {
+    #[error("routing table snapshot is malformed")]
+    Snapshot,
+    /// Snapshots are only restored into empty tables
+    #[error("routing table isn't empty")]
+    NotEmpty,
+}
+
+/// Why a block or snapshot wasn't stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StoreError {
+    #[error("block has expired")]
+    Expired,
+    /// Nothing that expires sooner could be evicted to make room
+    #[error("no room for the block")]
+    Full,
+    #[error("snapshot is malformed")]
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DhtError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Routing(#[from] RoutingError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Put(#[from] crate::node::PutError),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{BlockKey, Timestamp},
+        bloom::PeerBloomFilter,
+        limits::PATH_ELEMENT_SIZE,
+        message::{Flags, PutMessage, PutMessageHeader},
+    };
+
+    use super::ParseError;
+
+    #[test]
+    fn context() {
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            1,
+            Timestamp::FOREVER,
+            PeerBloomFilter::default(),
+            BlockKey::from([0; 64]),
+            None,
+            b"block",
+        )
+        .unwrap();
+        let mut b = put.as_bytes().to_vec();
+        // claim a path longer than the block
+        b[14..16].copy_from_slice(&1u16.to_be_bytes());
+
+        let offset = size_of::<PutMessageHeader>();
+        let err = PutMessage::parse(&b).err().unwrap();
+        let field = "put path";
+        assert_eq!(
+            err,
+            ParseError::Overrun {
+                what: "PUT",
+                field,
+                len: PATH_ELEMENT_SIZE,
+                offset,
+                remaining: 5
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            format!("PUT: put path of 96 bytes at offset {offset} exceeds the remaining 5")
+        );
+
+        let err = PutMessage::parse(&b[..offset - 1]).err().unwrap();
+        assert_eq!(
+            err,
+            ParseError::TooShort {
+                what: "PUT",
+                len: offset - 1
+            }
+        );
+    }
+}
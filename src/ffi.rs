@@ -0,0 +1,495 @@
+//! A C interface to [`DhtNode`], so C applications can embed the DHT and
+//! bring their own transport.
+//!
+//! The node is driven the same way as from Rust: the application feeds it
+//! what its transport sees with `r6n_node_peer_connected`,
+//! `r6n_node_receive` etc, calls `r6n_node_tick` when it's due, and after
+//! each call drains `r6n_node_poll_action` for what to send and who to
+//! connect to. Results of GETs come from `r6n_node_poll_event`.
+//!
+//! The `r6n-ffi` crate in `ffi/` builds it as a C library:
+//!
+//! ```text
+//! cargo build --release -p r6n-ffi
+//! ```
+//!
+//! Link against `target/release/libr6n_ffi.so` (or `.a`) with the
+//! declarations in `ffi/include/r6n.h`. The header is generated from this
+//! module with [cbindgen](https://github.com/mozilla/cbindgen), see
+//! `ffi/cbindgen.toml`.
+//!
+//! A node is not thread safe; calls for the same node must not overlap.
+//! Peers are 32 byte Ed25519 public keys and keys 64 byte hashes.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    ffi::{c_char, CStr},
+    ptr, slice,
+    time::Duration,
+};
+
+use ed25519_dalek::SigningKey;
+
+use crate::{
+    block::BlockKey,
+    identity::LocalPeer,
+    maintenance::Budget,
+    node::{DhtNode, PutOptions},
+    query::{GetOptions, QueryEvent, QueryId},
+    underlay::{ConnectionInfo, Underlay, UnderlaySignal},
+    Message, Peer,
+};
+
+/// Send `data` to `peer`
+pub const R6N_ACTION_SEND: u32 = 1;
+/// Connect to `peer` at the address in `data`, a UTF-8 string without a NUL
+pub const R6N_ACTION_CONNECT: u32 = 2;
+/// Prefer keeping the connection to `peer`
+pub const R6N_ACTION_HOLD: u32 = 3;
+/// Undo a hold on `peer`
+pub const R6N_ACTION_DROP: u32 = 4;
+
+/// A block found by a GET
+pub const R6N_EVENT_RESULT: u32 = 1;
+/// The GET timed out, and won't produce any more results
+pub const R6N_EVENT_EXPIRED: u32 = 2;
+
+/// Something for the application's transport to do.
+///
+/// `data` points into the node and stays valid until the next
+/// `r6n_node_poll_action` or `r6n_node_free`.
+#[repr(C)]
+pub struct R6nAction {
+    pub kind: u32,
+    pub peer: [u8; 32],
+    pub data: *const u8,
+    pub len: usize,
+}
+
+/// Something that happened to a GET.
+///
+/// `data` points into the node and stays valid until the next
+/// `r6n_node_poll_event` or `r6n_node_free`. For expired queries it's null.
+#[repr(C)]
+pub struct R6nEvent {
+    pub kind: u32,
+    pub query: u64,
+    pub block_type: u32,
+    /// Microseconds since the UNIX epoch
+    pub expiration: u64,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+enum Action {
+    Send(Peer, Message),
+    Connect(Peer, String),
+    Hold(Peer),
+    Drop(Peer),
+}
+
+/// Queues whatever the node asks of it for the application to poll.
+struct FfiUnderlay {
+    actions: RefCell<VecDeque<Action>>,
+    network_size: Cell<u64>,
+}
+
+impl Underlay for FfiUnderlay {
+    type Address = String;
+    type NetworkSizeEstimate = u64;
+    type Error = ();
+
+    fn try_connect(&self, peer: Peer, addr: String) -> Result<(), ()> {
+        self.push(Action::Connect(peer, addr));
+        Ok(())
+    }
+
+    fn hold(&self, peer: Peer) {
+        self.push(Action::Hold(peer));
+    }
+
+    fn drop(&self, peer: Peer) {
+        self.push(Action::Drop(peer));
+    }
+
+    fn send(&self, peer: Peer, message: Message) -> Result<(), ()> {
+        self.push(Action::Send(peer, message));
+        Ok(())
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.network_size.get()
+    }
+}
+
+impl FfiUnderlay {
+    fn push(&self, action: Action) {
+        self.actions.borrow_mut().push_back(action);
+    }
+}
+
+/// A DHT node owned by C code
+pub struct R6nNode {
+    node: DhtNode<FfiUnderlay>,
+    peer: Peer,
+    // kept so the pointers handed out stay valid until the next poll
+    action: Option<Action>,
+    event: Option<QueryEvent>,
+}
+
+/// Create a node with the Ed25519 secret key at `secret`, or a fresh one if
+/// it's null, assuming a network of `network_size` peers until
+/// `r6n_node_set_network_size` says otherwise. Free it with `r6n_node_free`.
+///
+/// # Safety
+///
+/// `secret` must be null or point to 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_new(secret: *const u8, network_size: u64) -> *mut R6nNode {
+    let identity = match secret.is_null() {
+        true => LocalPeer::generate(),
+        false => LocalPeer::new(SigningKey::from_bytes(&*secret.cast())),
+    };
+    let underlay = FfiUnderlay {
+        actions: RefCell::default(),
+        network_size: Cell::new(network_size),
+    };
+    let peer = identity.peer();
+    let mut node = DhtNode::new(identity.peer_id(), underlay);
+    node.set_identity(identity);
+    Box::into_raw(Box::new(R6nNode {
+        node,
+        peer,
+        action: None,
+        event: None,
+    }))
+}
+
+/// # Safety
+///
+/// `node` must be null or from `r6n_node_new`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_free(node: *mut R6nNode) {
+    if !node.is_null() {
+        drop(Box::from_raw(node));
+    }
+}
+
+/// Write the node's public key to `out`.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `out` point to 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_peer(node: *const R6nNode, out: *mut u8) {
+    ptr::copy_nonoverlapping((*node).peer.as_bytes().as_ptr(), out, 32);
+}
+
+/// # Safety
+///
+/// `node` must be from `r6n_node_new`.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_set_network_size(node: *mut R6nNode, network_size: u64) {
+    (*node).node.underlay().network_size.set(network_size);
+}
+
+/// The transport connected to `peer`. `constrained` peers, eg phones, are
+/// talked to but not routed through.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `peer` point to 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_peer_connected(
+    node: *mut R6nNode,
+    peer: *const u8,
+    constrained: bool,
+) {
+    let info = ConnectionInfo {
+        constrained,
+        ..ConnectionInfo::default()
+    };
+    let signal = UnderlaySignal::PeerConnected(read_peer(peer), info);
+    (*node).node.handle_signal(signal);
+}
+
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `peer` point to 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_peer_disconnected(node: *mut R6nNode, peer: *const u8) {
+    let signal = UnderlaySignal::PeerDisconnected(read_peer(peer));
+    (*node).node.handle_signal(signal);
+}
+
+/// We're reachable at `addr`, eg `ip+udp://192.0.2.1:2086`. Returns -1 if
+/// it isn't UTF-8.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `addr` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_address_added(node: *mut R6nNode, addr: *const c_char) -> i32 {
+    match CStr::from_ptr(addr).to_str() {
+        Ok(addr) => {
+            let signal = UnderlaySignal::AddressAdded(addr.to_owned());
+            (*node).node.handle_signal(signal);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `addr` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_address_deleted(node: *mut R6nNode, addr: *const c_char) -> i32 {
+    match CStr::from_ptr(addr).to_str() {
+        Ok(addr) => {
+            let signal = UnderlaySignal::AddressDeleted(addr.to_owned());
+            (*node).node.handle_signal(signal);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Process a message received from `peer`.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new`, `peer` point to 32 bytes and `data`
+/// to `len`.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_receive(
+    node: *mut R6nNode,
+    peer: *const u8,
+    data: *const u8,
+    len: usize,
+) {
+    let message = Message::from_bytes(read_bytes(data, len).to_vec());
+    let signal = UnderlaySignal::Receive(read_peer(peer), message);
+    (*node).node.handle_signal(signal);
+}
+
+/// Run due maintenance, returning how many microseconds until it should be
+/// called again.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new`.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_tick(node: *mut R6nNode) -> u64 {
+    let node = &mut (*node).node;
+    let next_due = node.tick(Budget::unlimited()).next_due;
+    let wait = next_due.saturating_sub(node.now());
+    wait.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Take the next thing for the transport to do, returning false once there
+/// are none left.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_poll_action(node: *mut R6nNode, out: *mut R6nAction) -> bool {
+    let node = &mut *node;
+    node.action = node.node.underlay().actions.borrow_mut().pop_front();
+    let Some(action) = &node.action else {
+        return false;
+    };
+    let (kind, peer, data): (_, _, &[u8]) = match action {
+        Action::Send(peer, message) => (R6N_ACTION_SEND, peer, message.as_bytes()),
+        Action::Connect(peer, addr) => (R6N_ACTION_CONNECT, peer, addr.as_bytes()),
+        Action::Hold(peer) => (R6N_ACTION_HOLD, peer, &[]),
+        Action::Drop(peer) => (R6N_ACTION_DROP, peer, &[]),
+    };
+    out.write(R6nAction {
+        kind,
+        peer: *peer.as_bytes(),
+        data: data.as_ptr(),
+        len: data.len(),
+    });
+    true
+}
+
+/// Start looking for blocks of `block_type` under the 64 byte `key`,
+/// returning the id their events will have.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `key` point to 64 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_get(node: *mut R6nNode, key: *const u8, block_type: u32) -> u64 {
+    let id = (*node)
+        .node
+        .get(read_key(key), block_type, vec![], GetOptions::default());
+    id.into()
+}
+
+/// Stop a GET. Its events stop too.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new`.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_cancel(node: *mut R6nNode, query: u64) {
+    (*node).node.queries_mut().cancel(QueryId::from(query));
+}
+
+/// Take the next event of a GET, returning false once there are none left.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new` and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_poll_event(node: *mut R6nNode, out: *mut R6nEvent) -> bool {
+    let node = &mut *node;
+    node.event = node.node.next_query_event();
+    let event = match &node.event {
+        None => return false,
+        Some(QueryEvent::Result {
+            id,
+            block_type,
+            expiration,
+            block,
+            ..
+        }) => R6nEvent {
+            kind: R6N_EVENT_RESULT,
+            query: (*id).into(),
+            block_type: *block_type,
+            expiration: expiration.as_micros(),
+            data: block.as_ptr(),
+            len: block.len(),
+        },
+        Some(QueryEvent::Expired(id)) => R6nEvent {
+            kind: R6N_EVENT_EXPIRED,
+            query: (*id).into(),
+            block_type: 0,
+            expiration: 0,
+            data: ptr::null(),
+            len: 0,
+        },
+    };
+    out.write(event);
+    true
+}
+
+/// Store `data` as a block of `block_type` under the 64 byte `key` for
+/// `lifetime_secs`, returning how many peers it was sent to, or -1 if it's
+/// too big to send.
+///
+/// # Safety
+///
+/// `node` must be from `r6n_node_new`, `key` point to 64 bytes and `data`
+/// to `len`.
+#[no_mangle]
+pub unsafe extern "C" fn r6n_node_put(
+    node: *mut R6nNode,
+    key: *const u8,
+    block_type: u32,
+    data: *const u8,
+    len: usize,
+    lifetime_secs: u64,
+) -> i64 {
+    let options = PutOptions {
+        expiration: Duration::from_secs(lifetime_secs),
+        ..PutOptions::default()
+    };
+    let block = read_bytes(data, len);
+    match (*node).node.put(block_type, read_key(key), block, &options) {
+        Ok(sent) => sent.try_into().unwrap_or(i64::MAX),
+        Err(_) => -1,
+    }
+}
+
+unsafe fn read_peer(peer: *const u8) -> Peer {
+    Peer::from_bytes(peer.cast::<[u8; 32]>().read_unaligned())
+}
+
+unsafe fn read_key(key: *const u8) -> BlockKey {
+    BlockKey::from(key.cast::<[u8; 64]>().read_unaligned())
+}
+
+// C callers pass null for empty buffers, which slices can't be
+unsafe fn read_bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    match len {
+        0 => &[],
+        _ => slice::from_raw_parts(data, len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use crate::testing::identities;
+
+    use super::*;
+
+    fn actions(node: *mut R6nNode) -> Vec<(u32, [u8; 32], Vec<u8>)> {
+        let mut actions = vec![];
+        let mut action = MaybeUninit::uninit();
+        while unsafe { r6n_node_poll_action(node, action.as_mut_ptr()) } {
+            let action = unsafe { action.assume_init_ref() };
+            let data = unsafe { read_bytes(action.data, action.len) };
+            actions.push((action.kind, action.peer, data.to_vec()));
+        }
+        actions
+    }
+
+    #[test]
+    fn put_and_get() {
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let secrets = [a.signing_key().to_bytes(), b.signing_key().to_bytes()];
+        let nodes = secrets.map(|secret| unsafe { r6n_node_new(secret.as_ptr(), 2) });
+        let peers = [a.peer(), b.peer()].map(|peer| *peer.as_bytes());
+        unsafe {
+            r6n_node_peer_connected(nodes[0], peers[1].as_ptr(), false);
+            r6n_node_peer_connected(nodes[1], peers[0].as_ptr(), false);
+        }
+
+        // shuttle messages between the two until they run out
+        let deliver = || loop {
+            let mut delivered = false;
+            for (from, to) in [(0, 1), (1, 0)] {
+                for (kind, peer, data) in actions(nodes[from]) {
+                    if kind == R6N_ACTION_SEND && peer == peers[to] {
+                        let sender = peers[from].as_ptr();
+                        unsafe { r6n_node_receive(nodes[to], sender, data.as_ptr(), data.len()) };
+                        delivered = true;
+                    }
+                }
+            }
+            if !delivered {
+                break;
+            }
+        };
+        deliver();
+
+        let key = [7; 64];
+        let sent = unsafe { r6n_node_put(nodes[0], key.as_ptr(), 13, b"hi".as_ptr(), 2, 60) };
+        assert_eq!(sent, 1);
+        deliver();
+        let query = unsafe { r6n_node_get(nodes[0], key.as_ptr(), 13) };
+        deliver();
+
+        let mut event = MaybeUninit::uninit();
+        let mut found = None;
+        while unsafe { r6n_node_poll_event(nodes[0], event.as_mut_ptr()) } {
+            let event = unsafe { event.assume_init_ref() };
+            if event.kind == R6N_EVENT_RESULT {
+                assert_eq!(event.query, query);
+                found = Some(unsafe { read_bytes(event.data, event.len) }.to_vec());
+            }
+        }
+        assert_eq!(found.as_deref(), Some(&b"hi"[..]));
+
+        for node in nodes {
+            unsafe { r6n_node_free(node) };
+        }
+    }
+}
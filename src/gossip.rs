@@ -0,0 +1,449 @@
+//! HELLO advertisement.
+//!
+//! Peers tell their neighbours how to reach them with a HelloMessage, both
+//! when they connect and periodically after. Neighbours keep the HELLOs they
+//! receive, and pass some of them on to newly connected peers as PUTs of
+//! HELLO blocks.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use ed25519_dalek::{ed25519::SignatureBytes, SigningKey};
+use rand::{seq::IteratorRandom, RngCore};
+
+use crate::{
+    block::{
+        encode_addresses, Addrs, BlockKey, HelloBlock, HelloBlockHeader,
+        HelloBlockSignaturePayload, Timestamp,
+    },
+    bloom::PeerBloomFilter,
+    encoding::{base32_decode, base32_encode, percent_decode, percent_encode},
+    error::EncodeError,
+    hellos::HelloCache,
+    limits::MAX_HELLO_SIZE,
+    maintenance::{Budget, TaskStatus},
+    message::{Flags, Hello, HelloMessage, PutMessage},
+    routing::RoutingTable,
+    Message, Peer,
+};
+
+/// A HELLO whose signature has been checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedHello {
+    peer: Peer,
+    signature: SignatureBytes,
+    expiration: Timestamp,
+    /// encoded as in the wire format
+    addrs: Vec<u8>,
+}
+
+impl SignedHello {
+    /// Sign a HELLO advertising `addrs` until `expiration`.
+    pub fn sign<'a>(
+        key: &SigningKey,
+        expiration: Timestamp,
+        addrs: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let addrs = encode_addresses(addrs);
+        let payload = HelloBlockSignaturePayload::new(expiration, &addrs);
+        Self {
+            peer: Peer::from_bytes(key.verifying_key().to_bytes()),
+            signature: payload.sign(key),
+            expiration,
+            addrs,
+        }
+    }
+
+    /// The HELLO a neighbour sent about itself, if it signed it and it
+    /// fits in a HELLO block.
+    pub fn from_message(peer: Peer, hello: &Hello<'_>) -> Option<Self> {
+        let fits = hello.raw_addresses().len() <= MAX_ADDRESSES_SIZE;
+        (fits && hello.verify(&peer)).then(|| Self {
+            peer,
+            signature: *hello.signature(),
+            expiration: hello.expiration(),
+            addrs: hello.raw_addresses().to_vec(),
+        })
+    }
+
+    /// The HELLO in a block. [`HelloBlock::parse`] already checked it.
+    pub fn from_block(block: &HelloBlock<'_>) -> Self {
+        Self {
+            peer: block.peer(),
+            signature: *block.signature(),
+            expiration: block.expiration(),
+            addrs: block.raw_addresses().to_vec(),
+        }
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    pub fn expiration(&self) -> Timestamp {
+        self.expiration
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expiration.is_expired(now)
+    }
+
+    pub fn addresses(&self) -> Addrs<'_> {
+        // only ever built from valid strings
+        Addrs::new(std::str::from_utf8(&self.addrs).unwrap_or_default())
+    }
+
+    /// A HelloMessage for sending our own HELLO to a neighbour. Fails if
+    /// there are too many addresses to fit in a message.
+    pub fn to_message(&self) -> Result<Message, EncodeError> {
+        HelloMessage::encode(&self.signature, self.expiration, &self.addrs)
+    }
+
+    /// This HELLO as an encoded HELLO block.
+    pub fn to_block(&self) -> Vec<u8> {
+        HelloBlock::encode(&self.peer, &self.signature, self.expiration, &self.addrs)
+    }
+
+    /// A PUT of this HELLO as a block, eg to pass it on to a neighbour.
+    pub fn to_put(
+        &self,
+        replication_level: u16,
+        peer_bloom_filter: PeerBloomFilter,
+    ) -> Result<Message, EncodeError> {
+        let block = self.to_block();
+        PutMessage::encode(
+            HelloBlock::BLOCK_TYPE,
+            Flags::default(),
+            replication_level,
+            self.expiration,
+            peer_bloom_filter,
+            BlockKey(self.peer.id().0),
+            None,
+            &block,
+        )
+    }
+}
+
+/// The most bytes of addresses a HELLO block has room for
+const MAX_ADDRESSES_SIZE: usize = MAX_HELLO_SIZE - size_of::<HelloBlockHeader>();
+
+/// HELLO URIs start with this, as GNUnet's do
+const HELLO_URI_PREFIX: &str = "gnunet://hello/";
+
+impl SignedHello {
+    /// This HELLO as a URI like GNUnet's, eg to pass to another peer out of
+    /// band:
+    ///
+    /// `gnunet://hello/<peer>/<signature>/<expiration>?<scheme>=<address>&...`
+    ///
+    /// The peer and signature are base32, and addresses are
+    /// percent-encoded. Unlike GNUnet, the expiration is in microseconds,
+    /// since that is what's signed.
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!(
+            "{HELLO_URI_PREFIX}{}/{}/{}",
+            self.peer,
+            base32_encode(&self.signature),
+            self.expiration.as_micros()
+        );
+        for (i, addr) in self.addresses().enumerate() {
+            uri.push(if i == 0 { '?' } else { '&' });
+            match addr.split_once("://") {
+                Some((scheme, rest)) => {
+                    uri.push_str(&percent_encode(scheme));
+                    uri.push('=');
+                    uri.push_str(&percent_encode(rest));
+                }
+                None => uri.push_str(&percent_encode(addr)),
+            }
+        }
+        uri
+    }
+}
+
+/// Parses a [HELLO URI](SignedHello::to_uri), checking its signature.
+impl FromStr for SignedHello {
+    type Err = ParseHelloError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix(HELLO_URI_PREFIX).ok_or(ParseHelloError)?;
+        let (path, query) = s.split_once('?').unwrap_or((s, ""));
+        let mut path = path.split('/');
+        let (Some(peer), Some(signature), Some(expiration), None) =
+            (path.next(), path.next(), path.next(), path.next())
+        else {
+            return Err(ParseHelloError);
+        };
+        let peer: Peer = peer.parse().map_err(|_| ParseHelloError)?;
+        let mut signature_bytes = [0; 64];
+        base32_decode(signature, &mut signature_bytes).map_err(|_| ParseHelloError)?;
+        let expiration = expiration.parse().map_err(|_| ParseHelloError)?;
+        let expiration = Timestamp::from_micros(expiration);
+
+        let mut addrs = vec![];
+        for addr in query.split('&').filter(|a| !a.is_empty()) {
+            let addr = match addr.split_once('=') {
+                Some((scheme, rest)) => {
+                    let scheme = percent_decode(scheme).ok_or(ParseHelloError)?;
+                    let rest = percent_decode(rest).ok_or(ParseHelloError)?;
+                    format!("{scheme}://{rest}")
+                }
+                None => percent_decode(addr).ok_or(ParseHelloError)?,
+            };
+            addrs.push(addr);
+        }
+        let addrs = encode_addresses(addrs.iter().map(String::as_str));
+        if addrs.len() > MAX_ADDRESSES_SIZE {
+            return Err(ParseHelloError);
+        }
+
+        let signature = SignatureBytes::from(signature_bytes);
+        let payload = HelloBlockSignaturePayload::new(expiration, &addrs);
+        if !payload.verify(&peer, &signature) {
+            return Err(ParseHelloError);
+        }
+        Ok(Self {
+            peer,
+            signature,
+            expiration,
+            addrs,
+        })
+    }
+}
+
+/// A HELLO URI that is malformed, or not signed by its peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseHelloError;
+
+impl fmt::Display for ParseHelloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid HELLO URI")
+    }
+}
+
+impl std::error::Error for ParseHelloError {}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct GossipConfig {
+    /// How often our HELLO is sent to neighbours
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub interval: Duration,
+    /// How many neighbours are sent our HELLO each interval
+    pub fan_out: usize,
+    /// How many cached HELLOs are sent to a newly connected peer
+    pub per_connect: usize,
+    /// How many HELLOs of other peers are kept
+    pub cache_size: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            fan_out: 3,
+            per_connect: 4,
+            cache_size: 256,
+        }
+    }
+}
+
+/// Our HELLO, and the HELLOs we have heard from others.
+pub struct Gossip {
+    config: GossipConfig,
+    local: Option<SignedHello>,
+    cache: HelloCache,
+    /// the neighbours picked for the current round, and how many of them
+    /// have been sent our HELLO
+    round: Vec<Peer>,
+    sent: usize,
+}
+
+impl Gossip {
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            config,
+            local: None,
+            cache: HelloCache::new(config.cache_size),
+            round: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    /// Change the configuration. A smaller cache is only enforced as new
+    /// HELLOs arrive.
+    pub fn set_config(&mut self, config: GossipConfig) {
+        self.cache.set_capacity(config.cache_size);
+        self.config = config;
+    }
+
+    pub fn local(&self) -> Option<&SignedHello> {
+        self.local.as_ref()
+    }
+
+    pub fn set_local(&mut self, hello: SignedHello) {
+        self.local = Some(hello);
+    }
+
+    /// The HELLOs of other peers
+    pub fn hellos(&self) -> &HelloCache {
+        &self.cache
+    }
+
+    pub fn hellos_mut(&mut self) -> &mut HelloCache {
+        &mut self.cache
+    }
+
+    /// Call `send` with our HELLO for [`fan_out`](GossipConfig::fan_out)
+    /// random neighbours, spending a unit of `budget` per neighbour. A round
+    /// that runs out of budget carries on with the same neighbours next
+    /// time.
+    pub fn run(
+        &mut self,
+        routing: &RoutingTable,
+        budget: &mut Budget,
+        rng: &mut dyn RngCore,
+        mut send: impl FnMut(Peer, Message),
+    ) -> TaskStatus {
+        let Some(message) = self.local.as_ref().and_then(|h| h.to_message().ok()) else {
+            return TaskStatus::Done;
+        };
+        if self.sent == self.round.len() {
+            let peers = routing.iter().map(|r| *r.peer());
+            self.round = peers.choose_multiple(rng, self.config.fan_out);
+            self.sent = 0;
+        }
+        while let Some(&peer) = self.round.get(self.sent) {
+            if !budget.spend() {
+                return TaskStatus::Pending;
+            }
+            send(peer, message.clone());
+            self.sent += 1;
+        }
+        TaskStatus::Done
+    }
+
+    /// The cached HELLOs to pass on to a newly connected peer: those of the
+    /// peers closest to it, which it is most likely to want to route to.
+    pub fn for_new_peer(&self, peer: &Peer) -> Vec<&SignedHello> {
+        self.cache.closest(&peer.id(), self.config.per_connect)
+    }
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self::new(GossipConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::Timestamp,
+        maintenance::{Budget, TaskStatus},
+        message::Hello,
+        routing::{RoutingTable, RoutingTableConfig},
+        testing::identities,
+        underlay::{memory::MemoryNetwork, memory::MemoryUnderlay, Underlay, UnderlaySignal},
+        DhtNode,
+    };
+
+    use super::{Gossip, ParseHelloError, SignedHello};
+
+    fn pump(node: &mut DhtNode<MemoryUnderlay>, rx: &Receiver<UnderlaySignal<MemoryUnderlay>>) {
+        while let Ok(signal) = rx.try_recv() {
+            node.handle_signal(signal);
+        }
+    }
+
+    #[test]
+    fn sign() {
+        let a = identities::peers()[0].signing_key();
+        let hello = SignedHello::sign(&a, Timestamp::from_micros(100), ["ip+udp://127.0.0.1:2086"]);
+        let message = hello.to_message().unwrap();
+        let parsed = Hello::parse(message.as_bytes()).unwrap();
+        assert_eq!(
+            SignedHello::from_message(*hello.peer(), &parsed).as_ref(),
+            Some(&hello)
+        );
+        let wrong = identities::peers()[1].peer();
+        assert_eq!(SignedHello::from_message(wrong, &parsed), None);
+        assert!(hello.addresses().eq(["ip+udp://127.0.0.1:2086"]));
+
+        let uri = hello.to_uri();
+        assert!(uri.ends_with("?ip%2Budp=127.0.0.1%3A2086"));
+        assert_eq!(uri.parse::<SignedHello>().as_ref(), Ok(&hello));
+        let forged = uri.replace("2086", "2087");
+        assert_eq!(forged.parse::<SignedHello>(), Err(ParseHelloError));
+    }
+
+    #[test]
+    fn exchange_on_connect() {
+        let network = MemoryNetwork::new();
+        let [a, b, c] = [0, 1, 2].map(|i| &identities::peers()[i]);
+        let (ua, rxa) = network.join(a.peer());
+        let (ub, rxb) = network.join(b.peer());
+        let addr_b = ub.address();
+        let mut na = DhtNode::new(a.peer_id(), ua);
+        let mut nb = DhtNode::new(b.peer_id(), ub);
+
+        let hello_a = SignedHello::sign(&a.signing_key(), Timestamp::FOREVER, ["memory://0"]);
+        na.set_local_hello(hello_a.clone());
+        // a has heard of c before
+        let hello_c = SignedHello::sign(&c.signing_key(), Timestamp::FOREVER, ["memory://2"]);
+        assert!(na
+            .gossip_mut()
+            .hellos_mut()
+            .insert(hello_c.clone(), Timestamp::from_micros(0)));
+
+        na.underlay().try_connect(b.peer(), addr_b).unwrap();
+        pump(&mut na, &rxa);
+        pump(&mut nb, &rxb);
+        assert_eq!(nb.gossip().hellos().get(&a.peer_id()), Some(&hello_a));
+        assert_eq!(nb.gossip().hellos().get(&c.peer_id()), Some(&hello_c));
+        // b has no HELLO of its own to send
+        assert!(na.gossip().hellos().get(&b.peer_id()).is_none());
+    }
+
+    #[test]
+    fn budgeted_rounds() {
+        let host = identities::host();
+        let mut routing = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        for fixture in &identities::peers()[..10] {
+            let _ = routing.insert(fixture.peer());
+        }
+        let mut gossip = Gossip::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut sent = Vec::new();
+        let mut run = |gossip: &mut Gossip, mut budget: Budget| {
+            gossip.run(&routing, &mut budget, &mut rng, |peer, _| sent.push(peer))
+        };
+
+        // nothing to advertise yet
+        assert_eq!(run(&mut gossip, Budget::unlimited()), TaskStatus::Done);
+        gossip.set_local(SignedHello::sign(
+            &host.signing_key(),
+            Timestamp::FOREVER,
+            [],
+        ));
+        assert_eq!(run(&mut gossip, Budget::work(2)), TaskStatus::Pending);
+        let round = gossip.round.clone();
+        // the round finishes with the neighbour it picked, rather than
+        // picking again
+        assert_eq!(run(&mut gossip, Budget::unlimited()), TaskStatus::Done);
+        assert_eq!(run(&mut gossip, Budget::unlimited()), TaskStatus::Done);
+        assert_eq!(sent.len(), 6);
+        assert_eq!(sent[..3], round);
+        assert_eq!(sent[3..], gossip.round);
+    }
+}
@@ -0,0 +1,307 @@
+//! The HELLOs of remote peers.
+//!
+//! Every validated HELLO we hear, whether from the peer itself or passed on
+//! in a PUT, is kept until it expires, so that we know how to reach peers
+//! we have seen before. Gossip passes them on to new neighbours, bootstrap
+//! reconnects to them after a restart and [`DhtNode::connect`] picks their
+//! addresses.
+//!
+//! HELLOs are signed whole, so the freshest one is what is passed on. A
+//! peer's older HELLOs may still have addresses the newest doesn't though,
+//! so every address is remembered until the last HELLO advertising it
+//! expires, and they are offered together in order of
+//! [scheme preference](HelloCache::set_scheme_preference).
+//!
+//! [`DhtNode::connect`]: crate::DhtNode::connect
+
+use std::{cmp::Reverse, collections::HashMap, mem::size_of, str::FromStr};
+
+use crate::{
+    block::{HelloBlock, Timestamp},
+    error::StoreError,
+    gossip::SignedHello,
+    underlay::SchemePreference,
+    Distance, PeerId,
+};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"r6hc";
+
+/// The freshest HELLO of each peer, up to a capacity. See the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct HelloCache {
+    capacity: usize,
+    hellos: HashMap<PeerId, Entry>,
+    preference: SchemePreference,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    hello: SignedHello,
+    /// every address heard for the peer, until when it was advertised
+    addresses: Vec<(String, Timestamp)>,
+}
+
+impl Entry {
+    fn new(hello: SignedHello) -> Self {
+        let until = hello.expiration();
+        let addresses = hello.addresses().map(|a| (a.to_owned(), until)).collect();
+        Self { hello, addresses }
+    }
+
+    /// Returns whether the HELLO had anything new.
+    fn merge(&mut self, hello: &SignedHello) -> bool {
+        let mut changed = false;
+        for addr in hello.addresses() {
+            match self.addresses.iter_mut().find(|(a, _)| a == addr) {
+                Some((_, until)) if *until >= hello.expiration() => {}
+                Some((_, until)) => {
+                    *until = hello.expiration();
+                    changed = true;
+                }
+                None => {
+                    self.addresses.push((addr.to_owned(), hello.expiration()));
+                    changed = true;
+                }
+            }
+        }
+        if hello.expiration() > self.hello.expiration() {
+            self.hello = hello.clone();
+            changed = true;
+        }
+        changed
+    }
+
+    fn remove_expired(&mut self, now: Timestamp) {
+        self.addresses.retain(|(_, until)| !until.is_expired(now));
+    }
+}
+
+impl HelloCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hellos: HashMap::new(),
+            preference: SchemePreference::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// A smaller capacity is only enforced as new HELLOs arrive.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// List addresses in this order of scheme. Within a scheme, those
+    /// advertised for longest come first.
+    pub fn set_scheme_preference(&mut self, preference: SchemePreference) {
+        self.preference = preference;
+    }
+
+    /// Merge a HELLO with those we have from the same peer. The freshest
+    /// is kept to pass on, and each address for as long as any of them
+    /// advertised it. When the cache is full, the peer whose HELLO expires
+    /// first makes room. Returns whether the HELLO had anything new.
+    pub fn insert(&mut self, hello: SignedHello, now: Timestamp) -> bool {
+        if hello.is_expired(now) || self.capacity == 0 {
+            return false;
+        }
+        let id = hello.peer().id();
+        if let Some(entry) = self.hellos.get_mut(&id) {
+            entry.remove_expired(now);
+            return entry.merge(&hello);
+        }
+        if self.hellos.len() >= self.capacity {
+            let soonest = self
+                .hellos
+                .iter()
+                .min_by_key(|(_, e)| e.hello.expiration())
+                .map(|(&id, _)| id);
+            if let Some(soonest) = soonest {
+                self.hellos.remove(&soonest);
+            }
+        }
+        self.hellos.insert(id, Entry::new(hello));
+        true
+    }
+
+    /// The freshest HELLO of a peer
+    pub fn get(&self, peer: &PeerId) -> Option<&SignedHello> {
+        self.hellos.get(peer).map(|e| &e.hello)
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) -> Option<SignedHello> {
+        self.hellos.remove(peer).map(|e| e.hello)
+    }
+
+    /// Every address of a peer from its HELLOs, in order of
+    /// [preference](Self::set_scheme_preference).
+    pub fn addresses(&self, peer: &PeerId) -> Vec<&str> {
+        let Some(entry) = self.hellos.get(peer) else {
+            return Vec::new();
+        };
+        let mut addrs: Vec<&(String, Timestamp)> = entry.addresses.iter().collect();
+        addrs.sort_by_key(|(addr, until)| (self.preference.rank(addr), Reverse(*until)));
+        addrs.into_iter().map(|(addr, _)| &addr[..]).collect()
+    }
+
+    /// The [`addresses`](Self::addresses) of a peer that parse as `A`, eg
+    /// an underlay's addresses.
+    pub fn parse_addresses<A: FromStr>(&self, peer: &PeerId) -> Vec<A> {
+        let addrs = self.addresses(peer).into_iter();
+        addrs.filter_map(|a| a.parse().ok()).collect()
+    }
+
+    /// The freshest HELLO of each peer
+    pub fn iter(&self) -> impl Iterator<Item = &SignedHello> {
+        self.hellos.values().map(|e| &e.hello)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hellos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hellos.is_empty()
+    }
+
+    /// The HELLOs of the `n` peers closest to `id`, closest first
+    pub fn closest(&self, id: &PeerId, n: usize) -> Vec<&SignedHello> {
+        let mut hellos: Vec<(Distance, &SignedHello)> = self
+            .hellos
+            .iter()
+            .filter(|(peer, _)| *peer != id)
+            .map(|(peer, e)| {
+                let distance = Distance::between(id.as_bytes(), peer.as_bytes());
+                (distance, &e.hello)
+            })
+            .collect();
+        hellos.sort_by_key(|&(distance, _)| distance);
+        hellos.into_iter().take(n).map(|(_, h)| h).collect()
+    }
+
+    /// Forget expired HELLOs, returning how many there were.
+    pub fn remove_expired(&mut self, now: Timestamp) -> usize {
+        let before = self.hellos.len();
+        self.hellos.retain(|_, e| !e.hello.is_expired(now));
+        for entry in self.hellos.values_mut() {
+            entry.remove_expired(now);
+        }
+        before - self.hellos.len()
+    }
+
+    /// Serialize the freshest HELLOs, as HELLO blocks, so that they can
+    /// be [`restore`](Self::restore)d after a restart. Addresses only in
+    /// older HELLOs are lost.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        for hello in self.iter() {
+            let block = hello.to_block();
+            // the length of a HELLO block is bounded by a message's
+            out.extend_from_slice(&(block.len() as u16).to_be_bytes());
+            out.extend_from_slice(&block);
+        }
+        out
+    }
+
+    /// Keep the HELLOs in a [`snapshot`](Self::snapshot), returning how
+    /// many were kept. Signatures are checked again, and expired HELLOs
+    /// skipped. Nothing is kept from a snapshot that doesn't parse.
+    pub fn restore(&mut self, snapshot: &[u8], now: Timestamp) -> Result<usize, StoreError> {
+        let mut rest = snapshot
+            .strip_prefix(&SNAPSHOT_MAGIC)
+            .ok_or(StoreError::Snapshot)?;
+        let mut hellos = Vec::new();
+        while !rest.is_empty() {
+            let len = rest.get(..size_of::<u16>()).ok_or(StoreError::Snapshot)?;
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            let block = rest
+                .get(size_of::<u16>()..size_of::<u16>() + len)
+                .ok_or(StoreError::Snapshot)?;
+            let block = HelloBlock::parse(block).map_err(|_| StoreError::Snapshot)?;
+            hellos.push(SignedHello::from_block(&block));
+            rest = &rest[size_of::<u16>() + len..];
+        }
+        let kept = hellos.into_iter().map(|h| self.insert(h, now));
+        Ok(kept.filter(|&kept| kept).count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::Timestamp, gossip::SignedHello, testing::identities, underlay::SchemePreference,
+    };
+
+    use super::{HelloCache, StoreError};
+
+    #[test]
+    fn cache() {
+        let [a, b, c] = [0, 1, 2].map(|i| identities::peers()[i].signing_key());
+        let at = Timestamp::from_micros;
+        let mut cache = HelloCache::new(2);
+
+        let hello = SignedHello::sign(&a, at(100), ["ip+udp://127.0.0.1:2086", "memory://1"]);
+        let id = hello.peer().id();
+        assert!(!cache.insert(hello.clone(), at(200)));
+        assert!(cache.insert(hello.clone(), at(0)));
+        // an older HELLO doesn't replace a newer one
+        let older = SignedHello::sign(&a, at(50), ["memory://1"]);
+        assert!(!cache.insert(older, at(0)));
+        assert_eq!(cache.get(&id), Some(&hello));
+        assert_eq!(cache.parse_addresses::<std::net::SocketAddr>(&id), []);
+        assert_eq!(cache.addresses(&id).len(), 2);
+
+        assert!(cache.insert(SignedHello::sign(&b, at(300), []), at(0)));
+        assert!(cache.insert(SignedHello::sign(&c, at(200), []), at(0)));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&id).is_none());
+        assert_eq!(cache.closest(&id, 5).len(), 2);
+
+        assert_eq!(cache.remove_expired(at(250)), 1);
+        assert_eq!(cache.len(), 1);
+
+        let snapshot = cache.snapshot();
+        let mut restored = HelloCache::new(2);
+        assert_eq!(restored.restore(&snapshot, at(0)), Ok(1));
+        assert!(restored.get(&identities::peers()[1].peer_id()).is_some());
+        assert_eq!(
+            restored.restore(&snapshot[..snapshot.len() - 1], at(0)),
+            Err(StoreError::Snapshot)
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let a = identities::peers()[0].signing_key();
+        let at = Timestamp::from_micros;
+        let mut cache = HelloCache::new(1);
+        let hello = |until, addrs: &[&str]| SignedHello::sign(&a, at(until), addrs.to_vec());
+
+        cache.insert(hello(100, &["ip+udp://127.0.0.1:1", "memory://1"]), at(0));
+        let newer = hello(200, &["ip+tcp://127.0.0.1:2", "memory://1"]);
+        assert!(cache.insert(newer.clone(), at(0)));
+        // nothing new
+        assert!(!cache.insert(hello(150, &["memory://1"]), at(0)));
+        let id = newer.peer().id();
+        assert_eq!(cache.get(&id), Some(&newer));
+        // advertised for longest first
+        assert_eq!(
+            cache.addresses(&id),
+            ["memory://1", "ip+tcp://127.0.0.1:2", "ip+udp://127.0.0.1:1"]
+        );
+
+        cache.set_scheme_preference(SchemePreference::new(["ip+udp", "memory"]));
+        assert_eq!(
+            cache.addresses(&id),
+            ["ip+udp://127.0.0.1:1", "memory://1", "ip+tcp://127.0.0.1:2"]
+        );
+
+        // the older HELLO's address goes when it expires
+        assert_eq!(cache.remove_expired(at(101)), 0);
+        assert_eq!(cache.addresses(&id), ["memory://1", "ip+tcp://127.0.0.1:2"]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Our own peer identity.
+//!
+//! Keys are stored as the raw 32 byte Ed25519 secret, or with the `pem`
+//! feature, as PKCS#8 PEM like `openssl genpkey -algorithm ed25519` writes.
+
+use std::{fs, io, path::Path};
+
+use ed25519_dalek::{ed25519::SignatureBytes, SigningKey};
+use rand::RngCore;
+
+use crate::{
+    block::{HopSignaturePayload, Timestamp},
+    gossip::SignedHello,
+    Peer, PeerId,
+};
+
+/// Our peer, with the key it signs with.
+#[derive(Clone)]
+pub struct LocalPeer {
+    key: SigningKey,
+    peer: Peer,
+    // cached, as computing it means hashing
+    id: PeerId,
+}
+
+impl LocalPeer {
+    pub fn new(key: SigningKey) -> Self {
+        let peer = Peer::from(key.verifying_key());
+        Self {
+            key,
+            id: peer.id(),
+            peer,
+        }
+    }
+
+    /// A new random identity.
+    pub fn generate() -> Self {
+        let mut secret = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        Self::from_bytes(&secret)
+    }
+
+    /// An identity from its raw Ed25519 secret key.
+    pub fn from_bytes(secret: &[u8; 32]) -> Self {
+        Self::new(SigningKey::from_bytes(secret))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.key
+    }
+
+    pub fn peer(&self) -> Peer {
+        self.peer
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.id
+    }
+
+    /// Load an identity saved with [`save`](Self::save), or with the `pem`
+    /// feature, a PEM file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if let Ok(secret) = <[u8; 32]>::try_from(&*bytes) {
+            return Ok(Self::from_bytes(&secret));
+        }
+        #[cfg(feature = "pem")]
+        if let Ok(pem) = std::str::from_utf8(&bytes) {
+            return Self::from_pem(pem);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an Ed25519 secret key",
+        ))
+    }
+
+    /// Load the identity at `path`, or generate one and save it there if
+    /// there isn't one yet.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        match Self::load(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let local = Self::generate();
+                local.save(path)?;
+                Ok(local)
+            }
+            res => res,
+        }
+    }
+
+    /// Save the raw secret key. On unix, the file is only readable by its
+    /// owner.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_secret(path.as_ref(), &self.to_bytes())
+    }
+
+    #[cfg(feature = "pem")]
+    pub fn from_pem(pem: &str) -> io::Result<Self> {
+        use ed25519_dalek::pkcs8::DecodePrivateKey;
+
+        SigningKey::from_pkcs8_pem(pem)
+            .map(Self::new)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(feature = "pem")]
+    pub fn to_pem(&self) -> String {
+        use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+
+        // encoding a valid key can't fail
+        let pem = self.key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        pem.to_string()
+    }
+
+    /// Save the secret key as PKCS#8 PEM. On unix, the file is only readable
+    /// by its owner.
+    #[cfg(feature = "pem")]
+    pub fn save_pem(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_secret(path.as_ref(), self.to_pem().as_bytes())
+    }
+
+    /// Sign our HELLO advertising `addrs` until `expiration`.
+    pub fn sign_hello<'a>(
+        &self,
+        expiration: Timestamp,
+        addrs: impl IntoIterator<Item = &'a str>,
+    ) -> SignedHello {
+        SignedHello::sign(&self.key, expiration, addrs)
+    }
+
+    /// Sign that we forwarded `block` from `pred` to `succ`, for the path of
+    /// a message with path recording.
+    pub fn sign_hop(
+        &self,
+        expiration: Timestamp,
+        block: &[u8],
+        pred: &Peer,
+        succ: &Peer,
+    ) -> SignatureBytes {
+        HopSignaturePayload::new(expiration, block, pred, succ).sign(&self.key)
+    }
+}
+
+impl From<SigningKey> for LocalPeer {
+    fn from(key: SigningKey) -> Self {
+        Self::new(key)
+    }
+}
+
+/// Doesn't print the secret key.
+impl std::fmt::Debug for LocalPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("LocalPeer").field(&self.peer).finish()
+    }
+}
+
+fn write_secret(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{HopSignaturePayload, Timestamp},
+        testing::identities,
+    };
+
+    use super::LocalPeer;
+
+    #[test]
+    fn load_and_save() {
+        let dir = std::env::temp_dir().join(format!("r6n-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key");
+
+        let local = LocalPeer::load_or_generate(&path).unwrap();
+        assert_eq!(local.peer().id(), local.peer_id());
+        let loaded = LocalPeer::load_or_generate(&path).unwrap();
+        assert_eq!(loaded.peer(), local.peer());
+
+        std::fs::write(&path, b"nonsense").unwrap();
+        assert!(LocalPeer::load(&path).is_err());
+
+        #[cfg(feature = "pem")]
+        {
+            local.save_pem(&path).unwrap();
+            assert_eq!(LocalPeer::load(&path).unwrap().peer(), local.peer());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn signing() {
+        let f = &identities::peers()[0];
+        let local = LocalPeer::from_bytes(&f.secret);
+        assert_eq!(local.peer(), f.peer());
+
+        let hello = local.sign_hello(Timestamp::FOREVER, ["memory://0"]);
+        assert_eq!(hello.peer(), &f.peer());
+
+        let [pred, succ] = [identities::host().peer(), identities::peers()[1].peer()];
+        let signature = local.sign_hop(Timestamp::FOREVER, b"block", &pred, &succ);
+        let payload = HopSignaturePayload::new(Timestamp::FOREVER, b"block", &pred, &succ);
+        assert!(payload.verify(&f.peer(), &signature));
+        let swapped = HopSignaturePayload::new(Timestamp::FOREVER, b"block", &succ, &pred);
+        assert!(!swapped.verify(&f.peer(), &signature));
+    }
+}
@@ -0,0 +1,297 @@
+//! GNUnet's transport HELLOs from before DHT HELLOs.
+//!
+//! Older peers, and the hostlists they serve, still advertise themselves
+//! with these. Each address is a transport plugin's name and its binary
+//! address, and none of them are signed: the old transport checked each one
+//! with a PING before using it. So a [`LegacyHello`] can't become a HELLO
+//! block, but its addresses can be converted to the strings HELLO blocks
+//! carry and handed to [`Bootstrap`](crate::bootstrap::Bootstrap) with
+//! [`add_legacy`](crate::bootstrap::Bootstrap::add_legacy). Once connected,
+//! the peer's own signed HELLO takes over.
+//!
+//! Only the `tcp` and `udp` plugins' addresses have an equivalent here;
+//! addresses of other transports are skipped.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ed25519_dalek::VerifyingKey;
+use zerocopy::{big_endian, FromBytes, FromZeroes, Unaligned};
+
+use crate::{
+    block::{encode_addresses, PublicKey, Timestamp},
+    error::{CryptoError, DhtError, ParseError},
+    message::MessageHeader,
+    Peer,
+};
+
+/// `GNUNET_MESSAGE_TYPE_HELLO_LEGACY`
+pub const LEGACY_HELLO_TYPE: u16 = 16;
+
+/// `GNUNET_HELLO_Message`
+#[derive(FromZeroes, FromBytes, Unaligned)]
+#[repr(C)]
+struct LegacyHelloHeader {
+    header: MessageHeader,
+    friend_only: big_endian::U32,
+    public_key: PublicKey,
+}
+
+/// A transport HELLO, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyHello {
+    peer: Peer,
+    friend_only: bool,
+    addresses: Vec<LegacyAddress>,
+}
+
+/// One address of a [`LegacyHello`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyAddress {
+    pub transport: String,
+    pub expiration: Timestamp,
+    /// As the transport plugin encodes it
+    pub address: Vec<u8>,
+}
+
+impl LegacyHello {
+    /// Parse a transport HELLO, message header included
+    pub fn parse(b: &[u8]) -> Result<Self, DhtError> {
+        const WHAT: &str = "legacy HELLO";
+        let too_short = ParseError::TooShort {
+            what: WHAT,
+            len: b.len(),
+        };
+        let header = LegacyHelloHeader::ref_from_prefix(b).ok_or(too_short)?;
+        let found = header.header.message_type();
+        if found != LEGACY_HELLO_TYPE {
+            return Err(ParseError::WrongType { what: WHAT, found }.into());
+        }
+        let size = header.header.message_size();
+        let mut offset = size_of::<LegacyHelloHeader>();
+        let mut rest = b.get(offset..size as usize).ok_or(ParseError::Size {
+            what: WHAT,
+            size,
+            len: b.len(),
+        })?;
+
+        let peer: Peer = header.public_key.into();
+        if VerifyingKey::from_bytes(peer.as_bytes()).is_err() {
+            return Err(CryptoError::PublicKey.into());
+        }
+
+        let mut addresses = vec![];
+        while !rest.is_empty() {
+            let address = LegacyAddress::split(&mut rest, &mut offset)
+                .ok_or(ParseError::Addresses { what: WHAT, offset })?;
+            addresses.push(address);
+        }
+        Ok(Self {
+            peer,
+            friend_only: header.friend_only.get() != 0,
+            addresses,
+        })
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Whether the peer only wants to be found by its friends. Such HELLOs
+    /// shouldn't be passed on.
+    pub fn is_friend_only(&self) -> bool {
+        self.friend_only
+    }
+
+    /// The addresses as they were advertised, including any we can't use
+    pub fn raw_addresses(&self) -> &[LegacyAddress] {
+        &self.addresses
+    }
+
+    /// When the last address expires
+    pub fn expiration(&self) -> Timestamp {
+        let latest = self.addresses.iter().map(|a| a.expiration).max();
+        latest.unwrap_or(Timestamp::from_micros(0))
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expiration().is_expired(now)
+    }
+
+    /// Drop the addresses that expired at `now`
+    pub fn remove_expired(&mut self, now: Timestamp) {
+        self.addresses.retain(|a| !a.expiration.is_expired(now));
+    }
+
+    /// The addresses as HELLO block addresses, eg
+    /// `ip+tcp://192.0.2.1:2086`. Addresses of transports without an
+    /// equivalent are skipped.
+    pub fn addresses(&self) -> impl Iterator<Item = String> + '_ {
+        self.addresses
+            .iter()
+            .filter_map(LegacyAddress::to_hello_address)
+    }
+
+    /// The usable addresses encoded as in a HELLO block, see
+    /// [`encode_addresses`]
+    pub fn encode_addresses(&self) -> Vec<u8> {
+        let addrs: Vec<String> = self.addresses().collect();
+        encode_addresses(addrs.iter().map(String::as_str))
+    }
+}
+
+impl LegacyAddress {
+    /// The address at the front of `rest`: the transport name with a 0
+    /// byte after it, the address length, the expiration, then the address.
+    fn split(rest: &mut &[u8], offset: &mut usize) -> Option<Self> {
+        let name_len = rest.iter().position(|&b| b == 0)?;
+        let transport = std::str::from_utf8(&rest[..name_len]).ok()?;
+        let fixed = rest.get(name_len + 1..name_len + 11)?;
+        let len = u16::from_be_bytes([fixed[0], fixed[1]]) as usize;
+        let expiration = u64::from_be_bytes(fixed[2..].try_into().unwrap());
+        let start = name_len + 11;
+        let address = rest.get(start..start + len)?;
+
+        let this = Self {
+            transport: transport.to_owned(),
+            expiration: Timestamp::from_micros(expiration),
+            address: address.to_vec(),
+        };
+        *rest = &rest[start + len..];
+        *offset += start + len;
+        Some(this)
+    }
+
+    /// This address as a HELLO block address, if its transport has an
+    /// equivalent here
+    pub fn to_hello_address(&self) -> Option<String> {
+        let scheme = match &*self.transport {
+            "tcp" => "ip+tcp",
+            "udp" => "ip+udp",
+            _ => return None,
+        };
+        // both plugins put 4 bytes of options in front of the IP and port
+        let (ip, port): (IpAddr, _) = match self.address.get(4..)? {
+            [ip @ .., a, b] if ip.len() == 4 => (
+                Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).into(),
+                [*a, *b],
+            ),
+            [ip @ .., a, b] if ip.len() == 16 => (
+                Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).into(),
+                [*a, *b],
+            ),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(port);
+        // port 0 is a peer that can't be connected to, only connect out
+        (port != 0).then(|| format!("{scheme}://{}", SocketAddr::new(ip, port)))
+    }
+}
+
+/// Parse the transport HELLOs in a hostlist, skipping entries that are
+/// invalid, expired at `now` or of another type, and dropping addresses
+/// that expired. Hostlists of newer peers
+/// are parsed with [`parse_hostlist`](crate::bootstrap::parse_hostlist).
+pub fn parse_legacy_hostlist(mut body: &[u8], now: Timestamp) -> Vec<LegacyHello> {
+    let mut hellos = vec![];
+    while let Some(header) = MessageHeader::ref_from_prefix(body) {
+        let size = header.message_size() as usize;
+        if size < size_of::<MessageHeader>() || size > body.len() {
+            break;
+        }
+        if let Ok(mut hello) = LegacyHello::parse(&body[..size]) {
+            hello.remove_expired(now);
+            if !hello.is_expired(now) {
+                hellos.push(hello);
+            }
+        }
+        body = &body[size..];
+    }
+    hellos
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::Timestamp,
+        bootstrap::{encode_hostlist, parse_hostlist, Bootstrap},
+        gossip::SignedHello,
+        testing::identities,
+    };
+
+    use super::{parse_legacy_hostlist, LegacyHello, LEGACY_HELLO_TYPE};
+
+    /// A transport HELLO as `GNUNET_HELLO_create` makes them
+    fn legacy_hello(public_key: [u8; 32], addresses: &[(&str, u64, &[u8])]) -> Vec<u8> {
+        let mut b = vec![0; 4];
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&public_key);
+        for (transport, expiration, address) in addresses {
+            b.extend_from_slice(transport.as_bytes());
+            b.push(0);
+            b.extend_from_slice(&(address.len() as u16).to_be_bytes());
+            b.extend_from_slice(&expiration.to_be_bytes());
+            b.extend_from_slice(address);
+        }
+        let size = b.len() as u16;
+        b[..2].copy_from_slice(&size.to_be_bytes());
+        b[2..4].copy_from_slice(&LEGACY_HELLO_TYPE.to_be_bytes());
+        b
+    }
+
+    #[test]
+    fn addresses() {
+        let peer = identities::peers()[0].peer();
+        let mut v6 = vec![0, 0, 0, 0];
+        v6.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&2086u16.to_be_bytes());
+        let b = legacy_hello(
+            *peer.as_bytes(),
+            &[
+                ("tcp", 100, &[0, 0, 0, 0, 192, 0, 2, 1, 8, 38]),
+                ("udp", 200, &v6),
+                ("http_client", 200, b"http://192.0.2.1:1080/"),
+                // listens on no port
+                ("tcp", 200, &[0, 0, 0, 0, 192, 0, 2, 1, 0, 0]),
+            ],
+        );
+
+        let mut hello = LegacyHello::parse(&b).unwrap();
+        assert_eq!(hello.peer(), &peer);
+        assert_eq!(hello.raw_addresses().len(), 4);
+        assert_eq!(hello.expiration(), Timestamp::from_micros(200));
+        let addrs: Vec<String> = hello.addresses().collect();
+        assert_eq!(addrs, ["ip+tcp://192.0.2.1:2086", "ip+udp://[::1]:2086"]);
+        hello.remove_expired(Timestamp::from_micros(150));
+        let addrs: Vec<String> = hello.addresses().collect();
+        assert_eq!(addrs, ["ip+udp://[::1]:2086"]);
+
+        // an address running past the end
+        let mut truncated = b[..b.len() - 1].to_vec();
+        truncated[..2].copy_from_slice(&(b.len() as u16 - 1).to_be_bytes());
+        assert!(LegacyHello::parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn mixed_hostlist() {
+        let at = Timestamp::from_micros;
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let signed = SignedHello::sign(&a.signing_key(), at(100), ["ip+udp://192.0.2.1:2086"]);
+        let mut body = encode_hostlist([&signed]);
+        let address: &[u8] = &[0, 0, 0, 0, 192, 0, 2, 2, 8, 38];
+        body.extend_from_slice(&legacy_hello(b.public, &[("tcp", 100, address)]));
+        body.extend_from_slice(&legacy_hello(b.public, &[("tcp", 10, address)]));
+
+        let signed_hellos = parse_hostlist(&body, at(50));
+        assert_eq!(signed_hellos, [signed]);
+        let legacy = parse_legacy_hostlist(&body, at(50));
+        assert_eq!(legacy.len(), 1);
+        assert_eq!(legacy[0].peer(), &b.peer());
+
+        let mut bootstrap = Bootstrap::default();
+        bootstrap.add(signed_hellos[0].clone(), Duration::ZERO);
+        bootstrap.add_legacy(&legacy[0], Duration::ZERO);
+        assert_eq!(bootstrap.len(), 2);
+    }
+}
@@ -1,137 +1,404 @@
-use std::{
-    mem,
-    time::{Duration, Instant},
-};
+//! Without the default `std` feature only the wire format builds: [`block`],
+//! [`bloom`], [`encoding`], [`limits`], [`message`] and the key types here,
+//! on `alloc`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{fmt, str::FromStr};
 
-use curve25519_dalek::edwards::CompressedEdwardsY;
+use encoding::ParseKeyError;
 
+#[cfg(feature = "std")]
+pub mod advertise;
+#[cfg(feature = "std")]
+pub mod bans;
 pub mod block;
 pub mod bloom;
+#[cfg(feature = "std")]
+pub mod bootstrap;
+#[cfg(feature = "tokio")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod datacache;
+#[cfg(feature = "std")]
+pub mod debug;
+#[cfg(feature = "std")]
+pub mod dedup;
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod gossip;
+#[cfg(feature = "std")]
+pub mod hellos;
+#[cfg(feature = "std")]
+pub mod identity;
+#[cfg(feature = "legacy-hello")]
+pub mod legacy;
+pub mod limits;
+#[cfg(feature = "std")]
+pub mod maintenance;
 pub mod message;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod monitor;
+#[cfg(feature = "std")]
+pub mod neighbours;
+#[cfg(feature = "std")]
+pub mod node;
+#[cfg(feature = "std")]
+pub mod nse;
+#[cfg(feature = "std")]
+pub mod outbound;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+#[cfg(feature = "std")]
+pub mod policy;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod quality;
+#[cfg(feature = "std")]
+pub mod query;
+#[cfg(feature = "std")]
+pub mod ratelimit;
+#[cfg(feature = "std")]
+pub mod refresh;
+#[cfg(feature = "std")]
+pub mod relay;
+#[cfg(feature = "std")]
+pub mod republish;
+#[cfg(feature = "std")]
+pub mod routing;
+#[cfg(feature = "std")]
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_vectors;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod time;
+#[cfg(feature = "std")]
 pub mod underlay;
 
+#[cfg(feature = "std")]
+pub use node::DhtNode;
+#[cfg(feature = "std")]
+pub use routing::{
+    InsertOutcome, RemovalReason, RouteEvent, RoutingTable, RoutingTableConfig, RoutingTableStats,
+    SharedRoutingTable,
+};
+
 // as far as I can tell, R5N requires EdDSA (Ed25519).
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Peer(curve25519_dalek::edwards::CompressedEdwardsY);
 
 impl PartialOrd for Peer {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 impl Ord for Peer {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
         Ord::cmp(self.0.as_bytes(), other.0.as_bytes())
     }
 }
 
 impl Peer {
-    fn id(&self) -> PeerId {
+    /// A peer from its Ed25519 public key. The key is not validated.
+    pub fn from_bytes(public_key: [u8; 32]) -> Self {
+        Self(curve25519_dalek::edwards::CompressedEdwardsY(public_key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    /// An abbreviated form for logs
+    pub fn short(&self) -> encoding::Short<'_> {
+        encoding::Short(self.as_bytes())
+    }
+
+    /// The hash of the public key. This is not cached, so prefer storing
+    /// the id over calling this repeatedly.
+    pub fn id(&self) -> PeerId {
         use sha2::Digest;
         PeerId(sha2::Sha512::digest(self.0.as_bytes()).into())
     }
 }
 
+impl From<ed25519_dalek::VerifyingKey> for Peer {
+    fn from(value: ed25519_dalek::VerifyingKey) -> Self {
+        Self::from_bytes(value.to_bytes())
+    }
+}
+
+/// Formats as Crockford base32, like GNUnet. Use `{:x}` for hex.
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encoding::base32_encode(self.as_bytes()))
+    }
+}
+
+impl fmt::LowerHex for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encoding::hex_encode(self.as_bytes()))
+    }
+}
+
+/// Parses either Crockford base32 or hex
+impl FromStr for Peer {
+    type Err = ParseKeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_key(s).map(Self::from_bytes)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct PeerId([u8; 64]);
-pub struct Message;
 
-pub struct RoutingTable {
-    host: PeerId,
-    epoch: Instant,
-    neighbours: Vec<u8>,
-    routes: Vec<Route>,
+impl PeerId {
+    /// An abbreviated form for logs
+    pub fn short(&self) -> encoding::Short<'_> {
+        encoding::Short(&self.0)
+    }
 }
 
-impl RoutingTable {
-    fn insert(&mut self, peer: Peer) -> Result<(), Peer> {
-        let id = peer.id();
-        let dist = log2_xor_dist(&self.host, &id);
-        self.neighbours[dist as usize] += 1;
-
-        let created = Instant::now().duration_since(self.epoch);
-        let new_route = Route {
-            dist,
-            created,
-            peer,
-        };
-
-        match self.routes.binary_search(&new_route) {
-            // peer already inserted? disconnect previous
-            Ok(i) => Err(mem::replace(&mut self.routes[i], new_route).peer),
-            Err(i) => {
-                self.routes.insert(i, new_route);
-                Ok(())
+/// Formats as Crockford base32, like GNUnet. Use `{:x}` for hex.
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encoding::base32_encode(&self.0))
+    }
+}
+
+impl fmt::LowerHex for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encoding::hex_encode(&self.0))
+    }
+}
+
+/// Parses either Crockford base32 or hex
+impl FromStr for PeerId {
+    type Err = ParseKeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_key(s).map(Self)
+    }
+}
+
+fn decode_key<const N: usize>(s: &str) -> Result<[u8; N], ParseKeyError> {
+    let mut out = [0; N];
+    if s.len() == N * 2 {
+        encoding::hex_decode(s, &mut out)?;
+    } else {
+        encoding::base32_decode(s, &mut out)?;
+    }
+    Ok(out)
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Peer {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        encoding::serialize_key(self.as_bytes(), s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Peer {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        encoding::deserialize_key(d).map(Self::from_bytes)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerId {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        encoding::serialize_key(&self.0, s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PeerId {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        encoding::deserialize_key(d).map(Self)
+    }
+}
+
+impl PeerId {
+    pub fn from_bytes(bytes: [u8; 64]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        &self.0
+    }
+}
+
+/// An encoded protocol message, as carried by the underlay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message(Vec<u8>);
+
+impl Message {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The header at the start of the message, if it's long enough to have one
+    pub fn header(&self) -> Option<&message::MessageHeader> {
+        use zerocopy::FromBytes;
+        message::MessageHeader::ref_from_prefix(&self.0)
+    }
+}
+
+/// The full XOR distance between two 512-bit keys. Ordered so that smaller is
+/// closer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance(pub [u8; 64]);
+
+impl Distance {
+    pub fn between(x: &[u8; 64], y: &[u8; 64]) -> Self {
+        Self(xor(x, y))
+    }
+
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for w in self.0.chunks_exact(8).map(be_word) {
+            zeros += w.leading_zeros();
+            if w != 0 {
+                break;
             }
         }
+        zeros
     }
 
-    /// Find the last peer in this k-bucket. corresponds to the shortest lived connection.
-    fn last_k(&self, dist: u16) -> Option<usize> {
-        if self.neighbours[dist as usize] == 0 {
-            return None;
-        }
+    /// The bucket index, as returned by [`log2_xor_dist`]
+    pub fn log2(&self) -> u16 {
+        512 - self.leading_zeros() as u16
+    }
+
+    pub fn bucket(&self) -> BucketIndex {
+        BucketIndex(self.log2())
+    }
+
+    pub fn is_closer_than(&self, other: &Distance) -> bool {
+        self < other
+    }
+}
 
-        let successor = Route {
-            dist: dist + 1,
-            created: Duration::ZERO,
-            peer: Peer(CompressedEdwardsY([0; 32])),
-        };
-
-        let last = match self.routes.binary_search(&successor) {
-            // this is the first of the next dist
-            // subtract 1 to get the last of the prev dist
-            Ok(i) => i.checked_sub(1)?,
-            // this is where the first of the next dist should get inserted
-            // which would be just after this dist.
-            Err(i) => i.checked_sub(1)?,
-        };
-
-        if self.routes[last].dist != dist {
-            return None;
+/// Which k-bucket a peer is in: the log2 of its XOR distance from the host,
+/// from 0, the host itself, to 512, where half of the network is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct BucketIndex(u16);
+
+impl BucketIndex {
+    /// The host's own, which no peer can be in
+    pub const HOST: Self = Self(0);
+    pub const FURTHEST: Self = Self(512);
+    /// How many there are, counting the host's
+    pub const COUNT: usize = 513;
+
+    /// `None` past [`FURTHEST`](Self::FURTHEST)
+    pub const fn new(dist: u16) -> Option<Self> {
+        match dist <= Self::FURTHEST.0 {
+            true => Some(Self(dist)),
+            false => None,
         }
+    }
+
+    /// The bucket `peer` is in, as seen from `host`
+    pub fn between(host: &PeerId, peer: &PeerId) -> Self {
+        Self(log2_xor_dist(host, peer))
+    }
+
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+
+    /// For indexing a list of all buckets
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// `n` buckets further out, if there are that many
+    pub fn checked_add(self, n: u16) -> Option<Self> {
+        self.0.checked_add(n).and_then(Self::new)
+    }
+
+    /// `n` buckets closer in, if there are that many
+    pub fn checked_sub(self, n: u16) -> Option<Self> {
+        self.0.checked_sub(n).map(Self)
+    }
+
+    /// Every bucket, closest first
+    pub fn all() -> impl DoubleEndedIterator<Item = Self> + Clone {
+        (0..=Self::FURTHEST.0).map(Self)
+    }
+}
 
-        Some(last)
+impl From<BucketIndex> for u16 {
+    fn from(bucket: BucketIndex) -> u16 {
+        bucket.0
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
-struct Route {
-    // log2 XOR distance from peer to host
-    dist: u16,
-    created: Duration,
-    peer: Peer,
+impl fmt::Display for BucketIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
+/// The raw [`BucketIndex`] of `peer2` as seen from `peer1`
 pub fn log2_xor_dist(peer1: &PeerId, peer2: &PeerId) -> u16 {
-    let mut dist = 0;
-
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..64 {
-        let xor = peer1.0[i] ^ peer2.0[i];
-        let clz = xor.leading_zeros() as u16;
-        dist += clz;
-        if clz < 8 {
-            break;
+    // a word at a time, most significant first
+    let words = peer1.0.chunks_exact(8).zip(peer2.0.chunks_exact(8));
+    for (i, (x, y)) in words.enumerate() {
+        let xor = be_word(x) ^ be_word(y);
+        if xor != 0 {
+            return 512 - (i as u16 * 64 + xor.leading_zeros() as u16);
         }
     }
-
-    512 - dist
+    0
 }
 
 pub fn xor(x: &[u8; 64], y: &[u8; 64]) -> [u8; 64] {
     let mut out = [0; 64];
-
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..64 {
-        out[i] = x[i] ^ y[i];
+    let words = x.chunks_exact(8).zip(y.chunks_exact(8));
+    for (out, (x, y)) in out.chunks_exact_mut(8).zip(words) {
+        out.copy_from_slice(&(ne_word(x) ^ ne_word(y)).to_ne_bytes());
     }
-
     out
 }
 
+/// 8 bytes of a key as a number, for comparing
+fn be_word(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("8 bytes"))
+}
+
+/// 8 bytes of a key as they are in memory, for bitwise operations
+fn ne_word(bytes: &[u8]) -> u64 {
+    u64::from_ne_bytes(bytes.try_into().expect("8 bytes"))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{log2_xor_dist, PeerId};
+    use crate::{log2_xor_dist, xor, BucketIndex, Distance, Peer, PeerId};
 
     #[test]
     fn xor_dist() {
@@ -152,5 +419,96 @@ mod tests {
 
         assert_eq!(log2_xor_dist(&peer2, &peer3), 469);
         assert_eq!(log2_xor_dist(&peer3, &peer2), 469);
+        assert_eq!(log2_xor_dist(&peer2, &peer2), 0);
+    }
+
+    #[test]
+    fn bucket_index() {
+        assert_eq!(BucketIndex::new(512), Some(BucketIndex::FURTHEST));
+        assert_eq!(BucketIndex::new(513), None);
+        assert_eq!(BucketIndex::FURTHEST.checked_add(1), None);
+        assert_eq!(BucketIndex::HOST.checked_sub(1), None);
+        let bucket = BucketIndex::new(7).unwrap();
+        assert_eq!(bucket.checked_add(3).map(u16::from), Some(10));
+        assert_eq!(bucket.checked_sub(7), Some(BucketIndex::HOST));
+        assert_eq!(BucketIndex::all().count(), BucketIndex::COUNT);
+        assert_eq!(BucketIndex::all().last(), Some(BucketIndex::FURTHEST));
+    }
+
+    #[test]
+    fn every_bit() {
+        let zero = [0; 64];
+        for bit in 0..512 {
+            let mut key = [0; 64];
+            key[bit / 8] = 0x80 >> (bit % 8);
+            // lower bits don't change the distance
+            key[63] |= 1;
+            let dist = 512 - bit as u16;
+            assert_eq!(log2_xor_dist(&PeerId(zero), &PeerId(key)), dist);
+            assert_eq!(Distance::between(&zero, &key).log2(), dist);
+            assert_eq!(xor(&key, &key), zero);
+            assert_eq!(xor(&zero, &key), key);
+        }
+    }
+
+    #[test]
+    fn display() {
+        let peer = Peer::from_bytes([0xab; 32]);
+        let s = peer.to_string();
+        assert_eq!(s.len(), 52);
+        assert_eq!(s.parse::<Peer>(), Ok(peer));
+        assert_eq!(format!("{peer:x}"), "ab".repeat(32));
+        assert_eq!("ab".repeat(32).parse::<Peer>(), Ok(peer));
+        assert!("not a peer".parse::<Peer>().is_err());
+
+        let id = peer.id();
+        let s = id.to_string();
+        assert_eq!(s.len(), 103);
+        assert_eq!(s.parse::<PeerId>(), Ok(id));
+        assert_eq!(format!("{id:x}").parse::<PeerId>(), Ok(id));
+        // each id has one encoding: the last character's 3 padding bits
+        // are zero, and hex has no signs
+        assert!(format!("{}1", &s[..102]).parse::<PeerId>().is_err());
+        let hex = format!("{id:x}");
+        assert!(format!("+{}", &hex[1..]).parse::<PeerId>().is_err());
+    }
+
+    #[test]
+    fn distance() {
+        let zero = [0; 64];
+        let mut a = [0; 64];
+        let mut b = [0; 64];
+        a[5] = 1 << 4;
+        b[5] = 1 << 3;
+        b[63] = 0xff;
+
+        let da = Distance::between(&zero, &a);
+        let db = Distance::between(&zero, &b);
+        assert_eq!(da.leading_zeros(), 5 * 8 + 3);
+        assert_eq!(da.log2(), log2_xor_dist(&PeerId(zero), &PeerId(a)));
+        assert_eq!(db.log2(), log2_xor_dist(&PeerId(zero), &PeerId(b)));
+        assert!(db.is_closer_than(&da));
+        assert!(!da.is_closer_than(&da));
+        assert_eq!(Distance::between(&a, &a).leading_zeros(), 512);
+        assert_eq!(Distance::between(&a, &a).log2(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde() {
+        let peer = crate::testing::identities::peers()[0].peer();
+        let json = serde_json::to_string(&peer).unwrap();
+        assert_eq!(json, format!("\"{peer}\""));
+        assert_eq!(serde_json::from_str::<Peer>(&json).unwrap(), peer);
+        let hex = format!("\"{peer:x}\"");
+        assert_eq!(serde_json::from_str::<Peer>(&hex).unwrap(), peer);
+
+        // compact formats get the bytes
+        let id = peer.id();
+        let mut cbor = vec![];
+        ciborium::into_writer(&id, &mut cbor).unwrap();
+        assert_eq!(cbor.len(), 2 + 64);
+        assert_eq!(ciborium::from_reader::<PeerId, _>(&cbor[..]).unwrap(), id);
+        assert!(ciborium::from_reader::<Peer, _>(&cbor[..]).is_err());
     }
 }
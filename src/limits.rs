@@ -0,0 +1,22 @@
+//! The largest sizes the wire format allows. Parsers reject anything larger
+//! and builders refuse to make it, so nothing is allocated for a size a peer
+//! made up.
+
+use crate::message::{max_block_size, ResultMessageHeader};
+
+/// Messages are at most 64 KiB less a byte, as their size is a `u16`
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// The largest HELLO block, which is the largest block a PUT can carry
+pub const MAX_HELLO_SIZE: usize = max_block_size(u16::MAX);
+
+/// A peer and the signature it made forwarding a block
+pub const PATH_ELEMENT_SIZE: usize = 32 + 64;
+
+/// The most elements a path can have, with both a RESULT's paths counted
+pub const MAX_PATH_LEN: usize =
+    (MAX_MESSAGE_SIZE - size_of::<ResultMessageHeader>()) / PATH_ELEMENT_SIZE;
+
+/// The largest result filter in bytes, including any mutator a block type
+/// puts in front of it
+pub const MAX_RESULT_FILTER_SIZE: usize = 1 << 15;
@@ -0,0 +1,257 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::time::{Clock, SystemClock};
+
+/// Periodic jobs a node has to run alongside message processing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Task {
+    /// Drop expired blocks and stale state.
+    Gc,
+    /// Look for peers to fill sparse buckets.
+    Refresh,
+    /// Re-PUT stored blocks towards the closest peers.
+    Republish,
+    /// Advertise our HELLO to neighbours.
+    Gossip,
+}
+
+impl Task {
+    pub const ALL: [Task; 4] = [Task::Gc, Task::Refresh, Task::Republish, Task::Gossip];
+}
+
+/// Whether a task completed its work for this period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Done,
+    /// The task ran out of budget and wants to continue on the next tick.
+    Pending,
+}
+
+/// Limits on how much maintenance a single [`Maintenance::tick`] may perform.
+///
+/// The time limit is measured with the system clock from when the budget is
+/// made, unless it's given another clock with [`with_clock`](Self::with_clock).
+/// [`DhtNode::tick`](crate::node::DhtNode::tick) gives it the node's.
+#[derive(Clone)]
+pub struct Budget {
+    /// Abstract units of work, eg blocks scanned or messages sent.
+    work: Option<u32>,
+    time: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    start: Duration,
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Budget")
+            .field("work", &self.work)
+            .field("time", &self.time)
+            .field("start", &self.start)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        let clock = Arc::new(SystemClock::new());
+        Self {
+            work: None,
+            time: None,
+            start: clock.now(),
+            clock,
+        }
+    }
+
+    pub fn work(units: u32) -> Self {
+        Self {
+            work: Some(units),
+            ..Self::unlimited()
+        }
+    }
+
+    pub fn time(limit: Duration) -> Self {
+        Self {
+            time: Some(limit),
+            ..Self::unlimited()
+        }
+    }
+
+    pub fn with_work(self, units: u32) -> Self {
+        Self {
+            work: Some(units),
+            ..self
+        }
+    }
+
+    pub fn with_time(self, limit: Duration) -> Self {
+        Self {
+            time: Some(limit),
+            ..self
+        }
+    }
+
+    /// Measure the time limit with `clock`, starting now
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            ..self
+        }
+    }
+
+    /// Take one unit of work from the budget. Returns false if the budget is
+    /// already exhausted, in which case the caller should stop.
+    pub fn spend(&mut self) -> bool {
+        if self.is_exhausted() {
+            return false;
+        }
+        if let Some(work) = &mut self.work {
+            *work -= 1;
+        }
+        true
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        let elapsed = || self.clock.now().saturating_sub(self.start);
+        self.work == Some(0) || self.time.is_some_and(|t| elapsed() >= t)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Schedule {
+    interval: Duration,
+    next: Duration,
+}
+
+/// Schedules the node's periodic [`Task`]s.
+///
+/// Times are durations since the node's epoch.
+pub struct Maintenance {
+    schedules: [Schedule; 4],
+}
+
+/// What happened during a call to [`Maintenance::tick`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tick {
+    /// Number of tasks that were run, complete or not.
+    pub ran: usize,
+    /// The budget ran out before all due tasks completed.
+    pub exhausted: bool,
+    /// When `tick` should next be called.
+    pub next_due: Duration,
+}
+
+impl Maintenance {
+    /// Schedule every task with the same interval, starting at `now`.
+    pub fn new(now: Duration, interval: Duration) -> Self {
+        Self {
+            schedules: [Schedule {
+                interval,
+                next: now + interval,
+            }; 4],
+        }
+    }
+
+    pub fn set_interval(&mut self, task: Task, interval: Duration) {
+        let s = &mut self.schedules[task as usize];
+        s.next = s.next - s.interval.min(s.next) + interval;
+        s.interval = interval;
+    }
+
+    pub fn interval(&self, task: Task) -> Duration {
+        self.schedules[task as usize].interval
+    }
+
+    pub fn next_due(&self) -> Duration {
+        self.schedules.iter().map(|s| s.next).min().unwrap()
+    }
+
+    /// Run due tasks, most overdue first, until they are all done or the
+    /// budget is exhausted. Tasks left [`Pending`](TaskStatus::Pending) stay
+    /// due and will be run first on the next tick.
+    pub fn tick(
+        &mut self,
+        now: Duration,
+        budget: &mut Budget,
+        mut run: impl FnMut(Task, &mut Budget) -> TaskStatus,
+    ) -> Tick {
+        let mut due: Vec<Task> = Task::ALL
+            .into_iter()
+            .filter(|&t| self.schedules[t as usize].next <= now)
+            .collect();
+        due.sort_by_key(|&t| self.schedules[t as usize].next);
+
+        let mut ran = 0;
+        for task in due {
+            if budget.is_exhausted() {
+                break;
+            }
+            ran += 1;
+            if run(task, budget) == TaskStatus::Done {
+                let s = &mut self.schedules[task as usize];
+                s.next = now + s.interval;
+            }
+        }
+
+        let next_due = self.next_due();
+        Tick {
+            ran,
+            exhausted: next_due <= now,
+            next_due,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Budget, Maintenance, Task, TaskStatus};
+
+    #[test]
+    fn budgeted_tick() {
+        let secs = Duration::from_secs;
+        let mut m = Maintenance::new(secs(0), secs(10));
+        m.set_interval(Task::Gossip, secs(5));
+
+        // nothing due yet
+        let tick = m.tick(secs(1), &mut Budget::unlimited(), |_, _| unreachable!());
+        assert_eq!(tick.ran, 0);
+        assert_eq!(tick.next_due, secs(5));
+
+        let mut ran = vec![];
+        let tick = m.tick(secs(5), &mut Budget::unlimited(), |t, _| {
+            ran.push(t);
+            TaskStatus::Done
+        });
+        assert_eq!(ran, [Task::Gossip]);
+        assert!(!tick.exhausted);
+        assert_eq!(tick.next_due, secs(10));
+
+        // republish needs 3 units of work but only gets 2
+        let mut ran = vec![];
+        let tick = m.tick(secs(10), &mut Budget::work(4), |t, budget| {
+            ran.push(t);
+            let needed = if t == Task::Republish { 3 } else { 1 };
+            for _ in 0..needed {
+                if !budget.spend() {
+                    return TaskStatus::Pending;
+                }
+            }
+            TaskStatus::Done
+        });
+        assert_eq!(ran, [Task::Gc, Task::Refresh, Task::Republish]);
+        assert!(tick.exhausted);
+        assert_eq!(tick.next_due, secs(10));
+
+        // the unfinished task and the starved one are still due
+        let mut ran = vec![];
+        let tick = m.tick(secs(11), &mut Budget::unlimited(), |t, _| {
+            ran.push(t);
+            TaskStatus::Done
+        });
+        assert_eq!(ran, [Task::Republish, Task::Gossip]);
+        assert!(!tick.exhausted);
+        assert_eq!(tick.next_due, secs(16));
+    }
+}
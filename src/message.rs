@@ -1,13 +1,20 @@
-use curve25519_dalek::edwards::CompressedEdwardsY;
+use alloc::{sync::Arc, vec, vec::Vec};
+
 use ed25519_dalek::ed25519::SignatureBytes;
 use zerocopy::{big_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
 
 use crate::{
-    block::{BlockKey, Timestamp},
-    bloom::{BloomFilter, PeerBloomFilter},
-    Peer,
+    block::{Addrs, BlockKey, BlockOperation, HelloBlockSignaturePayload, Timestamp, XQuery},
+    bloom::PeerBloomFilter,
+    error::{EncodeError, ParseError},
+    limits::{MAX_MESSAGE_SIZE, MAX_PATH_LEN, MAX_RESULT_FILTER_SIZE, PATH_ELEMENT_SIZE},
+    Message, Peer,
 };
 
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod dissect;
+
 #[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 pub struct MessageHeader {
@@ -15,25 +22,363 @@ pub struct MessageHeader {
     message_type: big_endian::U16,
 }
 
-#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+impl MessageHeader {
+    pub(crate) fn new(message_size: usize, message_type: u16) -> Option<Self> {
+        if message_size > MAX_MESSAGE_SIZE {
+            return None;
+        }
+        Some(Self {
+            message_size: big_endian::U16::new(message_size as u16),
+            message_type: big_endian::U16::new(message_type),
+        })
+    }
+
+    /// The size of the whole message, including this header
+    pub fn message_size(&self) -> u16 {
+        self.message_size.get()
+    }
+
+    pub fn message_type(&self) -> u16 {
+        self.message_type.get()
+    }
+}
+
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned, Clone, Copy, Default, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct Flags(u8);
 
 impl Flags {
-    fn get_demultiplex(&self) -> bool {
-        (self.0 >> 0) & 1 == 1
+    pub fn set_demultiplex(&mut self, set: bool) -> &mut Self {
+        self.set(0, set)
+    }
+    pub fn set_record_route(&mut self, set: bool) -> &mut Self {
+        self.set(1, set)
+    }
+    pub fn set_find_approximate(&mut self, set: bool) -> &mut Self {
+        self.set(2, set)
+    }
+    fn set(&mut self, bit: u8, set: bool) -> &mut Self {
+        self.0 = self.0 & !(1 << bit) | u8::from(set) << bit;
+        self
+    }
+
+    pub fn get_demultiplex(&self) -> bool {
+        self.0 & 1 == 1
     }
-    fn get_record_route(&self) -> bool {
+    pub fn get_record_route(&self) -> bool {
         (self.0 >> 1) & 1 == 1
     }
-    fn get_find_approximate(&self) -> bool {
+    pub fn get_find_approximate(&self) -> bool {
         (self.0 >> 2) & 1 == 1
     }
-    fn get_truncated(&self) -> bool {
+    pub fn get_truncated(&self) -> bool {
         (self.0 >> 3) & 1 == 1
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Flags {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(Self)
+    }
+}
+
+/// The largest block that can be PUT through connections that carry
+/// messages of at most `mtu` bytes. This leaves room for a last hop
+/// signature and a truncated origin, so the PUT fits however it is sent, as
+/// does a RESULT with the block.
+pub const fn max_block_size(mtu: u16) -> usize {
+    let overhead = size_of::<PutMessageHeader>() + 32 + size_of::<SignatureBytes>();
+    (mtu as usize).saturating_sub(overhead)
+}
+
+/// An owned copy of a parsed message, eg to queue it or hand it to another
+/// task. Messages are only parsed again to borrow from them, which is cheap.
+macro_rules! owned {
+    ($owned:ident, $borrowed:ident) => {
+        #[doc = concat!("A [`", stringify!($borrowed), "`] that owns its bytes")]
+        #[derive(Clone, Debug)]
+        pub struct $owned {
+            bytes: Arc<[u8]>,
+        }
+
+        impl $owned {
+            pub fn get(&self) -> $borrowed<'_> {
+                $borrowed::parse(&self.bytes).expect("parsed before")
+            }
+
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.bytes
+            }
+        }
+
+        impl<'a> $borrowed<'a> {
+            pub fn to_owned(&self) -> $owned {
+                $owned {
+                    bytes: self.bytes.into(),
+                }
+            }
+
+            /// The whole message
+            pub fn as_bytes(&self) -> &'a [u8] {
+                self.bytes
+            }
+        }
+    };
+}
+
+owned!(PutMessageOwned, PutMessage);
+owned!(GetMessageOwned, GetMessage);
+owned!(ResultMessageOwned, ResultMessage);
+
+/// Messages that start here, built from arbitrary fields. Fields that make
+/// the message too large are rejected.
+#[cfg(feature = "fuzzing")]
+mod arbitrary_impls {
+    use arbitrary::{Arbitrary, Error, Result, Unstructured};
+
+    use super::*;
+
+    impl<'a> Arbitrary<'a> for PutMessageOwned {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let signature: Option<SignatureBytes> = u.arbitrary()?;
+            let block: &[u8] = u.arbitrary()?;
+            let message = PutMessage::encode(
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                signature.as_ref(),
+                block,
+            )
+            .map_err(|_| Error::IncorrectFormat)?;
+            Ok(PutMessage::parse(message.as_bytes())
+                .expect("encoded")
+                .to_owned())
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for GetMessageOwned {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let result_filter: &[u8] = u.arbitrary()?;
+            let xquery: &[u8] = u.arbitrary()?;
+            let message = GetMessage::encode(
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                result_filter,
+                xquery,
+            )
+            .map_err(|_| Error::IncorrectFormat)?;
+            Ok(GetMessage::parse(message.as_bytes())
+                .expect("encoded")
+                .to_owned())
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for ResultMessageOwned {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let block: &[u8] = u.arbitrary()?;
+            let message =
+                ResultMessage::encode(u.arbitrary()?, u.arbitrary()?, u.arbitrary()?, block)
+                    .map_err(|_| Error::IncorrectFormat)?;
+            Ok(ResultMessage::parse(message.as_bytes())
+                .expect("encoded")
+                .to_owned())
+        }
+    }
+}
+
+/// Buffers to encode messages into, so that sending doesn't allocate for
+/// each message. Buffers keep the capacity of the largest message they've
+/// held, so this suits messages that are sent straight away, then
+/// [`recycle`](Self::recycle)d, rather than queued.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// A pool that keeps up to `max_free` buffers for reuse
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            free: Vec::new(),
+            max_free,
+        }
+    }
+
+    /// Encode a message into a buffer from the pool, with one of the
+    /// `encode_into`s, eg
+    /// `pool.encode(|buf| ResultMessage::encode_into(13, expiration, key, block, buf))`
+    pub fn encode(
+        &mut self,
+        mut encode: impl FnMut(&mut [u8]) -> Result<usize, EncodeError>,
+    ) -> Result<Message, EncodeError> {
+        // an empty buffer finds the size, so only that much is zeroed
+        let size = match encode(&mut []) {
+            Err(EncodeError::BufferTooSmall { size, .. }) | Ok(size) => size,
+            Err(e) => return Err(e),
+        };
+        let mut buf = self.free.pop().unwrap_or_default();
+        buf.resize(size, 0);
+        match encode(&mut buf) {
+            Ok(size) => {
+                buf.truncate(size);
+                Ok(Message::from_bytes(buf))
+            }
+            Err(e) => {
+                self.keep(buf);
+                Err(e)
+            }
+        }
+    }
+
+    /// Give a sent message's buffer back to the pool
+    pub fn recycle(&mut self, message: Message) {
+        self.keep(message.into_bytes());
+    }
+
+    fn keep(&mut self, buf: Vec<u8>) {
+        if self.free.len() < self.max_free {
+            self.free.push(buf);
+        }
+    }
+
+    /// How many buffers are waiting to be reused
+    pub fn free(&self) -> usize {
+        self.free.len()
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+/// Write a message's fixed size `header` and the `parts` after it to the
+/// front of `buf`, returning its size.
+fn write_parts(
+    buf: &mut [u8],
+    what: &'static str,
+    header: &[u8],
+    parts: &[&[u8]],
+) -> Result<usize, EncodeError> {
+    let size = header.len() + parts.iter().map(|p| p.len()).sum::<usize>();
+    let Some(out) = buf.get_mut(..size) else {
+        let len = buf.len();
+        return Err(EncodeError::BufferTooSmall { what, size, len });
+    };
+    let (out, mut rest) = out.split_at_mut(header.len());
+    out.copy_from_slice(header);
+    for part in parts {
+        let (out, next) = rest.split_at_mut(part.len());
+        out.copy_from_slice(part);
+        rest = next;
+    }
+    Ok(size)
+}
+
+/// Splits a message into its fixed size header `H` and the fields after
+/// it, checking the type and that the size it claims fits in `b`.
+fn split_header<'a, H: FromBytes>(
+    b: &'a [u8],
+    what: &'static str,
+    message_type: u16,
+) -> Result<(&'a H, Fields<'a>), ParseError> {
+    let too_short = ParseError::TooShort { what, len: b.len() };
+    let header = MessageHeader::ref_from_prefix(b).ok_or(too_short)?;
+    if header.message_type() != message_type {
+        let found = header.message_type();
+        return Err(ParseError::WrongType { what, found });
+    }
+    let fixed = H::ref_from_prefix(b).ok_or(too_short)?;
+    let size = header.message_size();
+    let rest = b
+        .get(size_of::<H>()..size as usize)
+        .ok_or(ParseError::Size {
+            what,
+            size,
+            len: b.len(),
+        })?;
+    let fields = Fields {
+        what,
+        b: rest,
+        offset: size_of::<H>(),
+    };
+    Ok((fixed, fields))
+}
+
+/// The variable length part of a message, read front to back
+struct Fields<'a> {
+    what: &'static str,
+    b: &'a [u8],
+    /// from the start of the message
+    offset: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, field: &'static str, len: usize) -> Result<&'a [u8], ParseError> {
+        let Some((taken, rest)) = self.b.split_at_checked(len) else {
+            return Err(self.overrun(field, len));
+        };
+        self.b = rest;
+        self.offset += len;
+        Ok(taken)
+    }
+
+    /// Like [`take`](Self::take), for fields with a size limit
+    fn take_at_most(
+        &mut self,
+        field: &'static str,
+        len: usize,
+        max: usize,
+    ) -> Result<&'a [u8], ParseError> {
+        if len > max {
+            let what = self.what;
+            return Err(ParseError::TooLarge {
+                what,
+                field,
+                len,
+                max,
+            });
+        }
+        self.take(field, len)
+    }
+
+    /// A path of `len` elements, when there can be at most `max`
+    fn take_path(
+        &mut self,
+        field: &'static str,
+        len: usize,
+        max: usize,
+    ) -> Result<&'a [u8], ParseError> {
+        let len = len * PATH_ELEMENT_SIZE;
+        self.take_at_most(field, len, max * PATH_ELEMENT_SIZE)
+    }
+
+    fn take_ref<T: FromBytes>(&mut self, field: &'static str) -> Result<&'a T, ParseError> {
+        let t = T::ref_from_prefix(self.b).ok_or(self.overrun(field, size_of::<T>()))?;
+        self.take(field, size_of::<T>())?;
+        Ok(t)
+    }
+
+    fn overrun(&self, field: &'static str, len: usize) -> ParseError {
+        ParseError::Overrun {
+            what: self.what,
+            field,
+            len,
+            offset: self.offset,
+            remaining: self.b.len(),
+        }
+    }
+}
+
 // https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-7.2
 #[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
@@ -46,6 +391,103 @@ pub struct HelloMessage {
     expiration: Timestamp,
 }
 
+impl HelloMessage {
+    /// `GNUNET_MESSAGE_TYPE_DHT_P2P_HELLO`
+    pub const MESSAGE_TYPE: u16 = 157;
+
+    /// Encode the sender's HELLO. `addrs` is the address list as encoded by
+    /// [`encode_addresses`](crate::block::encode_addresses). Fails if the
+    /// message would be too large.
+    pub fn encode(
+        signature: &SignatureBytes,
+        expiration: Timestamp,
+        addrs: &[u8],
+    ) -> Result<Message, EncodeError> {
+        let mut message = vec![0; size_of::<Self>() + addrs.len()];
+        Self::encode_into(signature, expiration, addrs, &mut message)?;
+        Ok(Message::from_bytes(message))
+    }
+
+    /// Like [`encode`](Self::encode), but into the front of `buf`, returning
+    /// the size of the message.
+    pub fn encode_into(
+        signature: &SignatureBytes,
+        expiration: Timestamp,
+        addrs: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, EncodeError> {
+        let size = size_of::<Self>() + addrs.len();
+        let too_large = EncodeError::TooLarge {
+            what: "HELLO",
+            size,
+        };
+        let num_addresses = addrs.iter().filter(|&&b| b == 0).count();
+        let header = HelloMessage {
+            header: MessageHeader::new(size, Self::MESSAGE_TYPE).ok_or(too_large)?,
+            version: big_endian::U16::new(0),
+            num_addresses: big_endian::U16::new(num_addresses.try_into().map_err(|_| too_large)?),
+            signature: *signature,
+            expiration,
+        };
+        write_parts(buf, "HELLO", header.as_bytes(), &[addrs])
+    }
+}
+
+/// A parsed [`HelloMessage`]. The signature is not checked until
+/// [`verify`](Self::verify), since the message doesn't say who sent it.
+#[derive(Clone, Copy)]
+pub struct Hello<'a> {
+    header: &'a HelloMessage,
+    addrs: &'a str,
+}
+
+impl<'a> Hello<'a> {
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        const WHAT: &str = "HELLO";
+        let (header, fields) = split_header::<HelloMessage>(b, WHAT, HelloMessage::MESSAGE_TYPE)?;
+        if header.version.get() != 0 {
+            let found = header.version.get();
+            return Err(ParseError::Version { what: WHAT, found });
+        }
+        let addrs = core::str::from_utf8(fields.b).map_err(|e| ParseError::Addresses {
+            what: WHAT,
+            offset: fields.offset + e.valid_up_to(),
+        })?;
+        let (expected, found) = (header.num_addresses.get(), Addrs::new(addrs).count());
+        if found != expected as usize {
+            return Err(ParseError::AddressCount {
+                what: WHAT,
+                expected,
+                found,
+            });
+        }
+        Ok(Self { header, addrs })
+    }
+
+    /// Whether this is a HELLO signed by `peer`
+    pub fn verify(&self, peer: &Peer) -> bool {
+        HelloBlockSignaturePayload::new(self.header.expiration, self.addrs.as_bytes())
+            .verify(peer, &self.header.signature)
+    }
+
+    pub fn signature(&self) -> &'a SignatureBytes {
+        &self.header.signature
+    }
+
+    pub fn expiration(&self) -> Timestamp {
+        self.header.expiration
+    }
+
+    pub fn addresses(&self) -> Addrs<'a> {
+        Addrs::new(self.addrs)
+    }
+
+    /// The addresses as they were encoded
+    pub fn raw_addresses(&self) -> &'a [u8] {
+        self.addrs.as_bytes()
+    }
+}
+
 // https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-7.3
 #[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
@@ -62,7 +504,25 @@ pub struct PutMessageHeader {
     block_key: BlockKey,
 }
 
+impl PutMessageHeader {
+    /// `GNUNET_MESSAGE_TYPE_DHT_P2P_PUT`
+    pub const MESSAGE_TYPE: u16 = 146;
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_hops(&mut self, hop_count: u16, replication_level: u16) {
+        self.hop_count.set(hop_count);
+        self.replication_level.set(replication_level);
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_peer_bloom_filter(&mut self, bloom: PeerBloomFilter) {
+        self.peer_bloom_filter = bloom;
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct PutMessage<'a> {
+    bytes: &'a [u8],
     header: &'a PutMessageHeader,
     truncated_origin: Option<&'a [u8; 32]>,
     put_path: &'a [u8],
@@ -71,36 +531,217 @@ pub struct PutMessage<'a> {
 }
 
 impl<'a> PutMessage<'a> {
-    pub fn parse(mut b: &'a [u8]) -> Option<Self> {
-        let header = PutMessageHeader::ref_from_prefix(b)?;
-        assert_eq!(header.header.message_type.get(), 146);
-
-        b = b.get(size_of_val(header)..header.header.message_size.get() as usize)?;
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        let (header, mut fields) =
+            split_header::<PutMessageHeader>(b, "PUT", PutMessageHeader::MESSAGE_TYPE)?;
 
         let truncated = if header.flags.get_truncated() {
-            let t = <[u8; 32]>::ref_from_prefix(b)?;
-            b = b.get(size_of_val(t)..)?;
-            Some(t)
+            Some(fields.take_ref("truncated origin")?)
         } else {
             None
         };
-        let (path, mut b) = b.split_at_checked(header.path_len.get() as usize)?;
+        let path_len = header.path_len.get() as usize;
+        let path = fields.take_path("put path", path_len, MAX_PATH_LEN)?;
         let signature = if header.flags.get_record_route() {
-            let s = SignatureBytes::ref_from_prefix(b)?;
-            b = b.get(size_of_val(s)..)?;
-            Some(s)
+            Some(fields.take_ref("last hop signature")?)
         } else {
             None
         };
 
-        Some(Self {
+        Ok(Self {
+            bytes: &b[..header.header.message_size() as usize],
             header,
             truncated_origin: truncated,
             put_path: path,
             last_hop_signature: signature,
-            block: b,
+            block: fields.b,
         })
     }
+
+    /// Encode a PUT that starts here, so with an empty path. The record
+    /// route flag is set if there is a last hop signature, whatever `flags`
+    /// says, and the truncated flag is cleared. Fails if the message would
+    /// be too large.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode(
+        block_type: u32,
+        flags: Flags,
+        replication_level: u16,
+        expiration: Timestamp,
+        peer_bloom_filter: PeerBloomFilter,
+        block_key: BlockKey,
+        last_hop_signature: Option<&SignatureBytes>,
+        block: &[u8],
+    ) -> Result<Message, EncodeError> {
+        let signature_len = last_hop_signature.map_or(0, |s| s.len());
+        let mut message = vec![0; size_of::<PutMessageHeader>() + signature_len + block.len()];
+        Self::encode_into(
+            block_type,
+            flags,
+            replication_level,
+            expiration,
+            peer_bloom_filter,
+            block_key,
+            last_hop_signature,
+            block,
+            &mut message,
+        )?;
+        Ok(Message::from_bytes(message))
+    }
+
+    /// Like [`encode`](Self::encode), but into the front of `buf`, returning
+    /// the size of the message.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode_into(
+        block_type: u32,
+        mut flags: Flags,
+        replication_level: u16,
+        expiration: Timestamp,
+        peer_bloom_filter: PeerBloomFilter,
+        block_key: BlockKey,
+        last_hop_signature: Option<&SignatureBytes>,
+        block: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, EncodeError> {
+        let signature = last_hop_signature.map_or(&[][..], |s| &s[..]);
+        let size = size_of::<PutMessageHeader>() + signature.len() + block.len();
+        let too_large = EncodeError::TooLarge { what: "PUT", size };
+        flags.set_record_route(last_hop_signature.is_some());
+        // there is no path to have truncated yet
+        flags.set(3, false);
+        let header = PutMessageHeader {
+            header: MessageHeader::new(size, PutMessageHeader::MESSAGE_TYPE).ok_or(too_large)?,
+            block_type: big_endian::U32::new(block_type),
+            version: 0,
+            flags,
+            hop_count: big_endian::U16::new(0),
+            replication_level: big_endian::U16::new(replication_level),
+            path_len: big_endian::U16::new(0),
+            expiration,
+            peer_bloom_filter,
+            block_key,
+        };
+        write_parts(buf, "PUT", header.as_bytes(), &[signature, block])
+    }
+
+    pub fn block_type(&self) -> u32 {
+        self.header.block_type.get()
+    }
+    pub fn flags(&self) -> &'a Flags {
+        &self.header.flags
+    }
+    pub fn hop_count(&self) -> u16 {
+        self.header.hop_count.get()
+    }
+    pub fn replication_level(&self) -> u16 {
+        self.header.replication_level.get()
+    }
+    pub fn expiration(&self) -> Timestamp {
+        self.header.expiration
+    }
+    pub fn peer_bloom_filter(&self) -> &'a PeerBloomFilter {
+        &self.header.peer_bloom_filter
+    }
+    pub fn block_key(&self) -> &'a BlockKey {
+        &self.header.block_key
+    }
+    pub fn truncated_origin(&self) -> Option<&'a [u8; 32]> {
+        self.truncated_origin
+    }
+    pub fn put_path(&self) -> &'a [u8] {
+        self.put_path
+    }
+    pub fn last_hop_signature(&self) -> Option<&'a SignatureBytes> {
+        self.last_hop_signature
+    }
+    pub fn block(&self) -> &'a [u8] {
+        self.block
+    }
+
+    /// This PUT with path recording as we forward it: `pred`, who sent it
+    /// to us, joins the path with its last hop signature, and `signature`
+    /// is ours. If the path makes the message too large, its oldest
+    /// elements are dropped and the truncated flag set, and if there's no
+    /// room even then the path isn't recorded.
+    #[cfg(feature = "std")]
+    pub(crate) fn with_hop(&self, pred: &Peer, signature: &SignatureBytes) -> Vec<u8> {
+        let mut path = self.put_path.to_vec();
+        if let Some(last) = self.last_hop_signature {
+            path.extend_from_slice(pred.as_bytes());
+            path.extend_from_slice(last);
+        }
+        let mut origin = self.truncated_origin.copied();
+        let fixed = size_of::<PutMessageHeader>() + signature.len() + self.block.len();
+        let fits = MAX_MESSAGE_SIZE
+            .checked_sub(fixed)
+            .is_some_and(|room| truncate_path(&mut path, &mut origin, room));
+        if !fits {
+            return self.without_path();
+        }
+        self.rewrite(origin.as_ref(), &path, Some(signature))
+    }
+
+    /// This PUT in at most `max` bytes, dropping the oldest path elements
+    /// and setting the truncated flag if needed. `None` if it doesn't fit
+    /// even without a path.
+    #[cfg(feature = "std")]
+    pub(crate) fn truncated_to(&self, max: usize) -> Option<Vec<u8>> {
+        let mut path = self.put_path.to_vec();
+        let mut origin = self.truncated_origin.copied();
+        let signature = self.last_hop_signature;
+        let fixed =
+            size_of::<PutMessageHeader>() + signature.map_or(0, |s| s.len()) + self.block.len();
+        let room = max.checked_sub(fixed)?;
+        truncate_path(&mut path, &mut origin, room)
+            .then(|| self.rewrite(origin.as_ref(), &path, signature))
+    }
+
+    /// This PUT without path recording, for when we can't sign our hop
+    #[cfg(feature = "std")]
+    pub(crate) fn without_path(&self) -> Vec<u8> {
+        self.rewrite(None, &[], None)
+    }
+
+    #[cfg(feature = "std")]
+    fn rewrite(
+        &self,
+        origin: Option<&[u8; 32]>,
+        path: &[u8],
+        signature: Option<&SignatureBytes>,
+    ) -> Vec<u8> {
+        let origin = origin.map_or(&[][..], |o| &o[..]);
+        let signature = signature.map_or(&[][..], |s| &s[..]);
+        let mut header = PutMessageHeader::read_from(self.header.as_bytes()).unwrap();
+        let size = size_of::<PutMessageHeader>()
+            + origin.len()
+            + path.len()
+            + signature.len()
+            + self.block.len();
+        // callers keep it in bounds
+        header.header = MessageHeader::new(size, PutMessageHeader::MESSAGE_TYPE).unwrap();
+        header.path_len.set((path.len() / PATH_ELEMENT_SIZE) as u16);
+        header.flags.set(3, !origin.is_empty());
+        header.flags.set_record_route(!signature.is_empty());
+        let mut bytes = vec![0; size];
+        let parts = [origin, path, signature, self.block];
+        write_parts(&mut bytes, "PUT", header.as_bytes(), &parts).unwrap();
+        bytes
+    }
+}
+
+/// Drop the oldest elements of `path` until it fits in `room` bytes, along
+/// with the truncated `origin` that replaces them. Returns false if it
+/// can't.
+#[cfg(feature = "std")]
+fn truncate_path(path: &mut Vec<u8>, origin: &mut Option<[u8; 32]>, room: usize) -> bool {
+    while 32 * usize::from(origin.is_some()) + path.len() > room {
+        if path.len() < PATH_ELEMENT_SIZE {
+            return false;
+        }
+        let dropped: Vec<u8> = path.drain(..PATH_ELEMENT_SIZE).collect();
+        *origin = Some(dropped[..32].try_into().unwrap());
+    }
+    true
 }
 
 // https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-7.4
@@ -115,8 +756,648 @@ pub struct GetMessageHeader {
     replication_level: big_endian::U16,
     result_filter_size: big_endian::U16,
     peer_bloom_filter: PeerBloomFilter,
-    query_hash: [u8; 64],
+    query_hash: BlockKey,
 }
 
-// https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-7.4
-pub struct ResultMessage {}
+impl GetMessageHeader {
+    /// `GNUNET_MESSAGE_TYPE_DHT_P2P_GET`
+    pub const MESSAGE_TYPE: u16 = 147;
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_hops(&mut self, hop_count: u16, replication_level: u16) {
+        self.hop_count.set(hop_count);
+        self.replication_level.set(replication_level);
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_peer_bloom_filter(&mut self, bloom: PeerBloomFilter) {
+        self.peer_bloom_filter = bloom;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct GetMessage<'a> {
+    bytes: &'a [u8],
+    header: &'a GetMessageHeader,
+    result_filter: &'a [u8],
+    xquery: &'a [u8],
+}
+
+impl<'a> GetMessage<'a> {
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        let (header, mut fields) =
+            split_header::<GetMessageHeader>(b, "GET", GetMessageHeader::MESSAGE_TYPE)?;
+        let result_filter = fields.take_at_most(
+            "result filter",
+            header.result_filter_size.get() as usize,
+            MAX_RESULT_FILTER_SIZE,
+        )?;
+        Ok(Self {
+            bytes: &b[..header.header.message_size() as usize],
+            header,
+            result_filter,
+            xquery: fields.b,
+        })
+    }
+
+    /// Encode a GET that starts here. Fails if the message would be too
+    /// large.
+    pub fn encode(
+        block_type: u32,
+        flags: Flags,
+        replication_level: u16,
+        peer_bloom_filter: PeerBloomFilter,
+        query_hash: BlockKey,
+        result_filter: &[u8],
+        xquery: &[u8],
+    ) -> Result<Message, EncodeError> {
+        let mut message =
+            vec![0; size_of::<GetMessageHeader>() + result_filter.len() + xquery.len()];
+        Self::encode_into(
+            block_type,
+            flags,
+            replication_level,
+            peer_bloom_filter,
+            query_hash,
+            result_filter,
+            xquery,
+            &mut message,
+        )?;
+        Ok(Message::from_bytes(message))
+    }
+
+    /// Like [`encode`](Self::encode), but into the front of `buf`, returning
+    /// the size of the message.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode_into(
+        block_type: u32,
+        flags: Flags,
+        replication_level: u16,
+        peer_bloom_filter: PeerBloomFilter,
+        query_hash: BlockKey,
+        result_filter: &[u8],
+        xquery: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, EncodeError> {
+        if result_filter.len() > MAX_RESULT_FILTER_SIZE {
+            return Err(EncodeError::FieldTooLarge {
+                what: "GET",
+                field: "result filter",
+                len: result_filter.len(),
+                max: MAX_RESULT_FILTER_SIZE,
+            });
+        }
+        let size = size_of::<GetMessageHeader>() + result_filter.len() + xquery.len();
+        let too_large = EncodeError::TooLarge { what: "GET", size };
+        let header = GetMessageHeader {
+            header: MessageHeader::new(size, GetMessageHeader::MESSAGE_TYPE).ok_or(too_large)?,
+            block_type: big_endian::U32::new(block_type),
+            version: 0,
+            flags,
+            hop_count: big_endian::U16::new(0),
+            replication_level: big_endian::U16::new(replication_level),
+            result_filter_size: big_endian::U16::new(result_filter.len() as u16),
+            peer_bloom_filter,
+            query_hash,
+        };
+        write_parts(buf, "GET", header.as_bytes(), &[result_filter, xquery])
+    }
+
+    pub fn block_type(&self) -> u32 {
+        self.header.block_type.get()
+    }
+    pub fn flags(&self) -> &'a Flags {
+        &self.header.flags
+    }
+    pub fn hop_count(&self) -> u16 {
+        self.header.hop_count.get()
+    }
+    pub fn replication_level(&self) -> u16 {
+        self.header.replication_level.get()
+    }
+    pub fn peer_bloom_filter(&self) -> &'a PeerBloomFilter {
+        &self.header.peer_bloom_filter
+    }
+    pub fn query_hash(&self) -> &'a BlockKey {
+        &self.header.query_hash
+    }
+    pub fn result_filter(&self) -> &'a [u8] {
+        self.result_filter
+    }
+    pub fn xquery(&self) -> &'a [u8] {
+        self.xquery
+    }
+
+    /// The extended query as block type `B` reads it, `None` if it isn't
+    /// one. Check [`block_type`](Self::block_type) first.
+    pub fn typed_xquery<B: BlockOperation>(&self) -> Option<B::XQuery> {
+        B::XQuery::decode(self.xquery)
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05#section-7.5
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+pub struct ResultMessageHeader {
+    header: MessageHeader,
+    block_type: big_endian::U32,
+    reserved: big_endian::U16,
+    version: u8,
+    flags: Flags,
+    put_path_len: big_endian::U16,
+    get_path_len: big_endian::U16,
+    expiration: Timestamp,
+    query_hash: BlockKey,
+}
+
+impl ResultMessageHeader {
+    /// `GNUNET_MESSAGE_TYPE_DHT_P2P_RESULT`
+    pub const MESSAGE_TYPE: u16 = 148;
+}
+
+#[derive(Clone, Copy)]
+pub struct ResultMessage<'a> {
+    bytes: &'a [u8],
+    header: &'a ResultMessageHeader,
+    truncated_origin: Option<&'a [u8; 32]>,
+    put_path: &'a [u8],
+    get_path: &'a [u8],
+    last_hop_signature: Option<&'a SignatureBytes>,
+    block: &'a [u8],
+}
+
+impl<'a> ResultMessage<'a> {
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        let (header, mut fields) =
+            split_header::<ResultMessageHeader>(b, "RESULT", ResultMessageHeader::MESSAGE_TYPE)?;
+
+        let truncated = if header.flags.get_truncated() {
+            Some(fields.take_ref("truncated origin")?)
+        } else {
+            None
+        };
+        let put_path_len = header.put_path_len.get() as usize;
+        let put_path = fields.take_path("put path", put_path_len, MAX_PATH_LEN)?;
+        let get_path_len = header.get_path_len.get() as usize;
+        let max = MAX_PATH_LEN - put_path_len;
+        let get_path = fields.take_path("get path", get_path_len, max)?;
+        let signature = if header.flags.get_record_route() {
+            Some(fields.take_ref("last hop signature")?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            bytes: &b[..header.header.message_size() as usize],
+            header,
+            truncated_origin: truncated,
+            put_path,
+            get_path,
+            last_hop_signature: signature,
+            block: fields.b,
+        })
+    }
+
+    /// Encode a result that starts here, with no paths and no flags set.
+    /// Fails if the message would be too large.
+    pub fn encode(
+        block_type: u32,
+        expiration: Timestamp,
+        query_hash: BlockKey,
+        block: &[u8],
+    ) -> Result<Message, EncodeError> {
+        let mut message = vec![0; size_of::<ResultMessageHeader>() + block.len()];
+        Self::encode_into(block_type, expiration, query_hash, block, &mut message)?;
+        Ok(Message::from_bytes(message))
+    }
+
+    /// Like [`encode`](Self::encode), but into the front of `buf`, returning
+    /// the size of the message.
+    pub fn encode_into(
+        block_type: u32,
+        expiration: Timestamp,
+        query_hash: BlockKey,
+        block: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, EncodeError> {
+        let size = size_of::<ResultMessageHeader>() + block.len();
+        let too_large = EncodeError::TooLarge {
+            what: "RESULT",
+            size,
+        };
+        let header = ResultMessageHeader {
+            header: MessageHeader::new(size, ResultMessageHeader::MESSAGE_TYPE).ok_or(too_large)?,
+            block_type: big_endian::U32::new(block_type),
+            reserved: big_endian::U16::new(0),
+            version: 0,
+            flags: Flags(0),
+            put_path_len: big_endian::U16::new(0),
+            get_path_len: big_endian::U16::new(0),
+            expiration,
+            query_hash,
+        };
+        write_parts(buf, "RESULT", header.as_bytes(), &[block])
+    }
+
+    pub fn block_type(&self) -> u32 {
+        self.header.block_type.get()
+    }
+    pub fn flags(&self) -> &'a Flags {
+        &self.header.flags
+    }
+    pub fn expiration(&self) -> Timestamp {
+        self.header.expiration
+    }
+    pub fn query_hash(&self) -> &'a BlockKey {
+        &self.header.query_hash
+    }
+    pub fn truncated_origin(&self) -> Option<&'a [u8; 32]> {
+        self.truncated_origin
+    }
+    pub fn put_path(&self) -> &'a [u8] {
+        self.put_path
+    }
+    pub fn get_path(&self) -> &'a [u8] {
+        self.get_path
+    }
+    pub fn last_hop_signature(&self) -> Option<&'a SignatureBytes> {
+        self.last_hop_signature
+    }
+    pub fn block(&self) -> &'a [u8] {
+        self.block
+    }
+
+    /// This result in at most `max` bytes, dropping the oldest elements of
+    /// its paths, PUT path first, and setting the truncated flag if needed.
+    /// `None` if it doesn't fit even without paths.
+    #[cfg(feature = "std")]
+    pub(crate) fn truncated_to(&self, max: usize) -> Option<Vec<u8>> {
+        let mut path = [self.put_path, self.get_path].concat();
+        let mut origin = self.truncated_origin.copied();
+        let signature = self.last_hop_signature.map_or(&[][..], |s| &s[..]);
+        let fixed = size_of::<ResultMessageHeader>() + signature.len() + self.block.len();
+        let room = max.checked_sub(fixed)?;
+        if !truncate_path(&mut path, &mut origin, room) {
+            return None;
+        }
+        let get_path_len = self.get_path.len().min(path.len());
+        let (put_path, get_path) = path.split_at(path.len() - get_path_len);
+        let origin = origin.as_ref().map_or(&[][..], |o| &o[..]);
+
+        let mut header = ResultMessageHeader::read_from(self.header.as_bytes()).unwrap();
+        let size = fixed + origin.len() + path.len();
+        // no larger than the message it came from
+        header.header = MessageHeader::new(size, ResultMessageHeader::MESSAGE_TYPE).unwrap();
+        header
+            .put_path_len
+            .set((put_path.len() / PATH_ELEMENT_SIZE) as u16);
+        header
+            .get_path_len
+            .set((get_path.len() / PATH_ELEMENT_SIZE) as u16);
+        header.flags.set(3, !origin.is_empty());
+        let mut bytes = vec![0; size];
+        let parts = [origin, put_path, get_path, signature, self.block];
+        write_parts(&mut bytes, "RESULT", header.as_bytes(), &parts).unwrap();
+        Some(bytes)
+    }
+}
+
+/// Any message, parsed by its type
+#[derive(Clone, Copy)]
+pub enum AnyMessage<'a> {
+    Hello(Hello<'a>),
+    Put(PutMessage<'a>),
+    Get(GetMessage<'a>),
+    Result(ResultMessage<'a>),
+    /// A type we don't know, eg an experimental one
+    Unknown(UnknownMessage<'a>),
+}
+
+impl<'a> AnyMessage<'a> {
+    /// Parse a message of any type. Only the header of unknown types is
+    /// checked, so they can be dropped or handled by the application
+    /// without failing the rest.
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        let too_short = ParseError::TooShort {
+            what: "message",
+            len: b.len(),
+        };
+        let header = MessageHeader::ref_from_prefix(b).ok_or(too_short)?;
+        Ok(match header.message_type() {
+            HelloMessage::MESSAGE_TYPE => Self::Hello(Hello::parse(b)?),
+            PutMessageHeader::MESSAGE_TYPE => Self::Put(PutMessage::parse(b)?),
+            GetMessageHeader::MESSAGE_TYPE => Self::Get(GetMessage::parse(b)?),
+            ResultMessageHeader::MESSAGE_TYPE => Self::Result(ResultMessage::parse(b)?),
+            _ => Self::Unknown(UnknownMessage::parse(b)?),
+        })
+    }
+}
+
+/// A message of a type we don't know
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownMessage<'a> {
+    message_type: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> UnknownMessage<'a> {
+    /// Parse any message, only checking its header
+    pub fn parse(b: &'a [u8]) -> Result<Self, ParseError> {
+        const WHAT: &str = "message";
+        let too_short = ParseError::TooShort {
+            what: WHAT,
+            len: b.len(),
+        };
+        let header = MessageHeader::ref_from_prefix(b).ok_or(too_short)?;
+        let size = header.message_size();
+        let payload = b
+            .get(size_of::<MessageHeader>()..size as usize)
+            .ok_or(ParseError::Size {
+                what: WHAT,
+                size,
+                len: b.len(),
+            })?;
+        Ok(Self {
+            message_type: header.message_type(),
+            payload,
+        })
+    }
+
+    pub fn message_type(&self) -> u16 {
+        self.message_type
+    }
+
+    /// Everything after the header
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{
+        block::{BlockKey, BlockOperation, HelloBlock, Timestamp, XQuery},
+        error::{EncodeError, ParseError},
+        identity::LocalPeer,
+        limits::{MAX_PATH_LEN, MAX_RESULT_FILTER_SIZE, PATH_ELEMENT_SIZE},
+        testing::identities,
+        Peer,
+    };
+
+    use super::{
+        max_block_size, BufferPool, Flags, GetMessage, PutMessage, ResultMessage,
+        ResultMessageHeader,
+    };
+
+    #[test]
+    fn encode_into() {
+        let key = BlockKey::from([1; 64]);
+        let message = ResultMessage::encode(13, Timestamp::FOREVER, key, b"block").unwrap();
+        let size = message.as_bytes().len();
+
+        let mut buf = [0xff; 256];
+        let written = ResultMessage::encode_into(13, Timestamp::FOREVER, key, b"block", &mut buf);
+        assert_eq!(written, Ok(size));
+        assert_eq!(&buf[..size], message.as_bytes());
+        assert_eq!(buf[size], 0xff);
+
+        let small =
+            ResultMessage::encode_into(13, Timestamp::FOREVER, key, b"block", &mut buf[..9]);
+        let what = "RESULT";
+        assert_eq!(
+            small,
+            Err(EncodeError::BufferTooSmall { what, size, len: 9 })
+        );
+
+        let mut pool = BufferPool::new(1);
+        let encode =
+            |buf: &mut [u8]| ResultMessage::encode_into(13, Timestamp::FOREVER, key, b"block", buf);
+        let pooled = pool.encode(encode).unwrap();
+        assert_eq!(pooled, message);
+        pool.recycle(pooled);
+        pool.recycle(message);
+        assert_eq!(pool.free(), 1);
+        assert_eq!(pool.encode(encode).unwrap().as_bytes().len(), size);
+        assert_eq!(pool.free(), 0);
+    }
+    #[test]
+    fn limits() {
+        let key = BlockKey::from([1; 64]);
+        let filter = vec![0; MAX_RESULT_FILTER_SIZE + 1];
+        let get = |filter| {
+            GetMessage::encode(
+                13,
+                Flags::default(),
+                5,
+                Default::default(),
+                key,
+                filter,
+                b"",
+            )
+        };
+        assert_eq!(
+            get(&filter).unwrap_err(),
+            EncodeError::FieldTooLarge {
+                what: "GET",
+                field: "result filter",
+                len: MAX_RESULT_FILTER_SIZE + 1,
+                max: MAX_RESULT_FILTER_SIZE
+            }
+        );
+
+        // a filter size over the limit is refused before it's taken
+        let mut b = get(&filter[1..]).unwrap().as_bytes().to_vec();
+        b[14..16].copy_from_slice(&(MAX_RESULT_FILTER_SIZE as u16 + 1).to_be_bytes());
+        b.push(0);
+        assert!(matches!(
+            GetMessage::parse(&b),
+            Err(ParseError::TooLarge {
+                field: "result filter",
+                ..
+            })
+        ));
+
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"block").unwrap();
+        let mut b = result.as_bytes().to_vec();
+        b[12..14].copy_from_slice(&(MAX_PATH_LEN as u16 + 1).to_be_bytes());
+        let err = ResultMessage::parse(&b).err().unwrap();
+        assert_eq!(
+            err,
+            ParseError::TooLarge {
+                what: "RESULT",
+                field: "put path",
+                len: (MAX_PATH_LEN + 1) * PATH_ELEMENT_SIZE,
+                max: MAX_PATH_LEN * PATH_ELEMENT_SIZE,
+            }
+        );
+
+        // the HELLO result filter stays in bounds however large it's asked to be
+        let identity = LocalPeer::new(identities::peers()[0].signing_key());
+        let block = identity.sign_hello(Timestamp::FOREVER, []).to_block();
+        let hello = HelloBlock::parse(&block).unwrap();
+        let filter = hello.setup_result_filter(u32::MAX, 0);
+        assert!(filter.len() <= MAX_RESULT_FILTER_SIZE);
+    }
+
+    #[test]
+    fn path_truncation() {
+        // room for the origin and two path elements
+        let len = max_block_size(u16::MAX) - 2 * PATH_ELEMENT_SIZE;
+        let block = vec![0; len];
+        let mut put = PutMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            Timestamp::FOREVER,
+            Default::default(),
+            BlockKey::from([1; 64]),
+            Some(&[0; 64]),
+            &block,
+        )
+        .unwrap()
+        .as_bytes()
+        .to_vec();
+        let peers: Vec<Peer> = identities::peers()[..4].iter().map(|f| f.peer()).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            put = PutMessage::parse(&put)
+                .unwrap()
+                .with_hop(peer, &[i as u8 + 1; 64]);
+        }
+
+        let put = PutMessage::parse(&put).unwrap();
+        assert!(put.flags().get_truncated() && put.flags().get_record_route());
+        assert_eq!(put.truncated_origin(), Some(peers[1].as_bytes()));
+        let path: Vec<_> = put.put_path().chunks(PATH_ELEMENT_SIZE).collect();
+        assert_eq!(path.len(), 2);
+        // PATH_LEN counts elements, not bytes
+        assert_eq!(put.as_bytes()[14..16], [0, 2]);
+        assert_eq!(&path[0][..32], peers[2].as_bytes());
+        assert_eq!(path[1][32..], [3; 64]);
+        assert_eq!(put.last_hop_signature(), Some(&[4; 64]));
+        assert_eq!(put.block(), block);
+    }
+
+    #[test]
+    fn mtu_truncation() {
+        let mut put = PutMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            Timestamp::FOREVER,
+            Default::default(),
+            BlockKey::from([1; 64]),
+            Some(&[0; 64]),
+            b"block",
+        )
+        .unwrap()
+        .as_bytes()
+        .to_vec();
+        let peers: Vec<Peer> = identities::peers()[..4].iter().map(|f| f.peer()).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            put = PutMessage::parse(&put)
+                .unwrap()
+                .with_hop(peer, &[i as u8 + 1; 64]);
+        }
+
+        // dropping an element makes room for the origin that replaces it
+        let put = PutMessage::parse(&put).unwrap();
+        let max = put.as_bytes().len() - PATH_ELEMENT_SIZE + 32;
+        let truncated = put.truncated_to(max).unwrap();
+        assert_eq!(truncated.len(), max);
+        let truncated = PutMessage::parse(&truncated).unwrap();
+        assert!(truncated.flags().get_truncated());
+        assert_eq!(truncated.truncated_origin(), Some(peers[0].as_bytes()));
+        assert_eq!(truncated.put_path(), &put.put_path()[PATH_ELEMENT_SIZE..]);
+        assert_eq!(truncated.last_hop_signature(), Some(&[4; 64]));
+        assert!(put.truncated_to(100).is_none());
+
+        // a RESULT loses its PUT path first
+        let key = BlockKey::from([1; 64]);
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"found").unwrap();
+        let (header, block) = result.as_bytes().split_at(size_of::<ResultMessageHeader>());
+        let mut b = header.to_vec();
+        for i in 1..=3 {
+            b.extend_from_slice(&[i; PATH_ELEMENT_SIZE]);
+        }
+        b.extend_from_slice(block);
+        let len = b.len() as u16;
+        b[..2].copy_from_slice(&len.to_be_bytes());
+        // path lengths count elements
+        b[12..14].copy_from_slice(&2u16.to_be_bytes());
+        b[14..16].copy_from_slice(&1u16.to_be_bytes());
+
+        let result = ResultMessage::parse(&b).unwrap();
+        let truncated = result.truncated_to(b.len() - 2 * PATH_ELEMENT_SIZE + 32);
+        let truncated = truncated.unwrap();
+        let truncated = ResultMessage::parse(&truncated).unwrap();
+        assert!(truncated.flags().get_truncated());
+        assert_eq!(truncated.truncated_origin(), Some(&[2; 32]));
+        assert!(truncated.put_path().is_empty());
+        assert_eq!(truncated.get_path(), [3; PATH_ELEMENT_SIZE]);
+        assert_eq!(truncated.block(), b"found");
+    }
+
+    #[test]
+    fn owned() {
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            Timestamp::FOREVER,
+            Default::default(),
+            BlockKey::from([1; 64]),
+            None,
+            b"block",
+        )
+        .unwrap();
+        let owned = PutMessage::parse(put.as_bytes()).unwrap().to_owned();
+        drop(put);
+        let block = std::thread::spawn(move || owned.get().block().to_vec());
+        assert_eq!(block.join().unwrap(), b"block");
+
+        let identity = LocalPeer::new(identities::peers()[0].signing_key());
+        let hello = identity.sign_hello(Timestamp::FOREVER, ["udp://127.0.0.1:2086"]);
+        let block = hello.to_block();
+        let owned = HelloBlock::parse(&block).unwrap().to_owned();
+        assert_eq!(owned.get().peer(), identity.peer());
+        assert_eq!(owned.as_bytes(), block);
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        use super::{GetMessageOwned, PutMessageOwned, ResultMessageOwned};
+
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 7 + i / 256) as u8).collect();
+        let mut u = Unstructured::new(&data);
+        for _ in 0..4 {
+            // the truncated flag would make the block start with an origin
+            let put = PutMessageOwned::arbitrary(&mut u).unwrap();
+            assert!(!put.get().flags().get_truncated());
+            assert!(put.get().truncated_origin().is_none());
+            let get = GetMessageOwned::arbitrary(&mut u).unwrap();
+            assert!(GetMessage::parse(get.as_bytes()).is_ok());
+            let result = ResultMessageOwned::arbitrary(&mut u).unwrap();
+            assert!(ResultMessage::parse(result.as_bytes()).is_ok());
+        }
+    }
+
+    #[test]
+    fn typed_xquery() {
+        let get = |xquery: &[u8]| {
+            let key = BlockKey::from([1; 64]);
+            let flags = Flags::default();
+            GetMessage::encode(7, flags, 5, Default::default(), key, &[], xquery).unwrap()
+        };
+        let empty = get(&().encode());
+        let get_message = GetMessage::parse(empty.as_bytes()).unwrap();
+        assert_eq!(get_message.typed_xquery::<HelloBlock>(), Some(()));
+
+        // HELLOs can't be narrowed down
+        let narrowed = get(b"udp");
+        let get_message = GetMessage::parse(narrowed.as_bytes()).unwrap();
+        assert_eq!(get_message.typed_xquery::<HelloBlock>(), None);
+    }
+}
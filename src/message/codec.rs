@@ -0,0 +1,143 @@
+//! Framing messages on byte streams, eg TCP or Unix sockets. Each message
+//! starts with its [`MessageHeader`], which says how long it is, so
+//! messages follow each other with nothing in between.
+//!
+//! ```no_run
+//! # async fn f(stream: tokio::net::TcpStream) {
+//! use tokio_util::codec::Framed;
+//! use r6n::message::codec::R5nCodec;
+//!
+//! let mut framed = Framed::new(stream, R5nCodec::new());
+//! # }
+//! ```
+
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use zerocopy::FromBytes;
+
+use crate::Message;
+
+use super::MessageHeader;
+
+/// Splits a byte stream into [`Message`]s, and writes them back out. Only
+/// the header is checked, see [`dissect`](super::dissect) or the parsers
+/// for the rest.
+#[derive(Debug, Clone, Copy)]
+pub struct R5nCodec {
+    max_size: u16,
+}
+
+impl R5nCodec {
+    pub fn new() -> Self {
+        Self { max_size: u16::MAX }
+    }
+
+    /// Refuse messages larger than `max_size`, eg the connection's MTU
+    pub fn with_max_size(max_size: u16) -> Self {
+        Self { max_size }
+    }
+
+    /// The size of a message, if it is one we take
+    fn check(&self, header: &MessageHeader) -> io::Result<usize> {
+        let size = header.message_size();
+        if (size as usize) < size_of::<MessageHeader>() {
+            let message = "message is smaller than its header";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        if size > self.max_size {
+            let message = format!("{size} byte message is over {}", self.max_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(size as usize)
+    }
+}
+
+impl Default for R5nCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for R5nCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        let Some(header) = MessageHeader::ref_from_prefix(src) else {
+            return Ok(None);
+        };
+        let size = self.check(header)?;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+        let message = src[..size].to_vec();
+        src.advance(size);
+        Ok(Some(Message::from_bytes(message)))
+    }
+}
+
+impl Encoder<Message> for R5nCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let b = message.as_bytes();
+        let header = message
+            .header()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message has no header"))?;
+        let size = self
+            .check(header)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if size != b.len() {
+            let message = format!("header says {size} bytes, message is {}", b.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        dst.put_slice(b);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        message::ResultMessage,
+        Message,
+    };
+
+    use super::R5nCodec;
+
+    #[test]
+    fn framing() {
+        let key = BlockKey::from([1; 64]);
+        let a = ResultMessage::encode(13, Timestamp::FOREVER, key, b"first").unwrap();
+        let b = ResultMessage::encode(13, Timestamp::FOREVER, key, b"second").unwrap();
+
+        let mut codec = R5nCodec::new();
+        let mut stream = BytesMut::new();
+        codec.encode(a.clone(), &mut stream).unwrap();
+        codec.encode(b.clone(), &mut stream).unwrap();
+
+        // fed a byte at a time, messages come out once they're whole
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in stream {
+            src.extend_from_slice(&[byte]);
+            decoded.extend(codec.decode(&mut src).unwrap());
+        }
+        assert_eq!(decoded, [a.clone(), b]);
+        assert!(src.is_empty());
+
+        let mut small = R5nCodec::with_max_size(a.as_bytes().len() as u16 - 1);
+        let mut src = BytesMut::from(a.as_bytes());
+        assert!(small.decode(&mut src).is_err());
+        assert!(R5nCodec::new()
+            .encode(Message::from_bytes(vec![0, 8, 0, 0]), &mut BytesMut::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,513 @@
+//! Human-readable breakdowns of messages, eg for debugging interop with
+//! GNUnet.
+//!
+//! ```text
+//! PUT (146), 360 bytes
+//!   block type:        13
+//!   version:           0
+//!   flags:             0x02 (record-route)
+//!   ...
+//!   last hop sig:      valid
+//! ```
+//!
+//! Path elements are checked as far as the message allows. The last one and
+//! the last hop signature can only be checked knowing who sent the message
+//! and to whom, see [`Dissect::sender`] and [`Dissect::receiver`].
+
+use alloc::{borrow::ToOwned, format, string::String, vec::Vec};
+use core::fmt;
+
+use ed25519_dalek::ed25519::SignatureBytes;
+use zerocopy::FromBytes;
+
+use crate::{
+    block::{HelloBlock, HopSignaturePayload, Timestamp},
+    bloom::PeerBloomFilter,
+    encoding::{base32_encode, hex_encode},
+    error::ParseError,
+    limits::PATH_ELEMENT_SIZE,
+    Peer,
+};
+
+use super::{
+    Flags, GetMessage, GetMessageHeader, Hello, HelloMessage, MessageHeader, PutMessage,
+    PutMessageHeader, ResultMessage, ResultMessageHeader,
+};
+
+/// How much of a block is shown
+const BLOCK_PREVIEW: usize = 32;
+
+/// A message to explain. Its [`Display`](fmt::Display) impl prints the
+/// breakdown.
+#[derive(Clone, Copy)]
+pub struct Dissect<'a> {
+    message: Parsed<'a>,
+    size: usize,
+    sender: Option<Peer>,
+    receiver: Option<Peer>,
+}
+
+#[derive(Clone, Copy)]
+enum Parsed<'a> {
+    Hello(Hello<'a>),
+    Put(PutMessage<'a>),
+    Get(GetMessage<'a>),
+    Result(ResultMessage<'a>),
+}
+
+/// Parse any message we know for explaining.
+pub fn explain(b: &[u8]) -> Result<Dissect<'_>, DissectError> {
+    let header = MessageHeader::ref_from_prefix(b).ok_or(DissectError::Truncated)?;
+    let size = header.message_size() as usize;
+    if b.len() < size {
+        return Err(DissectError::Truncated);
+    }
+    let message_type = header.message_type();
+    let message = match message_type {
+        HelloMessage::MESSAGE_TYPE => Hello::parse(b).map(Parsed::Hello),
+        PutMessageHeader::MESSAGE_TYPE => PutMessage::parse(b).map(Parsed::Put),
+        GetMessageHeader::MESSAGE_TYPE => GetMessage::parse(b).map(Parsed::Get),
+        ResultMessageHeader::MESSAGE_TYPE => ResultMessage::parse(b).map(Parsed::Result),
+        _ => return Err(DissectError::UnknownType(message_type)),
+    };
+    let message = message.map_err(DissectError::Malformed)?;
+    Ok(Dissect::new(message, header))
+}
+
+macro_rules! explain {
+    ($($message:ident => $variant:ident,)*) => {$(
+        impl<'a> $message<'a> {
+            /// Explain this message, eg to print it. Its `Display` impl
+            /// does the same.
+            pub fn explain(&self) -> Dissect<'a> {
+                Dissect::new(Parsed::$variant(*self), &self.header.header)
+            }
+        }
+
+        impl fmt::Display for $message<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.explain().fmt(f)
+            }
+        }
+    )*};
+}
+
+explain! {
+    Hello => Hello,
+    PutMessage => Put,
+    GetMessage => Get,
+    ResultMessage => Result,
+}
+
+impl<'a> Dissect<'a> {
+    fn new(message: Parsed<'a>, header: &MessageHeader) -> Self {
+        Self {
+            message,
+            size: header.message_size() as usize,
+            sender: None,
+            receiver: None,
+        }
+    }
+
+    /// The peer the message came from, to check its HELLO or last hop
+    /// signature.
+    pub fn sender(mut self, peer: Peer) -> Self {
+        self.sender = Some(peer);
+        self
+    }
+
+    /// The peer the message was sent to, to check its last hop signature.
+    pub fn receiver(mut self, peer: Peer) -> Self {
+        self.receiver = Some(peer);
+        self
+    }
+
+    /// The size of the message, as its header says. Anything after it is
+    /// another message.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn hello(&self, f: &mut fmt::Formatter<'_>, hello: &Hello<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "HELLO ({}), {} bytes",
+            HelloMessage::MESSAGE_TYPE,
+            self.size
+        )?;
+        field(f, "expiration", timestamp(hello.expiration()))?;
+        let signature = self.sender.map(|peer| Check::from(hello.verify(&peer)));
+        field(f, "signature", signature.unwrap_or_default())?;
+        for addr in hello.addresses() {
+            field(f, "address", addr)?;
+        }
+        Ok(())
+    }
+
+    fn put(&self, f: &mut fmt::Formatter<'_>, put: &PutMessage<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "PUT ({}), {} bytes",
+            PutMessageHeader::MESSAGE_TYPE,
+            self.size
+        )?;
+        field(f, "block type", put.block_type())?;
+        field(f, "version", put.header.version)?;
+        field(f, "flags", put.flags())?;
+        field(f, "hop count", put.hop_count())?;
+        field(f, "replication", put.replication_level())?;
+        field(f, "expiration", timestamp(put.expiration()))?;
+        self.bloom(f, put.peer_bloom_filter())?;
+        field(f, "key", base32_encode(&put.block_key().0))?;
+        let path = Path {
+            block: put.block(),
+            expiration: put.expiration(),
+            origin: put.truncated_origin(),
+            elements: put.put_path(),
+            last_hop_signature: put.last_hop_signature(),
+        };
+        self.path(f, "put path", &path, 0)?;
+        block(f, put.block_type(), put.block())
+    }
+
+    fn get(&self, f: &mut fmt::Formatter<'_>, get: &GetMessage<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "GET ({}), {} bytes",
+            GetMessageHeader::MESSAGE_TYPE,
+            self.size
+        )?;
+        field(f, "block type", get.block_type())?;
+        field(f, "version", get.header.version)?;
+        field(f, "flags", get.flags())?;
+        field(f, "hop count", get.hop_count())?;
+        field(f, "replication", get.replication_level())?;
+        self.bloom(f, get.peer_bloom_filter())?;
+        field(f, "query", base32_encode(&get.query_hash().0))?;
+        field(f, "result filter", Bytes(get.result_filter()))?;
+        field(f, "xquery", Bytes(get.xquery()))
+    }
+
+    fn result(&self, f: &mut fmt::Formatter<'_>, result: &ResultMessage<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RESULT ({}), {} bytes",
+            ResultMessageHeader::MESSAGE_TYPE,
+            self.size
+        )?;
+        field(f, "block type", result.block_type())?;
+        field(f, "version", result.header.version)?;
+        field(f, "flags", result.flags())?;
+        field(f, "expiration", timestamp(result.expiration()))?;
+        field(f, "query", base32_encode(&result.query_hash().0))?;
+        // the signatures cover both paths as one
+        let mut elements = result.put_path().to_vec();
+        elements.extend_from_slice(result.get_path());
+        let path = Path {
+            block: result.block(),
+            expiration: result.expiration(),
+            origin: result.truncated_origin(),
+            elements: &elements,
+            last_hop_signature: result.last_hop_signature(),
+        };
+        let put_path = result.put_path().len() / PATH_ELEMENT_SIZE;
+        self.path(f, "path", &path, put_path)?;
+        block(f, result.block_type(), result.block())
+    }
+
+    fn bloom(&self, f: &mut fmt::Formatter<'_>, bloom: &PeerBloomFilter) -> fmt::Result {
+        let bloom_ref = bloom.get_ref();
+        let bytes = bloom_ref.as_bytes();
+        let set: u32 = bytes.iter().map(|b| b.count_ones()).sum();
+        let bits = bytes.len() * 8;
+        let mut fill = format!("{set}/{bits} bits set");
+        if let Some(sender) = &self.sender {
+            let has = if bloom.contains_peer(sender) {
+                "has"
+            } else {
+                "lacks"
+            };
+            fill.push_str(&format!(", {has} sender"));
+        }
+        field(f, "peer bloom filter", fill)
+    }
+
+    /// `put_path` elements of the path came from the PUT, the rest from the
+    /// GET.
+    fn path(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        name: &str,
+        path: &Path<'_>,
+        put_path: usize,
+    ) -> fmt::Result {
+        if let Some(origin) = path.origin {
+            field(f, "truncated origin", Peer::from_bytes(*origin))?;
+        }
+        let elements: Vec<_> = path
+            .elements
+            .chunks_exact(PATH_ELEMENT_SIZE)
+            .map(|e| {
+                let peer = Peer::from_bytes(e[..32].try_into().unwrap());
+                let signature: SignatureBytes = e[32..].try_into().unwrap();
+                (peer, signature)
+            })
+            .collect();
+        field(f, name, format!("{} elements", elements.len()))?;
+
+        let zero = Peer::from_bytes([0; 32]);
+        let first = path.origin.map_or(zero, |o| Peer::from_bytes(*o));
+        for (i, (peer, signature)) in elements.iter().enumerate() {
+            let pred = i.checked_sub(1).map_or(first, |i| elements[i].0);
+            let succ = elements.get(i + 1).map(|e| e.0).or(self.sender);
+            let check = succ.map(|succ| path.check(&pred, peer, &succ, signature));
+            let from = if put_path > 0 && i >= put_path {
+                " (get)"
+            } else {
+                ""
+            };
+            let element = format!("{peer}{from}, {}", check.unwrap_or_default());
+            field(f, &format!("  {i}"), element)?;
+        }
+        if let Some(signature) = path.last_hop_signature {
+            let pred = elements.last().map_or(first, |e| e.0);
+            let check = match (self.sender, self.receiver) {
+                (Some(sender), Some(receiver)) => path.check(&pred, &sender, &receiver, signature),
+                _ => Check::Unknown,
+            };
+            field(f, "last hop sig", check)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Dissect<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Parsed::Hello(hello) => self.hello(f, hello),
+            Parsed::Put(put) => self.put(f, put),
+            Parsed::Get(get) => self.get(f, get),
+            Parsed::Result(result) => self.result(f, result),
+        }
+    }
+}
+
+/// Why a message couldn't be dissected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DissectError {
+    /// Shorter than its header says
+    Truncated,
+    UnknownType(u16),
+    /// A message of a known type that doesn't parse
+    Malformed(ParseError),
+}
+
+impl fmt::Display for DissectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("message is truncated"),
+            Self::UnknownType(t) => write!(f, "unknown message type {t}"),
+            Self::Malformed(e) => write!(f, "malformed message: {e}"),
+        }
+    }
+}
+
+impl core::error::Error for DissectError {}
+
+struct Path<'a> {
+    block: &'a [u8],
+    expiration: Timestamp,
+    origin: Option<&'a [u8; 32]>,
+    elements: &'a [u8],
+    last_hop_signature: Option<&'a SignatureBytes>,
+}
+
+impl Path<'_> {
+    fn check(&self, pred: &Peer, signer: &Peer, succ: &Peer, signature: &SignatureBytes) -> Check {
+        HopSignaturePayload::new(self.expiration, self.block, pred, succ)
+            .verify(signer, signature)
+            .into()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+enum Check {
+    Valid,
+    Invalid,
+    /// we don't know who signed it, or for whom
+    #[default]
+    Unknown,
+}
+
+impl From<bool> for Check {
+    fn from(valid: bool) -> Self {
+        if valid {
+            Self::Valid
+        } else {
+            Self::Invalid
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Valid => "valid",
+            Self::Invalid => "INVALID",
+            Self::Unknown => "unchecked",
+        })
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.get_demultiplex(), "demultiplex"),
+            (self.get_record_route(), "record-route"),
+            (self.get_find_approximate(), "find-approximate"),
+            (self.get_truncated(), "truncated"),
+        ];
+        let set: Vec<&str> = names.iter().filter(|n| n.0).map(|n| n.1).collect();
+        write!(f, "{:#04x}", self.0)?;
+        if !set.is_empty() {
+            write!(f, " ({})", set.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl fmt::Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.0.len())?;
+        if !self.0.is_empty() {
+            let preview = &self.0[..self.0.len().min(BLOCK_PREVIEW)];
+            write!(f, ": {}", hex_encode(preview))?;
+            if preview.len() < self.0.len() {
+                f.write_str("...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn field(f: &mut fmt::Formatter<'_>, name: &str, value: impl fmt::Display) -> fmt::Result {
+    let name = if name.is_empty() {
+        String::new()
+    } else {
+        format!("{name}:")
+    };
+    writeln!(f, "  {name:<19}{value}")
+}
+
+fn timestamp(t: Timestamp) -> String {
+    if t == Timestamp::FOREVER {
+        "never".to_owned()
+    } else {
+        format!("{} us", t.as_micros())
+    }
+}
+
+fn block(f: &mut fmt::Formatter<'_>, block_type: u32, block: &[u8]) -> fmt::Result {
+    field(f, "block", Bytes(block))?;
+    if block_type != HelloBlock::BLOCK_TYPE {
+        return Ok(());
+    }
+    match HelloBlock::parse(block) {
+        Ok(hello) => {
+            field(f, "  HELLO of", hello.peer())?;
+            field(f, "  expiration", timestamp(hello.expiration()))?;
+            for addr in hello.addresses() {
+                field(f, "  address", addr)?;
+            }
+            Ok(())
+        }
+        Err(_) => field(f, "", "not a validly signed HELLO"),
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::{
+        block::{BlockKey, Timestamp},
+        bloom::PeerBloomFilter,
+        identity::LocalPeer,
+        message::{Flags, GetMessage, PutMessage},
+        testing::identities,
+        Peer,
+    };
+
+    use super::{explain, DissectError};
+
+    #[test]
+    fn put_path() {
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let zero = Peer::from_bytes([0; 32]);
+        let key = BlockKey::from([1; 64]);
+        let signature = LocalPeer::new(a.signing_key()).sign_hop(
+            Timestamp::FOREVER,
+            b"block",
+            &zero,
+            &b.peer(),
+        );
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            Timestamp::FOREVER,
+            PeerBloomFilter::default(),
+            key,
+            Some(&signature),
+            b"block",
+        )
+        .unwrap();
+
+        let explained = explain(put.as_bytes()).unwrap().to_string();
+        assert!(explained.starts_with("PUT (146)"));
+        assert!(explained.contains("flags:             0x02 (record-route)"));
+        assert!(explained.contains("last hop sig:      unchecked"));
+        assert!(explained.contains("block:             5 bytes: 626c6f636b"));
+
+        let known = explain(put.as_bytes()).unwrap().sender(a.peer());
+        let valid = known.receiver(b.peer()).to_string();
+        assert!(valid.contains("last hop sig:      valid"), "{valid}");
+        let invalid = known.receiver(a.peer()).to_string();
+        assert!(invalid.contains("last hop sig:      INVALID"));
+    }
+
+    #[test]
+    fn errors() {
+        let get = GetMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            PeerBloomFilter::default(),
+            BlockKey::from([1; 64]),
+            &[],
+            b"x",
+        )
+        .unwrap();
+        let get = get.as_bytes();
+        assert!(explain(get)
+            .unwrap()
+            .to_string()
+            .contains("xquery:            1 bytes: 78"));
+        assert_eq!(
+            explain(&get[..get.len() - 1]).err(),
+            Some(DissectError::Truncated)
+        );
+        let mut unknown = get.to_vec();
+        unknown[3] = 0;
+        assert_eq!(explain(&unknown).err(), Some(DissectError::UnknownType(0)));
+
+        // a result filter bigger than the message
+        let mut malformed = get.to_vec();
+        malformed[14..16].copy_from_slice(&900u16.to_be_bytes());
+        let err = explain(&malformed).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "malformed message: GET: result filter of 900 bytes at offset 208 exceeds the remaining 1"
+        );
+    }
+}
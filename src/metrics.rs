@@ -0,0 +1,405 @@
+//! Counters for what a node is doing, for operators to graph.
+//!
+//! The counters are atomics so that they can be read through an
+//! `Arc<Metrics>` while the node keeps running. [`Metrics::snapshot`] copies
+//! them into plain [`Stats`]. With the `prometheus` feature, they can be
+//! scraped through [`prometheus::Collector`].
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    task::Poll,
+};
+
+use crate::{
+    message::{GetMessageHeader, HelloMessage, PutMessageHeader, ResultMessageHeader},
+    monitor::{self, MessageKind},
+    policy::DropReason,
+    routing::Occupancy,
+    underlay::Underlay,
+    BucketIndex, Message, Peer, RoutingTable,
+};
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+/// Messages counted by their type
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MessageCounts {
+    pub get: u64,
+    pub put: u64,
+    pub result: u64,
+    pub hello: u64,
+    /// anything else, including messages too short to have a type
+    pub other: u64,
+    pub bytes: u64,
+    /// GETs, PUTs and RESULTs by the type of block they're about
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::debug::block_type_counts")
+    )]
+    pub by_block_type: BTreeMap<(MessageKind, u32), u64>,
+}
+
+impl MessageCounts {
+    pub fn total(&self) -> u64 {
+        self.get + self.put + self.result + self.hello + self.other
+    }
+}
+
+/// A copy of a node's [`Metrics`] at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    pub sent: MessageCounts,
+    pub received: MessageCounts,
+    /// sends the underlay refused
+    pub send_errors: u64,
+    /// HELLOs, in messages or blocks, that failed to verify
+    pub signature_failures: u64,
+    /// results given to a local query
+    pub results_delivered: u64,
+    /// results no query wanted, as they were duplicates, invalid, or for a
+    /// key no one is looking for
+    pub results_filtered: u64,
+    pub peers_added: u64,
+    pub peers_removed: u64,
+    /// messages dropped for arriving faster than their peer's rate limit
+    pub rate_limited: u64,
+    /// peers cut off for exceeding their rate limit too often
+    pub rate_limit_disconnects: u64,
+    /// peers banned for misbehaving, see [`bans`](crate::bans)
+    pub peers_banned: u64,
+    /// our addresses left out of our HELLOs, see
+    /// [`LocalHelloConfig`](crate::advertise::LocalHelloConfig)
+    pub hello_addresses_omitted: u64,
+    /// GETs that arrived again within the deduplication window
+    pub duplicate_gets: u64,
+    /// PUTs of a block that was PUT within the deduplication window
+    pub duplicate_puts: u64,
+    /// blocks from PUTs we stored
+    pub blocks_stored: u64,
+    /// GETs we had results for ourselves
+    pub gets_answered_locally: u64,
+    /// stored blocks left out of answers as the GET's result filter had
+    /// them, and peers not forwarded to as the message's peer bloom filter
+    /// had them
+    pub bloom_rejections: u64,
+    /// messages dropped because the outbound queues were full
+    pub outbound_dropped: u64,
+    /// messages dropped because they were larger than the connection's MTU,
+    /// even with their paths truncated
+    pub outbound_mtu_dropped: u64,
+    /// GETs and PUTs the [`ForwardingPolicy`](crate::policy::ForwardingPolicy)
+    /// refused, by [reason](DropReason::as_str) and block type
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::debug::policy_drop_counts")
+    )]
+    pub dropped_by_policy: BTreeMap<(&'static str, u32), u64>,
+    /// The non-empty routing table buckets as `(bucket, len)`
+    pub routing_table: Vec<(BucketIndex, usize)>,
+    pub pending_queries: u64,
+}
+
+#[derive(Default)]
+struct Counts {
+    get: AtomicU64,
+    put: AtomicU64,
+    result: AtomicU64,
+    hello: AtomicU64,
+    other: AtomicU64,
+    bytes: AtomicU64,
+    by_block_type: Mutex<BTreeMap<(MessageKind, u32), u64>>,
+}
+
+impl Counts {
+    fn count(&self, message: &Message) {
+        let counter = match message.header().map(|h| h.message_type()) {
+            Some(GetMessageHeader::MESSAGE_TYPE) => &self.get,
+            Some(PutMessageHeader::MESSAGE_TYPE) => &self.put,
+            Some(ResultMessageHeader::MESSAGE_TYPE) => &self.result,
+            Some(HelloMessage::MESSAGE_TYPE) => &self.hello,
+            _ => &self.other,
+        };
+        add(counter, 1);
+        add(&self.bytes, message.as_bytes().len() as u64);
+        if let Some((kind, block_type, ..)) = monitor::describe(message) {
+            *lock(&self.by_block_type)
+                .entry((kind, block_type))
+                .or_default() += 1;
+        }
+    }
+
+    fn snapshot(&self) -> MessageCounts {
+        MessageCounts {
+            get: load(&self.get),
+            put: load(&self.put),
+            result: load(&self.result),
+            hello: load(&self.hello),
+            other: load(&self.other),
+            bytes: load(&self.bytes),
+            by_block_type: lock(&self.by_block_type).clone(),
+        }
+    }
+}
+
+/// The live counters of a node. See [`DhtNode::metrics`](crate::DhtNode::metrics).
+#[derive(Default)]
+pub struct Metrics {
+    sent: Counts,
+    received: Counts,
+    send_errors: AtomicU64,
+    signature_failures: AtomicU64,
+    results_delivered: AtomicU64,
+    results_filtered: AtomicU64,
+    peers_added: AtomicU64,
+    peers_removed: AtomicU64,
+    rate_limited: AtomicU64,
+    rate_limit_disconnects: AtomicU64,
+    peers_banned: AtomicU64,
+    hello_addresses_omitted: AtomicU64,
+    duplicate_gets: AtomicU64,
+    duplicate_puts: AtomicU64,
+    blocks_stored: AtomicU64,
+    gets_answered_locally: AtomicU64,
+    bloom_rejections: AtomicU64,
+    outbound_dropped: AtomicU64,
+    outbound_mtu_dropped: AtomicU64,
+    dropped_by_policy: Mutex<BTreeMap<(&'static str, u32), u64>>,
+    // gauges, updated by the node as it goes
+    routing_table: Mutex<Option<Occupancy>>,
+    pending_queries: AtomicU64,
+}
+
+// the counters are independent, so there's nothing to order
+fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+// a panic while counting can't leave the counts inconsistent
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            sent: self.sent.snapshot(),
+            received: self.received.snapshot(),
+            send_errors: load(&self.send_errors),
+            signature_failures: load(&self.signature_failures),
+            results_delivered: load(&self.results_delivered),
+            results_filtered: load(&self.results_filtered),
+            peers_added: load(&self.peers_added),
+            peers_removed: load(&self.peers_removed),
+            rate_limited: load(&self.rate_limited),
+            rate_limit_disconnects: load(&self.rate_limit_disconnects),
+            peers_banned: load(&self.peers_banned),
+            hello_addresses_omitted: load(&self.hello_addresses_omitted),
+            duplicate_gets: load(&self.duplicate_gets),
+            duplicate_puts: load(&self.duplicate_puts),
+            blocks_stored: load(&self.blocks_stored),
+            gets_answered_locally: load(&self.gets_answered_locally),
+            bloom_rejections: load(&self.bloom_rejections),
+            outbound_dropped: load(&self.outbound_dropped),
+            outbound_mtu_dropped: load(&self.outbound_mtu_dropped),
+            dropped_by_policy: lock(&self.dropped_by_policy).clone(),
+            routing_table: lock(&self.routing_table)
+                .as_ref()
+                .map_or_else(Vec::new, |o| o.buckets().collect()),
+            pending_queries: load(&self.pending_queries),
+        }
+    }
+
+    pub(crate) fn received(&self, message: &Message) {
+        self.received.count(message);
+    }
+
+    pub(crate) fn signature_failure(&self) {
+        add(&self.signature_failures, 1);
+    }
+
+    /// A result that was new to `delivered` queries
+    pub(crate) fn result(&self, delivered: usize) {
+        match delivered {
+            0 => add(&self.results_filtered, 1),
+            n => add(&self.results_delivered, n as u64),
+        }
+    }
+
+    pub(crate) fn peer_added(&self) {
+        add(&self.peers_added, 1);
+    }
+
+    pub(crate) fn peer_removed(&self) {
+        add(&self.peers_removed, 1);
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        add(&self.rate_limited, 1);
+    }
+
+    pub(crate) fn rate_limit_disconnect(&self) {
+        add(&self.rate_limit_disconnects, 1);
+    }
+
+    pub(crate) fn peer_banned(&self) {
+        add(&self.peers_banned, 1);
+    }
+
+    pub(crate) fn hello_addresses_omitted(&self, n: usize) {
+        add(&self.hello_addresses_omitted, n as u64);
+    }
+
+    pub(crate) fn duplicate_get(&self) {
+        add(&self.duplicate_gets, 1);
+    }
+
+    pub(crate) fn duplicate_put(&self) {
+        add(&self.duplicate_puts, 1);
+    }
+
+    pub(crate) fn block_stored(&self) {
+        add(&self.blocks_stored, 1);
+    }
+
+    pub(crate) fn get_answered_locally(&self) {
+        add(&self.gets_answered_locally, 1);
+    }
+
+    pub(crate) fn bloom_rejections(&self, n: usize) {
+        add(&self.bloom_rejections, n as u64);
+    }
+
+    pub(crate) fn dropped_by_policy(&self, reason: DropReason, block_type: u32) {
+        *lock(&self.dropped_by_policy)
+            .entry((reason.as_str(), block_type))
+            .or_default() += 1;
+    }
+
+    /// The queue counts its own drops
+    pub(crate) fn set_outbound_dropped(&self, dropped: u64, mtu_dropped: u64) {
+        self.outbound_dropped.store(dropped, Ordering::Relaxed);
+        self.outbound_mtu_dropped
+            .store(mtu_dropped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn update_gauges(&self, routing: &RoutingTable, pending_queries: usize) {
+        let mut occupancy = lock(&self.routing_table);
+        if occupancy.as_ref().map(|o| o.generation) != Some(routing.generation()) {
+            *occupancy = Some(routing.occupancy());
+        }
+        self.pending_queries
+            .store(pending_queries as u64, Ordering::Relaxed);
+    }
+
+    /// An underlay that counts what is sent through it.
+    pub fn wrap<U: Underlay>(&self, underlay: U) -> Counted<'_, U> {
+        Counted {
+            underlay,
+            metrics: self,
+        }
+    }
+}
+
+/// See [`Metrics::wrap`].
+pub struct Counted<'a, U> {
+    underlay: U,
+    metrics: &'a Metrics,
+}
+
+impl<U: Underlay> Underlay for Counted<'_, U> {
+    type Address = U::Address;
+    type NetworkSizeEstimate = U::NetworkSizeEstimate;
+    type Error = U::Error;
+
+    fn try_connect(&self, peer: Peer, addr: Self::Address) -> Result<(), Self::Error> {
+        self.underlay.try_connect(peer, addr)
+    }
+
+    fn hold(&self, peer: Peer) {
+        self.underlay.hold(peer)
+    }
+
+    fn drop(&self, peer: Peer) {
+        self.underlay.drop(peer)
+    }
+
+    fn send(&self, peer: Peer, message: Message) -> Result<(), Self::Error> {
+        self.metrics.sent.count(&message);
+        let res = self.underlay.send(peer, message);
+        if res.is_err() {
+            add(&self.metrics.send_errors, 1);
+        }
+        res
+    }
+
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        self.underlay.poll_ready(peer)
+    }
+
+    fn estimate_network_size(&self) -> Self::NetworkSizeEstimate {
+        self.underlay.estimate_network_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{BlockKey, Timestamp},
+        message::ResultMessage,
+        monitor::MessageKind,
+        query::GetOptions,
+        testing::identities,
+        underlay::{memory::MemoryNetwork, Underlay, UnderlaySignal},
+        DhtNode,
+    };
+
+    #[test]
+    fn counts() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let (ua, rxa) = network.join(a.peer());
+        let (ub, _rxb) = network.join(b.peer());
+        ua.try_connect(b.peer(), ub.address()).unwrap();
+        let mut node = DhtNode::new(a.peer_id(), ua);
+        while let Ok(signal) = rxa.try_recv() {
+            node.handle_signal(signal);
+        }
+
+        let key = BlockKey::from([5; 64]);
+        node.get(key, 13, vec![], GetOptions::default());
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"found").unwrap();
+        let len = result.as_bytes().len() as u64;
+        ub.send(a.peer(), result.clone()).unwrap();
+        ub.send(a.peer(), result).unwrap();
+        while let Ok(signal) = rxa.try_recv() {
+            node.handle_signal(signal);
+        }
+        assert_eq!(node.metrics().snapshot().routing_table.len(), 1);
+        assert_eq!(node.metrics().snapshot().pending_queries, 1);
+        node.handle_signal(UnderlaySignal::PeerDisconnected(b.peer()));
+
+        let stats = node.metrics().snapshot();
+        assert_eq!(stats.sent.get, 1);
+        assert_eq!(stats.received.result, 2);
+        assert_eq!(stats.received.by_block_type[&(MessageKind::Result, 13)], 2);
+        assert_eq!(stats.received.bytes, 2 * len);
+        assert_eq!(stats.results_delivered, 1);
+        assert_eq!(stats.results_filtered, 1);
+        assert_eq!((stats.peers_added, stats.peers_removed), (1, 1));
+    }
+}
@@ -0,0 +1,335 @@
+//! Exporting a node's [`Metrics`] to a prometheus [`Registry`].
+//!
+//! All names start with `r6n_`. Labels are:
+//! - `direction`: `sent` or `received`
+//! - `message_type`: `get`, `put`, `result`, `hello` or `other`
+//! - `block_type`: the block type number, eg `7` for HELLOs
+//! - `bucket`: the routing table bucket, by distance from the host
+//! - `reason`: why the forwarding policy dropped a message, eg
+//!   `disabled_block_type`
+
+use std::sync::Arc;
+
+use prometheus::{
+    core::Desc, proto::MetricFamily, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry,
+};
+
+use super::{MessageCounts, Metrics, Stats};
+
+/// Collects a node's metrics each time the registry is gathered.
+pub struct Collector {
+    metrics: Arc<Metrics>,
+    // only used for their descriptions, each collection makes new ones
+    families: Families,
+}
+
+impl Collector {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+            families: Families::new(),
+        }
+    }
+
+    /// Register a collector for `metrics` with `registry`.
+    pub fn register(metrics: Arc<Metrics>, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(Self::new(metrics)))
+    }
+}
+
+impl prometheus::core::Collector for Collector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.families.all().flat_map(|c| c.desc()).collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let families = Families::new();
+        families.set(&self.metrics.snapshot());
+        families.all().flat_map(|c| c.collect()).collect()
+    }
+}
+
+struct Families {
+    messages: IntCounterVec,
+    bytes: IntCounterVec,
+    block_messages: IntCounterVec,
+    send_errors: IntCounter,
+    signature_failures: IntCounter,
+    results: IntCounterVec,
+    routing_changes: IntCounterVec,
+    rate_limited: IntCounter,
+    rate_limit_disconnects: IntCounter,
+    peers_banned: IntCounter,
+    hello_addresses_omitted: IntCounter,
+    duplicate_gets: IntCounter,
+    duplicate_puts: IntCounter,
+    blocks_stored: IntCounter,
+    gets_answered_locally: IntCounter,
+    bloom_rejections: IntCounter,
+    outbound_dropped: IntCounter,
+    outbound_mtu_dropped: IntCounter,
+    dropped_by_policy: IntCounterVec,
+    routing_table: IntGaugeVec,
+    pending_queries: IntGauge,
+}
+
+fn counter(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    // the names and labels are all valid
+    IntCounterVec::new(Opts::new(name, help), labels).unwrap()
+}
+
+impl Families {
+    fn new() -> Self {
+        Self {
+            messages: counter(
+                "r6n_messages_total",
+                "Messages by type",
+                &["direction", "message_type"],
+            ),
+            bytes: counter(
+                "r6n_message_bytes_total",
+                "Bytes of messages",
+                &["direction"],
+            ),
+            block_messages: counter(
+                "r6n_block_messages_total",
+                "GETs, PUTs and RESULTs by block type",
+                &["direction", "message_type", "block_type"],
+            ),
+            send_errors: IntCounter::new("r6n_send_errors_total", "Sends the underlay refused")
+                .unwrap(),
+            signature_failures: IntCounter::new(
+                "r6n_signature_failures_total",
+                "HELLOs that failed to verify",
+            )
+            .unwrap(),
+            results: counter(
+                "r6n_results_total",
+                "Results received, by whether a local query wanted them",
+                &["outcome"],
+            ),
+            routing_changes: counter(
+                "r6n_routing_changes_total",
+                "Peers added to or removed from the routing table",
+                &["change"],
+            ),
+            rate_limited: IntCounter::new(
+                "r6n_rate_limited_total",
+                "Messages dropped by the per-peer rate limits",
+            )
+            .unwrap(),
+            rate_limit_disconnects: IntCounter::new(
+                "r6n_rate_limit_disconnects_total",
+                "Peers cut off for exceeding their rate limits",
+            )
+            .unwrap(),
+            peers_banned: IntCounter::new("r6n_peers_banned_total", "Peers banned for misbehaving")
+                .unwrap(),
+            hello_addresses_omitted: IntCounter::new(
+                "r6n_hello_addresses_omitted_total",
+                "Our addresses left out of our HELLOs",
+            )
+            .unwrap(),
+            duplicate_gets: IntCounter::new(
+                "r6n_duplicate_gets_total",
+                "GETs that arrived again within the deduplication window",
+            )
+            .unwrap(),
+            duplicate_puts: IntCounter::new(
+                "r6n_duplicate_puts_total",
+                "PUTs of a block that was PUT within the deduplication window",
+            )
+            .unwrap(),
+            blocks_stored: IntCounter::new("r6n_blocks_stored_total", "Blocks from PUTs we stored")
+                .unwrap(),
+            gets_answered_locally: IntCounter::new(
+                "r6n_gets_answered_locally_total",
+                "GETs we had results for ourselves",
+            )
+            .unwrap(),
+            bloom_rejections: IntCounter::new(
+                "r6n_bloom_rejections_total",
+                "Results and peers skipped as a bloom filter had them",
+            )
+            .unwrap(),
+            outbound_dropped: IntCounter::new(
+                "r6n_outbound_dropped_total",
+                "Messages dropped because the outbound queues were full",
+            )
+            .unwrap(),
+            outbound_mtu_dropped: IntCounter::new(
+                "r6n_outbound_mtu_dropped_total",
+                "Messages dropped because they were larger than the connection's MTU",
+            )
+            .unwrap(),
+            dropped_by_policy: counter(
+                "r6n_dropped_by_policy_total",
+                "GETs and PUTs the forwarding policy refused",
+                &["reason", "block_type"],
+            ),
+            routing_table: IntGaugeVec::new(
+                Opts::new(
+                    "r6n_routing_table_peers",
+                    "Peers in each routing table bucket",
+                ),
+                &["bucket"],
+            )
+            .unwrap(),
+            pending_queries: IntGauge::new("r6n_pending_queries", "Local queries running").unwrap(),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &dyn prometheus::core::Collector> {
+        [
+            &self.messages as &dyn prometheus::core::Collector,
+            &self.bytes,
+            &self.block_messages,
+            &self.send_errors,
+            &self.signature_failures,
+            &self.results,
+            &self.routing_changes,
+            &self.rate_limited,
+            &self.rate_limit_disconnects,
+            &self.peers_banned,
+            &self.hello_addresses_omitted,
+            &self.duplicate_gets,
+            &self.duplicate_puts,
+            &self.blocks_stored,
+            &self.gets_answered_locally,
+            &self.bloom_rejections,
+            &self.outbound_dropped,
+            &self.outbound_mtu_dropped,
+            &self.dropped_by_policy,
+            &self.routing_table,
+            &self.pending_queries,
+        ]
+        .into_iter()
+    }
+
+    fn set(&self, stats: &Stats) {
+        self.set_messages("sent", &stats.sent);
+        self.set_messages("received", &stats.received);
+        self.send_errors.inc_by(stats.send_errors);
+        self.signature_failures.inc_by(stats.signature_failures);
+        let results = [
+            ("delivered", stats.results_delivered),
+            ("filtered", stats.results_filtered),
+        ];
+        for (outcome, n) in results {
+            self.results.with_label_values(&[outcome]).inc_by(n);
+        }
+        let changes = [
+            ("added", stats.peers_added),
+            ("removed", stats.peers_removed),
+        ];
+        for (change, n) in changes {
+            self.routing_changes.with_label_values(&[change]).inc_by(n);
+        }
+        self.rate_limited.inc_by(stats.rate_limited);
+        self.rate_limit_disconnects
+            .inc_by(stats.rate_limit_disconnects);
+        self.peers_banned.inc_by(stats.peers_banned);
+        self.hello_addresses_omitted
+            .inc_by(stats.hello_addresses_omitted);
+        self.duplicate_gets.inc_by(stats.duplicate_gets);
+        self.duplicate_puts.inc_by(stats.duplicate_puts);
+        self.blocks_stored.inc_by(stats.blocks_stored);
+        self.gets_answered_locally
+            .inc_by(stats.gets_answered_locally);
+        self.bloom_rejections.inc_by(stats.bloom_rejections);
+        self.outbound_dropped.inc_by(stats.outbound_dropped);
+        self.outbound_mtu_dropped.inc_by(stats.outbound_mtu_dropped);
+        for (&(reason, block_type), &n) in &stats.dropped_by_policy {
+            let block_type = block_type.to_string();
+            self.dropped_by_policy
+                .with_label_values(&[reason, &block_type])
+                .inc_by(n);
+        }
+        for &(bucket, len) in &stats.routing_table {
+            let bucket = bucket.to_string();
+            self.routing_table
+                .with_label_values(&[&bucket])
+                .set(len as i64);
+        }
+        self.pending_queries.set(stats.pending_queries as i64);
+    }
+
+    fn set_messages(&self, direction: &str, counts: &MessageCounts) {
+        let types = [
+            ("get", counts.get),
+            ("put", counts.put),
+            ("result", counts.result),
+            ("hello", counts.hello),
+            ("other", counts.other),
+        ];
+        for (message_type, n) in types {
+            self.messages
+                .with_label_values(&[direction, message_type])
+                .inc_by(n);
+        }
+        self.bytes
+            .with_label_values(&[direction])
+            .inc_by(counts.bytes);
+        for (&(kind, block_type), &n) in &counts.by_block_type {
+            let block_type = block_type.to_string();
+            self.block_messages
+                .with_label_values(&[direction, kind.as_str(), &block_type])
+                .inc_by(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        bloom::PeerBloomFilter,
+        message::{Flags, PutMessage},
+        metrics::Metrics,
+        policy::DropReason,
+        Message,
+    };
+
+    use super::Collector;
+
+    #[test]
+    fn scrape() {
+        let metrics = Arc::new(Metrics::new());
+        let key = BlockKey::from([1; 64]);
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            1,
+            Timestamp::FOREVER,
+            PeerBloomFilter::default(),
+            key,
+            None,
+            b"block",
+        )
+        .unwrap();
+        metrics.received(&put);
+        metrics.received(&Message::from_bytes(vec![0; 2]));
+        metrics.dropped_by_policy(DropReason::DisabledBlockType(13), 13);
+
+        let registry = Registry::new();
+        Collector::register(metrics, &registry).unwrap();
+        let mut text = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut text)
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains(r#"r6n_messages_total{direction="received",message_type="put"} 1"#));
+        assert!(text.contains(r#"r6n_messages_total{direction="received",message_type="other"} 1"#));
+        assert!(text.contains(
+            r#"r6n_block_messages_total{block_type="13",direction="received",message_type="put"} 1"#
+        ));
+        assert!(text.contains(
+            r#"r6n_dropped_by_policy_total{block_type="13",reason="disabled_block_type"} 1"#
+        ));
+    }
+}
@@ -0,0 +1,261 @@
+//! Observing the DHT traffic passing through a node, like GNUnet's DHT
+//! monitor.
+//!
+//! Observers registered with a [`Monitor`] are told about every GET, PUT and
+//! RESULT the node sends or receives. They are called while the node is
+//! processing, so they should be quick, eg pushing to a channel.
+
+use std::{sync::mpsc, task::Poll};
+
+use crate::{
+    block::BlockKey,
+    message::{
+        GetMessage, GetMessageHeader, PutMessage, PutMessageHeader, ResultMessage,
+        ResultMessageHeader,
+    },
+    underlay::Underlay,
+    Message, Peer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageKind {
+    Get,
+    Put,
+    Result,
+}
+
+impl MessageKind {
+    /// The lowercase name, eg for metric labels
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Get => "get",
+            MessageKind::Put => "put",
+            MessageKind::Result => "result",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from this peer
+    From(Peer),
+    /// Sent to this peer
+    To(Peer),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitorEvent {
+    pub kind: MessageKind,
+    pub direction: Direction,
+    pub block_type: u32,
+    /// The block key of a PUT, or the query hash of a GET or RESULT
+    pub key: BlockKey,
+    /// Results don't count their hops
+    pub hop_count: Option<u16>,
+}
+
+impl MonitorEvent {
+    /// The event for a message, if it is a GET, PUT or RESULT.
+    pub fn from_message(message: &Message, direction: Direction) -> Option<Self> {
+        let (kind, block_type, key, hop_count) = describe(message)?;
+        Some(Self {
+            kind,
+            direction,
+            block_type,
+            key,
+            hop_count,
+        })
+    }
+}
+
+/// The kind, block type, key and hop count of a GET, PUT or RESULT
+pub(crate) fn describe(message: &Message) -> Option<(MessageKind, u32, BlockKey, Option<u16>)> {
+    let b = message.as_bytes();
+    let described = match message.header()?.message_type() {
+        GetMessageHeader::MESSAGE_TYPE => {
+            let get = GetMessage::parse(b).ok()?;
+            let hops = Some(get.hop_count());
+            (MessageKind::Get, get.block_type(), *get.query_hash(), hops)
+        }
+        PutMessageHeader::MESSAGE_TYPE => {
+            let put = PutMessage::parse(b).ok()?;
+            let hops = Some(put.hop_count());
+            (MessageKind::Put, put.block_type(), *put.block_key(), hops)
+        }
+        ResultMessageHeader::MESSAGE_TYPE => {
+            let result = ResultMessage::parse(b).ok()?;
+            (
+                MessageKind::Result,
+                result.block_type(),
+                *result.query_hash(),
+                None,
+            )
+        }
+        _ => return None,
+    };
+    Some(described)
+}
+
+pub trait Observer: Send {
+    fn observe(&self, event: &MonitorEvent);
+}
+
+impl<F: Fn(&MonitorEvent) + Send> Observer for F {
+    fn observe(&self, event: &MonitorEvent) {
+        self(event)
+    }
+}
+
+/// Events are dropped once the receiver is gone.
+impl Observer for mpsc::Sender<MonitorEvent> {
+    fn observe(&self, event: &MonitorEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Identifies an observer so that it can be removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+/// The observers of a node's traffic.
+#[derive(Default)]
+pub struct Monitor {
+    observers: Vec<(ObserverId, Box<dyn Observer>)>,
+    next_id: u64,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, observer: impl Observer + 'static) -> ObserverId {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.observers.push((id, Box::new(observer)));
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: ObserverId) -> bool {
+        let before = self.observers.len();
+        self.observers.retain(|(i, _)| *i != id);
+        self.observers.len() < before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    /// Tell every observer about a message, if it is one they care about.
+    pub fn notify(&self, message: &Message, direction: Direction) {
+        if self.observers.is_empty() {
+            return;
+        }
+        if let Some(event) = MonitorEvent::from_message(message, direction) {
+            for (_, observer) in &self.observers {
+                observer.observe(&event);
+            }
+        }
+    }
+
+    /// An underlay that reports what is sent through it.
+    pub fn wrap<'a, U: Underlay>(&'a self, underlay: &'a U) -> Monitored<'a, U> {
+        Monitored {
+            underlay,
+            monitor: self,
+        }
+    }
+}
+
+/// See [`Monitor::wrap`].
+pub struct Monitored<'a, U> {
+    underlay: &'a U,
+    monitor: &'a Monitor,
+}
+
+impl<U: Underlay> Underlay for Monitored<'_, U> {
+    type Address = U::Address;
+    type NetworkSizeEstimate = U::NetworkSizeEstimate;
+    type Error = U::Error;
+
+    fn try_connect(&self, peer: Peer, addr: Self::Address) -> Result<(), Self::Error> {
+        self.underlay.try_connect(peer, addr)
+    }
+
+    fn hold(&self, peer: Peer) {
+        self.underlay.hold(peer)
+    }
+
+    fn drop(&self, peer: Peer) {
+        self.underlay.drop(peer)
+    }
+
+    fn send(&self, peer: Peer, message: Message) -> Result<(), Self::Error> {
+        self.monitor.notify(&message, Direction::To(peer));
+        self.underlay.send(peer, message)
+    }
+
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        self.underlay.poll_ready(peer)
+    }
+
+    fn estimate_network_size(&self) -> Self::NetworkSizeEstimate {
+        self.underlay.estimate_network_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        message::ResultMessage,
+        query::GetOptions,
+        testing::identities,
+        underlay::{memory::MemoryNetwork, Underlay},
+        DhtNode,
+    };
+
+    use super::{Direction, MessageKind, MonitorEvent};
+
+    #[test]
+    fn observe() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let (ua, rxa) = network.join(a.peer());
+        let (ub, _rxb) = network.join(b.peer());
+        ua.try_connect(b.peer(), ub.address()).unwrap();
+        let mut node = DhtNode::new(a.peer_id(), ua);
+        while let Ok(signal) = rxa.try_recv() {
+            node.handle_signal(signal);
+        }
+
+        let (tx, events) = mpsc::channel();
+        let id = node.monitor_mut().subscribe(tx);
+        let key = BlockKey::from([5; 64]);
+        node.get(key, 13, vec![], GetOptions::default());
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"found").unwrap();
+        ub.send(a.peer(), result).unwrap();
+        while let Ok(signal) = rxa.try_recv() {
+            node.handle_signal(signal);
+        }
+
+        let event = |kind, direction, hop_count| MonitorEvent {
+            kind,
+            direction,
+            block_type: 13,
+            key,
+            hop_count,
+        };
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                event(MessageKind::Get, Direction::To(b.peer()), Some(0)),
+                event(MessageKind::Result, Direction::From(b.peer()), None),
+            ]
+        );
+        assert!(node.monitor_mut().unsubscribe(id));
+        assert!(node.monitor().is_empty());
+    }
+}
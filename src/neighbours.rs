@@ -0,0 +1,209 @@
+//! Keeping our closest peers connected.
+//!
+//! The peers closest to us are the ones that route messages for keys near
+//! our own, so losing them costs more than losing any other. The node
+//! keeps track of the [`size`](NeighbourConfig::size) closest routed peers,
+//! and holds each connection for as long as its peer is one of them, on
+//! top of the hold routing takes. Peers in the
+//! [HELLO cache](crate::hellos) that would be closer than the furthest of
+//! them are [candidates](Neighbours::candidates) to connect to.
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    block::BlockKey, gossip::SignedHello, hellos::HelloCache, routing::RoutingTable, Distance, Peer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct NeighbourConfig {
+    /// How many of the closest peers are kept connected
+    pub size: usize,
+    /// How long before a candidate that didn't connect is tried again
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub retry_interval: Duration,
+}
+
+impl Default for NeighbourConfig {
+    fn default() -> Self {
+        Self {
+            size: 8,
+            retry_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How the neighbour set changed in an [`update`](Neighbours::update)
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub entered: Vec<Peer>,
+    pub left: Vec<Peer>,
+}
+
+/// The closest peers to us. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Neighbours {
+    config: NeighbourConfig,
+    /// closest first
+    peers: Vec<Peer>,
+    /// the routing table generation `peers` was taken from
+    generation: Option<u64>,
+    /// when each candidate was last tried
+    tried: HashMap<Peer, Duration>,
+}
+
+impl Neighbours {
+    pub fn new(config: NeighbourConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &NeighbourConfig {
+        &self.config
+    }
+
+    /// A new size takes effect on the next [`update`](Self::update).
+    pub fn set_config(&mut self, config: NeighbourConfig) {
+        self.config = config;
+        self.generation = None;
+    }
+
+    /// The closest peers, closest first
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    pub fn contains(&self, peer: &Peer) -> bool {
+        self.peers.contains(peer)
+    }
+
+    /// Take the closest peers from the routing table again, if it changed.
+    pub fn update(&mut self, routing: &RoutingTable) -> Changes {
+        if self.generation == Some(routing.generation()) {
+            return Changes::default();
+        }
+        self.generation = Some(routing.generation());
+        let host = BlockKey(routing.host().0);
+        let peers: Vec<Peer> = routing
+            .closest_peers(&host, self.config.size)
+            .into_iter()
+            .copied()
+            .collect();
+        let changes = Changes {
+            entered: peers
+                .iter()
+                .filter(|p| !self.contains(p))
+                .copied()
+                .collect(),
+            left: self
+                .peers
+                .iter()
+                .filter(|p| !peers.contains(p))
+                .copied()
+                .collect(),
+        };
+        self.peers = peers;
+        changes
+    }
+
+    /// Cached HELLOs of unrouted peers that would be closer than the
+    /// furthest neighbour, or any if there aren't enough neighbours yet,
+    /// closest first. Each is only offered once per
+    /// [`retry_interval`](NeighbourConfig::retry_interval).
+    pub fn candidates<'a>(
+        &mut self,
+        routing: &RoutingTable,
+        hellos: &'a HelloCache,
+        now: Duration,
+    ) -> Vec<&'a SignedHello> {
+        let retry = self.config.retry_interval;
+        self.tried.retain(|_, at| now.saturating_sub(*at) < retry);
+        let host = routing.host();
+        let furthest = match self.peers.last() {
+            Some(peer) if self.peers.len() >= self.config.size => {
+                Some(Distance::between(&host.0, &peer.id().0))
+            }
+            _ => None,
+        };
+        let mut candidates = Vec::new();
+        // some of the closest may be neighbours already
+        for hello in hellos.closest(host, 2 * self.config.size) {
+            let peer = hello.peer();
+            let distance = Distance::between(&host.0, &peer.id().0);
+            if furthest.is_some_and(|furthest| distance >= furthest) {
+                break;
+            }
+            if routing.contains(peer) || self.tried.contains_key(peer) {
+                continue;
+            }
+            self.tried.insert(*peer, now);
+            candidates.push(hello);
+        }
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::Timestamp,
+        gossip::SignedHello,
+        hellos::HelloCache,
+        routing::{RoutingTable, RoutingTableConfig},
+        testing::identities,
+        Distance,
+    };
+
+    use super::{NeighbourConfig, Neighbours};
+
+    #[test]
+    fn neighbours() {
+        let host = identities::host().peer_id();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut routing = RoutingTable::new(host, config);
+        let mut neighbours = Neighbours::new(NeighbourConfig {
+            size: 2,
+            retry_interval: Duration::from_secs(10),
+        });
+        // peers from closest to furthest
+        let mut fixtures: Vec<_> = identities::peers().iter().collect();
+        fixtures.sort_by_key(|f| Distance::between(&host.0, &f.id));
+        let peers: Vec<_> = fixtures.iter().map(|f| f.peer()).collect();
+
+        let _ = routing.insert(peers[2]);
+        let _ = routing.insert(peers[3]);
+        let changes = neighbours.update(&routing);
+        assert_eq!(changes.entered, [peers[2], peers[3]]);
+        assert!(neighbours.update(&routing).entered.is_empty());
+
+        let _ = routing.insert(peers[1]);
+        let changes = neighbours.update(&routing);
+        assert_eq!(
+            (changes.entered, changes.left),
+            (vec![peers[1]], vec![peers[3]])
+        );
+        assert_eq!(neighbours.peers(), [peers[1], peers[2]]);
+
+        // only peers closer than the furthest neighbour are worth connecting
+        let mut hellos = HelloCache::new(16);
+        for i in [0, 3] {
+            let hello = SignedHello::sign(&fixtures[i].signing_key(), Timestamp::FOREVER, []);
+            hellos.insert(hello, Timestamp::from_micros(0));
+        }
+        let secs = Duration::from_secs;
+        let candidates = neighbours.candidates(&routing, &hellos, secs(0));
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(*candidates[0].peer(), peers[0]);
+        // and only once in a while
+        assert!(neighbours.candidates(&routing, &hellos, secs(5)).is_empty());
+        assert_eq!(neighbours.candidates(&routing, &hellos, secs(10)).len(), 1);
+    }
+}
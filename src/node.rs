@@ -0,0 +1,2306 @@
+use std::{cell::RefCell, collections::VecDeque, fmt, str::FromStr, sync::Arc, time::Duration};
+
+use ed25519_dalek::ed25519::SignatureBytes;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    advertise::{AddressBook, LocalHello},
+    bans::{BanConfig, BanList, Offence},
+    block::{BlockKey, HelloBlock},
+    bloom::PeerBloomFilter,
+    config::{ConfigError, ConfigUpdate, DhtConfig},
+    datacache::{DataCache, DataCacheConfig, StoredBlock},
+    dedup::{DedupConfig, GetCache, PutCache},
+    error::DhtError,
+    gossip::{Gossip, GossipConfig, SignedHello},
+    hellos::HelloCache,
+    identity::LocalPeer,
+    maintenance::{Budget, Maintenance, Task, TaskStatus, Tick},
+    message::{
+        max_block_size, AnyMessage, Flags, GetMessage, PutMessage, ResultMessage, UnknownMessage,
+    },
+    metrics::{Counted, Metrics},
+    monitor::{Direction, Monitor, Monitored},
+    neighbours::{NeighbourConfig, Neighbours},
+    nse::Nse,
+    outbound::{OutboundConfig, OutboundQueue, Queued},
+    policy::ForwardingPolicy,
+    quality::{PeerQuality, QualityConfig},
+    query::{self, GetOptions, QueryEvent, QueryId, QueryManager, BLOCK_TYPE_ANY},
+    ratelimit::{RateLimitConfig, RateLimiter, Verdict},
+    refresh::{BucketRefresh, RefreshConfig},
+    relay::{RelayConfig, ReturnRoutes},
+    republish::{MigrationConfig, Migrations, RepublishConfig, Republisher},
+    routing::math,
+    state::NodeState,
+    time::{Clock, SystemClock},
+    underlay::{AddressSchemes, HoldTracker, SchemePreference, Underlay, UnderlaySignal},
+    InsertOutcome, Message, Peer, PeerId, RemovalReason, RouteEvent, RoutingTable,
+    RoutingTableConfig,
+};
+
+/// How often maintenance tasks run unless configured otherwise
+pub(crate) const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// HELLOs passed on to a new neighbour are only meant for it
+const HELLO_REPLICATION_LEVEL: u16 = 1;
+
+/// Republished and migrated blocks go straight to the peers that should
+/// store them
+const REPUBLISH_REPLICATION_LEVEL: u16 = 1;
+
+/// How many of the closest blocks an approximate GET is answered with
+const APPROXIMATE_RESULTS: usize = 4;
+
+/// The most route events kept for the application, the oldest are dropped
+/// past this
+const MAX_ROUTE_EVENTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct PutOptions {
+    /// How many peers the block is stored at, roughly
+    pub replication_level: u16,
+    /// How long the block is stored for
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub expiration: Duration,
+    /// Record the path the PUT takes. This needs the node's
+    /// [identity](DhtNode::set_identity) to sign the first hop.
+    pub record_route: bool,
+    /// Have every peer along the way store the block, not just the closest
+    pub demultiplex: bool,
+}
+
+impl Default for PutOptions {
+    fn default() -> Self {
+        Self {
+            // as in GNUnet
+            replication_level: 5,
+            expiration: Duration::from_secs(60 * 60),
+            record_route: false,
+            demultiplex: false,
+        }
+    }
+}
+
+impl PutOptions {
+    fn flags(&self) -> Flags {
+        let mut flags = Flags::default();
+        flags.set_demultiplex(self.demultiplex);
+        flags
+    }
+}
+
+/// Why a PUT couldn't be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutError {
+    /// The block doesn't fit in a message
+    TooLarge,
+    /// Recording the route needs the node's identity
+    NoIdentity,
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PutError::TooLarge => f.write_str("block too large"),
+            PutError::NoIdentity => f.write_str("no identity to sign the route with"),
+        }
+    }
+}
+
+impl std::error::Error for PutError {}
+
+/// The DHT protocol state of one peer, driven by [`UnderlaySignal`]s from an
+/// [`Underlay`].
+///
+/// The node does no IO of its own. The application feeds it signals and calls
+/// [`tick`](Self::tick) when [`Tick::next_due`] has passed, and the node calls
+/// back into the underlay to hold, drop and send.
+pub struct DhtNode<U: Underlay> {
+    underlay: U,
+    routing: RoutingTable,
+    /// changes to `routing`, until the application takes them
+    route_events: VecDeque<RouteEvent>,
+    policy: ForwardingPolicy,
+    maintenance: Maintenance,
+    /// overrides the underlay's estimate when set
+    nse: Option<Nse>,
+    clock: Arc<dyn Clock>,
+    /// where peer selection and gossip draw their randomness from
+    rng: RefCell<Box<dyn RngCore + Send>>,
+    holds: HoldTracker,
+    gossip: Gossip,
+    /// when our HELLO is signed again
+    local_hello: LocalHello,
+    queries: QueryManager,
+    identity: Option<LocalPeer>,
+    monitor: Monitor,
+    metrics: Arc<Metrics>,
+    limiter: RateLimiter,
+    bans: BanList,
+    /// recently seen GETs, so copies arriving along other paths are ignored
+    gets: GetCache,
+    /// recently seen PUTs, so replication storms are only handled once
+    puts: PutCache,
+    datacache: DataCache,
+    /// who to pass results back to
+    relay: ReturnRoutes,
+    republisher: Republisher,
+    refresh: BucketRefresh,
+    neighbours: Neighbours,
+    /// how well peers answer our GETs, which weighs them in random routing
+    quality: PeerQuality,
+    /// blocks to offer to new peers closer to them than us
+    migrations: Migrations,
+    /// what GETs and PUTs start from, see [`DhtConfig`](crate::config::DhtConfig)
+    get_options: GetOptions,
+    put_options: PutOptions,
+    /// set by shutdown, after which maintenance no longer runs
+    stopped: bool,
+    // messages are queued while processing and flushed after
+    outbox: RefCell<OutboundQueue>,
+    /// addresses the underlay says we are reachable at
+    addresses: AddressBook<U::Address>,
+    /// called with messages of types we don't know
+    unknown_messages: Option<UnknownMessageHandler>,
+}
+
+type UnknownMessageHandler = Box<dyn FnMut(Peer, UnknownMessage<'_>) + Send>;
+
+impl<U: Underlay> DhtNode<U> {
+    pub fn new(host: PeerId, underlay: U) -> Self {
+        Self::with_clock(host, underlay, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(host: PeerId, underlay: U, clock: Arc<dyn Clock>) -> Self {
+        let routing =
+            RoutingTable::new(host, RoutingTableConfig::default()).with_clock(clock.clone());
+        Self {
+            underlay,
+            routing,
+            route_events: VecDeque::new(),
+            policy: ForwardingPolicy::default(),
+            maintenance: maintenance(clock.now(), &GossipConfig::default()),
+            nse: None,
+            clock,
+            rng: RefCell::new(Box::new(StdRng::from_entropy())),
+            holds: HoldTracker::new(),
+            gossip: Gossip::default(),
+            local_hello: LocalHello::default(),
+            queries: QueryManager::default(),
+            identity: None,
+            monitor: Monitor::new(),
+            metrics: Arc::new(Metrics::new()),
+            limiter: RateLimiter::default(),
+            bans: BanList::default(),
+            gets: GetCache::default(),
+            puts: PutCache::default(),
+            datacache: DataCache::default(),
+            relay: ReturnRoutes::default(),
+            republisher: Republisher::default(),
+            refresh: BucketRefresh::default(),
+            neighbours: Neighbours::default(),
+            quality: PeerQuality::default(),
+            migrations: Migrations::default(),
+            get_options: GetOptions::default(),
+            put_options: PutOptions::default(),
+            stopped: false,
+            outbox: RefCell::default(),
+            addresses: AddressBook::new(),
+            unknown_messages: None,
+        }
+    }
+
+    pub fn underlay(&self) -> &U {
+        &self.underlay
+    }
+
+    /// Time since the node's epoch, which [`Tick::next_due`] is relative to
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// The key to sign with. It must be the host's.
+    pub fn set_identity(&mut self, identity: LocalPeer) {
+        assert_eq!(
+            identity.peer_id(),
+            *self.routing.host(),
+            "not the host's identity"
+        );
+        self.identity = Some(identity);
+        if self.local_hello.is_automatic() {
+            self.local_hello.set_automatic(true, self.clock.now());
+            self.refresh_local_hello();
+        }
+    }
+
+    /// Make the node's random choices from `rng` instead of a generator
+    /// seeded from the OS, eg so tests and simulations are reproducible.
+    pub fn set_rng(&mut self, rng: impl RngCore + Send + 'static) {
+        self.rng = RefCell::new(Box::new(rng));
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing
+    }
+
+    /// The settings the node is running with
+    pub fn config(&self) -> DhtConfig {
+        DhtConfig {
+            routing: *self.routing.config(),
+            maintenance_interval: self.maintenance.interval(Task::Gc),
+            gossip: *self.gossip.config(),
+            local_hello: *self.local_hello.config(),
+            query: *self.queries.config(),
+            rate_limits: *self.limiter.config(),
+            bans: *self.bans.config(),
+            get_cache: *self.gets.config(),
+            put_cache: *self.puts.config(),
+            datacache: *self.datacache.config(),
+            republish: *self.republisher.config(),
+            refresh: *self.refresh.config(),
+            neighbours: *self.neighbours.config(),
+            quality: *self.quality.config(),
+            migration: *self.migrations.config(),
+            relay: *self.relay.config(),
+            outbound: *self.outbox.borrow().config(),
+            nse: self.nse.as_ref().map(|nse| *nse.config()),
+            get: self.get_options,
+            put: self.put_options,
+        }
+    }
+
+    /// Change some settings of the running node. If any change is rejected,
+    /// nothing changes.
+    pub fn update_config(&mut self, update: &ConfigUpdate) -> Result<(), ConfigError> {
+        let mut config = self.config();
+        update.apply(self.routing.host(), &mut config)?;
+        config.validate(self.identity.is_some())?;
+        self.apply_config(&config);
+        Ok(())
+    }
+
+    /// Set everything but the routing config, which is fixed.
+    pub(crate) fn apply_config(&mut self, config: &DhtConfig) {
+        self.maintenance
+            .set_interval(Task::Gc, config.maintenance_interval);
+        self.set_gossip_config(config.gossip);
+        self.local_hello.set_config(config.local_hello);
+        self.queries.set_config(config.query);
+        self.set_rate_limits(config.rate_limits);
+        self.set_ban_config(config.bans);
+        self.set_get_cache_config(config.get_cache);
+        self.set_put_cache_config(config.put_cache);
+        self.set_datacache_config(config.datacache);
+        self.set_republish_config(config.republish);
+        self.set_refresh_config(config.refresh);
+        self.set_neighbour_config(config.neighbours);
+        self.set_quality_config(config.quality);
+        self.set_migration_config(config.migration);
+        self.set_relay_config(config.relay);
+        self.set_outbound_config(config.outbound);
+        match (&mut self.nse, config.nse) {
+            (Some(nse), Some(config)) => nse.set_config(config),
+            (None, Some(config)) => self.set_nse(Nse::new(config)),
+            (_, None) => {}
+        }
+        self.set_default_options(config.get, config.put);
+    }
+
+    /// Replace the routing table with an empty one configured with
+    /// `config`, before any peers have connected.
+    pub(crate) fn with_routing_config(mut self, config: RoutingTableConfig) -> Self {
+        let host = *self.routing.host();
+        self.routing = RoutingTable::new(host, config).with_clock(self.clock.clone());
+        self
+    }
+
+    /// The options GETs start from
+    pub fn default_get_options(&self) -> GetOptions {
+        self.get_options
+    }
+
+    /// The options PUTs start from
+    pub fn default_put_options(&self) -> PutOptions {
+        self.put_options
+    }
+
+    pub fn set_default_options(&mut self, get: GetOptions, put: PutOptions) {
+        self.get_options = get;
+        self.put_options = put;
+    }
+
+    /// Snapshots of what should outlive the node, to
+    /// [`save`](NodeState::save) before it stops.
+    pub fn state(&self) -> NodeState {
+        NodeState {
+            routing: self.routing.snapshot(),
+            datacache: self.datacache.snapshot(),
+            hellos: self.gossip.hellos().snapshot(),
+        }
+    }
+
+    /// Fill the datacache and HELLO cache from a saved state. The routing
+    /// table isn't restored, as none of its peers are connected yet:
+    /// [`restore`](RoutingTable::restore) it into a table of its own to
+    /// find peers to reconnect to.
+    pub fn restore(&mut self, state: &NodeState) {
+        let now = self.clock.timestamp();
+        let _ = self.datacache.restore(&state.datacache, now);
+        let _ = self.gossip.hellos_mut().restore(&state.hellos, now);
+    }
+
+    /// Stop running maintenance and release every hold, eg before exiting.
+    /// Queued messages are still sent by [`tick`](Self::tick), until
+    /// [`is_drained`](Self::is_drained).
+    pub fn shutdown(&mut self) {
+        self.stopped = true;
+        self.holds.release_all(&self.underlay);
+        self.flush();
+    }
+
+    /// Whether every queued message has been sent
+    pub fn is_drained(&self) -> bool {
+        self.outbox.borrow().is_empty()
+    }
+
+    pub fn policy(&self) -> &ForwardingPolicy {
+        &self.policy
+    }
+
+    pub fn policy_mut(&mut self) -> &mut ForwardingPolicy {
+        &mut self.policy
+    }
+
+    pub fn maintenance_mut(&mut self) -> &mut Maintenance {
+        &mut self.maintenance
+    }
+
+    pub fn gossip(&self) -> &Gossip {
+        &self.gossip
+    }
+
+    pub fn gossip_mut(&mut self) -> &mut Gossip {
+        &mut self.gossip
+    }
+
+    pub fn set_gossip_config(&mut self, config: GossipConfig) {
+        self.maintenance.set_interval(Task::Gossip, config.interval);
+        self.gossip.set_config(config);
+    }
+
+    /// The HELLOs of the peers we've heard from
+    pub fn hellos(&self) -> &HelloCache {
+        self.gossip.hellos()
+    }
+
+    /// Advertise this HELLO to neighbours instead of signing our own from
+    /// the underlay's addresses, eg to advertise others. It should be
+    /// signed by the host, and be replaced before it expires.
+    pub fn set_local_hello(&mut self, hello: SignedHello) {
+        self.local_hello.set_automatic(false, self.clock.now());
+        self.gossip.set_local(hello);
+    }
+
+    /// Go back to signing our own HELLO after
+    /// [`set_local_hello`](Self::set_local_hello), starting now.
+    pub fn sign_local_hello(&mut self) {
+        self.local_hello.set_automatic(true, self.clock.now());
+        self.refresh_local_hello();
+    }
+
+    /// Sign a new HELLO if one is due. Until we have an address there's
+    /// nothing worth advertising, but once we've had one an empty HELLO
+    /// says they're all gone.
+    fn refresh_local_hello(&mut self) {
+        let Some(identity) = &self.identity else {
+            self.local_hello.cancel();
+            return;
+        };
+        if self.addresses.is_empty() && self.gossip.local().is_none() {
+            self.local_hello.cancel();
+            return;
+        }
+        let addrs = self.addresses.addresses().iter();
+        let addrs: Vec<String> = addrs.map(ToString::to_string).collect();
+        let hello = self.local_hello.poll(
+            identity,
+            addrs.iter().map(String::as_str),
+            self.clock.now(),
+            self.clock.timestamp(),
+            &mut **self.rng.borrow_mut(),
+        );
+        if let Some(hello) = hello {
+            let omitted = self.local_hello.omitted();
+            if omitted > 0 {
+                tracing::warn!(omitted, "too many addresses to advertise them all");
+                self.metrics.hello_addresses_omitted(omitted);
+            }
+            tracing::debug!(addresses = addrs.len() - omitted, "signed our HELLO");
+            self.gossip.set_local(hello);
+        }
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    /// Register observers of the GETs, PUTs and RESULTs passing through
+    pub fn monitor_mut(&mut self) -> &mut Monitor {
+        &mut self.monitor
+    }
+
+    /// Counters for what the node has done. They can be read through the
+    /// `Arc` while the node runs.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Limit how fast each neighbour can send us messages.
+    pub fn set_rate_limits(&mut self, config: RateLimitConfig) {
+        self.limiter.set_config(config);
+    }
+
+    /// The peers banned for misbehaving, and the offences of the rest
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    /// When peers are banned, and for how long
+    pub fn set_ban_config(&mut self, config: BanConfig) {
+        self.bans.set_config(config);
+    }
+
+    /// Ban a peer for the configured [`duration`](BanConfig::duration),
+    /// as if it had misbehaved.
+    pub fn ban(&mut self, peer: Peer) {
+        self.bans.ban(peer, self.clock.now());
+        self.metrics.peer_banned();
+        self.disconnect(peer, RemovalReason::Banned);
+    }
+
+    /// Lift a ban early. The peer is routed again once it reconnects.
+    pub fn unban(&mut self, peer: &Peer) -> bool {
+        self.bans.unban(peer)
+    }
+
+    /// Lift every ban
+    pub fn clear_bans(&mut self) {
+        self.bans.clear();
+    }
+
+    /// How long, and how many, GETs are remembered to recognise copies
+    pub fn set_get_cache_config(&mut self, config: DedupConfig) {
+        self.gets.set_config(config);
+    }
+
+    /// How long, and how many, PUTs are remembered to recognise copies
+    pub fn set_put_cache_config(&mut self, config: DedupConfig) {
+        self.puts.set_config(config);
+    }
+
+    /// The blocks stored here for other peers
+    pub fn datacache(&self) -> &DataCache {
+        &self.datacache
+    }
+
+    pub fn set_datacache_config(&mut self, config: DataCacheConfig) {
+        self.datacache.set_config(config);
+    }
+
+    pub fn set_republish_config(&mut self, config: RepublishConfig) {
+        self.maintenance
+            .set_interval(Task::Republish, config.interval);
+        self.republisher.set_config(config);
+    }
+
+    /// How often, and how eagerly, sparse buckets are looked for peers
+    pub fn set_refresh_config(&mut self, config: RefreshConfig) {
+        self.maintenance
+            .set_interval(Task::Refresh, config.interval);
+        self.refresh.set_config(config);
+    }
+
+    /// HELLOs of peers that would fill sparse buckets, found since last
+    /// time, eg to add to [`Bootstrap`](crate::bootstrap::Bootstrap). See
+    /// [`refresh`](crate::refresh).
+    pub fn take_discovered(&mut self) -> Vec<SignedHello> {
+        self.refresh.take_discovered()
+    }
+
+    /// The closest peers, which are kept connected. See
+    /// [`neighbours`](crate::neighbours).
+    pub fn neighbours(&self) -> &Neighbours {
+        &self.neighbours
+    }
+
+    pub fn set_neighbour_config(&mut self, config: NeighbourConfig) {
+        self.neighbours.set_config(config);
+        self.update_neighbours();
+    }
+
+    /// Hold peers as they become our closest, and release them as they
+    /// stop being.
+    fn update_neighbours(&mut self) {
+        let changes = self.neighbours.update(&self.routing);
+        for peer in changes.entered {
+            self.holds.hold(&self.underlay, peer);
+        }
+        // peers that disconnected have no holds left to release
+        for peer in changes.left {
+            self.holds.release(&self.underlay, peer);
+        }
+    }
+
+    /// How well each peer has been answering. See
+    /// [`quality`](crate::quality).
+    pub fn peer_quality(&self) -> &PeerQuality {
+        &self.quality
+    }
+
+    pub fn set_quality_config(&mut self, config: QualityConfig) {
+        self.quality.set_config(config);
+        let peers: Vec<Peer> = self.quality.peers().copied().collect();
+        self.reweigh(&peers);
+    }
+
+    /// Weigh peers in the routing table by their quality again.
+    fn reweigh(&mut self, peers: &[Peer]) {
+        for peer in peers {
+            self.routing.set_weight(peer, self.quality.weight(peer));
+        }
+    }
+
+    /// Count GETs sent to `peers`.
+    fn gets_sent(&mut self, peers: &[Peer]) {
+        for &peer in peers {
+            self.quality.get_sent(peer);
+        }
+        self.reweigh(peers);
+    }
+
+    /// How fast stored blocks are offered to newly connected peers that are
+    /// closer to them
+    pub fn set_migration_config(&mut self, config: MigrationConfig) {
+        self.migrations.set_config(config);
+    }
+
+    /// How long, and for how many GETs, results are passed back to the
+    /// peers that sent them
+    pub fn set_relay_config(&mut self, config: RelayConfig) {
+        self.relay.set_config(config);
+    }
+
+    /// Limit how much can wait to be sent, and how fast it's sent.
+    pub fn set_outbound_config(&mut self, config: OutboundConfig) {
+        self.queries.set_mtu(config.mtu);
+        self.outbox.get_mut().set_config(config);
+    }
+
+    pub fn queries(&self) -> &QueryManager {
+        &self.queries
+    }
+
+    pub fn queries_mut(&mut self) -> &mut QueryManager {
+        &mut self.queries
+    }
+
+    /// Start looking for blocks under `key`, and send the query right away.
+    /// Results arrive as [`QueryEvent`]s.
+    pub fn get(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+    ) -> QueryId {
+        let id = self
+            .queries
+            .start(key, block_type, xquery, options, self.clock.now());
+        self.poll_queries();
+        id
+    }
+
+    /// Like [`get`](Self::get), but the query is sent again every
+    /// `interval` until cancelled, and only reports results it hasn't seen.
+    pub fn watch(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        interval: Duration,
+    ) -> QueryId {
+        let now = self.clock.now();
+        let id = self
+            .queries
+            .watch(key, block_type, xquery, options, interval, now);
+        self.poll_queries();
+        id
+    }
+
+    fn poll_queries(&mut self) {
+        let network_size = self.network_size();
+        let now = self.clock.now();
+        let sent = self.queries.poll(
+            now,
+            &self.routing,
+            network_size,
+            &queue(&self.underlay, &self.outbox, now),
+            &mut **self.rng.borrow_mut(),
+        );
+        self.gets_sent(&sent);
+        self.flush();
+        self.update_gauges();
+    }
+
+    /// Send what was queued while processing.
+    fn flush(&mut self) {
+        let sender = sender(&self.underlay, &self.monitor, &self.metrics);
+        let outbox = self.outbox.get_mut();
+        outbox.flush(&sender, self.clock.now());
+        self.metrics
+            .set_outbound_dropped(outbox.dropped(), outbox.mtu_dropped());
+    }
+
+    pub fn next_query_event(&mut self) -> Option<QueryEvent> {
+        self.queries.next_event()
+    }
+
+    /// Call `handler` with every message of a type the node doesn't know,
+    /// eg to handle experimental types. They are dropped otherwise, and
+    /// counted as [`other`](crate::metrics::MessageCounts::other) messages
+    /// either way.
+    pub fn set_unknown_message_handler(
+        &mut self,
+        handler: impl FnMut(Peer, UnknownMessage<'_>) + Send + 'static,
+    ) {
+        self.unknown_messages = Some(Box::new(handler));
+    }
+
+    /// The next change to the routing table, oldest first. Only the last
+    /// 1024 are kept.
+    pub fn next_route_event(&mut self) -> Option<RouteEvent> {
+        self.route_events.pop_front()
+    }
+
+    fn route_event(&mut self, event: RouteEvent) {
+        if self.route_events.len() >= MAX_ROUTE_EVENTS {
+            self.route_events.pop_front();
+        }
+        self.route_events.push_back(event);
+    }
+
+    /// Send a block to the peers that should store it, returning how many
+    /// there were.
+    pub fn put(
+        &mut self,
+        block_type: u32,
+        key: BlockKey,
+        block: &[u8],
+        options: &PutOptions,
+    ) -> Result<usize, PutError> {
+        if block.len() > max_block_size(self.outbox.get_mut().config().mtu) {
+            return Err(PutError::TooLarge);
+        }
+        let signer = match &self.identity {
+            _ if !options.record_route => None,
+            Some(identity) => Some(identity),
+            None => return Err(PutError::NoIdentity),
+        };
+        let expiration = self.clock.timestamp().saturating_add(options.expiration);
+        let mut bloom = PeerBloomFilter::default();
+        bloom.insert_peer_id(self.routing.host());
+        let network_size = self.network_size();
+        let peers: Vec<Peer> = self
+            .routing
+            .get_forwarding_peers(
+                &key,
+                options.replication_level,
+                0,
+                &mut bloom,
+                network_size,
+                &mut **self.rng.borrow_mut(),
+            )
+            .into_iter()
+            .copied()
+            .collect();
+        tracing::debug!(key = %key.short(), block_type, peers = peers.len(), "put");
+        if options.demultiplex || self.routing.is_closest(&key) {
+            let now = self.clock.timestamp();
+            let _ = self
+                .datacache
+                .insert(key, block_type, expiration, block, now);
+        }
+
+        let encode = |signature: Option<&SignatureBytes>| {
+            PutMessage::encode(
+                block_type,
+                options.flags(),
+                options.replication_level,
+                expiration,
+                bloom.clone(),
+                key,
+                signature,
+                block,
+            )
+            .map_err(|_| PutError::TooLarge)
+        };
+        let underlay = queue(&self.underlay, &self.outbox, self.clock.now());
+        match signer {
+            None => {
+                let message = encode(None)?;
+                for &peer in &peers {
+                    let _ = underlay.send(peer, message.clone());
+                }
+            }
+            // the first hop has no predecessor, which GNUnet signs as all
+            // zeros
+            Some(identity) => {
+                let pred = Peer::from_bytes([0; 32]);
+                for &peer in &peers {
+                    let signature = identity.sign_hop(expiration, block, &pred, &peer);
+                    let _ = underlay.send(peer, encode(Some(&signature))?);
+                }
+            }
+        }
+        self.flush();
+        Ok(peers.len())
+    }
+
+    pub fn holds(&self) -> &HoldTracker {
+        &self.holds
+    }
+
+    /// Keep the connection to a peer open, on top of the holds for routed
+    /// peers. Each call needs a matching [`release`](Self::release).
+    pub fn hold(&mut self, peer: Peer) {
+        self.holds.hold(&self.underlay, peer);
+    }
+
+    pub fn release(&mut self, peer: Peer) -> bool {
+        self.holds.release(&self.underlay, peer)
+    }
+
+    /// The addresses the local peer is currently reachable at, in the
+    /// order they are advertised, see [`AddressBook`]
+    pub fn addresses(&self) -> &[U::Address] {
+        self.addresses.addresses()
+    }
+
+    /// Estimate the network size ourselves instead of asking the underlay.
+    /// The estimate is updated on every [`tick`](Self::tick).
+    pub fn set_nse(&mut self, mut nse: Nse) {
+        nse.update(&self.routing);
+        self.nse = Some(nse);
+    }
+
+    /// Ignore our own addresses that aren't valid for these schemes. By
+    /// default every address the underlay reports is advertised.
+    pub fn set_address_schemes(&mut self, schemes: AddressSchemes) {
+        if self.addresses.set_schemes(schemes) {
+            self.addresses_changed();
+        }
+    }
+
+    /// Advertise our addresses, and try those of other peers, in this
+    /// order of scheme, eg QUIC before TCP before UDP. Reachability comes
+    /// first though, so a public UDP address is still before a link-local
+    /// QUIC one.
+    pub fn set_scheme_preference(&mut self, preference: SchemePreference) {
+        self.gossip
+            .hellos_mut()
+            .set_scheme_preference(preference.clone());
+        if self.addresses.set_preference(preference) {
+            self.addresses_changed();
+        }
+    }
+
+    fn addresses_changed(&mut self) {
+        let now = self.clock.now();
+        self.local_hello
+            .addresses_changed(now, &mut **self.rng.borrow_mut());
+    }
+
+    pub fn network_size(&self) -> u64 {
+        match &self.nse {
+            Some(nse) => nse.get(),
+            None => self.underlay.estimate_network_size().into(),
+        }
+    }
+
+    /// Process one event from the underlay.
+    pub fn handle_signal(&mut self, signal: UnderlaySignal<U>) {
+        match signal {
+            UnderlaySignal::PeerConnected(peer, info) => {
+                self.outbox.get_mut().set_mtu(peer, info.mtu);
+                self.quality.connected(peer, info.cost);
+                if self.policy.check_peer(&peer).is_err() {
+                    tracing::debug!(peer = %peer.short(), "not in the private network");
+                } else {
+                    self.connected(peer, info.constrained);
+                }
+            }
+            UnderlaySignal::PeerDisconnected(peer) => {
+                if self.routing.remove(&peer).is_some() {
+                    tracing::debug!(peer = %peer.short(), "unrouted");
+                    self.metrics.peer_removed();
+                    let reason = RemovalReason::Disconnected;
+                    self.route_event(RouteEvent::Removed { peer, reason });
+                }
+                self.holds.forget(&peer);
+                self.limiter.forget(&peer);
+                self.relay.forget(&peer);
+                self.migrations.forget(&peer);
+                self.quality.forget(&peer);
+                self.outbox.get_mut().forget(&peer);
+            }
+            UnderlaySignal::AddressAdded(addr) => {
+                if self.addresses.add(addr) {
+                    self.addresses_changed();
+                }
+            }
+            UnderlaySignal::AddressDeleted(addr) => {
+                if self.addresses.remove(&addr) {
+                    self.addresses_changed();
+                }
+            }
+            UnderlaySignal::Receive(peer, message) => {
+                let now = self.clock.now();
+                if self.policy.check_peer(&peer).is_err() {
+                    tracing::trace!(peer = %peer.short(), "ignoring peer outside the private network");
+                } else if self.bans.is_banned(&peer, now) {
+                    tracing::trace!(peer = %peer.short(), "ignoring banned peer");
+                } else {
+                    match self.limiter.check(peer, &message, now) {
+                        Verdict::Allow => self.receive(peer, &message),
+                        Verdict::Drop => {
+                            self.metrics.rate_limited();
+                            self.offence(peer, Offence::RateLimited);
+                        }
+                        Verdict::Disconnect => {
+                            self.metrics.rate_limited();
+                            if !self.offence(peer, Offence::RateLimited) {
+                                self.cut_off(peer);
+                            }
+                        }
+                    }
+                }
+            }
+            // what was held back for it is flushed below
+            UnderlaySignal::SendReady(_) => {}
+        }
+        self.update_neighbours();
+        self.flush();
+        self.update_gauges();
+    }
+
+    fn connected(&mut self, peer: Peer, constrained: bool) {
+        // constrained peers can still use us, we just don't route through
+        // them
+        let banned = self.bans.is_banned(&peer, self.clock.now());
+        if !constrained && !banned && !self.limiter.is_blocked(&peer) {
+            self.route(peer);
+            self.reweigh(&[peer]);
+            let host = self.routing.host();
+            self.migrations.peer_connected(peer, host, &self.datacache);
+        }
+        self.greet(peer);
+        self.migrate(self.clock.now());
+    }
+
+    fn update_gauges(&self) {
+        self.metrics
+            .update_gauges(&self.routing, self.queries.len());
+    }
+
+    /// Stop routing through a peer that won't stop flooding us, and let the
+    /// underlay close the connection. Its messages are ignored until it
+    /// disconnects.
+    fn cut_off(&mut self, peer: Peer) {
+        tracing::info!(peer = %peer.short(), "rate limit exceeded, disconnecting");
+        self.metrics.rate_limit_disconnect();
+        self.disconnect(peer, RemovalReason::RateLimited);
+    }
+
+    /// Count an offence by `peer`, and cut it off if that gets it banned.
+    /// Returns whether it did.
+    fn offence(&mut self, peer: Peer, offence: Offence) -> bool {
+        if !self.bans.record(peer, offence, self.clock.now()) {
+            return false;
+        }
+        tracing::info!(peer = %peer.short(), ?offence, "banned");
+        self.metrics.peer_banned();
+        self.disconnect(peer, RemovalReason::Banned);
+        true
+    }
+
+    /// Stop routing through `peer`, and let the underlay close the
+    /// connection if we were the ones keeping it open.
+    fn disconnect(&mut self, peer: Peer, reason: RemovalReason) {
+        if self.routing.remove(&peer).is_some() {
+            self.metrics.peer_removed();
+            self.route_event(RouteEvent::Removed { peer, reason });
+        }
+        if self.holds.is_held(&peer) {
+            self.holds.forget(&peer);
+            self.underlay.drop(peer);
+        }
+        self.relay.forget(&peer);
+        self.migrations.forget(&peer);
+        self.quality.forget(&peer);
+        self.outbox.get_mut().forget(&peer);
+    }
+
+    fn route(&mut self, peer: Peer) {
+        match self.routing.insert(peer) {
+            InsertOutcome::Inserted => {
+                tracing::debug!(peer = %peer.short(), "routed");
+                self.metrics.peer_added();
+                self.holds.hold(&self.underlay, peer);
+                self.route_event(RouteEvent::Added(peer));
+            }
+            // the underlay already replaced the connection
+            InsertOutcome::ReplacedExisting(_) => self.route_event(RouteEvent::Replaced(peer)),
+            InsertOutcome::BucketFull { evict } => {
+                if evict != peer {
+                    tracing::debug!(peer = %peer.short(), evict = %evict.short(), "routed");
+                    self.metrics.peer_added();
+                    self.metrics.peer_removed();
+                    self.holds.release(&self.underlay, evict);
+                    self.holds.hold(&self.underlay, peer);
+                    let reason = RemovalReason::Evicted { by: peer };
+                    self.route_event(RouteEvent::Removed {
+                        peer: evict,
+                        reason,
+                    });
+                    self.route_event(RouteEvent::Added(peer));
+                }
+            }
+            InsertOutcome::Rejected(_) => {}
+        }
+    }
+
+    /// Send a new neighbour our HELLO, and the HELLOs of the peers we know
+    /// closest to it.
+    fn greet(&self, peer: Peer) {
+        let underlay = queue(&self.underlay, &self.outbox, self.clock.now());
+        if let Some(message) = self.gossip.local().and_then(|h| h.to_message().ok()) {
+            let _ = underlay.send(peer, message);
+        }
+        let mut bloom = PeerBloomFilter::default();
+        bloom.insert_peer_id(self.routing.host());
+        for hello in self.gossip.for_new_peer(&peer) {
+            if let Ok(put) = hello.to_put(HELLO_REPLICATION_LEVEL, bloom.clone()) {
+                let _ = underlay.send(peer, put);
+            }
+        }
+    }
+
+    fn receive(&mut self, peer: Peer, message: &Message) {
+        let _span = tracing::trace_span!(
+            "receive",
+            peer = %peer.short(),
+            message_type = ?message.header().map(|h| h.message_type()),
+            len = message.as_bytes().len(),
+        )
+        .entered();
+        self.monitor.notify(message, Direction::From(peer));
+        self.metrics.received(message);
+        let now = self.clock.timestamp();
+        let Ok(parsed) = AnyMessage::parse(message.as_bytes()) else {
+            self.offence(peer, Offence::Malformed);
+            return;
+        };
+        let hello = match parsed {
+            AnyMessage::Result(result) => {
+                let delivered = self.queries.handle_result(&result);
+                self.metrics.result(delivered);
+                self.quality.result(peer);
+                self.reweigh(&[peer]);
+                self.relay_result(peer, &result, message);
+                // any HELLO we come across might fill a bucket, not just
+                // those we asked for
+                match result.block_type() {
+                    HelloBlock::BLOCK_TYPE => HelloBlock::parse(result.block())
+                        .ok()
+                        .map(|block| SignedHello::from_block(&block)),
+                    _ => None,
+                }
+            }
+            AnyMessage::Get(get) => {
+                if let Err(reason) = self.policy.check_block_type(get.block_type()) {
+                    tracing::trace!(?reason, "GET dropped by policy");
+                    self.metrics.dropped_by_policy(reason, get.block_type());
+                    return;
+                }
+                if math::exceeds_max_hops(get.hop_count(), self.network_size()) {
+                    tracing::trace!(hop_count = get.hop_count(), "GET went too far");
+                    return;
+                }
+                if !self.gets.insert(&get, self.clock.now()) {
+                    tracing::trace!("duplicate GET");
+                    self.metrics.duplicate_get();
+                    return;
+                }
+                let key = get.query_hash();
+                if get.flags().get_demultiplex() || self.routing.is_closest(key) {
+                    self.answer(peer, &get);
+                }
+                let bloom = get.peer_bloom_filter();
+                let forwarded = self.forward(
+                    peer,
+                    message,
+                    key,
+                    get.replication_level(),
+                    get.hop_count(),
+                    bloom,
+                );
+                self.gets_sent(&forwarded);
+                if !forwarded.is_empty() {
+                    let now = self.clock.now();
+                    self.relay.insert(*key, get.block_type(), peer, now);
+                }
+                None
+            }
+            AnyMessage::Hello(hello) => {
+                let signed = SignedHello::from_message(peer, &hello);
+                if signed.is_none() {
+                    self.metrics.signature_failure();
+                    self.offence(peer, Offence::InvalidSignature);
+                }
+                signed
+            }
+            AnyMessage::Put(put) => {
+                if let Err(reason) = self.policy.check_put(&put) {
+                    tracing::trace!(?reason, "PUT dropped by policy");
+                    self.metrics.dropped_by_policy(reason, put.block_type());
+                    return;
+                }
+                if math::exceeds_max_hops(put.hop_count(), self.network_size()) {
+                    tracing::trace!(hop_count = put.hop_count(), "PUT went too far");
+                    return;
+                }
+                if !self.puts.insert(&put, self.clock.now()) {
+                    tracing::trace!("duplicate PUT");
+                    self.metrics.duplicate_put();
+                    return;
+                }
+                let hello = match put.block_type() {
+                    HelloBlock::BLOCK_TYPE => match HelloBlock::parse(put.block()) {
+                        Ok(block) => Some(SignedHello::from_block(&block)),
+                        Err(DhtError::Parse(_)) => {
+                            self.offence(peer, Offence::Malformed);
+                            return;
+                        }
+                        Err(_) => {
+                            self.metrics.signature_failure();
+                            self.offence(peer, Offence::InvalidSignature);
+                            return;
+                        }
+                    },
+                    _ => None,
+                };
+                let key = put.block_key();
+                if put.flags().get_demultiplex() || self.routing.is_closest(key) {
+                    let (block_type, expiration) = (put.block_type(), put.expiration());
+                    let stored =
+                        self.datacache
+                            .insert(*key, block_type, expiration, put.block(), now);
+                    if stored.is_ok() {
+                        self.metrics.block_stored();
+                    }
+                }
+                let bloom = put.peer_bloom_filter();
+                self.forward(
+                    peer,
+                    message,
+                    key,
+                    put.replication_level(),
+                    put.hop_count(),
+                    bloom,
+                );
+                hello
+            }
+            // counted as other messages, and otherwise up to the
+            // application
+            AnyMessage::Unknown(unknown) => {
+                tracing::trace!("unknown message type");
+                if let Some(handler) = &mut self.unknown_messages {
+                    handler(peer, unknown);
+                }
+                None
+            }
+        };
+        let hello = hello.filter(|h| {
+            h.peer().id() != *self.routing.host() && self.policy.is_peer_allowed(h.peer())
+        });
+        if let Some(hello) = hello {
+            if !hello.is_expired(now) {
+                let network_size = self.network_size();
+                self.refresh.discover(&self.routing, &hello, network_size);
+            }
+            self.gossip.hellos_mut().insert(hello, now);
+        }
+    }
+
+    /// Send `peer` the blocks we have for a GET, except those its result
+    /// filter says it has. Approximate GETs get the blocks under the
+    /// closest keys we have.
+    fn answer(&self, peer: Peer, get: &GetMessage<'_>) {
+        let underlay = queue(&self.underlay, &self.outbox, self.clock.now());
+        let key = get.query_hash();
+        let (blocks, limit): (Vec<&StoredBlock>, _) = match get.flags().get_find_approximate() {
+            true => {
+                let closest = self.datacache.closest(key, get.block_type());
+                let blocks = closest.into_iter().map(|(_, stored)| stored).collect();
+                (blocks, APPROXIMATE_RESULTS)
+            }
+            false => (
+                self.datacache.get(key, get.block_type()).collect(),
+                usize::MAX,
+            ),
+        };
+        let mut answered = 0;
+        let (filtered, wanted): (Vec<_>, Vec<_>) = blocks
+            .into_iter()
+            .partition(|stored| query::is_filtered(get.result_filter(), &stored.block));
+        self.metrics.bloom_rejections(filtered.len());
+        for stored in wanted.into_iter().take(limit) {
+            let (block_type, expiration) = (stored.block_type, stored.expiration);
+            if let Ok(result) = ResultMessage::encode(block_type, expiration, *key, &stored.block) {
+                let _ = underlay.send(peer, result);
+                answered += 1;
+            }
+        }
+        if answered > 0 {
+            self.metrics.get_answered_locally();
+        }
+        tracing::trace!(answered, "answered GET");
+    }
+
+    /// Send a GET or PUT from `from` on towards `key`, returning the peers
+    /// it went to. PUTs with path recording get our hop added to their path.
+    fn forward(
+        &self,
+        from: Peer,
+        message: &Message,
+        key: &BlockKey,
+        replication_level: u16,
+        hop_count: u16,
+        bloom: &PeerBloomFilter,
+    ) -> Vec<Peer> {
+        let network_size = self.network_size();
+        let skipped = self
+            .routing
+            .iter()
+            .filter(|r| bloom.contains_peer(r.peer()));
+        self.metrics.bloom_rejections(skipped.count());
+        let mut bloom = bloom.clone();
+        bloom.insert_peer_id(self.routing.host());
+        let peers = self.routing.get_forwarding_peers(
+            key,
+            replication_level,
+            hop_count,
+            &mut bloom,
+            network_size,
+            &mut **self.rng.borrow_mut(),
+        );
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let Some(mut forwarded) = math::forwarded(message, network_size, &bloom) else {
+            return Vec::new();
+        };
+        let recorded = PutMessage::parse(forwarded.as_bytes())
+            .ok()
+            .filter(|put| put.flags().get_record_route());
+        let signer = match recorded {
+            Some(put) => match &self.identity {
+                Some(identity) => Some((put, identity)),
+                None => {
+                    forwarded = Message::from_bytes(put.without_path());
+                    None
+                }
+            },
+            None => None,
+        };
+        let underlay = queue(&self.underlay, &self.outbox, self.clock.now());
+        for peer in &peers {
+            let message = match signer {
+                Some((put, identity)) => {
+                    let (expiration, block) = (put.expiration(), put.block());
+                    let signature = identity.sign_hop(expiration, block, &from, peer);
+                    Message::from_bytes(put.with_hop(&from, &signature))
+                }
+                None => forwarded.clone(),
+            };
+            let _ = underlay.send(**peer, message);
+        }
+        peers.into_iter().copied().collect()
+    }
+
+    /// Pass a result from `from` back to the peers whose GETs we forwarded.
+    fn relay_result(&mut self, from: Peer, result: &ResultMessage<'_>, message: &Message) {
+        let now = self.clock.now();
+        let peers = self
+            .relay
+            .requesters(result.query_hash(), result.block_type(), now);
+        let underlay = queue(&self.underlay, &self.outbox, now);
+        for peer in peers.into_iter().filter(|&p| p != from) {
+            let _ = underlay.send(peer, message.clone());
+        }
+    }
+
+    /// Offer new peers the blocks they are closer to, as fast as the
+    /// migration rate allows.
+    fn migrate(&mut self, now: Duration) {
+        let due = self.migrations.due(now);
+        if due.is_empty() {
+            return;
+        }
+        let underlay = queue(&self.underlay, &self.outbox, now);
+        let (host, identity) = (self.routing.host(), self.identity.as_ref());
+        for (peer, key) in due {
+            for stored in self.datacache.get(&key, BLOCK_TYPE_ANY) {
+                send_stored(&underlay, identity, host, &[&peer], &key, stored);
+            }
+        }
+    }
+
+    /// Run due maintenance within `budget`, measuring its time limit with
+    /// the node's clock. Every task takes at least a unit of work: garbage
+    /// collection, each gossiped HELLO, updating neighbours, migrating
+    /// blocks and polling queries, as well as the republished blocks and
+    /// refresh lookups.
+    pub fn tick(&mut self, budget: Budget) -> Tick {
+        let now = self.clock.now();
+        if self.stopped {
+            self.flush();
+            let next_due = self.outbox.get_mut().next_due(now);
+            return Tick {
+                ran: 0,
+                exhausted: false,
+                next_due: next_due.unwrap_or(Duration::MAX),
+            };
+        }
+        if let Some(nse) = &mut self.nse {
+            nse.update(&self.routing);
+        }
+        let min = self.routing.config().bucket_size;
+        let bucket_size = math::bucket_size(self.network_size(), min);
+        self.routing.set_bucket_size(bucket_size);
+        self.refresh_local_hello();
+        let timestamp = self.clock.timestamp();
+        let network_size = self.network_size();
+        let underlay = queue(&self.underlay, &self.outbox, now);
+        let (routing, gossip) = (&self.routing, &mut self.gossip);
+        let host = routing.host();
+        let (datacache, republisher) = (&mut self.datacache, &mut self.republisher);
+        let identity = self.identity.as_ref();
+        let rng = &self.rng;
+        let bans = &mut self.bans;
+        let refresh = &mut self.refresh;
+        let mut searched = Vec::new();
+        let mut budget = budget.with_clock(self.clock.clone());
+        let mut tick = self.maintenance.tick(now, &mut budget, |task, budget| {
+            match task {
+                Task::Gc => {
+                    budget.spend();
+                    gossip.hellos_mut().remove_expired(timestamp);
+                    datacache.remove_expired(timestamp);
+                    bans.remove_expired(now);
+                }
+                Task::Republish => {
+                    let peers = republisher.config().peers;
+                    return republisher.run(datacache, budget, |key, stored| {
+                        let targets = routing.closest_peers(key, peers);
+                        send_stored(&underlay, identity, host, &targets, key, stored);
+                    });
+                }
+                Task::Gossip => {
+                    let rng = &mut **rng.borrow_mut();
+                    return gossip.run(routing, budget, rng, |peer, message| {
+                        let _ = underlay.send(peer, message);
+                    });
+                }
+                Task::Refresh => {
+                    let level = refresh.config().replication_level;
+                    let rng = &mut **rng.borrow_mut();
+                    return refresh.run(routing, network_size, budget, rng, |key| {
+                        searched.extend(find_peers(&underlay, routing, &key, level));
+                    });
+                }
+            }
+            TaskStatus::Done
+        });
+
+        self.gets_sent(&searched);
+        let mut starved = true;
+        if budget.spend() {
+            self.update_neighbours();
+            if budget.spend() {
+                self.migrate(now);
+                if budget.spend() {
+                    self.poll_queries();
+                    starved = false;
+                }
+            }
+        }
+        if starved {
+            tick.exhausted = true;
+            tick.next_due = now;
+        }
+        let due = [
+            self.queries.next_due(),
+            self.outbox.get_mut().next_due(now),
+            self.migrations.next_due(now),
+            self.local_hello.next_due(),
+        ];
+        for due in due.into_iter().flatten() {
+            tick.next_due = tick.next_due.min(due);
+        }
+        tick
+    }
+}
+
+impl<U: Underlay> DhtNode<U>
+where
+    U::Address: FromStr,
+{
+    /// Try to connect to a peer at the addresses in its cached HELLOs, in
+    /// order of preference, until the underlay starts an attempt. Returns whether it did.
+    pub fn connect(&self, peer: Peer) -> bool {
+        let addrs = self.gossip.hellos().parse_addresses(&peer.id());
+        addrs
+            .into_iter()
+            .any(|addr| self.underlay.try_connect(peer, addr).is_ok())
+    }
+
+    /// Try to connect to cached peers that would be closer than our
+    /// furthest [neighbour](crate::neighbours), returning how many
+    /// attempts were started. Each peer is only tried once per
+    /// [`retry_interval`](crate::neighbours::NeighbourConfig::retry_interval).
+    pub fn connect_neighbours(&mut self) -> usize {
+        let now = self.clock.now();
+        let candidates = self
+            .neighbours
+            .candidates(&self.routing, self.gossip.hellos(), now);
+        let peers: Vec<Peer> = candidates
+            .into_iter()
+            .map(|hello| *hello.peer())
+            .filter(|peer| self.policy.is_peer_allowed(peer) && !self.bans.is_banned(peer, now))
+            .collect();
+        peers.into_iter().filter(|&peer| self.connect(peer)).count()
+    }
+}
+
+/// Put a stored block straight to `targets`, as its first hop.
+fn send_stored(
+    underlay: &impl Underlay,
+    identity: Option<&LocalPeer>,
+    host: &PeerId,
+    targets: &[&Peer],
+    key: &BlockKey,
+    stored: &StoredBlock,
+) {
+    let mut bloom = PeerBloomFilter::default();
+    bloom.insert_peer_id(host);
+    for peer in targets {
+        bloom.insert_peer(peer);
+    }
+    // we are the first hop, so there's no predecessor
+    let pred = Peer::from_bytes([0; 32]);
+    let (expiration, block) = (stored.expiration, &stored.block[..]);
+    for peer in targets {
+        let signature = identity.map(|id| id.sign_hop(expiration, block, &pred, peer));
+        let message = PutMessage::encode(
+            stored.block_type,
+            Flags::default(),
+            REPUBLISH_REPLICATION_LEVEL,
+            expiration,
+            bloom.clone(),
+            *key,
+            signature.as_ref(),
+            block,
+        );
+        if let Ok(message) = message {
+            let _ = underlay.send(**peer, message);
+        }
+    }
+}
+
+/// Ask the peers closest to `key` for HELLOs near it, to fill the bucket
+/// it's in, returning the peers asked. See [`refresh`](crate::refresh).
+fn find_peers(
+    underlay: &impl Underlay,
+    routing: &RoutingTable,
+    key: &BlockKey,
+    level: u16,
+) -> Vec<Peer> {
+    let targets: Vec<Peer> = routing
+        .closest_peers(key, level.into())
+        .into_iter()
+        .copied()
+        .collect();
+    let mut bloom = PeerBloomFilter::default();
+    bloom.insert_peer_id(routing.host());
+    for peer in &targets {
+        bloom.insert_peer(peer);
+    }
+    let mut flags = Flags::default();
+    flags.set_find_approximate(true).set_demultiplex(true);
+    // no result filter, as any HELLO near the key will do
+    let message = GetMessage::encode(HelloBlock::BLOCK_TYPE, flags, level, bloom, *key, &[], &[]);
+    let Ok(message) = message else {
+        return Vec::new();
+    };
+    for &peer in &targets {
+        let _ = underlay.send(peer, message.clone());
+    }
+    targets
+}
+
+/// Everything the node sends is queued first
+fn queue<'a, U: Underlay>(
+    underlay: &'a U,
+    outbox: &'a RefCell<OutboundQueue>,
+    now: Duration,
+) -> Queued<'a, U> {
+    Queued {
+        underlay,
+        queue: outbox,
+        now,
+    }
+}
+
+/// and then sent through here, so that it's observed and counted.
+fn sender<'a, U: Underlay>(
+    underlay: &'a U,
+    monitor: &'a Monitor,
+    metrics: &'a Metrics,
+) -> Counted<'a, Monitored<'a, U>> {
+    metrics.wrap(monitor.wrap(underlay))
+}
+
+fn maintenance(now: Duration, gossip: &GossipConfig) -> Maintenance {
+    let mut maintenance = Maintenance::new(now, DEFAULT_MAINTENANCE_INTERVAL);
+    maintenance.set_interval(Task::Gossip, gossip.interval);
+    maintenance.set_interval(Task::Refresh, RefreshConfig::default().interval);
+    maintenance.set_interval(Task::Republish, RepublishConfig::default().interval);
+    maintenance
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        advertise::LocalHelloConfig,
+        bans::BanConfig,
+        block::{BlockKey, HelloBlock, HopSignaturePayload, Timestamp},
+        bloom::PeerBloomFilter,
+        config::ConfigUpdate,
+        gossip::SignedHello,
+        identity::LocalPeer,
+        maintenance::Budget,
+        message::{
+            Flags, GetMessage, GetMessageHeader, PutMessage, PutMessageHeader, ResultMessage,
+            ResultMessageHeader,
+        },
+        neighbours::NeighbourConfig,
+        nse::{Nse, NseConfig},
+        quality::QualityConfig,
+        ratelimit::{Rate, RateLimitConfig},
+        refresh::RefreshConfig,
+        republish::{MigrationConfig, RepublishConfig},
+        testing::identities,
+        time::MockClock,
+        underlay::{AddressSchemes, ConnectionInfo, SchemePreference, Underlay, UnderlaySignal},
+        Message, Peer, RemovalReason, RouteEvent, RoutingTable, RoutingTableConfig,
+    };
+
+    use super::{DhtNode, PutOptions};
+
+    #[derive(Default)]
+    struct Recorder {
+        held: RefCell<Vec<Peer>>,
+        dropped: RefCell<Vec<Peer>>,
+        sent: RefCell<Vec<(Peer, Message)>>,
+    }
+
+    impl Underlay for Recorder {
+        type Address = String;
+        type NetworkSizeEstimate = u64;
+        type Error = ();
+
+        fn try_connect(&self, _: Peer, _: String) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn hold(&self, peer: Peer) {
+            self.held.borrow_mut().push(peer);
+        }
+
+        fn drop(&self, peer: Peer) {
+            self.dropped.borrow_mut().push(peer);
+        }
+
+        fn send(&self, peer: Peer, message: Message) -> Result<(), ()> {
+            self.sent.borrow_mut().push((peer, message));
+            Ok(())
+        }
+
+        fn estimate_network_size(&self) -> u64 {
+            1000
+        }
+    }
+
+    #[test]
+    fn signals() {
+        let host = identities::host().peer_id();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock.clone());
+        let bucket_size = RoutingTableConfig::default().bucket_size;
+
+        for f in identities::in_bucket(512) {
+            clock.advance(Duration::from_secs(1));
+            node.handle_signal(UnderlaySignal::PeerConnected(f.peer(), Default::default()));
+        }
+        assert_eq!(node.routing_table().len(), bucket_size);
+        // only the routed peers are held, and nothing was evicted since the
+        // newest connection always loses
+        assert_eq!(node.underlay().held.borrow().len(), bucket_size);
+        assert!(node.underlay().dropped.borrow().is_empty());
+
+        let constrained = identities::in_bucket(511).next().unwrap().peer();
+        let info = ConnectionInfo {
+            constrained: true,
+            ..Default::default()
+        };
+        node.handle_signal(UnderlaySignal::PeerConnected(constrained, info));
+        assert!(!node.routing_table().contains(&constrained));
+
+        let first = identities::in_bucket(512).next().unwrap().peer();
+        node.hold(first);
+        assert!(!node.release(first));
+        // routed, and one of our closest
+        assert_eq!(node.holds().count(&first), 2);
+        node.handle_signal(UnderlaySignal::PeerDisconnected(first));
+        assert!(!node.routing_table().contains(&first));
+        assert!(!node.holds().is_held(&first));
+
+        node.handle_signal(UnderlaySignal::AddressAdded("udp:1".to_owned()));
+        node.handle_signal(UnderlaySignal::AddressAdded("udp:1".to_owned()));
+        assert_eq!(node.addresses(), ["udp:1"]);
+        node.handle_signal(UnderlaySignal::AddressDeleted("udp:1".to_owned()));
+        assert!(node.addresses().is_empty());
+
+        node.set_address_schemes(AddressSchemes::builtin());
+        node.handle_signal(UnderlaySignal::AddressAdded("udp:1".to_owned()));
+        let udp = "ip+udp://127.0.0.1:2086".to_owned();
+        node.handle_signal(UnderlaySignal::AddressAdded(udp.clone()));
+        assert_eq!(node.addresses(), [&udp[..]]);
+        let quic = "quic://127.0.0.1:443".to_owned();
+        node.handle_signal(UnderlaySignal::AddressAdded(quic.clone()));
+        node.set_scheme_preference(SchemePreference::new(["quic"]));
+        assert_eq!(node.addresses(), [quic, udp]);
+
+        assert_eq!(node.network_size(), 1000);
+        node.set_nse(Nse::with_estimator(
+            |_: &RoutingTable| Some(50.0),
+            NseConfig::default(),
+        ));
+        assert_eq!(node.network_size(), 50);
+        clock.advance(Duration::from_secs(120));
+        // everything but republishing, which is hourly
+        assert_eq!(node.tick(Budget::unlimited()).ran, 3);
+
+        let held = node.underlay().held.borrow().len();
+        node.shutdown();
+        assert!(node.holds().is_empty() && node.is_drained());
+        assert_eq!(node.underlay().dropped.borrow().len(), held - 1);
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(node.tick(Budget::unlimited()).ran, 0);
+    }
+
+    #[test]
+    fn route_events() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        for peer in [a, a, b] {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        node.handle_signal(UnderlaySignal::PeerDisconnected(a));
+        node.ban(b);
+
+        let events: Vec<_> = std::iter::from_fn(|| node.next_route_event()).collect();
+        let removed = |peer, reason| RouteEvent::Removed { peer, reason };
+        assert_eq!(
+            events,
+            [
+                RouteEvent::Added(a),
+                RouteEvent::Replaced(a),
+                RouteEvent::Added(b),
+                removed(a, RemovalReason::Disconnected),
+                removed(b, RemovalReason::Banned),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_messages() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler_seen = seen.clone();
+        node.set_unknown_message_handler(move |peer, message| {
+            let seen = (peer, message.message_type(), message.payload().to_vec());
+            handler_seen.lock().unwrap().push(seen);
+        });
+        let peer = identities::peers()[0].peer();
+        node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+
+        let message = Message::from_bytes(vec![0, 6, 0x12, 0x34, 1, 2]);
+        node.handle_signal(UnderlaySignal::Receive(peer, message));
+        assert_eq!(*seen.lock().unwrap(), [(peer, 0x1234, vec![1, 2])]);
+        assert_eq!(node.metrics().snapshot().received.other, 1);
+        // it's not held against the peer
+        assert_eq!(node.bans().offences(&peer), Default::default());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let host = identities::host().peer_id();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock);
+        node.set_rate_limits(RateLimitConfig {
+            other: Some(Rate::new(1.0, 2.0)),
+            disconnect_after: Some(2),
+            ..RateLimitConfig::unlimited()
+        });
+        let flooder = identities::peers()[0].peer();
+        node.handle_signal(UnderlaySignal::PeerConnected(flooder, Default::default()));
+        assert!(node.routing_table().contains(&flooder));
+
+        for _ in 0..4 {
+            let message = Message::from_bytes(vec![0; 4]);
+            node.handle_signal(UnderlaySignal::Receive(flooder, message));
+        }
+        assert!(!node.routing_table().contains(&flooder));
+        assert_eq!(*node.underlay().dropped.borrow(), [flooder]);
+        let stats = node.metrics().snapshot();
+        assert_eq!((stats.rate_limited, stats.rate_limit_disconnects), (2, 1));
+
+        // a new connection starts with a clean slate
+        node.handle_signal(UnderlaySignal::PeerDisconnected(flooder));
+        assert_eq!(node.rate_limiter().dropped(&flooder), 0);
+    }
+
+    #[test]
+    fn bans() {
+        let host = identities::host().peer_id();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock.clone());
+        node.set_ban_config(BanConfig {
+            malformed: Some(2),
+            duration: Duration::from_secs(60),
+            ..BanConfig::disabled()
+        });
+        let peer = identities::peers()[0].peer();
+        node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        assert!(node.routing_table().contains(&peer));
+
+        // a GET header with nothing after it
+        let mut truncated = GetMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            PeerBloomFilter::default(),
+            BlockKey::from([1; 64]),
+            b"",
+            b"",
+        )
+        .unwrap()
+        .into_bytes();
+        truncated.truncate(8);
+        for _ in 0..3 {
+            let message = Message::from_bytes(truncated.clone());
+            node.handle_signal(UnderlaySignal::Receive(peer, message));
+        }
+        assert!(node.bans().is_banned(&peer, node.now()));
+        assert!(!node.routing_table().contains(&peer));
+        assert_eq!(*node.underlay().dropped.borrow(), [peer]);
+        assert_eq!(node.metrics().snapshot().peers_banned, 1);
+        // the third was ignored, not counted
+        assert_eq!(node.metrics().snapshot().received.get, 2);
+
+        // reconnecting doesn't get it routed again
+        node.handle_signal(UnderlaySignal::PeerDisconnected(peer));
+        node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        assert!(!node.routing_table().contains(&peer));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!node.bans().is_banned(&peer, node.now()));
+        node.ban(peer);
+        assert!(node.unban(&peer));
+        node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        assert!(node.routing_table().contains(&peer));
+    }
+
+    #[test]
+    fn local_hello() {
+        let host = identities::host();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host.peer_id(), Recorder::default(), clock.clone());
+        node.set_identity(LocalPeer::new(host.signing_key()));
+        // nothing to advertise yet
+        node.tick(Budget::unlimited());
+        assert!(node.gossip().local().is_none());
+
+        node.handle_signal(UnderlaySignal::AddressAdded("memory://0".to_owned()));
+        node.handle_signal(UnderlaySignal::AddressAdded("memory://1".to_owned()));
+        let due = node.tick(Budget::unlimited()).next_due;
+        assert!(due <= LocalHelloConfig::default().jitter);
+        clock.advance(due);
+        node.tick(Budget::unlimited());
+        let hello = node.gossip().local().unwrap().clone();
+        assert!(hello.addresses().eq(["memory://0", "memory://1"]));
+
+        // replaced before it expires
+        let config = LocalHelloConfig::default();
+        clock.advance(config.lifetime - config.refresh_before);
+        node.tick(Budget::unlimited());
+        let refreshed = node.gossip().local().unwrap();
+        assert!(refreshed.expiration() > hello.expiration());
+
+        // only as many addresses as fit
+        let update = ConfigUpdate {
+            local_hello: Some(LocalHelloConfig {
+                max_addresses: Some(1),
+                ..config
+            }),
+            ..Default::default()
+        };
+        node.update_config(&update).unwrap();
+        node.handle_signal(UnderlaySignal::AddressAdded("memory://2".to_owned()));
+        clock.advance(config.jitter);
+        node.tick(Budget::unlimited());
+        let local = node.gossip().local().unwrap();
+        assert!(local.addresses().eq(["memory://0"]));
+        assert_eq!(node.metrics().snapshot().hello_addresses_omitted, 2);
+
+        // until the application takes over
+        node.set_local_hello(hello.clone());
+        clock.advance(config.lifetime);
+        node.tick(Budget::unlimited());
+        assert_eq!(node.gossip().local(), Some(&hello));
+    }
+
+    #[test]
+    fn budgeted_tick() {
+        let clock = Arc::new(MockClock::default());
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock.clone());
+        clock.advance(Duration::from_secs(120));
+
+        // garbage collection takes the only unit
+        let tick = node.tick(Budget::work(1));
+        assert_eq!(tick.ran, 1);
+        assert!(tick.exhausted);
+        assert_eq!(tick.next_due, node.now());
+
+        // measured on the node's clock, which doesn't move during the tick
+        let tick = node.tick(Budget::time(Duration::from_secs(1)));
+        assert_eq!(tick.ran, 2);
+        assert!(!tick.exhausted);
+        // buckets grew with the estimate of 1000 peers
+        assert_eq!(node.routing_table().bucket_size(), 10);
+    }
+
+    #[test]
+    fn connect() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let peer = &identities::peers()[0];
+        assert!(!node.connect(peer.peer()));
+
+        let hello = SignedHello::sign(&peer.signing_key(), Timestamp::FOREVER, ["memory://0"]);
+        let now = node.clock().timestamp();
+        assert!(node.gossip_mut().hellos_mut().insert(hello, now));
+        assert!(node.connect(peer.peer()));
+    }
+
+    #[test]
+    fn neighbours() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        node.set_neighbour_config(NeighbourConfig {
+            size: 1,
+            ..Default::default()
+        });
+        let far = identities::in_bucket(512).next().unwrap().peer();
+        node.handle_signal(UnderlaySignal::PeerConnected(far, Default::default()));
+        assert_eq!(node.neighbours().peers(), [far]);
+        assert_eq!(node.holds().count(&far), 2);
+
+        // a closer peer we know of is worth connecting to
+        let near = identities::in_bucket(511).next().unwrap();
+        let hello = SignedHello::sign(&near.signing_key(), Timestamp::FOREVER, ["memory://0"]);
+        let now = node.clock().timestamp();
+        node.gossip_mut().hellos_mut().insert(hello, now);
+        assert_eq!(node.connect_neighbours(), 1);
+        assert_eq!(node.connect_neighbours(), 0);
+
+        // and takes over once it connects
+        node.handle_signal(UnderlaySignal::PeerConnected(
+            near.peer(),
+            Default::default(),
+        ));
+        assert_eq!(node.neighbours().peers(), [near.peer()]);
+        assert_eq!(node.holds().count(&far), 1);
+        node.handle_signal(UnderlaySignal::PeerDisconnected(near.peer()));
+        assert_eq!(node.neighbours().peers(), [far]);
+        assert_eq!(node.holds().count(&far), 2);
+    }
+
+    #[test]
+    fn private_network() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let [member, stranger] = [0, 1].map(|i| identities::peers()[i].peer());
+        node.policy_mut().allow_only([member]);
+        for peer in [member, stranger] {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        assert!(node.routing_table().contains(&member));
+        assert!(!node.routing_table().contains(&stranger));
+
+        let key = BlockKey::from([1; 64]);
+        node.put(13, key, b"block", &PutOptions::default()).unwrap();
+        node.underlay().sent.take();
+        let get = GetMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            PeerBloomFilter::default(),
+            key,
+            b"",
+            b"",
+        )
+        .unwrap();
+        node.handle_signal(UnderlaySignal::Receive(stranger, get.clone()));
+        assert!(node.underlay().sent.borrow().is_empty());
+        assert_eq!(node.metrics().snapshot().received.get, 0);
+        node.handle_signal(UnderlaySignal::Receive(member, get));
+        assert_eq!(node.metrics().snapshot().received.get, 1);
+    }
+
+    #[test]
+    fn duplicates() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let get = GetMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            PeerBloomFilter::default(),
+            BlockKey::from([1; 64]),
+            b"",
+            b"",
+        )
+        .unwrap();
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            5,
+            Timestamp::FOREVER,
+            PeerBloomFilter::default(),
+            BlockKey::from([1; 64]),
+            None,
+            b"block",
+        )
+        .unwrap();
+        // the second copies arrive along another path
+        for f in &identities::peers()[..2] {
+            node.handle_signal(UnderlaySignal::Receive(f.peer(), get.clone()));
+            node.handle_signal(UnderlaySignal::Receive(f.peer(), put.clone()));
+        }
+        let stats = node.metrics().snapshot();
+        assert_eq!((stats.duplicate_gets, stats.duplicate_puts), (1, 1));
+    }
+
+    #[test]
+    fn disabled_block_types() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        node.policy_mut().disable_block_type(13);
+        let key = BlockKey::from([1; 64]);
+        let get =
+            GetMessage::encode(13, Flags::default(), 5, Default::default(), key, b"", b"").unwrap();
+        let put = PutMessage::encode(
+            13,
+            Flags::default(),
+            // past the hop limit too, but the policy comes first
+            u16::MAX,
+            Timestamp::FOREVER,
+            PeerBloomFilter::default(),
+            key,
+            None,
+            b"block",
+        )
+        .unwrap();
+        for f in &identities::peers()[..2] {
+            node.handle_signal(UnderlaySignal::Receive(f.peer(), get.clone()));
+            node.handle_signal(UnderlaySignal::Receive(f.peer(), put.clone()));
+        }
+        let stats = node.metrics().snapshot();
+        assert_eq!(stats.dropped_by_policy[&("disabled_block_type", 13)], 4);
+        assert_eq!((stats.duplicate_gets, stats.duplicate_puts), (0, 0));
+        assert!(node.datacache().is_empty());
+    }
+
+    #[test]
+    fn demultiplex() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let peers: Vec<Peer> = identities::peers()[..4].iter().map(|f| f.peer()).collect();
+        for &peer in &peers {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        // a neighbour is closer to the key than we are
+        let key = BlockKey::from(*identities::peers()[0].peer_id().as_bytes());
+        let mut everywhere = Flags::default();
+        everywhere.set_demultiplex(true);
+        let put = |flags, block: &[u8]| {
+            let bloom = PeerBloomFilter::default();
+            PutMessage::encode(13, flags, 5, Timestamp::FOREVER, bloom, key, None, block).unwrap()
+        };
+        let get = |flags, result_filter: &[u8]| {
+            let bloom = PeerBloomFilter::default();
+            GetMessage::encode(13, flags, 5, bloom, key, result_filter, b"").unwrap()
+        };
+        let sent = |underlay: &Recorder, message_type| {
+            let sent = underlay.sent.take();
+            sent.into_iter()
+                .filter(|(_, m)| m.header().unwrap().message_type() == message_type)
+                .collect::<Vec<_>>()
+        };
+
+        let closest_only = put(Flags::default(), b"closest only");
+        node.handle_signal(UnderlaySignal::Receive(peers[1], closest_only));
+        node.handle_signal(UnderlaySignal::Receive(
+            peers[1],
+            put(everywhere, b"everywhere"),
+        ));
+        let stored: Vec<_> = node
+            .datacache()
+            .get(&key, 13)
+            .map(|b| &b.block[..])
+            .collect();
+        assert_eq!(stored, [b"everywhere"]);
+        // both went on regardless
+        let forwarded = sent(node.underlay(), PutMessageHeader::MESSAGE_TYPE);
+        assert!(forwarded.len() >= 2);
+        for (_, m) in &forwarded {
+            assert_eq!(PutMessage::parse(m.as_bytes()).unwrap().hop_count(), 1);
+        }
+
+        node.handle_signal(UnderlaySignal::Receive(peers[2], get(everywhere, b"")));
+        node.handle_signal(UnderlaySignal::Receive(
+            peers[3],
+            get(Flags::default(), &[0; 8]),
+        ));
+        let results = sent(node.underlay(), ResultMessageHeader::MESSAGE_TYPE);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, peers[2]);
+        let result = ResultMessage::parse(results[0].1.as_bytes()).unwrap();
+        assert_eq!(result.block(), b"everywhere");
+
+        // results from further along are passed back to whoever asked
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"found").unwrap();
+        node.handle_signal(UnderlaySignal::Receive(peers[0], result));
+        let relayed = sent(node.underlay(), ResultMessageHeader::MESSAGE_TYPE);
+        let to: Vec<Peer> = relayed.iter().map(|(p, _)| *p).collect();
+        assert_eq!(to.len(), 2);
+        assert!(to.contains(&peers[2]) && to.contains(&peers[3]));
+    }
+
+    #[test]
+    fn store_counters() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        let peers: Vec<Peer> = identities::peers()[..3].iter().map(|f| f.peer()).collect();
+        for &peer in &peers {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        let key = BlockKey::from([1; 64]);
+        let mut everywhere = Flags::default();
+        everywhere.set_demultiplex(true);
+        let bloom = PeerBloomFilter::default();
+        let put = PutMessage::encode(
+            13,
+            everywhere,
+            5,
+            Timestamp::FOREVER,
+            bloom,
+            key,
+            None,
+            b"block",
+        );
+        node.handle_signal(UnderlaySignal::Receive(peers[0], put.unwrap()));
+
+        let get = |result_filter: &[u8], bloom| {
+            GetMessage::encode(13, everywhere, 5, bloom, key, result_filter, b"").unwrap()
+        };
+        node.handle_signal(UnderlaySignal::Receive(
+            peers[0],
+            get(b"", Default::default()),
+        ));
+        // the requester already has every block, and has been to two of
+        // our peers
+        let mut bloom = PeerBloomFilter::default();
+        bloom.insert_peer(&peers[1]);
+        bloom.insert_peer(&peers[2]);
+        node.handle_signal(UnderlaySignal::Receive(peers[0], get(&[0xff; 8], bloom)));
+
+        let stats = node.metrics().snapshot();
+        assert_eq!(stats.blocks_stored, 1);
+        assert_eq!(stats.gets_answered_locally, 1);
+        assert_eq!(stats.bloom_rejections, 3);
+    }
+
+    #[test]
+    fn quality() {
+        let host = identities::host().peer_id();
+        let mut node = DhtNode::new(host, Recorder::default());
+        node.set_rng(StdRng::seed_from_u64(0));
+        let [asker, good, bad, costly] = [0, 1, 2, 3].map(|i| identities::peers()[i].peer());
+        for peer in [asker, good, bad] {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        let info = ConnectionInfo {
+            cost: 3,
+            ..Default::default()
+        };
+        node.handle_signal(UnderlaySignal::PeerConnected(costly, info));
+        let weight = |node: &DhtNode<Recorder>, peer: Peer| {
+            let routes = node.routing_table().iter();
+            routes
+                .filter(|r| *r.peer() == peer)
+                .map(|r| r.weight())
+                .next()
+        };
+        assert_eq!(weight(&node, costly), Some(0.25));
+
+        // GETs forwarded to both, but only one answers
+        for i in 0..64 {
+            let key = BlockKey::from([i; 64]);
+            let bloom = PeerBloomFilter::default();
+            let get = GetMessage::encode(13, Flags::default(), 2, bloom, key, b"", b"").unwrap();
+            node.handle_signal(UnderlaySignal::Receive(asker, get));
+            let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"found").unwrap();
+            node.handle_signal(UnderlaySignal::Receive(good, result));
+        }
+        assert_eq!(weight(&node, good), Some(1.0));
+        let bad_weight = weight(&node, bad).unwrap();
+        assert!(bad_weight < 0.5, "{bad_weight}");
+        // as of the last GET to it
+        let now = node.peer_quality().weight(&bad);
+        assert!((now - bad_weight).abs() < 0.01, "{now}");
+
+        // without the cost counted against it
+        let update = ConfigUpdate {
+            quality: Some(QualityConfig {
+                cost: false,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        node.update_config(&update).unwrap();
+        assert!(weight(&node, costly).unwrap() > 0.25);
+    }
+
+    #[test]
+    fn republish() {
+        let host = identities::host();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host.peer_id(), Recorder::default(), clock.clone());
+        node.set_identity(LocalPeer::new(host.signing_key()));
+        node.set_republish_config(RepublishConfig {
+            interval: Duration::from_secs(60),
+            peers: 2,
+        });
+        let key = BlockKey::from([1; 64]);
+        let options = PutOptions {
+            demultiplex: true,
+            ..Default::default()
+        };
+        node.put(13, key, b"block", &options).unwrap();
+        assert_eq!(node.datacache().len(), 1);
+
+        // peers that joined since are sent the block
+        let peers: Vec<Peer> = identities::peers()[..3].iter().map(|f| f.peer()).collect();
+        for &peer in &peers {
+            node.handle_signal(UnderlaySignal::PeerConnected(peer, Default::default()));
+        }
+        node.underlay().sent.take();
+        clock.advance(Duration::from_secs(60));
+        node.tick(Budget::unlimited());
+        let sent = node.underlay().sent.take();
+        assert_eq!(sent.len(), 2);
+        let closest = node.routing_table().closest_peers(&key, 2);
+        for (peer, message) in &sent {
+            assert!(closest.contains(&peer));
+            let put = PutMessage::parse(message.as_bytes()).unwrap();
+            assert_eq!((put.block_key(), put.block()), (&key, &b"block"[..]));
+            assert!(put.flags().get_record_route() && put.last_hop_signature().is_some());
+        }
+    }
+
+    #[test]
+    fn record_route() {
+        let [a, b, c] = [0, 1, 2].map(|i| &identities::peers()[i]);
+        let mut sender = DhtNode::new(a.peer_id(), Recorder::default());
+        sender.set_identity(LocalPeer::new(a.signing_key()));
+        sender.handle_signal(UnderlaySignal::PeerConnected(b.peer(), Default::default()));
+        let key = BlockKey::from([1; 64]);
+        let options = PutOptions {
+            record_route: true,
+            ..Default::default()
+        };
+        sender.put(13, key, b"block", &options).unwrap();
+        let (_, put) = sender.underlay().sent.take().pop().unwrap();
+
+        let forward = |identity: Option<LocalPeer>| {
+            let mut node = DhtNode::new(b.peer_id(), Recorder::default());
+            if let Some(identity) = identity {
+                node.set_identity(identity);
+            }
+            node.handle_signal(UnderlaySignal::PeerConnected(c.peer(), Default::default()));
+            node.handle_signal(UnderlaySignal::Receive(a.peer(), put.clone()));
+            let (to, message) = node.underlay().sent.take().pop().unwrap();
+            assert_eq!(to, c.peer());
+            message
+        };
+
+        let message = forward(Some(LocalPeer::new(b.signing_key())));
+        let forwarded = PutMessage::parse(message.as_bytes()).unwrap();
+        let (expiration, block) = (forwarded.expiration(), forwarded.block());
+        let (pred, signature) = forwarded.put_path().split_at(32);
+        assert_eq!(pred, a.peer().as_bytes());
+        // the first hop signed with no predecessor
+        let origin = Peer::from_bytes([0; 32]);
+        let first = HopSignaturePayload::new(expiration, block, &origin, &b.peer());
+        assert!(first.verify(&a.peer(), signature.try_into().unwrap()));
+        let last = HopSignaturePayload::new(expiration, block, &a.peer(), &c.peer());
+        assert!(last.verify(&b.peer(), forwarded.last_hop_signature().unwrap()));
+
+        // without an identity, the path is left off rather than left wrong
+        let message = forward(None);
+        let forwarded = PutMessage::parse(message.as_bytes()).unwrap();
+        assert!(!forwarded.flags().get_record_route());
+        assert!(forwarded.put_path().is_empty() && forwarded.last_hop_signature().is_none());
+        assert_eq!(forwarded.block(), b"block");
+    }
+
+    #[test]
+    fn refresh() {
+        let host = identities::host().peer_id();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock.clone());
+        let first = identities::in_bucket(512).next().unwrap().peer();
+        node.handle_signal(UnderlaySignal::PeerConnected(first, Default::default()));
+
+        // the network is big enough for the furthest buckets to have peers
+        clock.advance(RefreshConfig::default().interval);
+        node.tick(Budget::unlimited());
+        let gets = node.underlay().sent.take();
+        assert!(!gets.is_empty());
+        for (peer, message) in &gets {
+            assert_eq!(*peer, first);
+            assert_eq!(
+                message.header().unwrap().message_type(),
+                GetMessageHeader::MESSAGE_TYPE
+            );
+            let get = GetMessage::parse(message.as_bytes()).unwrap();
+            assert_eq!(get.block_type(), HelloBlock::BLOCK_TYPE);
+            assert!(get.flags().get_find_approximate() && get.flags().get_demultiplex());
+        }
+
+        // and HELLOs that come back are for the application to connect to
+        let found = identities::in_bucket(511).next().unwrap();
+        let hello = SignedHello::sign(&found.signing_key(), Timestamp::FOREVER, ["memory://1"]);
+        let block = hello.to_block();
+        let key = BlockKey(found.peer_id().0);
+        let result =
+            ResultMessage::encode(HelloBlock::BLOCK_TYPE, Timestamp::FOREVER, key, &block).unwrap();
+        node.handle_signal(UnderlaySignal::Receive(first, result));
+        assert_eq!(node.take_discovered(), [hello]);
+        assert!(node.hellos().get(&found.peer_id()).is_some());
+    }
+
+    #[test]
+    fn migration() {
+        let host = identities::host().peer_id();
+        let clock = Arc::new(MockClock::default());
+        let mut node = DhtNode::with_clock(host, Recorder::default(), clock.clone());
+        node.set_migration_config(MigrationConfig {
+            rate: Rate::new(1.0, 1.0),
+            ..Default::default()
+        });
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let options = PutOptions {
+            demultiplex: true,
+            ..Default::default()
+        };
+        // stored under the new peers' own ids, so they are closer than us
+        for peer in [a, a, b] {
+            let key = BlockKey::from(*peer.id().as_bytes());
+            let block = [node.datacache().len() as u8];
+            node.put(13, key, &block, &options).unwrap();
+        }
+        node.underlay().sent.take();
+        let puts = |underlay: &Recorder| {
+            let sent = underlay.sent.take();
+            sent.into_iter()
+                .filter(|(_, m)| {
+                    m.header().unwrap().message_type() == PutMessageHeader::MESSAGE_TYPE
+                })
+                .map(|(p, _)| p)
+                .collect::<Vec<_>>()
+        };
+
+        node.handle_signal(UnderlaySignal::PeerConnected(a, Default::default()));
+        // both of its blocks are under one key
+        assert_eq!(puts(node.underlay()), [a, a]);
+        node.handle_signal(UnderlaySignal::PeerConnected(b, Default::default()));
+        assert!(puts(node.underlay()).is_empty());
+
+        let tick = node.tick(Budget::unlimited());
+        assert_eq!(tick.next_due, Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        node.tick(Budget::unlimited());
+        assert_eq!(puts(node.underlay()), [b]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Network size estimation.
+//!
+//! R5N needs an estimate of the number of peers to decide how far and how
+//! wide to forward. Underlays may provide one, otherwise the node can derive
+//! one with an [`Nse`], by default from how full the routing table is.
+
+use crate::{BucketIndex, RoutingTable};
+
+/// A source of network size samples.
+pub trait Estimator {
+    /// A fresh estimate, or `None` if there isn't enough information yet.
+    fn estimate(&mut self, table: &RoutingTable) -> Option<f64>;
+}
+
+/// Estimates the network size from the bucket occupancy of the routing
+/// table.
+///
+/// Bucket `d` covers a `2^(d - 513)` fraction of the key space, so in a
+/// network of `n` peers it should hold about `n * 2^(d - 513)` of them. Full
+/// buckets only give a lower bound and are skipped, and the rest are
+/// combined as `sum(len) / sum(2^(d - 513))`.
+///
+/// This assumes we are connected to every peer we could route to. A poorly
+/// connected peer will underestimate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BucketDensity;
+
+impl Estimator for BucketDensity {
+    fn estimate(&mut self, table: &RoutingTable) -> Option<f64> {
+        let bucket_size = table.bucket_size();
+        let occupancy = table.occupancy();
+        let closest = occupancy.buckets().next()?.0;
+
+        let mut peers = 0;
+        let mut coverage = 0.0;
+        for bucket in BucketIndex::all().skip(closest.index()) {
+            let len = occupancy.bucket_len(bucket);
+            if len >= bucket_size {
+                continue;
+            }
+            peers += len;
+            coverage += 2f64.powi(i32::from(bucket.get()) - 513);
+        }
+        (peers > 0).then(|| peers as f64 / coverage)
+    }
+}
+
+impl<F: FnMut(&RoutingTable) -> Option<f64>> Estimator for F {
+    fn estimate(&mut self, table: &RoutingTable) -> Option<f64> {
+        self(table)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct NseConfig {
+    /// How far a new sample moves the estimate, between 0 (never) and 1
+    /// (replaces it).
+    pub smoothing: f64,
+    /// Used until the estimator has produced a sample
+    pub fallback: u64,
+}
+
+impl Default for NseConfig {
+    fn default() -> Self {
+        Self {
+            smoothing: 0.25,
+            fallback: 1000,
+        }
+    }
+}
+
+/// A smoothed network size estimate.
+pub struct Nse {
+    estimator: Box<dyn Estimator + Send>,
+    config: NseConfig,
+    current: Option<f64>,
+}
+
+impl Nse {
+    pub fn new(config: NseConfig) -> Self {
+        Self::with_estimator(BucketDensity, config)
+    }
+
+    pub fn with_estimator(estimator: impl Estimator + Send + 'static, config: NseConfig) -> Self {
+        Self {
+            estimator: Box::new(estimator),
+            config,
+            current: None,
+        }
+    }
+
+    pub fn config(&self) -> &NseConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: NseConfig) {
+        self.config = config;
+    }
+
+    /// Take a new sample, and return the updated estimate.
+    pub fn update(&mut self, table: &RoutingTable) -> u64 {
+        if let Some(sample) = self.estimator.estimate(table) {
+            let alpha = self.config.smoothing.clamp(0.0, 1.0);
+            self.current = Some(match self.current {
+                Some(current) => current + alpha * (sample - current),
+                None => sample,
+            });
+        }
+        self.get()
+    }
+
+    /// The current estimate. Never less than 1.
+    pub fn get(&self) -> u64 {
+        match self.current {
+            Some(n) => (n.round() as u64).max(1),
+            None => self.config.fallback.max(1),
+        }
+    }
+}
+
+impl Default for Nse {
+    fn default() -> Self {
+        Self::new(NseConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing::identities, InsertOutcome, RoutingTable, RoutingTableConfig};
+
+    use super::{Nse, NseConfig};
+
+    #[test]
+    fn bucket_density() {
+        let config = RoutingTableConfig { bucket_size: 16 };
+        let mut table = RoutingTable::new(identities::host().peer_id(), config);
+        let mut nse = Nse::new(NseConfig::default());
+        assert_eq!(nse.update(&table), 1000);
+
+        // what a network of 32 peers looks like: 16 in the far half, 8 in
+        // the next quarter and so on
+        for (bucket, n) in [(512, 16), (511, 8), (510, 4), (509, 2), (508, 1)] {
+            for f in identities::in_bucket(bucket).take(n) {
+                assert_eq!(table.insert(f.peer()), InsertOutcome::Inserted);
+            }
+        }
+        assert_eq!(nse.update(&table), 32);
+    }
+
+    #[test]
+    fn smoothing() {
+        let table = RoutingTable::new(identities::host().peer_id(), Default::default());
+        let mut samples = [100.0, 200.0].into_iter();
+        let mut nse = Nse::with_estimator(
+            move |_: &RoutingTable| samples.next(),
+            NseConfig {
+                smoothing: 0.5,
+                fallback: 1,
+            },
+        );
+        assert_eq!(nse.get(), 1);
+        assert_eq!(nse.update(&table), 100);
+        // a sample only moves the estimate part of the way
+        assert_eq!(nse.update(&table), 150);
+        assert_eq!(nse.update(&table), 150);
+    }
+}
@@ -0,0 +1,606 @@
+//! Shaping what the node sends.
+//!
+//! Messages are queued per peer and sent in order of priority: results
+//! first, as someone is waiting for them, then GETs, PUTs and gossip. Queues
+//! are limited in bytes, per peer and overall, and when they are full the
+//! lowest priority messages are dropped first. Sending can also be limited
+//! to a number of bytes per second, and waits for connections the underlay
+//! says aren't [ready](Underlay::poll_ready), so their messages queue here,
+//! in order of priority, rather than overrunning the underlay's queues.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{
+    message::{AnyMessage, GetMessageHeader, PutMessageHeader, ResultMessageHeader},
+    underlay::Underlay,
+    Message, Peer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// HELLOs and anything else
+    Gossip,
+    Put,
+    Get,
+    Result,
+}
+
+impl Priority {
+    const ALL: [Priority; 4] = [
+        Priority::Result,
+        Priority::Get,
+        Priority::Put,
+        Priority::Gossip,
+    ];
+
+    pub fn of(message: &Message) -> Self {
+        match message.header().map(|h| h.message_type()) {
+            Some(ResultMessageHeader::MESSAGE_TYPE) => Priority::Result,
+            Some(GetMessageHeader::MESSAGE_TYPE) => Priority::Get,
+            Some(PutMessageHeader::MESSAGE_TYPE) => Priority::Put,
+            _ => Priority::Gossip,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct OutboundConfig {
+    /// How many bytes can wait for each peer
+    pub peer_queue_bytes: usize,
+    /// How many bytes can wait in total
+    pub queue_bytes: usize,
+    /// How fast each peer can be sent to, unlimited if `None`
+    pub peer_bytes_per_second: Option<u64>,
+    /// How fast we can send in total, unlimited if `None`
+    pub bytes_per_second: Option<u64>,
+    /// The largest message we send. Connections with a smaller
+    /// [`mtu`](crate::underlay::ConnectionInfo::mtu) get smaller ones.
+    /// PUTs and results that are too large have their paths truncated to
+    /// fit, and anything else is dropped.
+    pub mtu: u16,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            peer_queue_bytes: 256 * 1024,
+            queue_bytes: 4 * 1024 * 1024,
+            peer_bytes_per_second: None,
+            bytes_per_second: None,
+            mtu: u16::MAX,
+        }
+    }
+}
+
+/// `message` with its path truncated to fit in `mtu` bytes, if it's a PUT
+/// or result that can
+fn truncate_path(message: &Message, mtu: usize) -> Option<Message> {
+    let bytes = match AnyMessage::parse(message.as_bytes()).ok()? {
+        AnyMessage::Put(put) => put.truncated_to(mtu)?,
+        AnyMessage::Result(result) => result.truncated_to(mtu)?,
+        _ => return None,
+    };
+    Some(Message::from_bytes(bytes))
+}
+
+/// Bytes that can be sent. It can hold a second's worth, but always at
+/// least the largest message, so that nothing is stuck forever.
+#[derive(Debug, Clone, Copy)]
+struct Allowance {
+    bytes: f64,
+    last: Duration,
+}
+
+impl Allowance {
+    fn new(now: Duration) -> Self {
+        Self {
+            bytes: f64::INFINITY,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Option<u64>, now: Duration) {
+        let Some(rate) = rate else {
+            self.bytes = f64::INFINITY;
+            return;
+        };
+        let rate = rate as f64;
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        let max = rate.max(u16::MAX as f64);
+        self.bytes = (self.bytes + elapsed * rate).min(max);
+        self.last = now;
+    }
+
+    /// How long until `len` bytes can be sent
+    fn wait(&self, rate: Option<u64>, len: usize) -> Duration {
+        match rate {
+            Some(rate) if self.bytes < len as f64 => {
+                Duration::from_secs_f64((len as f64 - self.bytes) / rate.max(1) as f64)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerQueue {
+    // indexed by priority
+    queues: [VecDeque<Message>; 4],
+    bytes: usize,
+    allowance: Allowance,
+    /// the connection wasn't ready at the last flush
+    blocked: bool,
+}
+
+impl PeerQueue {
+    fn lowest(&self) -> Option<Priority> {
+        Priority::ALL
+            .into_iter()
+            .rev()
+            .find(|&p| !self.queues[p as usize].is_empty())
+    }
+
+    fn drop_newest(&mut self, priority: Priority) -> usize {
+        let len = self.queues[priority as usize]
+            .pop_back()
+            .map_or(0, |m| m.as_bytes().len());
+        self.bytes -= len;
+        len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
+
+/// Messages waiting to be sent. See the [module docs](self).
+#[derive(Debug)]
+pub struct OutboundQueue {
+    config: OutboundConfig,
+    /// ordered, so peers are served in the same order every flush
+    peers: BTreeMap<Peer, PeerQueue>,
+    /// of connections with a lower MTU than the config's
+    mtus: BTreeMap<Peer, u16>,
+    bytes: usize,
+    allowance: Allowance,
+    dropped: u64,
+    mtu_dropped: u64,
+}
+
+impl Default for OutboundQueue {
+    fn default() -> Self {
+        Self::new(OutboundConfig::default())
+    }
+}
+
+impl OutboundQueue {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            config,
+            peers: BTreeMap::new(),
+            mtus: BTreeMap::new(),
+            bytes: 0,
+            allowance: Allowance::new(Duration::ZERO),
+            dropped: 0,
+            mtu_dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> &OutboundConfig {
+        &self.config
+    }
+
+    /// Queued messages are kept, even if they are now over budget.
+    pub fn set_config(&mut self, config: OutboundConfig) {
+        self.config = config;
+    }
+
+    /// Set the MTU of the connection to `peer`, as the underlay reported it
+    /// in [`ConnectionInfo`](crate::underlay::ConnectionInfo).
+    pub fn set_mtu(&mut self, peer: Peer, mtu: Option<u16>) {
+        match mtu {
+            Some(mtu) => self.mtus.insert(peer, mtu),
+            None => self.mtus.remove(&peer),
+        };
+    }
+
+    /// The largest message that will be sent to `peer`
+    pub fn mtu(&self, peer: &Peer) -> u16 {
+        let mtu = self.mtus.get(peer).copied().unwrap_or(u16::MAX);
+        mtu.min(self.config.mtu)
+    }
+
+    /// Queue a message for `peer`, making room by dropping lower priority
+    /// messages if needed. Returns whether it was queued. PUTs and results
+    /// larger than the peer's [`mtu`](Self::mtu) have their oldest path
+    /// elements dropped until they fit, and other messages that don't fit
+    /// never are.
+    pub fn push(&mut self, peer: Peer, mut message: Message, now: Duration) -> bool {
+        let mtu = self.mtu(&peer) as usize;
+        if message.as_bytes().len() > mtu {
+            match truncate_path(&message, mtu) {
+                Some(truncated) => message = truncated,
+                None => {
+                    self.mtu_dropped += 1;
+                    return false;
+                }
+            }
+        }
+        let len = message.as_bytes().len();
+        let priority = Priority::of(&message);
+        if len > self.config.peer_queue_bytes || len > self.config.queue_bytes {
+            self.dropped += 1;
+            return false;
+        }
+
+        let queue = self.peers.entry(peer).or_insert_with(|| PeerQueue {
+            queues: Default::default(),
+            bytes: 0,
+            allowance: Allowance::new(now),
+            blocked: false,
+        });
+        while queue.bytes + len > self.config.peer_queue_bytes {
+            match queue.lowest() {
+                Some(lowest) if lowest < priority => {
+                    self.bytes -= queue.drop_newest(lowest);
+                    self.dropped += 1;
+                }
+                _ => {
+                    self.dropped += 1;
+                    return false;
+                }
+            }
+        }
+        while self.bytes + len > self.config.queue_bytes {
+            let lowest = self
+                .peers
+                .iter()
+                .filter_map(|(p, q)| Some((q.lowest()?, *p)))
+                .min();
+            match lowest {
+                Some((lowest, victim)) if lowest < priority => {
+                    let victim = self.peers.get_mut(&victim).expect("found above");
+                    self.bytes -= victim.drop_newest(lowest);
+                    self.dropped += 1;
+                }
+                _ => {
+                    self.dropped += 1;
+                    return false;
+                }
+            }
+        }
+
+        let queue = self.peers.get_mut(&peer).expect("inserted above");
+        queue.bytes += len;
+        queue.queues[priority as usize].push_back(message);
+        self.bytes += len;
+        true
+    }
+
+    /// Send as much as the rate limits and the connections'
+    /// [readiness](Underlay::poll_ready) allow, highest priority first.
+    /// Returns how many messages were sent.
+    pub fn flush<U: Underlay>(&mut self, underlay: &U, now: Duration) -> usize {
+        let OutboundConfig {
+            peer_bytes_per_second: peer_rate,
+            bytes_per_second: rate,
+            ..
+        } = self.config;
+        self.allowance.refill(rate, now);
+        for queue in self.peers.values_mut() {
+            queue.allowance.refill(peer_rate, now);
+            queue.blocked = false;
+        }
+
+        let mut sent = 0;
+        for priority in Priority::ALL {
+            for (&peer, queue) in &mut self.peers {
+                let messages = &mut queue.queues[priority as usize];
+                while let Some(message) = messages.front() {
+                    let len = message.as_bytes().len();
+                    if queue.allowance.bytes < len as f64 || self.allowance.bytes < len as f64 {
+                        break;
+                    }
+                    if queue.blocked || underlay.poll_ready(peer).is_pending() {
+                        queue.blocked = true;
+                        break;
+                    }
+                    queue.allowance.bytes -= len as f64;
+                    self.allowance.bytes -= len as f64;
+                    queue.bytes -= len;
+                    self.bytes -= len;
+                    let message = messages.pop_front().expect("peeked");
+                    let _ = underlay.send(peer, message);
+                    sent += 1;
+                }
+            }
+        }
+        self.peers.retain(|_, q| !q.is_empty());
+        sent
+    }
+
+    /// When [`flush`](Self::flush) can next send something, if anything is
+    /// waiting. Peers that weren't ready wait for
+    /// [`SendReady`](crate::underlay::UnderlaySignal::SendReady) instead.
+    pub fn next_due(&self, now: Duration) -> Option<Duration> {
+        let OutboundConfig {
+            peer_bytes_per_second: peer_rate,
+            bytes_per_second: rate,
+            ..
+        } = self.config;
+        self.peers
+            .values()
+            .filter(|q| !q.blocked)
+            .filter_map(|q| {
+                let next = Priority::ALL
+                    .into_iter()
+                    .find_map(|p| q.queues[p as usize].front())?;
+                let len = next.as_bytes().len();
+                let wait = q
+                    .allowance
+                    .wait(peer_rate, len)
+                    .max(self.allowance.wait(rate, len));
+                Some(now + wait)
+            })
+            .min()
+    }
+
+    /// Bytes waiting to be sent
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.values().all(PeerQueue::is_empty)
+    }
+
+    /// How many messages were dropped for lack of room
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// How many messages were dropped for being larger than the MTU, even
+    /// with their paths truncated
+    pub fn mtu_dropped(&self) -> u64 {
+        self.mtu_dropped
+    }
+
+    /// Throw away everything queued for a peer, eg because it disconnected.
+    pub fn forget(&mut self, peer: &Peer) {
+        self.mtus.remove(peer);
+        if let Some(queue) = self.peers.remove(peer) {
+            self.bytes -= queue.bytes;
+        }
+    }
+}
+
+/// An underlay that queues sends in an [`OutboundQueue`] rather than sending
+/// them.
+pub(crate) struct Queued<'a, U> {
+    pub(crate) underlay: &'a U,
+    pub(crate) queue: &'a RefCell<OutboundQueue>,
+    pub(crate) now: Duration,
+}
+
+impl<U: Underlay> Underlay for Queued<'_, U> {
+    type Address = U::Address;
+    type NetworkSizeEstimate = U::NetworkSizeEstimate;
+    type Error = U::Error;
+
+    fn try_connect(&self, peer: Peer, addr: Self::Address) -> Result<(), Self::Error> {
+        self.underlay.try_connect(peer, addr)
+    }
+
+    fn hold(&self, peer: Peer) {
+        self.underlay.hold(peer)
+    }
+
+    fn drop(&self, peer: Peer) {
+        self.underlay.drop(peer)
+    }
+
+    /// Dropped messages aren't errors, as the underlay is allowed to drop
+    /// them anyway.
+    fn send(&self, peer: Peer, message: Message) -> Result<(), Self::Error> {
+        self.queue.borrow_mut().push(peer, message, self.now);
+        Ok(())
+    }
+
+    fn estimate_network_size(&self) -> Self::NetworkSizeEstimate {
+        self.underlay.estimate_network_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        task::Poll,
+        time::Duration,
+    };
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        limits::PATH_ELEMENT_SIZE,
+        message::{max_block_size, PutMessage, ResultMessage},
+        testing::identities,
+        underlay::{memory::MemoryNetwork, Underlay, UnderlaySignal},
+        Message, Peer,
+    };
+
+    use super::{OutboundConfig, OutboundQueue, Priority};
+
+    #[test]
+    fn priorities() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [identities::peers()[0].peer(), identities::peers()[1].peer()];
+        let (ua, _rxa) = network.join(a);
+        let (ub, rxb) = network.join(b);
+        ua.try_connect(b, ub.address()).unwrap();
+        let _ = rxb.try_iter().count();
+
+        let gossip = Message::from_bytes(vec![0; 100]);
+        let result =
+            ResultMessage::encode(13, Timestamp::FOREVER, BlockKey::from([0; 64]), &[0; 20])
+                .unwrap();
+        let len = result.as_bytes().len();
+        assert_eq!(Priority::of(&result), Priority::Result);
+        assert_eq!(Priority::of(&gossip), Priority::Gossip);
+
+        let mut queue = OutboundQueue::new(OutboundConfig {
+            peer_queue_bytes: 250,
+            bytes_per_second: Some(100),
+            ..Default::default()
+        });
+        let now = Duration::ZERO;
+        assert!(queue.push(b, gossip.clone(), now));
+        assert!(queue.push(b, gossip.clone(), now));
+        // gossip can't push out gossip, but results can
+        assert!(!queue.push(b, gossip.clone(), now));
+        assert!(queue.push(b, result.clone(), now));
+        assert_eq!(queue.bytes(), 100 + len);
+        assert_eq!(queue.dropped(), 2);
+
+        // results go first
+        assert_eq!(queue.flush(&ua, now), 2);
+        let sent: Vec<usize> = rxb
+            .try_iter()
+            .filter_map(|signal| match signal {
+                UnderlaySignal::Receive(_, m) => Some(m.as_bytes().len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, [len, 100]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_due(now), None);
+
+        // once the allowance is spent, it has to build up again
+        queue.allowance.bytes = 0.0;
+        assert!(queue.push(b, gossip, now));
+        assert_eq!(queue.flush(&ua, now), 0);
+        assert_eq!(queue.next_due(now), Some(Duration::from_secs(1)));
+        assert_eq!(queue.flush(&ua, Duration::from_secs(1)), 1);
+    }
+
+    /// Takes `room` messages before it needs to be flushed
+    #[derive(Default)]
+    struct Bounded {
+        room: Cell<usize>,
+        sent: RefCell<Vec<Message>>,
+    }
+
+    impl Underlay for Bounded {
+        type Address = String;
+        type NetworkSizeEstimate = u64;
+        type Error = ();
+
+        fn try_connect(&self, _: Peer, _: String) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn hold(&self, _: Peer) {}
+
+        fn drop(&self, _: Peer) {}
+
+        fn send(&self, _: Peer, message: Message) -> Result<(), ()> {
+            let room = self.room.get().checked_sub(1).ok_or(())?;
+            self.room.set(room);
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn poll_ready(&self, _: Peer) -> Poll<()> {
+            match self.room.get() {
+                0 => Poll::Pending,
+                _ => Poll::Ready(()),
+            }
+        }
+
+        fn estimate_network_size(&self) -> u64 {
+            1000
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let b = identities::peers()[1].peer();
+        let underlay = Bounded::default();
+        underlay.room.set(1);
+        let mut queue = OutboundQueue::default();
+        let now = Duration::ZERO;
+        let gossip = Message::from_bytes(vec![0; 100]);
+        let result =
+            ResultMessage::encode(13, Timestamp::FOREVER, BlockKey::from([0; 64]), &[0; 20])
+                .unwrap();
+        for message in [&gossip, &gossip, &result] {
+            assert!(queue.push(b, message.clone(), now));
+        }
+
+        // only what the underlay has room for is sent, and the rest waits
+        // for it to be ready rather than being dropped
+        assert_eq!(queue.flush(&underlay, now), 1);
+        assert_eq!(underlay.sent.take(), [result]);
+        assert_eq!(queue.bytes(), 200);
+        assert_eq!(queue.next_due(now), None);
+        assert_eq!(queue.dropped(), 0);
+
+        underlay.room.set(8);
+        assert_eq!(queue.flush(&underlay, now), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn mtu() {
+        let [b, c] = [identities::peers()[1].peer(), identities::peers()[2].peer()];
+        let mut queue = OutboundQueue::new(OutboundConfig {
+            mtu: 1000,
+            ..Default::default()
+        });
+        queue.set_mtu(b, Some(200));
+        assert_eq!((queue.mtu(&b), queue.mtu(&c)), (200, 1000));
+
+        let now = Duration::ZERO;
+        assert!(!queue.push(b, Message::from_bytes(vec![0; 300]), now));
+        assert!(queue.push(c, Message::from_bytes(vec![0; 300]), now));
+        assert!(!queue.push(c, Message::from_bytes(vec![0; 1001]), now));
+        queue.forget(&b);
+        assert_eq!(queue.mtu(&b), 1000);
+
+        // the largest block still fits with a signature and truncated origin
+        let block = vec![0; max_block_size(600)];
+        let signature = [0; 64];
+        let put = PutMessage::encode(
+            13,
+            Default::default(),
+            5,
+            Timestamp::FOREVER,
+            Default::default(),
+            BlockKey::from([0; 64]),
+            Some(&signature),
+            &block,
+        )
+        .unwrap();
+        assert_eq!(put.as_bytes().len(), 600 - 32);
+        assert_eq!((queue.mtu_dropped(), queue.dropped()), (2, 0));
+
+        // as a path element is swapped for the origin when it's too large
+        let pred = identities::peers()[0].peer();
+        let forwarded = PutMessage::parse(put.as_bytes())
+            .unwrap()
+            .with_hop(&pred, &signature);
+        assert_eq!(forwarded.len(), 600 - 32 + PATH_ELEMENT_SIZE);
+        queue.set_mtu(b, Some(600));
+        assert!(queue.push(b, Message::from_bytes(forwarded), now));
+        assert_eq!(queue.bytes(), 300 + 600);
+        assert_eq!(queue.mtu_dropped(), 2);
+    }
+}
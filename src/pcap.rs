@@ -0,0 +1,544 @@
+//! Reading messages out of packet captures, eg to analyze traces of a real
+//! network offline.
+//!
+//! [`PcapReader`] reads classic libpcap files (not pcapng) of UDP and TCP
+//! traffic over IPv4 or IPv6, and splits it into messages following the
+//! [`udp`](crate::underlay::udp) and [`tcp`](crate::underlay::tcp)
+//! underlays' framing. TCP streams are reassembled, but only those whose
+//! start was captured, since the framing can't be picked up mid-stream. IP
+//! fragments are skipped.
+//!
+//! ```no_run
+//! # use std::{fs::File, io::BufReader};
+//! # use r6n::pcap::{PcapError, PcapReader};
+//! # fn main() -> Result<(), PcapError> {
+//! let file = File::open("trace.pcap").map_err(PcapError::Io)?;
+//! for captured in PcapReader::new(BufReader::new(file))?.port(2086) {
+//!     let captured = captured?;
+//!     println!("{} -> {}: {:?}", captured.src, captured.dst, captured.message);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use zerocopy::FromBytes;
+
+use crate::{message::MessageHeader, Message, Peer};
+
+// as in the udp underlay
+const FRAME_MESSAGE: u8 = 1;
+const FRAME_HEADER: usize = 33;
+
+/// Out of order TCP segments kept per stream while waiting for a gap to be
+/// filled. Past this, the stream is given up on.
+const MAX_PENDING_SEGMENTS: usize = 256;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// A message found in a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captured {
+    /// When the packet finishing the message was captured, since the UNIX
+    /// epoch
+    pub time: Duration,
+    pub transport: Transport,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// The peer that sent the message, as it claimed
+    pub sender: Peer,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Reads [`Captured`] messages from a pcap file, in the order they were
+/// completed.
+pub struct PcapReader<R> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+    ports: Vec<u16>,
+    streams: HashMap<(SocketAddr, SocketAddr), Stream>,
+    ready: VecDeque<Captured>,
+}
+
+impl<R: io::Read> PcapReader<R> {
+    /// Read the file header. Fails if it isn't a pcap file, or its link
+    /// type isn't one we know.
+    pub fn new(mut reader: R) -> Result<Self, PcapError> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header).map_err(PcapError::Io)?;
+        let magic: [u8; 4] = header[..4].try_into().unwrap();
+        let (big_endian, nanos) = match magic {
+            [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+            [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+            _ => return Err(PcapError::NotPcap),
+        };
+        let mut reader = Self {
+            reader,
+            big_endian,
+            nanos,
+            link_type: 0,
+            ports: vec![],
+            streams: HashMap::new(),
+            ready: VecDeque::new(),
+        };
+        // the upper bits can hold the FCS length
+        reader.link_type = reader.u32(&header[20..]) & 0x0fff_ffff;
+        match reader.link_type {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL
+            | LINKTYPE_LINUX_SLL2 => Ok(reader),
+            link_type => Err(PcapError::LinkType(link_type)),
+        }
+    }
+
+    /// Only read traffic to or from this port. Can be called more than
+    /// once to read several ports. All traffic is read by default.
+    pub fn port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = b[..4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    /// The next packet's time and data, or `None` at the end of the file
+    fn packet(&mut self) -> Result<Option<(Duration, Vec<u8>, bool)>, PcapError> {
+        let mut header = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(PcapError::Io(e)),
+        }
+        let secs = self.u32(&header[..4]);
+        let fraction = self.u32(&header[4..8]);
+        let captured = self.u32(&header[8..12]);
+        let original = self.u32(&header[12..]);
+        let fraction = match self.nanos {
+            true => Duration::from_nanos(fraction.into()),
+            false => Duration::from_micros(fraction.into()),
+        };
+        let time = Duration::from_secs(secs.into()) + fraction;
+
+        let mut data = vec![0; captured as usize];
+        self.reader.read_exact(&mut data).map_err(PcapError::Io)?;
+        Ok(Some((time, data, captured < original)))
+    }
+
+    fn handle_packet(&mut self, time: Duration, data: &[u8], truncated: bool) {
+        let Some(ip) = link_payload(self.link_type, data) else {
+            return;
+        };
+        let Some((src, dst, protocol, payload)) = ip_payload(ip) else {
+            return;
+        };
+        match protocol {
+            IPPROTO_UDP => {
+                let Some(udp) = payload.get(..8) else { return };
+                let src = SocketAddr::new(src, u16::from_be_bytes([udp[0], udp[1]]));
+                let dst = SocketAddr::new(dst, u16::from_be_bytes([udp[2], udp[3]]));
+                if truncated || !self.wanted(&src, &dst) {
+                    return;
+                }
+                if let Some((sender, message)) = datagram(&payload[8..]) {
+                    self.ready.push_back(Captured {
+                        time,
+                        transport: Transport::Udp,
+                        src,
+                        dst,
+                        sender,
+                        message,
+                    });
+                }
+            }
+            IPPROTO_TCP => {
+                let Some(tcp) = payload.get(..20) else { return };
+                let src = SocketAddr::new(src, u16::from_be_bytes([tcp[0], tcp[1]]));
+                let dst = SocketAddr::new(dst, u16::from_be_bytes([tcp[2], tcp[3]]));
+                if !self.wanted(&src, &dst) {
+                    return;
+                }
+                let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+                let offset = (tcp[12] >> 4) as usize * 4;
+                let flags = tcp[13];
+                let (syn, fin, rst) = (flags & 0x02 != 0, flags & 0x01 != 0, flags & 0x04 != 0);
+                if syn {
+                    self.streams
+                        .insert((src, dst), Stream::new(seq.wrapping_add(1)));
+                }
+                let Some(stream) = self.streams.get_mut(&(src, dst)) else {
+                    return;
+                };
+                // a segment cut short by the snap length leaves a gap that
+                // is never filled, so the stream is eventually given up on
+                let segment = match payload.get(offset..) {
+                    Some(segment) if !truncated => segment,
+                    _ => &[],
+                };
+                let messages = stream.push(seq, segment);
+                let Some(messages) = messages else {
+                    self.streams.remove(&(src, dst));
+                    return;
+                };
+                for (sender, message) in messages {
+                    self.ready.push_back(Captured {
+                        time,
+                        transport: Transport::Tcp,
+                        src,
+                        dst,
+                        sender,
+                        message,
+                    });
+                }
+                if fin || rst {
+                    self.streams.remove(&(src, dst));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn wanted(&self, src: &SocketAddr, dst: &SocketAddr) -> bool {
+        self.ports.is_empty()
+            || self.ports.contains(&src.port())
+            || self.ports.contains(&dst.port())
+    }
+}
+
+impl<R: io::Read> Iterator for PcapReader<R> {
+    type Item = Result<Captured, PcapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(captured) = self.ready.pop_front() {
+                return Some(Ok(captured));
+            }
+            match self.packet() {
+                Ok(Some((time, data, truncated))) => self.handle_packet(time, &data, truncated),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The IP packet in a link layer frame
+fn link_payload(link_type: u32, data: &[u8]) -> Option<&[u8]> {
+    let (ethertype, payload) = match link_type {
+        // the address family is in the capturing host's byte order, so the
+        // IP version is checked instead
+        LINKTYPE_NULL => return data.get(4..),
+        LINKTYPE_RAW => return Some(data),
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*data.get(12)?, *data.get(13)?]);
+            let mut payload = data.get(14..)?;
+            if ethertype == ETHERTYPE_VLAN {
+                ethertype = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+                payload = payload.get(4..)?;
+            }
+            (ethertype, payload)
+        }
+        LINKTYPE_LINUX_SLL => {
+            let ethertype = u16::from_be_bytes([*data.get(14)?, *data.get(15)?]);
+            (ethertype, data.get(16..)?)
+        }
+        LINKTYPE_LINUX_SLL2 => {
+            let ethertype = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+            (ethertype, data.get(20..)?)
+        }
+        _ => return None,
+    };
+    matches!(ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6).then_some(payload)
+}
+
+/// The source, destination, protocol and payload of an IP packet, unless
+/// it's a fragment
+fn ip_payload(ip: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+            let fragment = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]);
+            // more fragments, or a fragment offset
+            if fragment & 0x3fff != 0 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            // the length trims any padding, eg to Ethernet's minimum
+            let payload = ip.get(header_len..total_len.min(ip.len()))?;
+            let (src, dst) = (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into());
+            Some((src, dst, ip[9], payload))
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]) as usize;
+            let mut next = *ip.get(6)?;
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            let mut payload = ip.get(40..(40 + payload_len).min(ip.len()))?;
+            // hop-by-hop, routing and destination options
+            while matches!(next, 0 | 43 | 60) {
+                let len = (*payload.get(1)? as usize + 1) * 8;
+                next = payload[0];
+                payload = payload.get(len..)?;
+            }
+            let (src, dst) = (Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into());
+            Some((src, dst, next, payload))
+        }
+        _ => None,
+    }
+}
+
+/// The message in a datagram framed as by the udp underlay
+fn datagram(b: &[u8]) -> Option<(Peer, Message)> {
+    let (frame, message) = b.split_at_checked(FRAME_HEADER)?;
+    if frame[32] != FRAME_MESSAGE {
+        return None;
+    }
+    let header = MessageHeader::ref_from_prefix(message)?;
+    if header.message_size() as usize != message.len() {
+        return None;
+    }
+    let sender = Peer::from_bytes(frame[..32].try_into().unwrap());
+    Some((sender, Message::from_bytes(message.to_vec())))
+}
+
+/// One direction of a TCP connection
+struct Stream {
+    next_seq: u32,
+    data: Vec<u8>,
+    /// segments past a gap, by sequence number
+    pending: BTreeMap<u32, Vec<u8>>,
+    sender: Option<Peer>,
+}
+
+impl Stream {
+    fn new(next_seq: u32) -> Self {
+        Self {
+            next_seq,
+            data: vec![],
+            pending: BTreeMap::new(),
+            sender: None,
+        }
+    }
+
+    /// Add a segment, returning the messages it completes. `None` if the
+    /// stream doesn't look like the tcp underlay's framing, or can't be
+    /// reassembled.
+    fn push(&mut self, seq: u32, segment: &[u8]) -> Option<Vec<(Peer, Message)>> {
+        if !segment.is_empty() {
+            self.pending.insert(seq, segment.to_vec());
+        }
+        // sequence numbers wrap, so they are compared relative to the next
+        while let Some((&seq, _)) = self
+            .pending
+            .iter()
+            .find(|(&seq, _)| (seq.wrapping_sub(self.next_seq) as i32) <= 0)
+        {
+            let segment = self.pending.remove(&seq).unwrap();
+            // skip what we already have, eg from a retransmit
+            let seen = self.next_seq.wrapping_sub(seq) as usize;
+            if let Some(new) = segment.get(seen..) {
+                self.data.extend_from_slice(new);
+                self.next_seq = self.next_seq.wrapping_add(new.len() as u32);
+            }
+        }
+        if self.pending.len() > MAX_PENDING_SEGMENTS {
+            return None;
+        }
+
+        if self.sender.is_none() {
+            let Some(key) = self.data.get(..32) else {
+                return Some(vec![]);
+            };
+            self.sender = Some(Peer::from_bytes(key.try_into().unwrap()));
+            self.data.drain(..32);
+        }
+        let sender = self.sender?;
+        let mut messages = vec![];
+        while let Some(header) = MessageHeader::ref_from_prefix(&self.data) {
+            let size = header.message_size() as usize;
+            if size < size_of::<MessageHeader>() {
+                return None;
+            }
+            if self.data.len() < size {
+                break;
+            }
+            let message = self.data.drain(..size).collect();
+            messages.push((sender, Message::from_bytes(message)));
+        }
+        Some(messages)
+    }
+}
+
+#[derive(Debug)]
+pub enum PcapError {
+    Io(io::Error),
+    /// The file isn't a classic pcap file
+    NotPcap,
+    /// Packets are captured with a link layer we don't know
+    LinkType(u32),
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "reading capture: {e}"),
+            Self::NotPcap => f.write_str("not a pcap file"),
+            Self::LinkType(t) => write!(f, "unsupported link type {t}"),
+        }
+    }
+}
+
+impl std::error::Error for PcapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        message::ResultMessage,
+        testing::identities,
+        Message,
+    };
+
+    use super::{PcapError, PcapReader, Transport};
+
+    fn pcap(packets: &[Vec<u8>]) -> Vec<u8> {
+        // little endian, microseconds, raw IP
+        let mut file = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&101u32.to_le_bytes());
+        for (i, packet) in packets.iter().enumerate() {
+            file.extend_from_slice(&(i as u32).to_le_bytes());
+            file.extend_from_slice(&0u32.to_le_bytes());
+            file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            file.extend_from_slice(packet);
+        }
+        file
+    }
+
+    fn ipv4(protocol: u8, src: u16, dst: u16, transport: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, protocol, 0, 0];
+        ip.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 2]);
+        ip[2..4].copy_from_slice(&(20 + 4 + transport.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&src.to_be_bytes());
+        ip.extend_from_slice(&dst.to_be_bytes());
+        ip.extend_from_slice(transport);
+        ip
+    }
+
+    fn udp(src: u16, dst: u16, payload: &[u8]) -> Vec<u8> {
+        let mut udp = ((8 + payload.len()) as u16).to_be_bytes().to_vec();
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(payload);
+        ipv4(17, src, dst, &udp)
+    }
+
+    fn tcp(src: u16, dst: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = seq.to_be_bytes().to_vec();
+        tcp.extend_from_slice(&[0, 0, 0, 0, 5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+        ipv4(6, src, dst, &tcp)
+    }
+
+    fn message(data: &[u8]) -> Message {
+        ResultMessage::encode(13, Timestamp::FOREVER, BlockKey::from([1; 64]), data).unwrap()
+    }
+
+    #[test]
+    fn udp_and_tcp() {
+        let peer = identities::peers()[0].peer();
+        let mut datagram = peer.as_bytes().to_vec();
+        datagram.push(1);
+        datagram.extend_from_slice(message(b"udp").as_bytes());
+        let mut probe = peer.as_bytes().to_vec();
+        probe.push(0);
+
+        let mut stream = peer.as_bytes().to_vec();
+        stream.extend_from_slice(message(b"one").as_bytes());
+        stream.extend_from_slice(message(b"two").as_bytes());
+        let (start, rest) = stream.split_at(100);
+        let seq = u32::MAX - 10;
+        let packets = [
+            udp(1, 2086, &probe),
+            udp(1, 2086, &datagram),
+            // no SYN was seen for this stream
+            tcp(3, 2086, 0, 0x18, &stream),
+            tcp(4, 2086, seq, 0x02, &[]),
+            // out of order, across a sequence number wrap
+            tcp(4, 2086, seq.wrapping_add(101), 0x18, rest),
+            tcp(4, 2086, seq.wrapping_add(1), 0x18, start),
+            tcp(4, 2086, seq.wrapping_add(1), 0x18, start),
+            udp(1, 9999, &datagram),
+        ];
+        let file = pcap(&packets);
+        let reader = PcapReader::new(&file[..]).unwrap().port(2086);
+        let captured: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(captured.len(), 3);
+
+        assert_eq!(captured[0].transport, Transport::Udp);
+        assert_eq!(captured[0].message, message(b"udp"));
+        assert_eq!(
+            captured[0].src,
+            "127.0.0.1:1".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(captured[0].sender, peer);
+        assert_eq!(captured[0].time, Duration::from_secs(1));
+
+        assert_eq!(captured[1].transport, Transport::Tcp);
+        assert_eq!(captured[1].message, message(b"one"));
+        assert_eq!(captured[2].message, message(b"two"));
+        assert_eq!(
+            captured[2].dst,
+            "127.0.0.2:2086".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(captured[2].time, Duration::from_secs(5));
+
+        assert!(matches!(
+            PcapReader::new(&[0; 24][..]),
+            Err(PcapError::NotPcap)
+        ));
+    }
+}
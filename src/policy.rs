@@ -0,0 +1,156 @@
+use std::collections::BTreeSet;
+
+use crate::{message::PutMessage, Peer};
+
+/// Operator controlled restrictions on which blocks this node is willing to
+/// store or forward, and which peers it deals with at all.
+#[derive(Default, Clone)]
+pub struct ForwardingPolicy {
+    disabled_block_types: BTreeSet<u32>,
+    /// set for a private network, where no one else is talked to
+    allowed_peers: Option<BTreeSet<Peer>>,
+}
+
+/// Why a message was dropped before being processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The block type was disabled by [`ForwardingPolicy::disable_block_type`].
+    DisabledBlockType(u32),
+    /// The peer isn't in the private network, see
+    /// [`ForwardingPolicy::allow_only`].
+    UnknownPeer(Peer),
+}
+
+impl DropReason {
+    /// A name for the reason, as used in [`Stats`](crate::metrics::Stats)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DisabledBlockType(_) => "disabled_block_type",
+            Self::UnknownPeer(_) => "unknown_peer",
+        }
+    }
+}
+
+impl ForwardingPolicy {
+    /// Refuse to store or forward blocks of this type.
+    pub fn disable_block_type(&mut self, block_type: u32) -> &mut Self {
+        self.disabled_block_types.insert(block_type);
+        self
+    }
+
+    pub fn enable_block_type(&mut self, block_type: u32) -> &mut Self {
+        self.disabled_block_types.remove(&block_type);
+        self
+    }
+
+    pub fn is_block_type_disabled(&self, block_type: u32) -> bool {
+        self.disabled_block_types.contains(&block_type)
+    }
+
+    pub fn disabled_block_types(&self) -> impl Iterator<Item = u32> + '_ {
+        self.disabled_block_types.iter().copied()
+    }
+
+    /// Check whether a message for this block type may be processed at all.
+    /// This should be done before any other work on a PUT or GET.
+    pub fn check_block_type(&self, block_type: u32) -> Result<(), DropReason> {
+        if self.is_block_type_disabled(block_type) {
+            return Err(DropReason::DisabledBlockType(block_type));
+        }
+        Ok(())
+    }
+
+    /// Make the network private: peers not allowed here aren't routed to,
+    /// their messages are ignored and their HELLOs aren't passed on. Peers
+    /// already routed stay until they disconnect.
+    pub fn allow_only(&mut self, peers: impl IntoIterator<Item = Peer>) -> &mut Self {
+        self.allowed_peers = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Let a peer into the private network. Does nothing if the network
+    /// isn't private.
+    pub fn allow_peer(&mut self, peer: Peer) -> &mut Self {
+        if let Some(allowed) = &mut self.allowed_peers {
+            allowed.insert(peer);
+        }
+        self
+    }
+
+    /// Leave the private network, and deal with everyone again
+    pub fn allow_all(&mut self) -> &mut Self {
+        self.allowed_peers = None;
+        self
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.allowed_peers.is_some()
+    }
+
+    pub fn is_peer_allowed(&self, peer: &Peer) -> bool {
+        self.allowed_peers
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(peer))
+    }
+
+    /// The peers of the private network, if it is one
+    pub fn allowed_peers(&self) -> Option<impl Iterator<Item = Peer> + '_> {
+        Some(self.allowed_peers.as_ref()?.iter().copied())
+    }
+
+    /// Check whether a peer may be dealt with at all. This should be done
+    /// before anything else it sends is looked at.
+    pub fn check_peer(&self, peer: &Peer) -> Result<(), DropReason> {
+        if !self.is_peer_allowed(peer) {
+            return Err(DropReason::UnknownPeer(*peer));
+        }
+        Ok(())
+    }
+
+    pub fn check_put(&self, put: &PutMessage<'_>) -> Result<(), DropReason> {
+        self.check_block_type(put.block_type())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::identities;
+
+    use super::{DropReason, ForwardingPolicy};
+
+    #[test]
+    fn disabled_block_types() {
+        let mut policy = ForwardingPolicy::default();
+        assert_eq!(policy.check_block_type(13), Ok(()));
+
+        policy.disable_block_type(13).disable_block_type(42);
+        assert_eq!(
+            policy.check_block_type(13),
+            Err(DropReason::DisabledBlockType(13))
+        );
+        assert_eq!(policy.check_block_type(7), Ok(()));
+        assert_eq!(policy.disabled_block_types().collect::<Vec<_>>(), [13, 42]);
+
+        policy.enable_block_type(13);
+        assert_eq!(policy.check_block_type(13), Ok(()));
+    }
+
+    #[test]
+    fn private() {
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let mut policy = ForwardingPolicy::default();
+        assert!(policy.is_peer_allowed(&a) && policy.allowed_peers().is_none());
+
+        // nothing to add to while the network is public
+        policy.allow_peer(b);
+        policy.allow_only([a]);
+        assert!(policy.is_private());
+        assert_eq!(policy.check_peer(&a), Ok(()));
+        assert_eq!(policy.check_peer(&b), Err(DropReason::UnknownPeer(b)));
+
+        policy.allow_peer(b);
+        assert_eq!(policy.allowed_peers().unwrap().count(), 2);
+        policy.allow_all();
+        assert!(!policy.is_private());
+    }
+}
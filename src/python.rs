@@ -0,0 +1,454 @@
+//! Python bindings, for scripting experiments.
+//!
+//! The `r6n` Python module has identities and peers, PUT and GET messages to
+//! build and take apart, bloom filters, and the [simulator](crate::sim).
+//! Keys and peers are `bytes`, times are microseconds since the UNIX epoch.
+//! Build it with [maturin](https://www.maturin.rs):
+//!
+//! ```text
+//! maturin develop --features python,pyo3/extension-module
+//! ```
+//!
+//! ```python
+//! import r6n
+//!
+//! sim = r6n.Simulation(nodes=20)
+//! key = bytes([1]) * 64
+//! sim.put(0, key, 13, b"hello")
+//! sim.run_until_quiet(100)
+//! print(sim.run_get(19, key, 13, 100))
+//! ```
+
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyType},
+};
+
+use crate::{
+    block::{BlockKey, Timestamp},
+    bloom,
+    error::{EncodeError, ParseError},
+    identity::LocalPeer,
+    message::{self, Flags, GetMessageOwned, PutMessageOwned},
+    sim::{self, SimConfig},
+};
+
+fn parse_error(e: ParseError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn encode_error(e: EncodeError) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn block_key(key: &[u8]) -> PyResult<BlockKey> {
+    let key: [u8; 64] = key
+        .try_into()
+        .map_err(|_| PyValueError::new_err("keys are 64 bytes"))?;
+    Ok(BlockKey::from(key))
+}
+
+fn bytes<'py>(py: Python<'py>, b: &[u8]) -> Bound<'py, PyBytes> {
+    PyBytes::new(py, b)
+}
+
+/// A peer's public key
+#[pyclass(name = "Peer", frozen, eq, hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PyPeer(crate::Peer);
+
+#[pymethods]
+impl PyPeer {
+    #[new]
+    fn new(public_key: [u8; 32]) -> Self {
+        Self(crate::Peer::from_bytes(public_key))
+    }
+
+    /// Parse base32 or hex
+    #[classmethod]
+    fn parse(_cls: &Bound<'_, PyType>, s: &str) -> PyResult<Self> {
+        let peer = s.parse().map_err(|_| PyValueError::new_err("not a peer"))?;
+        Ok(Self(peer))
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.as_bytes())
+    }
+
+    /// The hash of the key that places the peer in the DHT
+    fn id<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.id().as_bytes())
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Peer('{}')", self.0)
+    }
+}
+
+/// Our own identity, an Ed25519 key pair
+#[pyclass(name = "Identity", frozen)]
+struct PyIdentity(LocalPeer);
+
+#[pymethods]
+impl PyIdentity {
+    /// A random identity, or the one with this secret key
+    #[new]
+    #[pyo3(signature = (secret = None))]
+    fn new(secret: Option<[u8; 32]>) -> Self {
+        Self(match secret {
+            Some(secret) => LocalPeer::from_bytes(&secret),
+            None => LocalPeer::generate(),
+        })
+    }
+
+    fn secret<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, &self.0.to_bytes())
+    }
+
+    fn peer(&self) -> PyPeer {
+        PyPeer(self.0.peer())
+    }
+
+    /// Our HELLO block advertising `addresses` until `expiration`
+    fn sign_hello<'py>(
+        &self,
+        py: Python<'py>,
+        expiration: u64,
+        addresses: Vec<String>,
+    ) -> Bound<'py, PyBytes> {
+        let expiration = Timestamp::from_micros(expiration);
+        let hello = self
+            .0
+            .sign_hello(expiration, addresses.iter().map(|a| &**a));
+        bytes(py, &hello.to_block())
+    }
+}
+
+/// A parsed PUT message
+#[pyclass(name = "PutMessage", frozen)]
+struct PyPutMessage(PutMessageOwned);
+
+#[pymethods]
+impl PyPutMessage {
+    #[staticmethod]
+    fn parse(b: &[u8]) -> PyResult<Self> {
+        let put = message::PutMessage::parse(b).map_err(parse_error)?;
+        Ok(Self(put.to_owned()))
+    }
+
+    /// A PUT that starts here
+    #[staticmethod]
+    #[pyo3(signature = (block_type, key, block, expiration, replication_level = 5))]
+    fn encode<'py>(
+        py: Python<'py>,
+        block_type: u32,
+        key: &[u8],
+        block: &[u8],
+        expiration: u64,
+        replication_level: u16,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let message = message::PutMessage::encode(
+            block_type,
+            Flags::default(),
+            replication_level,
+            Timestamp::from_micros(expiration),
+            Default::default(),
+            block_key(key)?,
+            None,
+            block,
+        )
+        .map_err(encode_error)?;
+        Ok(bytes(py, message.as_bytes()))
+    }
+
+    #[getter]
+    fn block_type(&self) -> u32 {
+        self.0.get().block_type()
+    }
+
+    #[getter]
+    fn hop_count(&self) -> u16 {
+        self.0.get().hop_count()
+    }
+
+    #[getter]
+    fn replication_level(&self) -> u16 {
+        self.0.get().replication_level()
+    }
+
+    #[getter]
+    fn expiration(&self) -> u64 {
+        self.0.get().expiration().as_micros()
+    }
+
+    #[getter]
+    fn key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, &self.0.get().block_key().0)
+    }
+
+    #[getter]
+    fn put_path<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.get().put_path())
+    }
+
+    #[getter]
+    fn block<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.get().block())
+    }
+}
+
+/// A parsed GET message
+#[pyclass(name = "GetMessage", frozen)]
+struct PyGetMessage(GetMessageOwned);
+
+#[pymethods]
+impl PyGetMessage {
+    #[staticmethod]
+    fn parse(b: &[u8]) -> PyResult<Self> {
+        let get = message::GetMessage::parse(b).map_err(parse_error)?;
+        Ok(Self(get.to_owned()))
+    }
+
+    /// A GET that starts here
+    #[staticmethod]
+    #[pyo3(signature = (block_type, key, result_filter = &b""[..], xquery = &b""[..], replication_level = 5))]
+    fn encode<'py>(
+        py: Python<'py>,
+        block_type: u32,
+        key: &[u8],
+        result_filter: &[u8],
+        xquery: &[u8],
+        replication_level: u16,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let message = message::GetMessage::encode(
+            block_type,
+            Flags::default(),
+            replication_level,
+            Default::default(),
+            block_key(key)?,
+            result_filter,
+            xquery,
+        )
+        .map_err(encode_error)?;
+        Ok(bytes(py, message.as_bytes()))
+    }
+
+    #[getter]
+    fn block_type(&self) -> u32 {
+        self.0.get().block_type()
+    }
+
+    #[getter]
+    fn hop_count(&self) -> u16 {
+        self.0.get().hop_count()
+    }
+
+    #[getter]
+    fn replication_level(&self) -> u16 {
+        self.0.get().replication_level()
+    }
+
+    #[getter]
+    fn key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, &self.0.get().query_hash().0)
+    }
+
+    #[getter]
+    fn result_filter<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.get().result_filter())
+    }
+
+    #[getter]
+    fn xquery<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.get().xquery())
+    }
+}
+
+/// A bloom filter over 64 byte keys, as in result filters
+#[pyclass(name = "BloomFilter")]
+struct PyBloomFilter(bloom::BloomFilter<Vec<u8>>);
+
+#[pymethods]
+impl PyBloomFilter {
+    /// An empty filter of `bits` bits, a power of two, setting `k` bits per
+    /// key
+    #[new]
+    #[pyo3(signature = (bits, k = None))]
+    fn new(bits: u32, k: Option<usize>) -> PyResult<Self> {
+        let filter = match k {
+            Some(k) => bloom::BloomFilter::with_k(bits, k),
+            None => bloom::BloomFilter::new(bits),
+        };
+        let filter = filter.ok_or_else(|| PyValueError::new_err("not a valid filter size"))?;
+        Ok(Self(filter))
+    }
+
+    /// A filter received in a message
+    #[staticmethod]
+    #[pyo3(signature = (b, k = None))]
+    fn from_bytes(b: Vec<u8>, k: Option<usize>) -> PyResult<Self> {
+        let filter = match k {
+            Some(k) => bloom::BloomFilter::from_with_k(b, k),
+            None => bloom::BloomFilter::from(b),
+        };
+        let filter = filter.ok_or_else(|| PyValueError::new_err("not a valid filter size"))?;
+        Ok(Self(filter))
+    }
+
+    fn insert(&mut self, key: &[u8]) -> PyResult<()> {
+        self.0.insert(&block_key(key)?.0);
+        Ok(())
+    }
+
+    fn __contains__(&self, key: &[u8]) -> PyResult<bool> {
+        Ok(self.0.test(&block_key(key)?.0))
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        bytes(py, self.0.as_bytes())
+    }
+}
+
+/// A network of nodes in virtual time, see [`sim::Simulation`]
+#[pyclass(name = "Simulation", unsendable)]
+struct PySimulation(sim::Simulation);
+
+#[pymethods]
+impl PySimulation {
+    #[new]
+    #[pyo3(signature = (nodes = 32, degree = 4, seed = 0, loss = 0.0))]
+    fn new(nodes: usize, degree: usize, seed: u64, loss: f64) -> Self {
+        Self(sim::Simulation::new(SimConfig {
+            nodes,
+            degree,
+            seed,
+            loss,
+            ..Default::default()
+        }))
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.len()
+    }
+
+    #[getter]
+    fn round(&self) -> u64 {
+        self.0.round()
+    }
+
+    fn peer(&self, node: usize) -> PyResult<PyPeer> {
+        self.check(node)?;
+        Ok(PyPeer(self.0.peer(node)))
+    }
+
+    /// Store a block from `node`, returning how many peers it was sent to
+    fn put(&mut self, node: usize, key: &[u8], block_type: u32, data: &[u8]) -> PyResult<usize> {
+        self.check(node)?;
+        Ok(self.0.put(node, block_key(key)?, block_type, data))
+    }
+
+    /// Start a GET from `node` and run until it finds something, returning
+    /// how many rounds that took
+    fn run_get(
+        &mut self,
+        node: usize,
+        key: &[u8],
+        block_type: u32,
+        max_rounds: u64,
+    ) -> PyResult<Option<u64>> {
+        self.check(node)?;
+        Ok(self
+            .0
+            .run_get(node, block_key(key)?, block_type, max_rounds))
+    }
+
+    /// Run one round, returning how many messages were handled
+    fn step(&mut self) -> usize {
+        self.0.step()
+    }
+
+    fn run_until_quiet(&mut self, max_rounds: u64) -> u64 {
+        self.0.run_until_quiet(max_rounds)
+    }
+
+    /// Every result so far, as `(node, round, block)`
+    fn results<'py>(&self, py: Python<'py>) -> Vec<(usize, u64, Bound<'py, PyBytes>)> {
+        let results = self.0.results().iter();
+        results
+            .map(|r| (r.node, r.round, bytes(py, &r.block)))
+            .collect()
+    }
+}
+
+impl PySimulation {
+    fn check(&self, node: usize) -> PyResult<()> {
+        match node < self.0.len() {
+            true => Ok(()),
+            false => Err(PyValueError::new_err("no such node")),
+        }
+    }
+}
+
+#[pymodule]
+fn r6n(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPeer>()?;
+    m.add_class::<PyIdentity>()?;
+    m.add_class::<PyPutMessage>()?;
+    m.add_class::<PyGetMessage>()?;
+    m.add_class::<PyBloomFilter>()?;
+    m.add_class::<PySimulation>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::{prelude::*, types::PyDict, wrap_pymodule};
+
+    use crate::testing::identities;
+
+    #[test]
+    fn module() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let locals = PyDict::new(py);
+            locals
+                .set_item("r6n", wrap_pymodule!(super::r6n)(py))
+                .unwrap();
+            let secret = identities::peers()[0].secret;
+            locals.set_item("secret", &secret[..]).unwrap();
+            let script = c"
+identity = r6n.Identity(secret)
+peer = identity.peer()
+assert r6n.Peer.parse(str(peer)) == peer
+assert len(identity.sign_hello(2**64 - 1, ['ip+udp://127.0.0.1:2086'])) > 32 + 64
+
+key = bytes([1]) * 64
+put = r6n.PutMessage.parse(r6n.PutMessage.encode(13, key, b'block', 1000))
+assert (put.block_type, put.key, put.block, put.expiration) == (13, key, b'block', 1000)
+get = r6n.GetMessage.parse(r6n.GetMessage.encode(13, key, xquery=b'xq'))
+assert (get.key, get.xquery, get.replication_level) == (key, b'xq', 5)
+try:
+    r6n.PutMessage.parse(b'\\0\\4')
+    assert False
+except ValueError:
+    pass
+
+bloom = r6n.BloomFilter(1024)
+bloom.insert(key)
+assert key in bloom and bytes(64) not in bloom
+assert key in r6n.BloomFilter.from_bytes(bytes(bloom))
+
+sim = r6n.Simulation(nodes=10, degree=2)
+assert len(sim) == 10
+sim.run_until_quiet(100)
+assert sim.put(0, key, 13, b'sim') > 0
+";
+            py.run(script, None, Some(&locals)).unwrap();
+        });
+    }
+}
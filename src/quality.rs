@@ -0,0 +1,178 @@
+//! How well each peer has been answering.
+//!
+//! Some neighbours drop messages or sit on them. For every peer we count
+//! the GETs sent to it and the results that came back from it, over about
+//! the last [`window`](QualityConfig::window) GETs. A peer returning fewer
+//! results per GET than the network as a whole is weighted down in the
+//! random phase of [`select_peer`](crate::RoutingTable::select_peer), and
+//! so is one with a costly connection. Weights never go below
+//! [`floor`](QualityConfig::floor), so every peer can still be picked, as
+//! the draft's random routing needs.
+
+use std::collections::HashMap;
+
+use crate::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct QualityConfig {
+    /// The lowest weight a peer can have, where the best have 1
+    pub floor: f64,
+    /// Roughly how many of a peer's most recent GETs count
+    pub window: u32,
+    /// How many GETs a new peer is assumed to have answered as well as the
+    /// rest of the network, so a few unanswered ones don't sink it
+    pub prior: f64,
+    /// Weigh peers down by the [cost](crate::underlay::ConnectionInfo::cost)
+    /// of their connections
+    pub cost: bool,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            floor: 0.1,
+            window: 64,
+            prior: 8.0,
+            cost: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Record {
+    gets: f64,
+    results: f64,
+    cost: u32,
+}
+
+/// Scores peers by the results they return. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct PeerQuality {
+    config: QualityConfig,
+    peers: HashMap<Peer, Record>,
+    // the sums over every record
+    gets: f64,
+    results: f64,
+}
+
+impl PeerQuality {
+    pub fn new(config: QualityConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &QualityConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: QualityConfig) {
+        self.config = config;
+    }
+
+    /// Start a record for a newly connected peer.
+    pub fn connected(&mut self, peer: Peer, cost: u32) {
+        self.forget(&peer);
+        let record = Record {
+            cost,
+            ..Default::default()
+        };
+        self.peers.insert(peer, record);
+    }
+
+    pub fn forget(&mut self, peer: &Peer) {
+        if let Some(record) = self.peers.remove(peer) {
+            self.gets -= record.gets;
+            self.results -= record.results;
+        }
+    }
+
+    /// Count a GET sent to the peer, ours or forwarded.
+    pub fn get_sent(&mut self, peer: Peer) {
+        let record = self.peers.entry(peer).or_default();
+        record.gets += 1.0;
+        self.gets += 1.0;
+        // halve the record, so older GETs count for less
+        if record.gets > f64::from(self.config.window) {
+            self.gets -= record.gets / 2.0;
+            self.results -= record.results / 2.0;
+            record.gets /= 2.0;
+            record.results /= 2.0;
+        }
+    }
+
+    /// Count a result that came back from the peer.
+    pub fn result(&mut self, peer: Peer) {
+        self.peers.entry(peer).or_default().results += 1.0;
+        self.results += 1.0;
+    }
+
+    /// How likely the peer is to be picked relative to others, from
+    /// [`floor`](QualityConfig::floor) to 1.
+    pub fn weight(&self, peer: &Peer) -> f64 {
+        let Some(record) = self.peers.get(peer) else {
+            return 1.0;
+        };
+        let mut weight = 1.0;
+        if self.gets > 0.0 && self.results > 0.0 {
+            let overall = self.results / self.gets;
+            let prior = self.config.prior;
+            let rate = (record.results + prior * overall) / (record.gets + prior);
+            weight = (rate / overall).min(1.0);
+        }
+        if self.config.cost {
+            weight /= 1.0 + f64::from(record.cost);
+        }
+        weight.max(self.config.floor)
+    }
+
+    /// Every peer with a record
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::identities;
+
+    use super::{PeerQuality, QualityConfig};
+
+    #[test]
+    fn weights() {
+        let [good, bad, costly] = [0, 1, 2].map(|i| identities::peers()[i].peer());
+        let mut quality = PeerQuality::new(QualityConfig {
+            floor: 0.25,
+            ..Default::default()
+        });
+        quality.connected(good, 0);
+        quality.connected(bad, 0);
+        quality.connected(costly, 1);
+        assert_eq!(quality.weight(&good), 1.0);
+        assert_eq!(quality.weight(&costly), 0.5);
+
+        for _ in 0..20 {
+            quality.get_sent(good);
+            quality.get_sent(bad);
+            quality.result(good);
+        }
+        assert_eq!(quality.weight(&good), 1.0);
+        let weight = quality.weight(&bad);
+        assert!((0.25..0.5).contains(&weight), "{weight}");
+
+        // without answers for long enough, it bottoms out
+        for _ in 0..200 {
+            quality.get_sent(bad);
+        }
+        assert_eq!(quality.weight(&bad), 0.25);
+        // and starts over if it reconnects
+        quality.connected(bad, 0);
+        assert_eq!(quality.weight(&bad), 1.0);
+    }
+}
@@ -0,0 +1,769 @@
+//! GETs started by the local peer.
+//!
+//! A [`QueryManager`] sends each query towards the key, and resends it to
+//! other peers every retry interval until it times out. Results are matched
+//! back to their queries by key, and each distinct block is only reported
+//! once per query.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+
+use crate::{
+    block::{BlockKey, HelloBlock, Timestamp},
+    bloom::{BloomFilter, PeerBloomFilter},
+    error::EncodeError,
+    message::{Flags, GetMessage, GetMessageHeader, ResultMessage},
+    underlay::Underlay,
+    Message, Peer, RoutingTable,
+};
+
+/// `GNUNET_BLOCK_TYPE_ANY`, which queries accept results of any type for
+pub const BLOCK_TYPE_ANY: u32 = 0;
+
+// bits set per block in result filters
+const RESULT_FILTER_K: usize = 8;
+
+/// Identifies a query for as long as the [`QueryManager`] that started it
+/// lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QueryId(u64);
+
+/// For handing ids across FFI. Ids not from a [`QueryManager`] match no
+/// query.
+impl From<u64> for QueryId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<QueryId> for u64 {
+    fn from(id: QueryId) -> Self {
+        id.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct QueryConfig {
+    /// How long a query runs before it is given up on
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub timeout: Duration,
+    /// How long to wait for results before sending the query to more peers
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub retry_interval: Duration,
+    /// The size of each query's result filter in bytes, rounded up to a
+    /// power of two
+    pub result_filter_size: usize,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            retry_interval: Duration::from_secs(5),
+            result_filter_size: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct GetOptions {
+    /// How many peers the query is sent to in parallel, roughly
+    pub replication_level: u16,
+    /// Ask for the path each result took back to us
+    pub record_route: bool,
+    /// Accept blocks whose key is close to the query's, not just equal
+    pub find_approximate: bool,
+    /// Have every peer along the way answer the query, not just the closest
+    pub demultiplex: bool,
+}
+
+impl GetOptions {
+    fn flags(&self) -> Flags {
+        let mut flags = Flags::default();
+        flags
+            .set_record_route(self.record_route)
+            .set_find_approximate(self.find_approximate)
+            .set_demultiplex(self.demultiplex);
+        flags
+    }
+}
+
+impl Default for GetOptions {
+    fn default() -> Self {
+        Self {
+            // as in GNUnet
+            replication_level: 5,
+            record_route: false,
+            find_approximate: false,
+            demultiplex: false,
+        }
+    }
+}
+
+/// A GET in progress.
+pub struct Query {
+    key: BlockKey,
+    block_type: u32,
+    xquery: Vec<u8>,
+    options: GetOptions,
+    /// hashes of the results we already have, sent along so that peers
+    /// don't send them again
+    result_filter: BloomFilter<Vec<u8>>,
+    /// us and the peers the query was sent to, so retries go elsewhere
+    tried: PeerBloomFilter,
+    started: Duration,
+    next_send: Duration,
+    results: usize,
+    /// not resent while paused
+    paused: bool,
+    /// for watches, how often the query starts over
+    repeat: Option<Duration>,
+    /// when the current round of a watch started
+    round: Duration,
+    /// lives as long as the query, so everything that happens to it is
+    /// logged under it
+    span: tracing::Span,
+}
+
+impl Query {
+    pub fn key(&self) -> &BlockKey {
+        &self.key
+    }
+
+    pub fn block_type(&self) -> u32 {
+        self.block_type
+    }
+
+    pub fn xquery(&self) -> &[u8] {
+        &self.xquery
+    }
+
+    pub fn options(&self) -> &GetOptions {
+        &self.options
+    }
+
+    /// When the query was started
+    pub fn started(&self) -> Duration {
+        self.started
+    }
+
+    /// How many distinct results have arrived
+    pub fn results(&self) -> usize {
+        self.results
+    }
+
+    pub fn result_filter(&self) -> &[u8] {
+        self.result_filter.as_bytes()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether this is a [watch](QueryManager::watch)
+    pub fn is_watch(&self) -> bool {
+        self.repeat.is_some()
+    }
+
+    fn is_expired(&self, now: Duration, timeout: Duration) -> bool {
+        self.repeat.is_none() && now.saturating_sub(self.started) >= timeout
+    }
+
+    /// When the query next needs attention
+    fn next_due(&self, timeout: Duration) -> Duration {
+        let end = match self.repeat {
+            Some(repeat) => self.round + repeat,
+            None => self.started + timeout,
+        };
+        match self.paused {
+            true => end,
+            false => self.next_send.min(end),
+        }
+    }
+
+    fn to_message(&self) -> Result<Message, EncodeError> {
+        GetMessage::encode(
+            self.block_type,
+            self.options.flags(),
+            self.options.replication_level,
+            self.tried.clone(),
+            self.key,
+            self.result_filter.as_bytes(),
+            &self.xquery,
+        )
+    }
+
+    /// Whether a result belongs to the query. Only approximate queries take
+    /// blocks stored under other keys.
+    fn accepts(&self, result: &ResultMessage<'_>, block_key: Option<&BlockKey>) -> bool {
+        let block_type =
+            self.block_type == BLOCK_TYPE_ANY || self.block_type == result.block_type();
+        let key = self.options.find_approximate || block_key.is_none_or(|k| *k == self.key);
+        block_type && key
+    }
+}
+
+/// Something that happened to a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryEvent {
+    /// A result that the query didn't have yet
+    Result {
+        id: QueryId,
+        /// The key the block is stored under, for block types that say.
+        /// Approximate queries can find blocks under keys other than their
+        /// own.
+        key: Option<BlockKey>,
+        block_type: u32,
+        expiration: Timestamp,
+        block: Vec<u8>,
+    },
+    /// The query timed out and was removed
+    Expired(QueryId),
+}
+
+/// The GETs started by the local peer.
+///
+/// Times are durations since the node's epoch.
+pub struct QueryManager {
+    config: QueryConfig,
+    /// ordered, so queries are sent in the order they started
+    queries: BTreeMap<QueryId, Query>,
+    by_key: HashMap<BlockKey, Vec<QueryId>>,
+    events: VecDeque<QueryEvent>,
+    next_id: u64,
+    mtu: u16,
+}
+
+impl QueryManager {
+    pub fn new(config: QueryConfig) -> Self {
+        Self {
+            config,
+            queries: BTreeMap::new(),
+            by_key: HashMap::new(),
+            events: VecDeque::new(),
+            next_id: 0,
+            mtu: u16::MAX,
+        }
+    }
+
+    pub fn config(&self) -> &QueryConfig {
+        &self.config
+    }
+
+    /// Change the configuration. Running queries keep their result filter
+    /// size.
+    pub fn set_config(&mut self, config: QueryConfig) {
+        self.config = config;
+    }
+
+    /// Result filters are made smaller than configured if they would make
+    /// GETs larger than `mtu`.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+    }
+
+    /// Start a query. It is sent on the next [`poll`](Self::poll).
+    pub fn start(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        now: Duration,
+    ) -> QueryId {
+        self.insert(key, block_type, xquery, options, None, now)
+    }
+
+    /// Start a query that never times out. Every `interval` it is sent
+    /// again as if it were new, except that it keeps its result filter, so
+    /// only new results arrive.
+    pub fn watch(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        interval: Duration,
+        now: Duration,
+    ) -> QueryId {
+        self.insert(key, block_type, xquery, options, Some(interval), now)
+    }
+
+    fn insert(
+        &mut self,
+        key: BlockKey,
+        block_type: u32,
+        xquery: Vec<u8>,
+        options: GetOptions,
+        repeat: Option<Duration>,
+        now: Duration,
+    ) -> QueryId {
+        let id = QueryId(self.next_id);
+        self.next_id += 1;
+
+        let room = (self.mtu as usize)
+            .saturating_sub(size_of::<GetMessageHeader>() + xquery.len())
+            .max(1);
+        // the largest power of two that fits, as sizes are rounded up
+        let room = 1 << room.ilog2();
+        let bytes = self.config.result_filter_size.min(room);
+        let bits = (bytes * 8).clamp(8, 1 << 15);
+        // a power of two of at least 8 bits is always a valid size
+        let result_filter =
+            BloomFilter::with_k(bits.next_power_of_two() as u32, RESULT_FILTER_K).unwrap();
+        let span = tracing::debug_span!(
+            "query",
+            id = id.0,
+            key = %key.short(),
+            block_type,
+            watch = ?repeat,
+        );
+        tracing::debug!(parent: &span, "started");
+        let query = Query {
+            key,
+            block_type,
+            xquery,
+            options,
+            result_filter,
+            tried: PeerBloomFilter::default(),
+            started: now,
+            next_send: now,
+            results: 0,
+            paused: false,
+            repeat,
+            round: now,
+            span,
+        };
+        self.queries.insert(id, query);
+        self.by_key.entry(key).or_default().push(id);
+        id
+    }
+
+    /// Stop a query. Returns whether it was running.
+    pub fn cancel(&mut self, id: QueryId) -> bool {
+        let Some(query) = self.queries.remove(&id) else {
+            return false;
+        };
+        tracing::debug!(parent: &query.span, results = query.results, "finished");
+        if let Some(ids) = self.by_key.get_mut(&query.key) {
+            ids.retain(|&i| i != id);
+            if ids.is_empty() {
+                self.by_key.remove(&query.key);
+            }
+        }
+        true
+    }
+
+    pub fn get(&self, id: QueryId) -> Option<&Query> {
+        self.queries.get(&id)
+    }
+
+    /// Stop resending a query, eg while its results aren't being consumed.
+    /// It still times out, and results still arrive from peers that already
+    /// have it. Returns whether the query is running.
+    pub fn set_paused(&mut self, id: QueryId, paused: bool) -> bool {
+        let Some(query) = self.queries.get_mut(&id) else {
+            return false;
+        };
+        query.paused = paused;
+        true
+    }
+
+    /// Add a block the application already has to the query's result
+    /// filter, so that it isn't sent or reported. Returns whether the query
+    /// is running.
+    pub fn exclude(&mut self, id: QueryId, block: &[u8]) -> bool {
+        let Some(query) = self.queries.get_mut(&id) else {
+            return false;
+        };
+        query.result_filter.insert(&Sha512::digest(block).into());
+        true
+    }
+
+    /// The queries in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (QueryId, &Query)> {
+        self.queries.iter().map(|(&id, q)| (id, q))
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+
+    /// Match a result to the queries for its key, returning how many of them
+    /// it was new to. Each gets a [`QueryEvent::Result`].
+    pub fn handle_result(&mut self, result: &ResultMessage<'_>) -> usize {
+        let Some(ids) = self.by_key.get(result.query_hash()) else {
+            return 0;
+        };
+        let Ok(block_key) = check_block(result.block_type(), result.block()) else {
+            return 0;
+        };
+        let hash: [u8; 64] = Sha512::digest(result.block()).into();
+        let mut matched = 0;
+        for &id in ids {
+            let query = self.queries.get_mut(&id).expect("indexed queries exist");
+            if !query.accepts(result, block_key.as_ref()) {
+                continue;
+            }
+            if query.result_filter.test(&hash) {
+                tracing::trace!(parent: &query.span, "duplicate result");
+                continue;
+            }
+            query.result_filter.insert(&hash);
+            query.results += 1;
+            tracing::debug!(parent: &query.span, results = query.results, "result");
+            matched += 1;
+            self.events.push_back(QueryEvent::Result {
+                id,
+                key: block_key,
+                block_type: result.block_type(),
+                expiration: result.expiration(),
+                block: result.block().to_vec(),
+            });
+        }
+        matched
+    }
+
+    /// Expire queries that timed out, and send the rest that are due to
+    /// peers they haven't been sent to yet. Returns the peers GETs went to.
+    pub fn poll<U: Underlay>(
+        &mut self,
+        now: Duration,
+        routing: &RoutingTable,
+        network_size: u64,
+        underlay: &U,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Peer> {
+        let timeout = self.config.timeout;
+        let expired: Vec<QueryId> = self
+            .queries
+            .iter()
+            .filter(|(_, q)| q.is_expired(now, timeout))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            tracing::debug!(parent: &self.queries[&id].span, "expired");
+            self.cancel(id);
+            self.events.push_back(QueryEvent::Expired(id));
+        }
+
+        let mut sent = Vec::new();
+        for query in self.queries.values_mut() {
+            if let Some(repeat) = query.repeat {
+                if now.saturating_sub(query.round) >= repeat {
+                    query.round = now;
+                    query.next_send = now;
+                    query.tried = PeerBloomFilter::default();
+                    tracing::debug!(parent: &query.span, "next round");
+                }
+            }
+            if query.paused || query.next_send > now {
+                continue;
+            }
+            query.next_send = now + self.config.retry_interval;
+            query.tried.insert_peer_id(routing.host());
+            let peers: Vec<_> = routing
+                .get_forwarding_peers(
+                    &query.key,
+                    query.options.replication_level,
+                    0,
+                    &mut query.tried,
+                    network_size,
+                    rng,
+                )
+                .into_iter()
+                .copied()
+                .collect();
+            // the peers are in the bloom filter now, so they know not to
+            // forward it to each other
+            let Ok(message) = query.to_message() else {
+                continue;
+            };
+            let _entered = query.span.enter();
+            tracing::debug!(peers = peers.len(), "sending");
+            for &peer in &peers {
+                let _ = underlay.send(peer, message.clone());
+            }
+            sent.extend(peers);
+        }
+        sent
+    }
+
+    /// When [`poll`](Self::poll) next has work to do
+    pub fn next_due(&self) -> Option<Duration> {
+        let timeout = self.config.timeout;
+        self.queries.values().map(|q| q.next_due(timeout)).min()
+    }
+
+    pub fn next_event(&mut self) -> Option<QueryEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Check that a result is well formed, for the block types we understand,
+/// and find the key it is stored under. Blocks of other types are passed on
+/// to the application unchecked, with no key.
+fn check_block(block_type: u32, block: &[u8]) -> Result<Option<BlockKey>, ()> {
+    match block_type {
+        HelloBlock::BLOCK_TYPE => match HelloBlock::parse(block) {
+            Ok(hello) => Ok(Some(BlockKey(hello.peer().id().0))),
+            Err(_) => Err(()),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Whether a GET's result filter says the requester already has `block`.
+/// Filters we can't read exclude nothing.
+pub(crate) fn is_filtered(result_filter: &[u8], block: &[u8]) -> bool {
+    BloomFilter::from_with_k(result_filter, RESULT_FILTER_K)
+        .is_some_and(|filter| filter.test(&Sha512::digest(block).into()))
+}
+
+impl Default for QueryManager {
+    fn default() -> Self {
+        Self::new(QueryConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::{BlockKey, HelloBlock, Timestamp},
+        error::ParseError,
+        gossip::SignedHello,
+        message::{GetMessage, GetMessageHeader, ResultMessage},
+        testing::identities,
+        underlay::{memory::MemoryNetwork, Underlay, UnderlaySignal},
+        InsertOutcome, RoutingTable,
+    };
+
+    use super::{GetOptions, QueryConfig, QueryEvent, QueryManager};
+
+    #[test]
+    fn lifecycle() {
+        let network = MemoryNetwork::new();
+        let host = identities::host();
+        let (underlay, _rx) = network.join(host.peer());
+        let mut routing = RoutingTable::new(host.peer_id(), Default::default());
+        let peers: Vec<_> = identities::peers()[..3]
+            .iter()
+            .map(|f| {
+                let (u, rx) = network.join(f.peer());
+                underlay.try_connect(f.peer(), u.address()).unwrap();
+                assert_eq!(routing.insert(f.peer()), InsertOutcome::Inserted);
+                (u, rx)
+            })
+            .collect();
+
+        let secs = Duration::from_secs;
+        let mut queries = QueryManager::new(QueryConfig {
+            timeout: secs(10),
+            retry_interval: secs(2),
+            ..Default::default()
+        });
+        let key = BlockKey::from([7; 64]);
+        let options = GetOptions {
+            replication_level: 1,
+            ..Default::default()
+        };
+        let id = queries.start(key, 13, vec![], options, secs(0));
+        assert_eq!(queries.next_due(), Some(secs(0)));
+        let rng = &mut StdRng::seed_from_u64(0);
+
+        // each retry goes to a peer that hasn't seen it
+        queries.poll(secs(0), &routing, 1000, &underlay, rng);
+        assert_eq!(queries.next_due(), Some(secs(2)));
+        queries.poll(secs(1), &routing, 1000, &underlay, rng);
+        queries.poll(secs(2), &routing, 1000, &underlay, rng);
+        let received: Vec<usize> = peers
+            .iter()
+            .map(|(_, rx)| {
+                rx.try_iter()
+                    .filter(|s| matches!(s, UnderlaySignal::Receive(..)))
+                    .count()
+            })
+            .collect();
+        assert_eq!(received.iter().sum::<usize>(), 2);
+        assert!(received.iter().all(|&n| n <= 1));
+
+        let result = ResultMessage::encode(13, Timestamp::FOREVER, key, b"block").unwrap();
+        let result = ResultMessage::parse(result.as_bytes()).unwrap();
+        assert_eq!(queries.handle_result(&result), 1);
+        // duplicates and other types are filtered
+        assert_eq!(queries.handle_result(&result), 0);
+        let other = ResultMessage::encode(14, Timestamp::FOREVER, key, b"other").unwrap();
+        assert_eq!(
+            queries.handle_result(&ResultMessage::parse(other.as_bytes()).unwrap()),
+            0
+        );
+        assert_eq!(
+            queries.next_event(),
+            Some(QueryEvent::Result {
+                id,
+                key: None,
+                block_type: 13,
+                expiration: Timestamp::FOREVER,
+                block: b"block".to_vec(),
+            })
+        );
+        assert_eq!(queries.get(id).unwrap().results(), 1);
+
+        // blocks we already have are filtered too
+        assert!(queries.exclude(id, b"known"));
+        let known = ResultMessage::encode(13, Timestamp::FOREVER, key, b"known").unwrap();
+        assert_eq!(
+            queries.handle_result(&ResultMessage::parse(known.as_bytes()).unwrap()),
+            0
+        );
+        assert!(queries.set_paused(id, true));
+        assert_eq!(queries.next_due(), Some(secs(10)));
+
+        queries.poll(secs(10), &routing, 1000, &underlay, rng);
+        assert_eq!(queries.next_event(), Some(QueryEvent::Expired(id)));
+        assert!(queries.is_empty());
+        assert_eq!(queries.handle_result(&result), 0);
+    }
+
+    #[test]
+    fn watch() {
+        let network = MemoryNetwork::new();
+        let [host, f] = [identities::host(), &identities::peers()[0]];
+        let (underlay, _rx) = network.join(host.peer());
+        let (peer, rx) = network.join(f.peer());
+        underlay.try_connect(f.peer(), peer.address()).unwrap();
+        let mut routing = RoutingTable::new(host.peer_id(), Default::default());
+        assert_eq!(routing.insert(f.peer()), InsertOutcome::Inserted);
+
+        let secs = Duration::from_secs;
+        let mut queries = QueryManager::new(QueryConfig {
+            timeout: secs(5),
+            retry_interval: secs(2),
+            ..Default::default()
+        });
+        let key = BlockKey::from([9; 64]);
+        let id = queries.watch(key, 13, vec![], GetOptions::default(), secs(10), secs(0));
+        let rng = &mut StdRng::seed_from_u64(0);
+        let result =
+            |data: &[u8]| ResultMessage::encode(13, Timestamp::FOREVER, key, data).unwrap();
+        let handle = |queries: &mut QueryManager, data: &[u8]| {
+            let message = result(data);
+            queries.handle_result(&ResultMessage::parse(message.as_bytes()).unwrap())
+        };
+
+        // the only peer has it after the first send, so retries go nowhere
+        for t in [0, 2, 4, 6] {
+            queries.poll(secs(t), &routing, 1000, &underlay, rng);
+        }
+        assert_eq!(handle(&mut queries, b"one"), 1);
+        assert_eq!(queries.next_due(), Some(secs(8)));
+
+        // watches don't time out, and start over every interval
+        queries.poll(secs(10), &routing, 1000, &underlay, rng);
+        assert!(queries.get(id).is_some());
+        let sent = rx
+            .try_iter()
+            .filter(|s| matches!(s, UnderlaySignal::Receive(..)))
+            .count();
+        assert_eq!(sent, 2);
+        assert_eq!(handle(&mut queries, b"one"), 0);
+        assert_eq!(handle(&mut queries, b"two"), 1);
+    }
+
+    #[test]
+    fn get_message() {
+        let key = BlockKey::from([1; 64]);
+        let options = GetOptions {
+            find_approximate: true,
+            ..Default::default()
+        };
+        let message = GetMessage::encode(
+            13,
+            options.flags(),
+            4,
+            Default::default(),
+            key,
+            &[0; 16],
+            b"xq",
+        )
+        .unwrap();
+        let get = GetMessage::parse(message.as_bytes()).unwrap();
+        assert_eq!(get.block_type(), 13);
+        assert!(get.flags().get_find_approximate() && !get.flags().get_record_route());
+        assert_eq!(get.replication_level(), 4);
+        assert_eq!(get.query_hash(), &key);
+        assert_eq!(get.result_filter(), [0; 16]);
+        assert_eq!(get.xquery(), b"xq");
+        let found = GetMessageHeader::MESSAGE_TYPE;
+        let err = ResultMessage::parse(message.as_bytes()).err();
+        assert_eq!(
+            err,
+            Some(ParseError::WrongType {
+                what: "RESULT",
+                found
+            })
+        );
+    }
+
+    #[test]
+    fn approximate() {
+        let hello = &identities::peers()[0];
+        let block = SignedHello::sign(&hello.signing_key(), Timestamp::FOREVER, ["udp:1"]);
+        // a HELLO is stored under its peer's ID, which isn't the query's key
+        let key = BlockKey::from([7; 64]);
+        let result = ResultMessage::encode(
+            HelloBlock::BLOCK_TYPE,
+            Timestamp::FOREVER,
+            key,
+            &block.to_block(),
+        )
+        .unwrap();
+        let result = ResultMessage::parse(result.as_bytes()).unwrap();
+
+        let mut queries = QueryManager::default();
+        let exact = GetOptions::default();
+        queries.start(key, HelloBlock::BLOCK_TYPE, vec![], exact, Duration::ZERO);
+        assert_eq!(queries.handle_result(&result), 0);
+
+        let approximate = GetOptions {
+            find_approximate: true,
+            ..Default::default()
+        };
+        let id = queries.start(
+            key,
+            HelloBlock::BLOCK_TYPE,
+            vec![],
+            approximate,
+            Duration::ZERO,
+        );
+        assert_eq!(queries.handle_result(&result), 1);
+        let Some(QueryEvent::Result { id: got, key, .. }) = queries.next_event() else {
+            panic!("no result");
+        };
+        assert_eq!(got, id);
+        assert_eq!(key, Some(BlockKey::from(*hello.peer_id().as_bytes())));
+    }
+}
@@ -0,0 +1,258 @@
+//! Limiting how fast each neighbour can send us messages.
+//!
+//! Every peer gets a token bucket per message type. A message takes a token,
+//! and messages that arrive to an empty bucket are dropped. Peers that keep
+//! at it can be cut off entirely.
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    message::{GetMessageHeader, HelloMessage, PutMessageHeader, ResultMessageHeader},
+    Message, Peer,
+};
+
+/// A sustained rate with some allowance for bursts
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct Rate {
+    pub per_second: f64,
+    /// How many messages can arrive at once after a quiet period
+    pub burst: f64,
+}
+
+impl Rate {
+    pub const fn new(per_second: f64, burst: f64) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// Limits per message type. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RateLimitConfig {
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub get: Option<Rate>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub put: Option<Rate>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub result: Option<Rate>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub hello: Option<Rate>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::or_unlimited"))]
+    pub other: Option<Rate>,
+    /// After this many dropped messages, the peer is disconnected and
+    /// everything else it sends is ignored until it reconnects.
+    pub disconnect_after: Option<u32>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            get: Some(Rate::new(50.0, 100.0)),
+            put: Some(Rate::new(20.0, 50.0)),
+            result: Some(Rate::new(100.0, 200.0)),
+            hello: Some(Rate::new(1.0, 10.0)),
+            other: None,
+            disconnect_after: None,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// No limits at all
+    pub fn unlimited() -> Self {
+        Self {
+            get: None,
+            put: None,
+            result: None,
+            hello: None,
+            other: None,
+            disconnect_after: None,
+        }
+    }
+
+    fn rate(&self, kind: usize) -> Option<Rate> {
+        [self.get, self.put, self.result, self.hello, self.other][kind]
+    }
+}
+
+/// What to do with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    /// Drop it, and the peer's connection with it
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    last: Duration,
+}
+
+impl Bucket {
+    pub(crate) fn full(rate: Rate, now: Duration) -> Self {
+        Self {
+            tokens: rate.burst,
+            last: now,
+        }
+    }
+
+    pub(crate) fn take(&mut self, rate: Rate, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// When the next token will be there
+    pub(crate) fn ready_at(&self, rate: Rate) -> Duration {
+        let missing = 1.0 - self.tokens;
+        if missing <= 0.0 {
+            return self.last;
+        }
+        // a rate of 0 never refills, which is an infinite wait
+        Duration::try_from_secs_f64(missing / rate.per_second)
+            .map_or(Duration::MAX, |wait| self.last.saturating_add(wait))
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerLimits {
+    // indexed by `kind`, created full on the first message of each type
+    buckets: [Option<Bucket>; 5],
+    dropped: u32,
+    blocked: bool,
+}
+
+fn kind(message: &Message) -> usize {
+    match message.header().map(|h| h.message_type()) {
+        Some(GetMessageHeader::MESSAGE_TYPE) => 0,
+        Some(PutMessageHeader::MESSAGE_TYPE) => 1,
+        Some(ResultMessageHeader::MESSAGE_TYPE) => 2,
+        Some(HelloMessage::MESSAGE_TYPE) => 3,
+        _ => 4,
+    }
+}
+
+/// Token buckets for each connected peer
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    peers: HashMap<Peer, PeerLimits>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Peers' buckets refill at the new rates from now on.
+    pub fn set_config(&mut self, config: RateLimitConfig) {
+        self.config = config;
+    }
+
+    /// Take a token for a message from `peer`.
+    pub fn check(&mut self, peer: Peer, message: &Message, now: Duration) -> Verdict {
+        let kind = kind(message);
+        let Some(rate) = self.config.rate(kind) else {
+            return match self.peers.get(&peer) {
+                Some(limits) if limits.blocked => Verdict::Drop,
+                _ => Verdict::Allow,
+            };
+        };
+        let limits = self.peers.entry(peer).or_default();
+        if limits.blocked {
+            return Verdict::Drop;
+        }
+        let bucket = limits.buckets[kind].get_or_insert(Bucket::full(rate, now));
+        if bucket.take(rate, now) {
+            return Verdict::Allow;
+        }
+        limits.dropped = limits.dropped.saturating_add(1);
+        match self.config.disconnect_after {
+            Some(n) if limits.dropped >= n => {
+                limits.blocked = true;
+                Verdict::Disconnect
+            }
+            _ => Verdict::Drop,
+        }
+    }
+
+    /// How many messages from the peer have been dropped since it connected
+    pub fn dropped(&self, peer: &Peer) -> u32 {
+        self.peers.get(peer).map_or(0, |l| l.dropped)
+    }
+
+    pub fn is_blocked(&self, peer: &Peer) -> bool {
+        self.peers.get(peer).is_some_and(|l| l.blocked)
+    }
+
+    /// Forget a peer's buckets, eg because it disconnected.
+    pub fn forget(&mut self, peer: &Peer) {
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        message::ResultMessage,
+        testing::identities,
+    };
+
+    use super::{Rate, RateLimitConfig, RateLimiter, Verdict};
+
+    #[test]
+    fn buckets() {
+        let peer = identities::peers()[0].peer();
+        let other = identities::peers()[1].peer();
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            result: Some(Rate::new(2.0, 3.0)),
+            disconnect_after: Some(3),
+            ..RateLimitConfig::unlimited()
+        });
+        let result =
+            ResultMessage::encode(13, Timestamp::FOREVER, BlockKey::from([0; 64]), b"").unwrap();
+        let at = Duration::from_millis;
+
+        // the burst, then nothing until tokens refill
+        for _ in 0..3 {
+            assert_eq!(limiter.check(peer, &result, at(0)), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(peer, &result, at(100)), Verdict::Drop);
+        assert_eq!(limiter.check(peer, &result, at(500)), Verdict::Allow);
+        assert_eq!(limiter.check(peer, &result, at(500)), Verdict::Drop);
+        assert_eq!(limiter.dropped(&peer), 2);
+        // other peers have their own buckets
+        assert_eq!(limiter.check(other, &result, at(500)), Verdict::Allow);
+
+        assert_eq!(limiter.check(peer, &result, at(500)), Verdict::Disconnect);
+        assert_eq!(limiter.check(peer, &result, at(60_000)), Verdict::Drop);
+        limiter.forget(&peer);
+        assert_eq!(limiter.check(peer, &result, at(60_000)), Verdict::Allow);
+    }
+}
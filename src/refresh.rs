@@ -0,0 +1,248 @@
+//! Looking for peers to fill sparse buckets, R5N's take on Kademlia's
+//! bucket refresh.
+//!
+//! Every interval, each bucket with fewer peers than its
+//! [threshold](BucketRefresh::threshold) gets a GET for HELLO blocks under
+//! a random key at its distance from us. The GETs find approximate matches
+//! and are answered all along the way, so the HELLOs that come back are of
+//! peers near that key, ie in that bucket. They are cached like any other
+//! HELLO, and the ones that would fill a sparse bucket are also kept as
+//! [discovered](BucketRefresh::take_discovered), for the application to
+//! connect to.
+
+use std::time::Duration;
+
+use rand::RngCore;
+
+use crate::{
+    block::BlockKey,
+    gossip::SignedHello,
+    maintenance::{Budget, TaskStatus},
+    routing::RoutingTable,
+    BucketIndex, PeerId,
+};
+
+/// The most discovered HELLOs kept waiting for the application
+const MAX_DISCOVERED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RefreshConfig {
+    /// How often sparse buckets are looked for peers
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub interval: Duration,
+    /// Buckets with fewer peers than this are refreshed, unless the network
+    /// is too small for them to have that many
+    pub min_peers: usize,
+    /// How many peers each GET is sent to in parallel, roughly
+    pub replication_level: u16,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        Self {
+            // as GNUnet's slowest search for peers
+            interval: Duration::from_secs(2 * 60),
+            min_peers: 4,
+            replication_level: 4,
+        }
+    }
+}
+
+/// Works through the sparse buckets a few at a time. See the
+/// [module docs](self).
+#[derive(Debug, Default)]
+pub struct BucketRefresh {
+    config: RefreshConfig,
+    // buckets left to refresh this round
+    pending: Vec<BucketIndex>,
+    discovered: Vec<SignedHello>,
+}
+
+impl BucketRefresh {
+    pub fn new(config: RefreshConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            discovered: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RefreshConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: RefreshConfig) {
+        self.config = config;
+    }
+
+    /// How many buckets are left this round
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// How many peers the bucket should have before
+    /// it's left alone. Half of the network is in the furthest bucket, a
+    /// quarter in the next and so on, so closer buckets may not have
+    /// [`min_peers`](RefreshConfig::min_peers) to find.
+    pub fn threshold(
+        &self,
+        routing: &RoutingTable,
+        bucket: BucketIndex,
+        network_size: u64,
+    ) -> usize {
+        if bucket == BucketIndex::HOST {
+            return 0;
+        }
+        let expected = network_size as f64 / 2f64.powi(513 - i32::from(bucket.get()));
+        let min_peers = self.config.min_peers.min(routing.bucket_size());
+        min_peers.min(expected as usize)
+    }
+
+    pub fn is_sparse(
+        &self,
+        routing: &RoutingTable,
+        bucket: BucketIndex,
+        network_size: u64,
+    ) -> bool {
+        routing.bucket_len(bucket) < self.threshold(routing, bucket, network_size)
+    }
+
+    /// Call `refresh` with a random key in each sparse bucket, furthest
+    /// first, spending a unit of `budget` per bucket. A round that runs out
+    /// of budget carries on where it left off next time.
+    pub fn run(
+        &mut self,
+        routing: &RoutingTable,
+        network_size: u64,
+        budget: &mut Budget,
+        rng: &mut dyn RngCore,
+        mut refresh: impl FnMut(BlockKey),
+    ) -> TaskStatus {
+        if self.pending.is_empty() {
+            self.pending = BucketIndex::all()
+                .skip(1)
+                .filter(|&b| self.is_sparse(routing, b, network_size))
+                .collect();
+        }
+        while let Some(&bucket) = self.pending.last() {
+            if !budget.spend() {
+                return TaskStatus::Pending;
+            }
+            // buckets that filled up since are skipped
+            if self.is_sparse(routing, bucket, network_size) {
+                refresh(random_key(routing.host(), bucket, rng));
+            }
+            self.pending.pop();
+        }
+        TaskStatus::Done
+    }
+
+    /// Keep a HELLO for the application to connect to, if its peer would
+    /// go in a sparse bucket. Returns whether it was kept.
+    pub fn discover(
+        &mut self,
+        routing: &RoutingTable,
+        hello: &SignedHello,
+        network_size: u64,
+    ) -> bool {
+        let peer = hello.peer();
+        let bucket = BucketIndex::between(routing.host(), &peer.id());
+        let known = routing.contains(peer) || self.discovered.iter().any(|h| h.peer() == peer);
+        if known
+            || !self.is_sparse(routing, bucket, network_size)
+            || self.discovered.len() >= MAX_DISCOVERED
+        {
+            return false;
+        }
+        self.discovered.push(hello.clone());
+        true
+    }
+
+    /// The HELLOs discovered since last time, eg to add to
+    /// [`Bootstrap`](crate::bootstrap::Bootstrap)
+    pub fn take_discovered(&mut self) -> Vec<SignedHello> {
+        std::mem::take(&mut self.discovered)
+    }
+}
+
+/// A random key in this bucket of `host`
+pub fn random_key(host: &PeerId, bucket: BucketIndex, rng: &mut dyn RngCore) -> BlockKey {
+    let mut distance = [0; 64];
+    if let Some(bit) = bucket.get().checked_sub(1) {
+        let byte = 63 - bit as usize / 8;
+        rng.fill_bytes(&mut distance[byte..]);
+        let top = 1u8 << (bit % 8);
+        distance[byte] = (distance[byte] & (top - 1)) | top;
+    }
+    BlockKey(crate::xor(&host.0, &distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::Timestamp,
+        gossip::SignedHello,
+        log2_xor_dist,
+        maintenance::{Budget, TaskStatus},
+        routing::{RoutingTable, RoutingTableConfig},
+        testing::identities,
+        BucketIndex, PeerId,
+    };
+
+    use super::{random_key, BucketRefresh, RefreshConfig};
+
+    #[test]
+    fn keys() {
+        let host = identities::host().peer_id();
+        let mut rng = StdRng::seed_from_u64(0);
+        for bucket in [1, 7, 8, 9, 300, 511, 512] {
+            let key = random_key(&host, BucketIndex::new(bucket).unwrap(), &mut rng);
+            assert_eq!(log2_xor_dist(&host, &PeerId(key.0)), bucket);
+        }
+    }
+
+    #[test]
+    fn refresh() {
+        let host = identities::host().peer_id();
+        let mut routing = RoutingTable::new(host, RoutingTableConfig::default());
+        let mut refresh = BucketRefresh::new(RefreshConfig::default());
+        let mut rng = StdRng::seed_from_u64(0);
+        // half of the network is in bucket 512, 2 in 506 and 1 in 505
+        for (bucket, threshold) in [(512, 4), (506, 2), (505, 1), (504, 0)] {
+            let bucket = BucketIndex::new(bucket).unwrap();
+            assert_eq!(refresh.threshold(&routing, bucket, 256), threshold);
+        }
+
+        let mut keys = Vec::new();
+        let status = refresh.run(&routing, 256, &mut Budget::work(3), &mut rng, |key| {
+            keys.push(key)
+        });
+        assert_eq!(status, TaskStatus::Pending);
+        let buckets: Vec<u16> = keys
+            .iter()
+            .map(|k| log2_xor_dist(&host, &PeerId(k.0)))
+            .collect();
+        assert_eq!(buckets, [512, 511, 510]);
+        refresh.run(&routing, 256, &mut Budget::unlimited(), &mut rng, |key| {
+            keys.push(key)
+        });
+        assert_eq!(keys.len(), 8);
+
+        // only peers that would fill a sparse bucket are discovered
+        let full = identities::in_bucket(512).next().unwrap();
+        let hello = SignedHello::sign(&full.signing_key(), Timestamp::FOREVER, []);
+        assert!(refresh.discover(&routing, &hello, 256));
+        assert!(!refresh.discover(&routing, &hello, 256));
+        assert_eq!(refresh.take_discovered(), std::slice::from_ref(&hello));
+        let _ = routing.insert(full.peer());
+        assert!(!refresh.discover(&routing, &hello, 256));
+        assert!(refresh.take_discovered().is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+//! Sending results back the way their GET came.
+//!
+//! When we forward a GET for a neighbour, we remember that it asked, so
+//! RESULTs for the query can be passed back to it. Requests are forgotten
+//! after a while, like GNUnet's routing of replies.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{block::BlockKey, query::BLOCK_TYPE_ANY, Peer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RelayConfig {
+    /// How long results are passed back for after a GET
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub lifetime: Duration,
+    /// How many requests are remembered at most. The oldest are forgotten
+    /// first.
+    pub capacity: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(60),
+            capacity: 4096,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    key: BlockKey,
+    block_type: u32,
+    peer: Peer,
+    at: Duration,
+}
+
+/// Who asked for which keys. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct ReturnRoutes {
+    config: RelayConfig,
+    // oldest first
+    requests: VecDeque<Request>,
+}
+
+impl ReturnRoutes {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            requests: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: RelayConfig) {
+        self.config = config;
+    }
+
+    /// Remember that `peer` asked for blocks of `block_type` under `key`.
+    pub fn insert(&mut self, key: BlockKey, block_type: u32, peer: Peer, now: Duration) {
+        self.expire(now);
+        self.requests
+            .retain(|r| !(r.key == key && r.block_type == block_type && r.peer == peer));
+        if self.config.capacity == 0 {
+            return;
+        }
+        while self.requests.len() >= self.config.capacity {
+            self.requests.pop_front();
+        }
+        self.requests.push_back(Request {
+            key,
+            block_type,
+            peer,
+            at: now,
+        });
+    }
+
+    /// The peers to pass a result of `block_type` under `key` back to
+    pub fn requesters(&mut self, key: &BlockKey, block_type: u32, now: Duration) -> Vec<Peer> {
+        self.expire(now);
+        // each peer has at most one request per key and type, but may have
+        // asked for any type too
+        let mut peers = Vec::new();
+        let matching = self
+            .requests
+            .iter()
+            .filter(|r| r.key == *key)
+            .filter(|r| r.block_type == BLOCK_TYPE_ANY || r.block_type == block_type);
+        for r in matching {
+            if !peers.contains(&r.peer) {
+                peers.push(r.peer);
+            }
+        }
+        peers
+    }
+
+    /// Forget a peer's requests, eg because it disconnected.
+    pub fn forget(&mut self, peer: &Peer) {
+        self.requests.retain(|r| r.peer != *peer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    fn expire(&mut self, now: Duration) {
+        while let Some(r) = self.requests.front() {
+            if now.saturating_sub(r.at) < self.config.lifetime {
+                break;
+            }
+            self.requests.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{block::BlockKey, query::BLOCK_TYPE_ANY, testing::identities};
+
+    use super::ReturnRoutes;
+
+    #[test]
+    fn requesters() {
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let key = BlockKey::from([1; 64]);
+        let mut routes = ReturnRoutes::default();
+        let at = Duration::from_secs;
+
+        routes.insert(key, 13, a, at(0));
+        routes.insert(key, BLOCK_TYPE_ANY, b, at(30));
+        routes.insert(key, 13, a, at(30));
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes.requesters(&key, 13, at(31)), [b, a]);
+        assert_eq!(routes.requesters(&key, 7, at(31)), [b]);
+        assert!(routes
+            .requesters(&BlockKey::from([2; 64]), 13, at(31))
+            .is_empty());
+
+        routes.forget(&b);
+        assert_eq!(routes.requesters(&key, 13, at(31)), [a]);
+        assert!(routes.requesters(&key, 13, at(90)).is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+//! Putting stored blocks again, so they outlive the peers storing them.
+//!
+//! Every interval, each block in the datacache is sent to the peers closest
+//! to its key. Peers come and go, so this both replaces copies that left
+//! with their peers, and moves blocks to closer peers that joined since they
+//! were stored.
+//!
+//! Newly connected peers don't have to wait for that. The blocks they are
+//! closer to than we are are offered to them as they connect, at a limited
+//! rate.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    block::BlockKey,
+    datacache::{DataCache, StoredBlock},
+    maintenance::{Budget, TaskStatus},
+    query::BLOCK_TYPE_ANY,
+    ratelimit::{Bucket, Rate},
+    Distance, Peer, PeerId,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RepublishConfig {
+    /// How often every stored block is put again
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::secs"))]
+    pub interval: Duration,
+    /// How many of the closest peers each block is sent to
+    pub peers: usize,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60 * 60),
+            peers: 5,
+        }
+    }
+}
+
+/// Works through the datacache a few blocks at a time. See the
+/// [module docs](self).
+#[derive(Debug, Default)]
+pub struct Republisher {
+    config: RepublishConfig,
+    // keys left to republish this round
+    pending: Vec<BlockKey>,
+}
+
+impl Republisher {
+    pub fn new(config: RepublishConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RepublishConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: RepublishConfig) {
+        self.config = config;
+    }
+
+    /// How many keys are left this round
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Call `republish` with each stored block, spending a unit of `budget`
+    /// per key. A round that runs out of budget carries on where it left off
+    /// next time.
+    pub fn run(
+        &mut self,
+        datacache: &DataCache,
+        budget: &mut Budget,
+        mut republish: impl FnMut(&BlockKey, &StoredBlock),
+    ) -> TaskStatus {
+        if self.pending.is_empty() {
+            self.pending = datacache.keys().copied().collect();
+        }
+        while let Some(key) = self.pending.last() {
+            if !budget.spend() {
+                return TaskStatus::Pending;
+            }
+            // blocks that expired or were evicted since are skipped
+            for stored in datacache.get(key, BLOCK_TYPE_ANY) {
+                republish(key, stored);
+            }
+            self.pending.pop();
+        }
+        TaskStatus::Done
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct MigrationConfig {
+    /// How fast keys are offered to new peers, across all of them
+    pub rate: Rate,
+    /// How many keys can wait to be offered. New peers miss out on the
+    /// rest, which republishing gets to eventually.
+    pub max_pending: usize,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            rate: Rate::new(10.0, 50.0),
+            max_pending: 1024,
+        }
+    }
+}
+
+/// Keys waiting to be offered to new peers. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Migrations {
+    config: MigrationConfig,
+    bucket: Option<Bucket>,
+    pending: VecDeque<(Peer, BlockKey)>,
+}
+
+impl Migrations {
+    pub fn new(config: MigrationConfig) -> Self {
+        Self {
+            config,
+            bucket: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MigrationConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MigrationConfig) {
+        self.config = config;
+    }
+
+    /// Queue the keys in `datacache` that `peer` is closer to than `host`.
+    pub fn peer_connected(&mut self, peer: Peer, host: &PeerId, datacache: &DataCache) {
+        let id = peer.id();
+        let closer = datacache.keys().filter(|key| {
+            let theirs = Distance::between(&key.0, &id.0);
+            theirs.is_closer_than(&Distance::between(&key.0, &host.0))
+        });
+        for &key in closer {
+            if self.pending.len() >= self.config.max_pending {
+                break;
+            }
+            self.pending.push_back((peer, key));
+        }
+    }
+
+    /// Forget what was waiting for a peer, eg because it disconnected.
+    pub fn forget(&mut self, peer: &Peer) {
+        self.pending.retain(|(p, _)| p != peer);
+    }
+
+    /// Take the keys that can be offered at `now` without going over the
+    /// rate.
+    pub fn due(&mut self, now: Duration) -> Vec<(Peer, BlockKey)> {
+        let rate = self.config.rate;
+        let bucket = self.bucket.get_or_insert(Bucket::full(rate, now));
+        let mut due = Vec::new();
+        while !self.pending.is_empty() && bucket.take(rate, now) {
+            due.extend(self.pending.pop_front());
+        }
+        due
+    }
+
+    /// When more keys can be offered, if any are waiting
+    pub fn next_due(&self, now: Duration) -> Option<Duration> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let ready = self.bucket.map_or(now, |b| b.ready_at(self.config.rate));
+        Some(ready.max(now))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        datacache::DataCache,
+        maintenance::{Budget, TaskStatus},
+        ratelimit::Rate,
+        testing::identities,
+    };
+
+    use super::{MigrationConfig, Migrations, Republisher};
+
+    #[test]
+    fn rounds() {
+        let mut datacache = DataCache::default();
+        let now = Timestamp::from_micros(0);
+        for i in 0..3 {
+            let key = BlockKey::from([i; 64]);
+            let _ = datacache.insert(key, 13, Timestamp::FOREVER, &[i], now);
+        }
+        let mut republisher = Republisher::default();
+        let mut seen = vec![];
+
+        let mut budget = Budget::work(2);
+        let status = republisher.run(&datacache, &mut budget, |_, b| seen.push(b.block[0]));
+        assert_eq!(status, TaskStatus::Pending);
+        assert_eq!((seen.len(), republisher.pending()), (2, 1));
+
+        let mut budget = Budget::unlimited();
+        let status = republisher.run(&datacache, &mut budget, |_, b| seen.push(b.block[0]));
+        assert_eq!(status, TaskStatus::Done);
+        seen.sort();
+        assert_eq!(seen, [0, 1, 2]);
+    }
+
+    #[test]
+    fn migrations() {
+        let host = identities::host().peer_id();
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let mut datacache = DataCache::default();
+        let now = Timestamp::from_micros(0);
+        for key in [a.id(), b.id(), host] {
+            let key = BlockKey::from(*key.as_bytes());
+            let _ = datacache.insert(key, 13, Timestamp::FOREVER, b"block", now);
+        }
+        let mut migrations = Migrations::new(MigrationConfig {
+            rate: Rate::new(1.0, 1.0),
+            max_pending: 4,
+        });
+        let at = Duration::from_secs;
+
+        // our own key stays with us
+        migrations.peer_connected(a, &host, &datacache);
+        migrations.peer_connected(b, &host, &datacache);
+        assert!(migrations.len() >= 2);
+        assert_eq!(migrations.due(at(0)).len(), 1);
+        assert_eq!(migrations.next_due(at(0)), Some(at(1)));
+        assert!(migrations.due(at(0)).is_empty());
+
+        migrations.forget(&a);
+        migrations.forget(&b);
+        assert!(migrations.is_empty());
+        assert_eq!(migrations.next_due(at(0)), None);
+    }
+}
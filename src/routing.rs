@@ -0,0 +1,1135 @@
+use std::{ops::Range, sync::Arc, time::Duration};
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+
+use rand::{seq::SliceRandom, RngCore};
+use zerocopy::{big_endian, AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::{
+    block::BlockKey,
+    bloom::PeerBloomFilter,
+    error::RoutingError,
+    time::{Clock, SystemClock},
+    BucketIndex, Distance, Peer, PeerId,
+};
+
+pub mod math;
+mod shared;
+
+pub use math::{forward_count, MAXIMUM_REPLICATION_LEVEL};
+pub use shared::SharedRoutingTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct RoutingTableConfig {
+    /// The maximum number of peers in each k-bucket. The draft leaves this
+    /// to the implementation, GNUnet uses 8. A [`DhtNode`](crate::DhtNode)
+    /// grows its buckets with the network size estimate, see
+    /// [`math::bucket_size`], and this is the least they hold.
+    pub bucket_size: usize,
+}
+
+impl Default for RoutingTableConfig {
+    fn default() -> Self {
+        Self { bucket_size: 8 }
+    }
+}
+
+pub struct RoutingTable {
+    host: PeerId,
+    config: RoutingTableConfig,
+    /// starts at the config's, see [`set_bucket_size`](Self::set_bucket_size)
+    bucket_size: usize,
+    clock: Arc<dyn Clock>,
+    /// added to the clock's time, so that restored routes that are older
+    /// than the clock still have a non-negative creation time.
+    epoch_offset: Duration,
+    /// k-buckets indexed by log2 distance, each ordered oldest connection
+    /// first
+    buckets: Vec<Vec<Route>>,
+    len: usize,
+    /// incremented on every change to the table
+    generation: u64,
+}
+
+/// The result of [`RoutingTable::insert`], telling the caller which
+/// connection, if any, it should [`drop`](crate::underlay::Underlay::drop).
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The peer was added.
+    Inserted,
+    /// The peer was already in the table. Its route now refers to the new
+    /// connection and the old one is returned.
+    ReplacedExisting(Peer),
+    /// The peer's k-bucket was full and the given peer was evicted. This can
+    /// be the peer that was just inserted.
+    BucketFull { evict: Peer },
+    /// The peer can't be in the routing table, eg because it's the host.
+    Rejected(Peer),
+}
+
+/// A change to the routing table made by a [`DhtNode`](crate::DhtNode),
+/// so the application can keep its own bookkeeping of routed peers in step.
+/// See [`DhtNode::next_route_event`](crate::DhtNode::next_route_event).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteEvent {
+    /// The peer was added to the table.
+    Added(Peer),
+    /// The peer was already routed and its route now refers to a new
+    /// connection.
+    Replaced(Peer),
+    /// The peer was removed from the table.
+    Removed { peer: Peer, reason: RemovalReason },
+}
+
+/// Why a peer was removed from the routing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// Its k-bucket was full and `by` took its place. Its connection is
+    /// released, so the underlay may drop it.
+    Evicted { by: Peer },
+    /// The underlay reported that it disconnected.
+    Disconnected,
+    /// It was banned.
+    Banned,
+    /// It wouldn't stop flooding us.
+    RateLimited,
+}
+
+/// A route in the table, as seen by [`RoutingTable::iter`].
+pub struct RouteView<'a> {
+    route: &'a Route,
+    now: Duration,
+}
+
+impl<'a> RouteView<'a> {
+    pub fn peer(&self) -> &'a Peer {
+        &self.route.peer
+    }
+
+    /// The k-bucket this route is in
+    pub fn bucket(&self) -> BucketIndex {
+        self.route.dist
+    }
+
+    /// The full distance between the host and this peer
+    pub fn distance(&self, host: &PeerId) -> Distance {
+        Distance::between(&host.0, &self.route.id.0)
+    }
+
+    /// How long the connection to this peer has been in the table
+    pub fn age(&self) -> Duration {
+        self.now.saturating_sub(self.route.created)
+    }
+
+    /// See [`RoutingTable::set_weight`]
+    pub fn weight(&self) -> f64 {
+        self.route.weight
+    }
+}
+
+/// Summary of a [`RoutingTable`], eg for a debug UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RoutingTableStats {
+    pub occupancy: Occupancy,
+    /// The age of the longest lived connection
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::config::secs::serialize_opt")
+    )]
+    pub oldest: Option<Duration>,
+    /// The age of the shortest lived connection
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::config::secs::serialize_opt")
+    )]
+    pub newest: Option<Duration>,
+}
+
+/// A consistent copy of the table's bucket occupancy, taken in one pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Occupancy {
+    /// The [`RoutingTable::generation`] this snapshot was taken at
+    pub generation: u64,
+    buckets: Vec<u32>,
+    total: usize,
+}
+
+impl Occupancy {
+    /// The number of peers in the table. Always the sum of all buckets.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn bucket_len(&self, bucket: BucketIndex) -> usize {
+        self.buckets.get(bucket.index()).map_or(0, |&n| n as usize)
+    }
+
+    /// Iterate over the non-empty buckets as `(bucket, len)`
+    pub fn buckets(&self) -> impl Iterator<Item = (BucketIndex, usize)> + '_ {
+        BucketIndex::all()
+            .zip(&self.buckets)
+            .filter(|(_, &n)| n > 0)
+            .map(|(b, &n)| (b, n as usize))
+    }
+}
+
+impl RoutingTable {
+    pub fn new(host: PeerId, config: RoutingTableConfig) -> Self {
+        Self {
+            host,
+            bucket_size: config.bucket_size,
+            config,
+            clock: Arc::new(SystemClock::new()),
+            epoch_offset: Duration::ZERO,
+            buckets: BucketIndex::all().map(|_| Vec::new()).collect(),
+            len: 0,
+            generation: 0,
+        }
+    }
+
+    /// A counter that changes every time the table is modified. Two reads
+    /// that see the same generation saw the same table.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Iterate over every route, closest bucket first. Within a bucket,
+    /// routes are ordered oldest connection first.
+    pub fn iter(&self) -> impl Iterator<Item = RouteView<'_>> + '_ {
+        let now = self.now();
+        self.routes().map(move |route| RouteView { route, now })
+    }
+
+    /// Iterate over the routes in a k-bucket, oldest connection first.
+    pub fn iter_bucket(&self, bucket: BucketIndex) -> impl Iterator<Item = RouteView<'_>> + '_ {
+        let now = self.now();
+        self.bucket(bucket)
+            .iter()
+            .map(move |route| RouteView { route, now })
+    }
+
+    pub fn stats(&self) -> RoutingTableStats {
+        let now = self.now();
+        let created = self.routes().map(|r| r.created);
+        RoutingTableStats {
+            occupancy: self.occupancy(),
+            oldest: created.clone().min().map(|c| now.saturating_sub(c)),
+            newest: created.max().map(|c| now.saturating_sub(c)),
+        }
+    }
+
+    /// Snapshot the bucket sizes. Reading [`len`](Self::len) and
+    /// [`bucket_len`](Self::bucket_len) separately can disagree if the table
+    /// changes in between, this can't.
+    pub fn occupancy(&self) -> Occupancy {
+        Occupancy {
+            generation: self.generation,
+            buckets: self.buckets.iter().map(|b| b.len() as u32).collect(),
+            total: self.len,
+        }
+    }
+
+    /// Use this clock for connection times instead of the system clock. This
+    /// should be done before any peers are inserted.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now() + self.epoch_offset
+    }
+
+    pub fn host(&self) -> &PeerId {
+        &self.host
+    }
+
+    pub fn config(&self) -> &RoutingTableConfig {
+        &self.config
+    }
+
+    /// The maximum number of peers in each k-bucket
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// Resize the k-buckets, eg as the network size estimate changes.
+    /// Buckets over the new size keep their peers until they leave.
+    pub fn set_bucket_size(&mut self, bucket_size: usize) {
+        self.bucket_size = bucket_size;
+    }
+
+    /// The number of peers in the table
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of peers in a k-bucket
+    pub fn bucket_len(&self, bucket: BucketIndex) -> usize {
+        self.bucket(bucket).len()
+    }
+
+    pub fn contains(&self, peer: &Peer) -> bool {
+        let bucket = BucketIndex::between(&self.host, &peer.id());
+        self.bucket(bucket).iter().any(|r| r.peer == *peer)
+    }
+
+    /// All routes in a k-bucket, oldest first
+    fn bucket(&self, bucket: BucketIndex) -> &[Route] {
+        &self.buckets[bucket.index()]
+    }
+
+    /// All routes, closest bucket first
+    fn routes(&self) -> impl Iterator<Item = &Route> + Clone {
+        self.buckets.iter().flatten()
+    }
+
+    /// Add a newly connected peer to the table.
+    ///
+    /// If the peer's k-bucket is full, the shortest lived connection in that
+    /// bucket is evicted. R5N prefers long lived connections, so this is
+    /// usually the peer that was just inserted.
+    pub fn insert(&mut self, peer: Peer) -> InsertOutcome {
+        let created = self.now();
+        self.insert_at(peer, created)
+    }
+
+    fn insert_at(&mut self, peer: Peer, created: Duration) -> InsertOutcome {
+        let id = peer.id();
+        let dist = BucketIndex::between(&self.host, &id);
+        if dist == BucketIndex::HOST {
+            // that's us
+            return InsertOutcome::Rejected(peer);
+        }
+
+        let new_route = Route {
+            dist,
+            created,
+            peer,
+            id,
+            weight: 1.0,
+        };
+
+        let bucket = &mut self.buckets[dist.index()];
+        let outcome = insert_route(bucket, new_route, self.bucket_size);
+        match outcome {
+            InsertOutcome::Inserted => self.len += 1,
+            // evicted straight away, so the bucket is as it was
+            InsertOutcome::BucketFull { evict } if evict == peer => return outcome,
+            _ => {}
+        }
+        self.generation += 1;
+        outcome
+    }
+
+    /// Find the `n` peers closest to `key`, closest first.
+    pub fn closest_peers(&self, key: &BlockKey, n: usize) -> Vec<&Peer> {
+        self.closest_routes_matching(key, n, |_| true)
+            .into_iter()
+            .map(|r| &r.peer)
+            .collect()
+    }
+
+    /// Find the routes to the `n` closest peers to `key` that pass the `filter`.
+    fn closest_routes_matching(
+        &self,
+        key: &BlockKey,
+        n: usize,
+        mut filter: impl FnMut(&PeerId) -> bool,
+    ) -> Vec<&Route> {
+        let target = BucketIndex::between(&self.host, &PeerId(key.0));
+
+        let mut closest = Vec::with_capacity(n);
+        for group in search_order(target) {
+            if closest.len() >= n {
+                break;
+            }
+            let group = rank(key, self.buckets[group].iter().flatten(), &mut filter);
+            closest.extend(group.into_iter().take(n - closest.len()));
+        }
+        closest
+    }
+
+    /// How likely a peer is to be picked in the random phase of
+    /// [`select_peer`](Self::select_peer), relative to the rest. Peers
+    /// start at 1, and a weight of 0 or less is never picked, so it should
+    /// be kept above that, eg by [`quality`](crate::quality). Returns
+    /// whether the peer was in the table.
+    pub fn set_weight(&mut self, peer: &Peer, weight: f64) -> bool {
+        let bucket = BucketIndex::between(&self.host, &peer.id());
+        set_route_weight(&mut self.buckets[bucket.index()], peer, weight)
+    }
+
+    /// Choose the next hop for a message about `key`, following the draft's
+    /// peer selection: while `hop_count` is below log2 of the network size,
+    /// pick a random peer, drawn from `rng` in proportion to its
+    /// [weight](Self::set_weight), to spread the message through the
+    /// network. After that, route to the closest peer. Peers already in
+    /// `bloom` are never selected.
+    pub fn select_peer(
+        &self,
+        key: &BlockKey,
+        hop_count: u16,
+        bloom: &PeerBloomFilter,
+        network_size: u64,
+        rng: &mut dyn RngCore,
+    ) -> Option<&Peer> {
+        let l2nse = (network_size.max(1) as f64).log2();
+        if f64::from(hop_count) < l2nse {
+            let candidates: Vec<&Route> = self
+                .routes()
+                .filter(|r| !bloom.contains_peer_id(&r.id))
+                .collect();
+            choose_route(&candidates, rng).map(|r| &r.peer)
+        } else {
+            self.closest_routes_matching(key, 1, |id| !bloom.contains_peer_id(id))
+                .pop()
+                .map(|r| &r.peer)
+        }
+    }
+
+    /// Choose the next hops for a PUT or GET about `key`. The number of peers
+    /// is given by [`forward_count`]. Selected peers are added to `bloom` so
+    /// that they are excluded by later hops.
+    pub fn get_forwarding_peers(
+        &self,
+        key: &BlockKey,
+        replication_level: u16,
+        hop_count: u16,
+        bloom: &mut PeerBloomFilter,
+        network_size: u64,
+        rng: &mut dyn RngCore,
+    ) -> Vec<&Peer> {
+        let count = forward_count(replication_level, hop_count, network_size, rng);
+        let mut peers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let Some(peer) = self.select_peer(key, hop_count, bloom, network_size, rng) else {
+                break;
+            };
+            bloom.insert_peer(peer);
+            peers.push(peer);
+        }
+        tracing::trace!(
+            key = %key.short(),
+            hop_count,
+            wanted = count,
+            selected = ?peers.iter().map(|p| p.short()).collect::<Vec<_>>(),
+            "forwarding",
+        );
+        peers
+    }
+
+    /// Whether we are closer to `key` than every peer in the table, and so
+    /// should be the one to store or answer it.
+    pub fn is_closest(&self, key: &BlockKey) -> bool {
+        let ours = Distance::between(&key.0, &self.host.0);
+        match self.closest_routes_matching(key, 1, |_| true).first() {
+            Some(route) => !Distance::between(&key.0, &route.id.0).is_closer_than(&ours),
+            None => true,
+        }
+    }
+
+    /// Remove a disconnected peer from the table.
+    pub fn remove(&mut self, peer: &Peer) -> Option<Peer> {
+        self.remove_by(&peer.id(), |r| r.peer == *peer)
+    }
+
+    /// Remove the peer with this id from the table.
+    pub fn remove_by_id(&mut self, id: &PeerId) -> Option<Peer> {
+        self.remove_by(id, |r| r.id == *id)
+    }
+
+    fn remove_by(&mut self, id: &PeerId, f: impl Fn(&Route) -> bool) -> Option<Peer> {
+        let bucket = &mut self.buckets[BucketIndex::between(&self.host, id).index()];
+        let route = remove_route(bucket, f)?;
+
+        self.len -= 1;
+        self.generation += 1;
+        Some(route.peer)
+    }
+
+    /// Serialize the table so that it can be [`restore`](Self::restore)d
+    /// after a restart. Each peer's connection age is kept, so that the
+    /// longest lived connections are still preferred.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let now = self.now();
+        let header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            count: big_endian::U32::new(self.len as u32),
+        };
+
+        let mut out =
+            Vec::with_capacity(size_of::<SnapshotHeader>() + self.len * size_of::<SnapshotEntry>());
+        out.extend_from_slice(header.as_bytes());
+        for route in self.routes() {
+            let age = now.saturating_sub(route.created).as_micros();
+            let entry = SnapshotEntry {
+                public_key: route.peer.0 .0,
+                dist: big_endian::U16::new(route.dist.get()),
+                age: big_endian::U64::new(age.try_into().unwrap_or(u64::MAX)),
+            };
+            out.extend_from_slice(entry.as_bytes());
+        }
+        out
+    }
+
+    /// Fill an empty table from a [`snapshot`](Self::snapshot), returning
+    /// the number of peers that were restored.
+    ///
+    /// Entries are re-validated: peers that are not valid public keys, that
+    /// don't match the recorded distance to our host, or that don't fit in
+    /// their bucket are skipped. None of the restored peers are connected, so
+    /// the caller should try to reconnect to them.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<usize, RoutingError> {
+        let (header, rest) = SnapshotHeader::ref_from_prefix(snapshot)
+            .map(|h| (h, &snapshot[size_of::<SnapshotHeader>()..]))
+            .ok_or(RoutingError::Snapshot)?;
+        if header.magic != SNAPSHOT_MAGIC {
+            return Err(RoutingError::Snapshot);
+        }
+        if !self.is_empty() {
+            return Err(RoutingError::NotEmpty);
+        }
+        let entries = SnapshotEntry::slice_from(rest).ok_or(RoutingError::Snapshot)?;
+        if entries.len() != header.count.get() as usize {
+            return Err(RoutingError::Snapshot);
+        }
+
+        let oldest = entries.iter().map(|e| e.age.get()).max().unwrap_or(0);
+        self.epoch_offset = Duration::from_micros(oldest);
+        let now = self.now();
+
+        for entry in entries {
+            let peer = Peer(CompressedEdwardsY(entry.public_key));
+            if peer.0.decompress().is_none() {
+                continue;
+            }
+            if BucketIndex::between(&self.host, &peer.id()).get() != entry.dist.get() {
+                continue;
+            }
+            let age = Duration::from_micros(entry.age.get());
+            let _ = self.insert_at(peer, now.saturating_sub(age));
+        }
+
+        Ok(self.len())
+    }
+}
+
+/// Add a route to its k-bucket, keeping the bucket ordered and within
+/// `bucket_size`.
+fn insert_route(bucket: &mut Vec<Route>, route: Route, bucket_size: usize) -> InsertOutcome {
+    // peer already inserted? the old connection is replaced
+    if let Some(i) = bucket.iter().position(|r| r.peer == route.peer) {
+        let old = bucket.remove(i);
+        let i = bucket.binary_search(&route).unwrap_err();
+        bucket.insert(i, route);
+        return InsertOutcome::ReplacedExisting(old.peer);
+    }
+
+    let i = bucket.binary_search(&route).unwrap_err();
+    bucket.insert(i, route);
+
+    if bucket.len() > bucket_size {
+        // the last entry is the shortest lived connection
+        let evict = bucket.pop().unwrap().peer;
+        return InsertOutcome::BucketFull { evict };
+    }
+
+    InsertOutcome::Inserted
+}
+
+fn set_route_weight(bucket: &mut [Route], peer: &Peer, weight: f64) -> bool {
+    match bucket.iter_mut().find(|r| r.peer == *peer) {
+        Some(route) => {
+            route.weight = weight;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Pick a random route by weight. Unweighted, it's the same draw as ever,
+/// so seeded runs don't change.
+fn choose_route<'a>(candidates: &[&'a Route], rng: &mut dyn RngCore) -> Option<&'a Route> {
+    if candidates.iter().all(|r| r.weight == 1.0) {
+        return candidates.choose(rng).copied();
+    }
+    let route = candidates.choose_weighted(rng, |r| r.weight.max(0.0));
+    route.ok().copied()
+}
+
+fn remove_route(bucket: &mut Vec<Route>, f: impl Fn(&Route) -> bool) -> Option<Route> {
+    let i = bucket.iter().position(f)?;
+    Some(bucket.remove(i))
+}
+
+/// Groups of buckets to search, closest to a key first, given the key's log2
+/// distance from the host.
+///
+/// Peers in the target's bucket share the most prefix with the key. Peers in
+/// any closer bucket are all exactly `target` away from the key, and peers in
+/// further buckets are as far from the key as they are from us.
+fn search_order(target: BucketIndex) -> impl Iterator<Item = Range<usize>> {
+    let further = BucketIndex::all()
+        .skip(target.index() + 1)
+        .map(|b| b.index()..b.index() + 1);
+    let target = target.index();
+    [target..target + 1, 0..target].into_iter().chain(further)
+}
+
+/// Sort the routes that pass the `filter` by distance to `key`
+fn rank<'a>(
+    key: &BlockKey,
+    routes: impl Iterator<Item = &'a Route>,
+    mut filter: impl FnMut(&PeerId) -> bool,
+) -> Vec<&'a Route> {
+    let mut routes: Vec<_> = routes
+        .filter(|r| filter(&r.id))
+        .map(|r| (Distance::between(&key.0, &r.id.0), r))
+        .collect();
+    routes.sort_unstable_by_key(|&(d, _)| d);
+    routes.into_iter().map(|(_, r)| r).collect()
+}
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"r6rt";
+
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct SnapshotHeader {
+    magic: [u8; 4],
+    count: big_endian::U32,
+}
+
+#[derive(FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct SnapshotEntry {
+    public_key: [u8; 32],
+    dist: big_endian::U16,
+    /// connection age in microseconds
+    age: big_endian::U64,
+}
+
+struct Route {
+    dist: BucketIndex,
+    created: Duration,
+    peer: Peer,
+    // cached, to avoid hashing the peer on every lookup
+    id: PeerId,
+    /// how likely the peer is to be picked at random, see
+    /// [`set_weight`](RoutingTable::set_weight)
+    weight: f64,
+}
+
+// ordered oldest first within a bucket, whatever the weights
+impl Route {
+    fn key(&self) -> (BucketIndex, Duration, &Peer, &PeerId) {
+        (self.dist, self.created, &self.peer, &self.id)
+    }
+}
+
+impl PartialEq for Route {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Route {}
+
+impl PartialOrd for Route {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Route {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+
+    use std::{
+        collections::{BTreeSet, HashSet},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use ::proptest::{collection::vec, prelude::*, sample::Index};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::BlockKey,
+        bloom::PeerBloomFilter,
+        log2_xor_dist,
+        routing::InsertOutcome,
+        testing::{identities, proptest},
+        time::MockClock,
+        xor, BucketIndex, Distance, Peer, PeerId, RoutingTable, RoutingTableConfig,
+    };
+
+    #[test]
+    fn accessors() {
+        let mut table = RoutingTable::new(PeerId([0; 64]), RoutingTableConfig::default());
+        assert!(table.is_empty());
+
+        let peer1 = Peer(CompressedEdwardsY([1; 32]));
+        let peer2 = Peer(CompressedEdwardsY([2; 32]));
+        let dist1 = BucketIndex::between(table.host(), &peer1.id());
+
+        assert!(table.insert(Peer(CompressedEdwardsY([1; 32]))) == InsertOutcome::Inserted);
+        assert_eq!(table.len(), 1);
+        assert!(!table.is_empty());
+        assert!(table.contains(&peer1));
+        assert!(!table.contains(&peer2));
+        assert_eq!(table.bucket_len(dist1), 1);
+        assert_eq!(table.bucket_len(BucketIndex::HOST), 0);
+    }
+
+    #[test]
+    fn bucket_capacity() {
+        let config = RoutingTableConfig { bucket_size: 2 };
+        let mut table = RoutingTable::new(PeerId([0; 64]), config);
+        let host = PeerId([0; 64]);
+
+        // find 3 peers that share a bucket
+        let mut peers = (0..=255u8).map(|i| Peer(CompressedEdwardsY([i; 32])));
+        let first = peers.next().unwrap();
+        let dist = BucketIndex::between(&host, &first.id());
+        let mut same: Vec<Peer> = peers
+            .filter(|p| BucketIndex::between(&host, &p.id()) == dist)
+            .take(2)
+            .collect();
+        let third = same.pop().unwrap();
+        let second = same.pop().unwrap();
+        let third_key = third.0;
+
+        assert!(table.insert(first) == InsertOutcome::Inserted);
+        assert!(table.insert(second) == InsertOutcome::Inserted);
+        assert_eq!(table.bucket_len(dist), 2);
+
+        // the bucket is full, the newest connection is evicted
+        let InsertOutcome::BucketFull { evict: evicted } = table.insert(third) else {
+            panic!("bucket should be full")
+        };
+        assert!(evicted == Peer(third_key));
+        assert!(!table.contains(&evicted));
+        assert_eq!(table.bucket_len(dist), 2);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn closest_peers() {
+        let host = identities::host();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut table = RoutingTable::new(host.peer_id(), config);
+        for f in identities::peers() {
+            assert!(table.insert(f.peer()) == InsertOutcome::Inserted);
+        }
+
+        let key = identities::peers()[7].id;
+        let closest = table.closest_peers(&BlockKey(key), 5);
+        assert_eq!(closest.len(), 5);
+        // the key is exactly one of the peers
+        assert!(*closest[0] == identities::peers()[7].peer());
+
+        // compare against sorting every peer
+        let mut all: Vec<_> = identities::peers()
+            .iter()
+            .map(|f| (xor(&key, &f.id), f.public))
+            .collect();
+        all.sort();
+        for (peer, (_, public)) in closest.iter().zip(&all) {
+            assert_eq!(peer.0 .0, *public);
+        }
+
+        assert_eq!(table.closest_peers(&BlockKey(key), 100).len(), table.len());
+        let closest_to_host = table.closest_peers(&BlockKey(host.id), 1);
+        assert_eq!(closest_to_host[0].id().0, all_closest_to(&host.id));
+    }
+
+    #[test]
+    fn select_peer() {
+        let host = identities::host();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut table = RoutingTable::new(host.peer_id(), config);
+        for f in identities::peers() {
+            assert!(table.insert(f.peer()) == InsertOutcome::Inserted);
+        }
+
+        let key = BlockKey(identities::peers()[7].id);
+        let mut bloom = PeerBloomFilter::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+
+        // past the random phase, the closest peer is chosen
+        let closest = identities::peers()[7].peer();
+        let peer = table.select_peer(&key, 10, &bloom, 64, rng).unwrap();
+        assert!(*peer == closest);
+
+        // unless it is already in the bloom filter
+        bloom.insert_peer(&closest);
+        let next = table.select_peer(&key, 10, &bloom, 64, rng).unwrap();
+        assert!(*next == *table.closest_peers(&key, 2)[1]);
+
+        // random phase never picks filtered peers
+        for _ in 0..100 {
+            let peer = table.select_peer(&key, 0, &bloom, 64, rng).unwrap();
+            assert!(!bloom.contains_peer(peer));
+        }
+        // and is the same for the same seed
+        let pick = |seed| {
+            let rng = &mut StdRng::seed_from_u64(seed);
+            let peers = (0..8).map(|_| table.select_peer(&key, 0, &bloom, 64, rng));
+            peers.collect::<Vec<_>>()
+        };
+        assert_eq!(pick(1), pick(1));
+        assert_ne!(pick(1), pick(2));
+
+        // everyone is filtered
+        for f in identities::peers() {
+            bloom.insert_peer(&f.peer());
+        }
+        assert!(table.select_peer(&key, 0, &bloom, 64, rng).is_none());
+        assert!(table.select_peer(&key, 10, &bloom, 64, rng).is_none());
+    }
+
+    #[test]
+    fn weighted_select_peer() {
+        let host = identities::host();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut table = RoutingTable::new(host.peer_id(), config);
+        let [a, b] = [0, 1].map(|i| identities::peers()[i].peer());
+        let _ = table.insert(a);
+        let _ = table.insert(b);
+        assert!(table.set_weight(&b, 0.1));
+        assert!(!table.set_weight(&identities::peers()[2].peer(), 0.1));
+        let weight = table.iter().find(|r| *r.peer() == b).unwrap().weight();
+        assert_eq!(weight, 0.1);
+
+        let key = BlockKey(identities::peers()[7].id);
+        let bloom = PeerBloomFilter::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let picks = (0..1000).map(|_| table.select_peer(&key, 0, &bloom, 64, rng));
+        let bs = picks.filter(|p| *p == Some(&b)).count();
+        // about 1 in 11
+        assert!((40..150).contains(&bs), "{bs}");
+        // the closest is still picked past the random phase
+        let closest = *table.closest_peers(&key, 1)[0];
+        assert_eq!(table.select_peer(&key, 10, &bloom, 64, rng), Some(&closest));
+    }
+
+    #[test]
+    fn get_forwarding_peers() {
+        let host = identities::host();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut table = RoutingTable::new(host.peer_id(), config);
+        for f in identities::peers() {
+            assert!(table.insert(f.peer()) == InsertOutcome::Inserted);
+        }
+
+        let key = BlockKey(identities::peers()[7].id);
+        let mut bloom = PeerBloomFilter::default();
+        let rng = &mut StdRng::seed_from_u64(0);
+        let peers = table.get_forwarding_peers(&key, 16, 0, &mut bloom, 64, rng);
+        assert!(peers.len() >= 3);
+        for (i, p) in peers.iter().enumerate() {
+            assert!(bloom.contains_peer(p));
+            assert!(!peers[..i].contains(p));
+        }
+    }
+
+    #[test]
+    fn insert_outcomes() {
+        let host = identities::host();
+        let mut table = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        let a = &identities::peers()[0];
+
+        assert!(table.insert(host.peer()) == InsertOutcome::Rejected(host.peer()));
+        assert!(table.insert(a.peer()) == InsertOutcome::Inserted);
+        assert!(table.insert(a.peer()) == InsertOutcome::ReplacedExisting(a.peer()));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.bucket_len(BucketIndex::new(a.bucket).unwrap()), 1);
+    }
+
+    #[test]
+    fn snapshot() {
+        let host = identities::host();
+        let mut table = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        for f in &identities::peers()[..10] {
+            let _ = table.insert(f.peer());
+        }
+
+        let snapshot = table.snapshot();
+        let mut restored = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        assert_eq!(restored.restore(&snapshot), Ok(10));
+        assert_eq!(restored.occupancy().buckets, table.occupancy().buckets);
+        for f in &identities::peers()[..10] {
+            assert!(restored.contains(&f.peer()));
+        }
+
+        // the distances are relative to the host, so a different host
+        // rejects the entries in the wrong buckets
+        let other = identities::peers()[20].peer_id();
+        let mut restored = RoutingTable::new(other, RoutingTableConfig::default());
+        assert!(restored.restore(&snapshot).unwrap() < table.len());
+
+        let mut restored = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        assert!(restored.restore(&snapshot[..snapshot.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn mock_clock() {
+        let host = identities::host();
+        let clock = Arc::new(MockClock::default());
+        let mut table = RoutingTable::new(host.peer_id(), RoutingTableConfig::default())
+            .with_clock(clock.clone());
+
+        let [a, b] = [0, 1].map(|i| &identities::peers()[i]);
+        assert!(table.insert(a.peer()) == InsertOutcome::Inserted);
+        clock.advance(Duration::from_secs(10));
+        assert!(table.insert(b.peer()) == InsertOutcome::Inserted);
+        clock.advance(Duration::from_secs(5));
+
+        let stats = table.stats();
+        assert_eq!(stats.oldest, Some(Duration::from_secs(15)));
+        assert_eq!(stats.newest, Some(Duration::from_secs(5)));
+
+        // ages survive a restore onto a fresh clock
+        let snapshot = table.snapshot();
+        let mut restored = RoutingTable::new(host.peer_id(), RoutingTableConfig::default())
+            .with_clock(Arc::new(MockClock::default()));
+        assert_eq!(restored.restore(&snapshot), Ok(2));
+        assert_eq!(restored.stats(), stats);
+    }
+
+    #[test]
+    fn iter() {
+        let host = identities::host();
+        let mut table = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        assert_eq!(table.stats().oldest, None);
+
+        for f in &identities::peers()[..10] {
+            let _ = table.insert(f.peer());
+        }
+
+        assert_eq!(table.iter().count(), table.len());
+        let buckets: Vec<BucketIndex> = table.iter().map(|r| r.bucket()).collect();
+        assert!(buckets.is_sorted());
+
+        for f in &identities::peers()[..10] {
+            let route = table
+                .iter_bucket(BucketIndex::new(f.bucket).unwrap())
+                .find(|r| *r.peer() == f.peer())
+                .unwrap();
+            assert_eq!(route.distance(&host.peer_id()).log2(), f.bucket);
+        }
+
+        let stats = table.stats();
+        assert_eq!(stats.occupancy.total(), table.len());
+        assert!(stats.oldest >= stats.newest);
+    }
+
+    #[test]
+    fn remove() {
+        let host = identities::host();
+        let mut table = RoutingTable::new(host.peer_id(), RoutingTableConfig::default());
+        let [a, b, c] = [0, 1, 2].map(|i| &identities::peers()[i]);
+        assert!(table.insert(a.peer()) == InsertOutcome::Inserted);
+        assert!(table.insert(b.peer()) == InsertOutcome::Inserted);
+
+        assert!(table.remove(&c.peer()).is_none());
+        assert!(table.remove(&a.peer()).unwrap() == a.peer());
+        assert!(!table.contains(&a.peer()));
+        let bucket = BucketIndex::new(a.bucket).unwrap();
+        assert_eq!(table.bucket_len(bucket), (a.bucket == b.bucket) as usize);
+        assert!(table.remove(&a.peer()).is_none());
+
+        assert!(table.remove_by_id(&b.peer_id()).unwrap() == b.peer());
+        assert!(table.is_empty());
+        assert_eq!(table.occupancy().total(), 0);
+        assert_eq!(table.occupancy().buckets().count(), 0);
+    }
+
+    #[test]
+    fn is_closest() {
+        let host = identities::host();
+        let config = RoutingTableConfig { bucket_size: 64 };
+        let mut table = RoutingTable::new(host.peer_id(), config);
+        assert!(table.is_closest(&BlockKey(identities::peers()[0].id)));
+
+        for f in identities::peers() {
+            assert!(table.insert(f.peer()) == InsertOutcome::Inserted);
+        }
+        assert!(table.is_closest(&BlockKey(host.id)));
+        assert!(!table.is_closest(&BlockKey(identities::peers()[0].id)));
+    }
+
+    fn all_closest_to(key: &[u8; 64]) -> [u8; 64] {
+        identities::peers()
+            .iter()
+            .map(|f| f.id)
+            .min_by_key(|id| xor(key, id))
+            .unwrap()
+    }
+
+    #[test]
+    fn generation() {
+        let mut table = RoutingTable::new(identities::host().peer_id(), Default::default());
+        let mut peers = identities::in_bucket(512).map(|f| f.peer());
+        for (i, peer) in peers.by_ref().take(table.bucket_size()).enumerate() {
+            let _ = table.insert_at(peer, Duration::from_secs(i as u64 + 1));
+        }
+        let generation = table.generation();
+
+        // the newest connection is evicted as it's inserted
+        let newest = peers.next().unwrap();
+        let outcome = table.insert_at(newest, Duration::from_secs(100));
+        assert_eq!(outcome, InsertOutcome::BucketFull { evict: newest });
+        assert_eq!(table.generation(), generation);
+
+        // but an older one takes another's place
+        let older = peers.next().unwrap();
+        let outcome = table.insert_at(older, Duration::ZERO);
+        assert!(matches!(outcome, InsertOutcome::BucketFull { evict } if evict != older));
+        assert_ne!(table.generation(), generation);
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut table = RoutingTable::new(PeerId([0; 64]), RoutingTableConfig::default());
+        let before = table.occupancy();
+        assert_eq!(before.total(), 0);
+
+        for i in 1..=4 {
+            assert!(table.insert(Peer(CompressedEdwardsY([i; 32]))) == InsertOutcome::Inserted);
+        }
+
+        let after = table.occupancy();
+        assert_ne!(before.generation, after.generation);
+        assert_eq!(after.generation, table.generation());
+        assert_eq!(after.total(), 4);
+        assert_eq!(after.buckets().map(|(_, n)| n).sum::<usize>(), 4);
+        for (dist, n) in after.buckets() {
+            assert_eq!(table.bucket_len(dist), n);
+        }
+    }
+
+    /// Everything that should hold of a table, whatever was done to it
+    fn check_invariants(table: &RoutingTable) {
+        let mut peers = HashSet::new();
+        for (dist, bucket) in table.buckets.iter().enumerate() {
+            assert!(bucket.len() <= table.bucket_size());
+            // oldest connection first
+            assert!(bucket.windows(2).all(|w| w[0] <= w[1]));
+            for route in bucket {
+                assert_eq!(route.dist.index(), dist);
+                assert_eq!(route.id, route.peer.id());
+                assert_eq!(log2_xor_dist(table.host(), &route.id) as usize, dist);
+                assert!(peers.insert(route.peer), "duplicate peer");
+            }
+        }
+        assert_eq!(table.len(), peers.len());
+        assert_eq!(table.iter().count(), peers.len());
+        assert_eq!(table.occupancy().total(), peers.len());
+    }
+
+    /// Something to do to a table
+    #[derive(Debug, Clone)]
+    enum Op {
+        /// A peer that is likely new
+        Insert(Peer),
+        /// Peers already in the table, if there are any
+        Reinsert(Index),
+        Remove(Index),
+        /// A peer that is likely not in the table
+        RemoveUnknown(Peer),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => proptest::peer().prop_map(Op::Insert),
+            1 => any::<Index>().prop_map(Op::Reinsert),
+            1 => any::<Index>().prop_map(Op::Remove),
+            1 => proptest::peer().prop_map(Op::RemoveUnknown),
+        ]
+    }
+
+    ::proptest::proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn invariants(
+            host in proptest::peer_id(),
+            bucket_size in 1..4usize,
+            ops in vec(op(), 0..200),
+            key in proptest::block_key(),
+        ) {
+            let clock = Arc::new(MockClock::default());
+            let config = RoutingTableConfig { bucket_size };
+            let mut table = RoutingTable::new(host, config).with_clock(clock.clone());
+            // what should be in the table, ordered so indices are stable
+            let mut expected = BTreeSet::new();
+
+            for op in ops {
+                clock.advance(Duration::from_secs(1));
+                let known = |i: &Index| match expected.is_empty() {
+                    true => None,
+                    false => expected.iter().nth(i.index(expected.len())).copied(),
+                };
+                match op {
+                    Op::Remove(i) => {
+                        let Some(peer) = known(&i) else { continue };
+                        prop_assert_eq!(table.remove(&peer), Some(peer));
+                        prop_assert!(!table.contains(&peer));
+                        expected.remove(&peer);
+                    }
+                    Op::Reinsert(i) => {
+                        let Some(peer) = known(&i) else { continue };
+                        let outcome = table.insert(peer);
+                        prop_assert!(outcome == InsertOutcome::ReplacedExisting(peer));
+                    }
+                    Op::RemoveUnknown(peer) if !expected.contains(&peer) => {
+                        prop_assert_eq!(table.remove(&peer), None);
+                    }
+                    Op::Insert(peer) if !expected.contains(&peer) => match table.insert(peer) {
+                        InsertOutcome::Inserted => {
+                            expected.insert(peer);
+                        }
+                        // the newest connection goes, which is this one
+                        InsertOutcome::BucketFull { evict } => prop_assert_eq!(evict, peer),
+                        _ => prop_assert!(false, "fresh peer wasn't inserted"),
+                    },
+                    Op::Insert(_) | Op::RemoveUnknown(_) => continue,
+                }
+                check_invariants(&table);
+                prop_assert!(expected.iter().all(|p| table.contains(p)));
+                prop_assert_eq!(table.len(), expected.len());
+            }
+
+            let mut by_distance: Vec<_> = expected.iter().collect();
+            by_distance.sort_by_key(|p| Distance::between(&key.0, &p.id().0));
+            by_distance.truncate(5);
+            prop_assert_eq!(table.closest_peers(&key, 5), by_distance);
+
+            let mut restored =
+                RoutingTable::new(*table.host(), config).with_clock(Arc::new(MockClock::default()));
+            prop_assert_eq!(restored.restore(&table.snapshot()), Ok(expected.len()));
+            check_invariants(&restored);
+            prop_assert!(expected.iter().all(|p| restored.contains(p)));
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! The draft's bounds on how far and how wide messages spread.
+//!
+//! https://datatracker.ietf.org/doc/html/draft-schanzen-r5n-05
+
+use rand::{Rng, RngCore};
+use zerocopy::FromBytes;
+
+use crate::{
+    bloom::PeerBloomFilter,
+    message::{GetMessage, GetMessageHeader, PutMessage, PutMessageHeader},
+    Message,
+};
+
+/// Replication levels above this are treated as this.
+pub const MAXIMUM_REPLICATION_LEVEL: u16 = 16;
+
+/// The replication level a message is handled with. 0 is treated as 1.
+pub fn clamp_replication_level(replication_level: u16) -> u16 {
+    replication_level.clamp(1, MAXIMUM_REPLICATION_LEVEL)
+}
+
+fn l2nse(network_size: u64) -> f64 {
+    (network_size.max(1) as f64).log2()
+}
+
+/// How many peers each k-bucket should hold: `log2(network_size)`, but no
+/// fewer than `min`. Larger networks need more peers per bucket for
+/// lookups to find a way around peers that left or misbehave.
+pub fn bucket_size(network_size: u64, min: usize) -> usize {
+    (l2nse(network_size).ceil() as usize).max(min)
+}
+
+/// The most hops a message can take, `4 * log2(network_size)`. Messages
+/// that took more are not forwarded.
+pub fn max_hop_count(network_size: u64) -> u16 {
+    (l2nse(network_size) * 4.0).min(u16::MAX as f64) as u16
+}
+
+/// Whether a message with `hop_count` hops has gone too far to handle.
+pub fn exceeds_max_hops(hop_count: u16, network_size: u64) -> bool {
+    f64::from(hop_count) > l2nse(network_size) * 4.0
+}
+
+/// The hop count to forward a message with, or `None` if it shouldn't be
+/// forwarded any further.
+pub fn next_hop_count(hop_count: u16, network_size: u64) -> Option<u16> {
+    hop_count
+        .checked_add(1)
+        .filter(|&next| !exceeds_max_hops(next, network_size))
+}
+
+/// The expected number of peers a message should be forwarded to. See
+/// [`forward_count`].
+pub fn target_forward_count(replication_level: u16, hop_count: u16, network_size: u64) -> f64 {
+    let l2nse = l2nse(network_size);
+    let hop_count = f64::from(hop_count);
+    if hop_count > l2nse * 4.0 {
+        return 0.0;
+    }
+    if hop_count > l2nse * 2.0 {
+        return 1.0;
+    }
+    let replication = f64::from(clamp_replication_level(replication_level));
+    let spread = l2nse + (replication - 1.0) * hop_count;
+    // in a network of one, there are no hops to spread the replication over
+    if spread == 0.0 {
+        return replication;
+    }
+    1.0 + (replication - 1.0) / spread
+}
+
+/// How many peers a message should be forwarded to, as in GNUnet.
+///
+/// After `2 * log2(network_size)` hops, messages only go to one peer, and
+/// after `4 * log2(network_size)` hops, they are not forwarded at all. Before
+/// that, the replication level is spread over the expected number of hops,
+/// randomly rounding the fractional part.
+pub fn forward_count(
+    replication_level: u16,
+    hop_count: u16,
+    network_size: u64,
+    rng: &mut dyn RngCore,
+) -> u32 {
+    let target = target_forward_count(replication_level, hop_count, network_size);
+    let count = target.floor();
+    let extra = rng.gen_bool(target - count);
+    (count as u32 + extra as u32).min(MAXIMUM_REPLICATION_LEVEL as u32)
+}
+
+/// A copy of a GET or PUT to send on to the next hop, with its hop count
+/// incremented, replication level clamped and peer bloom filter replaced by
+/// `bloom`. `None` if the message is neither, or has gone as far as it may.
+pub fn forwarded(message: &Message, network_size: u64, bloom: &PeerBloomFilter) -> Option<Message> {
+    let mut bytes = message.as_bytes().to_vec();
+    match message.header()?.message_type() {
+        GetMessageHeader::MESSAGE_TYPE => {
+            let get = GetMessage::parse(message.as_bytes()).ok()?;
+            let hop_count = next_hop_count(get.hop_count(), network_size)?;
+            let replication_level = clamp_replication_level(get.replication_level());
+            let header = GetMessageHeader::mut_from_prefix(&mut bytes)?;
+            header.set_hops(hop_count, replication_level);
+            header.set_peer_bloom_filter(bloom.clone());
+        }
+        PutMessageHeader::MESSAGE_TYPE => {
+            let put = PutMessage::parse(message.as_bytes()).ok()?;
+            let hop_count = next_hop_count(put.hop_count(), network_size)?;
+            let replication_level = clamp_replication_level(put.replication_level());
+            let header = PutMessageHeader::mut_from_prefix(&mut bytes)?;
+            header.set_hops(hop_count, replication_level);
+            header.set_peer_bloom_filter(bloom.clone());
+        }
+        _ => return None,
+    }
+    Some(Message::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        block::{BlockKey, Timestamp},
+        bloom::PeerBloomFilter,
+        message::{Flags, PutMessage},
+        testing::identities,
+    };
+
+    use super::{
+        bucket_size, clamp_replication_level, exceeds_max_hops, forward_count, forwarded,
+        max_hop_count, next_hop_count, target_forward_count,
+    };
+
+    #[test]
+    fn bounds() {
+        assert_eq!(clamp_replication_level(0), 1);
+        assert_eq!(clamp_replication_level(5), 5);
+        assert_eq!(clamp_replication_level(u16::MAX), 16);
+
+        // 1024 peers -> l2nse = 10
+        assert_eq!(max_hop_count(1024), 40);
+        assert!(!exceeds_max_hops(40, 1024));
+        assert!(exceeds_max_hops(41, 1024));
+        assert_eq!(next_hop_count(0, 1024), Some(1));
+        assert_eq!(next_hop_count(39, 1024), Some(40));
+        assert_eq!(next_hop_count(40, 1024), None);
+        assert_eq!(next_hop_count(u16::MAX, u64::MAX), None);
+        assert_eq!(bucket_size(1024, 8), 10);
+        assert_eq!(bucket_size(1025, 8), 11);
+        assert_eq!(bucket_size(100, 8), 8);
+
+        let key = BlockKey::from([1; 64]);
+        let bloom = PeerBloomFilter::default();
+        let flags = Flags::default();
+        let mut put = PutMessage::encode(
+            13,
+            flags,
+            100,
+            Timestamp::FOREVER,
+            bloom,
+            key,
+            None,
+            b"block",
+        )
+        .ok();
+        let mut bloom = PeerBloomFilter::default();
+        for (hop, f) in (1..=40).zip(identities::peers().iter().cycle()) {
+            bloom.insert_peer(&f.peer());
+            put = forwarded(put.as_ref().unwrap(), 1024, &bloom);
+            let parsed = PutMessage::parse(put.as_ref().unwrap().as_bytes()).unwrap();
+            assert_eq!(parsed.hop_count(), hop);
+            assert_eq!(parsed.replication_level(), 16);
+            assert!(parsed.peer_bloom_filter().contains_peer(&f.peer()));
+            assert_eq!(parsed.block(), b"block");
+        }
+        assert!(forwarded(put.as_ref().unwrap(), 1024, &bloom).is_none());
+    }
+
+    #[test]
+    fn fan_out() {
+        // 1 + (REPL_LVL - 1) / (L2NSE + (REPL_LVL - 1) * HOPCOUNT)
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(target_forward_count(5, 0, 1024), 1.4));
+        assert!(close(target_forward_count(5, 1, 1024), 1.0 + 4.0 / 14.0));
+        assert!(close(target_forward_count(16, 0, 1024), 2.5));
+        assert!(close(target_forward_count(5, 20, 1024), 1.0 + 4.0 / 90.0));
+        assert!(close(target_forward_count(0, 0, 1024), 1.0));
+
+        let rng = &mut StdRng::seed_from_u64(0);
+        assert_eq!(forward_count(5, 41, 1024, rng), 0);
+        assert_eq!(forward_count(5, 21, 1024, rng), 1);
+        // 1 + 4/10 at the first hop is 1 or 2
+        for _ in 0..100 {
+            assert!((1..=2).contains(&forward_count(5, 0, 1024, rng)));
+        }
+        // 1 + 15/10 is always at least 2
+        for _ in 0..100 {
+            assert!((2..=3).contains(&forward_count(16, 0, 1024, rng)));
+            assert!((2..=3).contains(&forward_count(u16::MAX, 0, 1024, rng)));
+        }
+        assert_eq!(forward_count(1, 0, 1024, rng), 1);
+        assert_eq!(forward_count(0, 0, 1024, rng), 1);
+        assert_eq!(forward_count(5, 0, 1, rng), 5);
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+use rand::RngCore;
+
+use crate::{
+    block::BlockKey, bloom::PeerBloomFilter, time::Clock, BucketIndex, Distance, Peer, PeerId,
+};
+
+use super::{
+    choose_route, forward_count, insert_route, rank, remove_route, search_order, set_route_weight,
+    InsertOutcome, Occupancy, Route, RoutingTable, RoutingTableConfig,
+};
+
+/// A [`RoutingTable`] that can be shared between threads.
+///
+/// Each k-bucket has its own lock, so lookups never wait on each other and
+/// only wait on writers to the buckets they read. Peers are returned by value
+/// since no lock is held once a method returns.
+pub struct SharedRoutingTable {
+    host: PeerId,
+    config: RoutingTableConfig,
+    bucket_size: AtomicUsize,
+    clock: Arc<dyn Clock>,
+    epoch_offset: Duration,
+    buckets: Box<[RwLock<Vec<Route>>]>,
+    len: AtomicUsize,
+    /// incremented on every change to the table, while the changed bucket is
+    /// still locked
+    generation: AtomicU64,
+}
+
+impl SharedRoutingTable {
+    pub fn new(host: PeerId, config: RoutingTableConfig) -> Self {
+        RoutingTable::new(host, config).into()
+    }
+
+    /// Use this clock for connection times instead of the system clock. This
+    /// should be done before any peers are inserted.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now() + self.epoch_offset
+    }
+
+    pub fn host(&self) -> &PeerId {
+        &self.host
+    }
+
+    pub fn config(&self) -> &RoutingTableConfig {
+        &self.config
+    }
+
+    /// See [`RoutingTable::bucket_size`]
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size.load(Ordering::Relaxed)
+    }
+
+    /// See [`RoutingTable::set_bucket_size`]
+    pub fn set_bucket_size(&self, bucket_size: usize) {
+        self.bucket_size.store(bucket_size, Ordering::Relaxed);
+    }
+
+    /// See [`RoutingTable::generation`]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The number of peers in the table
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of peers in a k-bucket
+    pub fn bucket_len(&self, bucket: BucketIndex) -> usize {
+        self.read(bucket.index()).len()
+    }
+
+    pub fn contains(&self, peer: &Peer) -> bool {
+        let bucket = BucketIndex::between(&self.host, &peer.id());
+        self.read(bucket.index()).iter().any(|r| r.peer == *peer)
+    }
+
+    /// Snapshot the bucket sizes. The buckets are read one at a time, so this
+    /// retries until no writer changed the table while it was reading.
+    pub fn occupancy(&self) -> Occupancy {
+        loop {
+            let generation = self.generation();
+            let buckets: Vec<u32> = (0..self.buckets.len())
+                .map(|d| self.read(d).len() as u32)
+                .collect();
+            if self.generation() == generation {
+                let total = buckets.iter().map(|&n| n as usize).sum();
+                return Occupancy {
+                    generation,
+                    buckets,
+                    total,
+                };
+            }
+        }
+    }
+
+    /// See [`RoutingTable::insert`]
+    pub fn insert(&self, peer: Peer) -> InsertOutcome {
+        let id = peer.id();
+        let dist = BucketIndex::between(&self.host, &id);
+        if dist == BucketIndex::HOST {
+            // that's us
+            return InsertOutcome::Rejected(peer);
+        }
+
+        let route = Route {
+            dist,
+            created: self.now(),
+            peer,
+            id,
+            weight: 1.0,
+        };
+
+        let mut bucket = self.write(dist.index());
+        let outcome = insert_route(&mut bucket, route, self.bucket_size());
+        match outcome {
+            InsertOutcome::Inserted => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+            // evicted straight away, so the bucket is as it was
+            InsertOutcome::BucketFull { evict } if evict == peer => return outcome,
+            _ => {}
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+        outcome
+    }
+
+    /// Remove a disconnected peer from the table.
+    pub fn remove(&self, peer: &Peer) -> Option<Peer> {
+        self.remove_by(&peer.id(), |r| r.peer == *peer)
+    }
+
+    /// Remove the peer with this id from the table.
+    pub fn remove_by_id(&self, id: &PeerId) -> Option<Peer> {
+        self.remove_by(id, |r| r.id == *id)
+    }
+
+    fn remove_by(&self, id: &PeerId, f: impl Fn(&Route) -> bool) -> Option<Peer> {
+        let mut bucket = self.write(BucketIndex::between(&self.host, id).index());
+        let route = remove_route(&mut bucket, f)?;
+
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Release);
+        Some(route.peer)
+    }
+
+    /// Find the `n` peers closest to `key`, closest first.
+    pub fn closest_peers(&self, key: &BlockKey, n: usize) -> Vec<Peer> {
+        self.closest_matching(key, n, |_| true)
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+
+    fn closest_matching(
+        &self,
+        key: &BlockKey,
+        n: usize,
+        mut filter: impl FnMut(&PeerId) -> bool,
+    ) -> Vec<(Peer, PeerId)> {
+        let target = BucketIndex::between(&self.host, &PeerId(key.0));
+
+        let mut closest = Vec::with_capacity(n);
+        for group in search_order(target) {
+            if closest.len() >= n {
+                break;
+            }
+            let guards: Vec<_> = group.map(|d| self.read(d)).collect();
+            let group = rank(key, guards.iter().flat_map(|b| b.iter()), &mut filter);
+            closest.extend(
+                group
+                    .into_iter()
+                    .map(|r| (r.peer, r.id))
+                    .take(n - closest.len()),
+            );
+        }
+        closest
+    }
+
+    /// See [`RoutingTable::set_weight`]
+    pub fn set_weight(&self, peer: &Peer, weight: f64) -> bool {
+        let mut bucket = self.write(BucketIndex::between(&self.host, &peer.id()).index());
+        set_route_weight(&mut bucket, peer, weight)
+    }
+
+    /// See [`RoutingTable::select_peer`]
+    pub fn select_peer(
+        &self,
+        key: &BlockKey,
+        hop_count: u16,
+        bloom: &PeerBloomFilter,
+        network_size: u64,
+        rng: &mut dyn RngCore,
+    ) -> Option<Peer> {
+        let l2nse = (network_size.max(1) as f64).log2();
+        if f64::from(hop_count) < l2nse {
+            let guards: Vec<_> = (0..self.buckets.len()).map(|d| self.read(d)).collect();
+            let candidates: Vec<&Route> = guards
+                .iter()
+                .flat_map(|b| b.iter())
+                .filter(|r| !bloom.contains_peer_id(&r.id))
+                .collect();
+            choose_route(&candidates, rng).map(|r| r.peer)
+        } else {
+            self.closest_matching(key, 1, |id| !bloom.contains_peer_id(id))
+                .pop()
+                .map(|(peer, _)| peer)
+        }
+    }
+
+    /// See [`RoutingTable::get_forwarding_peers`]
+    pub fn get_forwarding_peers(
+        &self,
+        key: &BlockKey,
+        replication_level: u16,
+        hop_count: u16,
+        bloom: &mut PeerBloomFilter,
+        network_size: u64,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Peer> {
+        let count = forward_count(replication_level, hop_count, network_size, rng);
+        let mut peers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let Some(peer) = self.select_peer(key, hop_count, bloom, network_size, rng) else {
+                break;
+            };
+            bloom.insert_peer(&peer);
+            peers.push(peer);
+        }
+        peers
+    }
+
+    /// Whether we are closer to `key` than every peer in the table.
+    pub fn is_closest(&self, key: &BlockKey) -> bool {
+        let ours = Distance::between(&key.0, &self.host.0);
+        match self.closest_matching(key, 1, |_| true).first() {
+            Some((_, id)) => !Distance::between(&key.0, &id.0).is_closer_than(&ours),
+            None => true,
+        }
+    }
+
+    // a panic while holding a bucket lock can't leave the bucket half
+    // modified, so poisoning is ignored
+    fn read(&self, dist: usize) -> RwLockReadGuard<'_, Vec<Route>> {
+        self.buckets[dist]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self, dist: usize) -> RwLockWriteGuard<'_, Vec<Route>> {
+        self.buckets[dist]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Share a table, eg one that was [`restore`](RoutingTable::restore)d.
+impl From<RoutingTable> for SharedRoutingTable {
+    fn from(table: RoutingTable) -> Self {
+        Self {
+            host: table.host,
+            config: table.config,
+            bucket_size: AtomicUsize::new(table.bucket_size),
+            clock: table.clock,
+            epoch_offset: table.epoch_offset,
+            buckets: table.buckets.into_iter().map(RwLock::new).collect(),
+            len: AtomicUsize::new(table.len),
+            generation: AtomicU64::new(table.generation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{
+        block::BlockKey, routing::InsertOutcome, testing::identities, RoutingTable,
+        RoutingTableConfig,
+    };
+
+    use super::SharedRoutingTable;
+
+    #[test]
+    fn concurrent_inserts() {
+        let host = identities::host().peer_id();
+        let table = SharedRoutingTable::new(host, RoutingTableConfig::default());
+        let mut expected = RoutingTable::new(host, RoutingTableConfig::default());
+
+        let peers = identities::peers();
+        thread::scope(|s| {
+            for chunk in peers.chunks(8) {
+                let table = &table;
+                s.spawn(move || {
+                    for f in chunk {
+                        let _ = table.insert(f.peer());
+                        assert!(table.closest_peers(&BlockKey([0; 64]), 4).len() <= 4);
+                    }
+                });
+            }
+        });
+
+        // buckets 511 and 512 overflow, but which peers were evicted depends
+        // on the order the threads ran in
+        for f in peers {
+            let _ = expected.insert(f.peer());
+        }
+        let occupancy = table.occupancy();
+        assert_eq!(occupancy.total(), table.len());
+        assert_eq!(occupancy, expected.occupancy());
+
+        let near = identities::in_bucket(507).next().unwrap();
+        let key = BlockKey(*near.peer_id().as_bytes());
+        assert_eq!(table.closest_peers(&key, 3)[0], near.peer());
+        assert!(table.is_closest(&BlockKey(*host.as_bytes())));
+
+        assert_eq!(table.remove(&near.peer()), Some(near.peer()));
+        assert!(!table.contains(&near.peer()));
+        let us = identities::host().peer();
+        assert_eq!(table.insert(us), InsertOutcome::Rejected(us));
+    }
+}
@@ -0,0 +1,592 @@
+//! A network of DHT nodes in one process, on a virtual clock, for measuring
+//! how protocol changes behave.
+//!
+//! Messages are delivered in rounds: by default everything sent in one
+//! round arrives in the next, so the number of rounds a lookup takes is the
+//! number of hops its messages made. [`Latency`], [`SimConfig::loss`] and
+//! [`Churn`] make links slower, lossy, and peers come and go.
+//!
+//! Identities, the initial topology, latencies, losses, churn and the
+//! nodes' own random choices all come from [`SimConfig::seed`], so the same
+//! seed runs the same simulation.
+//!
+//! ```
+//! # use r6n::{block::BlockKey, sim::{SimConfig, Simulation}};
+//! let mut sim = Simulation::new(SimConfig { nodes: 20, ..Default::default() });
+//! let key = BlockKey::from([1; 64]);
+//! sim.put(0, key, 13, b"hello");
+//! sim.run_until_quiet(100);
+//! let rounds = sim.run_get(19, key, 13, 100).expect("the block is found");
+//! assert!(rounds <= 20);
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use ed25519_dalek::SigningKey;
+use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+
+use crate::{
+    block::{BlockKey, Timestamp},
+    config::{DhtBuilder, DhtConfig},
+    identity::LocalPeer,
+    maintenance::Budget,
+    query::{GetOptions, QueryEvent, QueryId},
+    time::MockClock,
+    underlay::{
+        memory::{Delivery, MemoryAddress, MemoryNetwork, MemoryUnderlay},
+        Underlay, UnderlaySignal,
+    },
+    DhtNode, Peer,
+};
+
+/// When the virtual clock starts, as a UNIX time in seconds. Late enough
+/// that expirations look like real ones.
+const SIM_EPOCH: u64 = 1_700_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// How many nodes there are
+    pub nodes: usize,
+    /// How many random peers each node connects to at the start. Nodes end
+    /// up with about twice this many connections.
+    pub degree: usize,
+    /// Decides the identities and topology
+    pub seed: u64,
+    /// How far the virtual clock moves each round
+    pub round_time: Duration,
+    /// How many rounds messages take to arrive
+    pub latency: Latency,
+    /// The chance that a message is lost, between 0 and 1
+    pub loss: f64,
+    pub churn: Churn,
+    /// The configuration of every node
+    pub dht: DhtConfig,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 32,
+            degree: 4,
+            seed: 0,
+            round_time: Duration::from_millis(100),
+            latency: Latency::Fixed(1),
+            loss: 0.0,
+            churn: Churn::default(),
+            dht: DhtConfig::default(),
+        }
+    }
+}
+
+/// How many rounds a message takes to arrive. Anything under one round is
+/// rounded up to one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(u32),
+    /// Uniformly between the two, inclusive
+    Uniform(u32, u32),
+    /// At least `min` rounds, plus an exponentially distributed number
+    /// averaging `mean`, for the occasional very slow link
+    Exponential {
+        min: u32,
+        mean: f64,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut impl Rng) -> u32 {
+        let rounds = match *self {
+            Self::Fixed(rounds) => rounds,
+            Self::Uniform(min, max) => rng.gen_range(min..=max.max(min)),
+            Self::Exponential { min, mean } => {
+                let tail = -mean * (1.0 - rng.gen::<f64>()).ln();
+                min.saturating_add(tail as u32)
+            }
+        };
+        rounds.max(1)
+    }
+}
+
+/// Peers leaving and coming back. Each round, every online node leaves with
+/// chance [`leave`](Self::leave) and every offline one comes back with
+/// chance [`join`](Self::join), so sessions last `1 / leave` rounds on
+/// average.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Churn {
+    /// Between 0 and 1
+    pub leave: f64,
+    /// Between 0 and 1
+    pub join: f64,
+    /// How many nodes, counting from the first, never leave. Handy for
+    /// putting and getting from.
+    pub stable: usize,
+}
+
+/// Something to happen at the start of a round, see
+/// [`Simulation::schedule`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    Put {
+        node: usize,
+        key: BlockKey,
+        block_type: u32,
+        data: Vec<u8>,
+    },
+    Get {
+        node: usize,
+        key: BlockKey,
+        block_type: u32,
+    },
+    /// Break the connection between two nodes
+    Disconnect(usize, usize),
+    /// Disconnect a node from everyone. It stops processing anything.
+    Leave(usize),
+    /// Bring back a node that left, connected to [`SimConfig::degree`]
+    /// random online nodes
+    Join(usize),
+}
+
+/// A GET started by the simulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimQuery {
+    pub node: usize,
+    pub id: QueryId,
+    /// The round it was started in
+    pub round: u64,
+}
+
+/// A block a [`SimQuery`] found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimResult {
+    pub node: usize,
+    pub id: QueryId,
+    /// The round it arrived in
+    pub round: u64,
+    pub block: Vec<u8>,
+}
+
+struct SimNode {
+    peer: Peer,
+    node: DhtNode<MemoryUnderlay>,
+    signals: mpsc::Receiver<UnderlaySignal<MemoryUnderlay>>,
+    online: bool,
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    network: MemoryNetwork,
+    clock: Arc<MockClock>,
+    nodes: Vec<SimNode>,
+    rng: StdRng,
+    round: u64,
+    events: BTreeMap<u64, Vec<Event>>,
+    queries: Vec<SimQuery>,
+    results: Vec<SimResult>,
+}
+
+impl Simulation {
+    /// Build the network and connect each node to [`SimConfig::degree`]
+    /// random others. Nothing is delivered until the first round.
+    pub fn new(config: SimConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let network = MemoryNetwork::new();
+        // every message waits for at least the next round
+        let mut links = StdRng::seed_from_u64(rng.gen());
+        let SimConfig { latency, loss, .. } = config;
+        network.set_hook(move |_, _| match links.gen_bool(loss) {
+            true => Delivery::Drop,
+            false => Delivery::Delay(latency.sample(&mut links) - 1),
+        });
+        let start = Timestamp::from_micros(SIM_EPOCH * 1_000_000);
+        let clock = Arc::new(MockClock::new(start));
+
+        let nodes: Vec<_> = (0..config.nodes)
+            .map(|_| {
+                let identity = LocalPeer::new(SigningKey::from_bytes(&rng.gen()));
+                let peer = identity.peer();
+                let (underlay, signals) = network.join(peer);
+                let node = DhtBuilder::new(underlay)
+                    .identity(identity)
+                    .clock(clock.clone())
+                    .rng(StdRng::seed_from_u64(rng.gen()))
+                    .config(config.dht)
+                    .build()
+                    .expect("SimConfig::dht should be valid");
+                SimNode {
+                    peer,
+                    node,
+                    signals,
+                    online: true,
+                }
+            })
+            .collect();
+
+        for (i, from) in nodes.iter().enumerate() {
+            let others = (0..nodes.len()).filter(|&j| j != i);
+            for j in others.choose_multiple(&mut rng, config.degree) {
+                let to = nodes[j].node.underlay().address();
+                let peer = nodes[j].peer;
+                from.node
+                    .underlay()
+                    .try_connect(peer, to)
+                    .expect("members of the network are reachable");
+            }
+        }
+
+        Self {
+            config,
+            network,
+            clock,
+            nodes,
+            rng,
+            round: 0,
+            events: BTreeMap::new(),
+            queries: vec![],
+            results: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The number of rounds run so far
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn node(&self, node: usize) -> &DhtNode<MemoryUnderlay> {
+        &self.nodes[node].node
+    }
+
+    pub fn node_mut(&mut self, node: usize) -> &mut DhtNode<MemoryUnderlay> {
+        &mut self.nodes[node].node
+    }
+
+    pub fn peer(&self, node: usize) -> Peer {
+        self.nodes[node].peer
+    }
+
+    /// Whether a node is part of the network, ie hasn't
+    /// [left](Event::Leave)
+    pub fn is_online(&self, node: usize) -> bool {
+        self.nodes[node].online
+    }
+
+    pub fn online(&self) -> usize {
+        self.nodes.iter().filter(|n| n.online).count()
+    }
+
+    /// Every block found by a GET so far, in the order they arrived
+    pub fn results(&self) -> &[SimResult] {
+        &self.results
+    }
+
+    /// Run `event` at the start of round `round`, or of the next round if
+    /// that has passed.
+    pub fn schedule(&mut self, round: u64, event: Event) {
+        let round = round.max(self.round);
+        self.events.entry(round).or_default().push(event);
+    }
+
+    /// Store a block from `node` now, returning how many peers it was sent
+    /// to. It reaches them in the next round.
+    pub fn put(&mut self, node: usize, key: BlockKey, block_type: u32, data: &[u8]) -> usize {
+        let options = self.config.dht.put;
+        let node = &mut self.nodes[node].node;
+        node.put(block_type, key, data, &options).unwrap_or(0)
+    }
+
+    /// Start a GET from `node` now. Its results show up in
+    /// [`results`](Self::results).
+    pub fn get(&mut self, node: usize, key: BlockKey, block_type: u32) -> SimQuery {
+        let options = GetOptions::default();
+        let id = self.nodes[node].node.get(key, block_type, vec![], options);
+        let query = SimQuery {
+            node,
+            id,
+            round: self.round,
+        };
+        self.queries.push(query);
+        query
+    }
+
+    /// How many rounds after it started `query` got its first result
+    pub fn first_result(&self, query: &SimQuery) -> Option<u64> {
+        self.results
+            .iter()
+            .find(|r| r.node == query.node && r.id == query.id)
+            .map(|r| r.round - query.round)
+    }
+
+    /// Start a GET and run until it finds something, for at most
+    /// `max_rounds`. Returns how many rounds that took, which with the
+    /// default latency is the hops of the GET and of the RESULT coming back
+    /// together.
+    pub fn run_get(
+        &mut self,
+        node: usize,
+        key: BlockKey,
+        block_type: u32,
+        max_rounds: u64,
+    ) -> Option<u64> {
+        let query = self.get(node, key, block_type);
+        for _ in 0..max_rounds {
+            if let Some(rounds) = self.first_result(&query) {
+                return Some(rounds);
+            }
+            self.step();
+        }
+        self.first_result(&query)
+    }
+
+    /// Run rounds until no messages are left in flight, for at most
+    /// `max_rounds`. Returns how many rounds ran.
+    pub fn run_until_quiet(&mut self, max_rounds: u64) -> u64 {
+        for n in 0..max_rounds {
+            let handled = self.step();
+            if handled == 0 && self.network.held() == 0 && self.events.is_empty() {
+                return n + 1;
+            }
+        }
+        max_rounds
+    }
+
+    /// Run one round: apply this round's events and churn, deliver what
+    /// has arrived, let every node handle it, and move the clock on. Returns
+    /// how many signals were handled.
+    pub fn step(&mut self) -> usize {
+        for event in self.events.remove(&self.round).unwrap_or_default() {
+            self.apply(event);
+        }
+        self.churn();
+        self.network.release_held();
+
+        let mut handled = 0;
+        for (i, sim) in self.nodes.iter_mut().enumerate() {
+            while let Ok(signal) = sim.signals.try_recv() {
+                if sim.online {
+                    sim.node.handle_signal(signal);
+                    handled += 1;
+                }
+            }
+            while let Some(event) = sim.node.next_query_event() {
+                if let QueryEvent::Result { id, block, .. } = event {
+                    self.results.push(SimResult {
+                        node: i,
+                        id,
+                        round: self.round,
+                        block,
+                    });
+                }
+            }
+        }
+
+        self.clock.advance(self.config.round_time);
+        for sim in self.nodes.iter_mut().filter(|n| n.online) {
+            sim.node.tick(Budget::unlimited());
+        }
+        self.round += 1;
+        handled
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Put {
+                node,
+                key,
+                block_type,
+                data,
+            } => {
+                self.put(node, key, block_type, &data);
+            }
+            Event::Get {
+                node,
+                key,
+                block_type,
+            } => {
+                self.get(node, key, block_type);
+            }
+            Event::Disconnect(a, b) => {
+                self.network.disconnect(MemoryAddress(a), MemoryAddress(b));
+            }
+            Event::Leave(node) => self.leave(node),
+            Event::Join(node) => self.join(node),
+        }
+    }
+
+    fn churn(&mut self) {
+        let Churn {
+            leave,
+            join,
+            stable,
+        } = self.config.churn;
+        for node in stable..self.nodes.len() {
+            match self.nodes[node].online {
+                true if self.rng.gen_bool(leave) => self.leave(node),
+                false if self.rng.gen_bool(join) => self.join(node),
+                _ => {}
+            }
+        }
+    }
+
+    fn leave(&mut self, node: usize) {
+        for other in 0..self.nodes.len() {
+            self.network
+                .disconnect(MemoryAddress(node), MemoryAddress(other));
+        }
+        // let it forget its peers, so it doesn't try them when it comes back
+        let sim = &mut self.nodes[node];
+        while let Ok(signal) = sim.signals.try_recv() {
+            sim.node.handle_signal(signal);
+        }
+        sim.online = false;
+    }
+
+    fn join(&mut self, node: usize) {
+        if self.nodes[node].online {
+            return;
+        }
+        self.nodes[node].online = true;
+        let others = (0..self.nodes.len()).filter(|&j| j != node && self.nodes[j].online);
+        for j in others.choose_multiple(&mut self.rng, self.config.degree) {
+            let to = self.nodes[j].node.underlay().address();
+            let peer = self.nodes[j].peer;
+            self.nodes[node]
+                .node
+                .underlay()
+                .try_connect(peer, to)
+                .expect("members of the network are reachable");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::BlockKey;
+
+    use super::{Churn, Event, Latency, SimConfig, Simulation};
+
+    #[test]
+    fn get_converges() {
+        let mut sim = Simulation::new(SimConfig {
+            nodes: 40,
+            ..Default::default()
+        });
+        sim.run_until_quiet(100);
+        for node in 0..sim.len() {
+            assert!(sim.node(node).routing_table().len() >= 4);
+        }
+
+        let key = BlockKey::from([7; 64]);
+        sim.put(0, key, 13, b"sim");
+        sim.run_until_quiet(100);
+        let rounds = sim.run_get(39, key, 13, 100).unwrap();
+        // log2(40) hops of random walk, then greedy routing, and back
+        assert!(rounds <= 30, "took {rounds} rounds");
+        assert_eq!(sim.results()[0].block, b"sim");
+    }
+
+    #[test]
+    fn events() {
+        let mut sim = Simulation::new(SimConfig {
+            nodes: 10,
+            degree: 2,
+            ..Default::default()
+        });
+        let key = BlockKey::from([3; 64]);
+        sim.schedule(
+            5,
+            Event::Put {
+                node: 1,
+                key,
+                block_type: 13,
+                data: b"late".to_vec(),
+            },
+        );
+        sim.schedule(
+            20,
+            Event::Get {
+                node: 2,
+                key,
+                block_type: 13,
+            },
+        );
+        sim.schedule(3, Event::Leave(9));
+        sim.run_until_quiet(100);
+        assert!(sim.round() > 20);
+        assert!(sim
+            .node(0)
+            .routing_table()
+            .iter()
+            .all(|r| *r.peer() != sim.peer(9)));
+        assert!(sim.results().iter().any(|r| r.node == 2 && r.round >= 20));
+    }
+
+    #[test]
+    fn churn() {
+        let mut sim = Simulation::new(SimConfig {
+            nodes: 60,
+            latency: Latency::Uniform(1, 3),
+            loss: 0.01,
+            churn: Churn {
+                leave: 0.005,
+                join: 0.1,
+                stable: 2,
+            },
+            ..Default::default()
+        });
+        let keys = (1..=8).map(|i| BlockKey::from([i; 64]));
+        for key in keys.clone() {
+            let data = b"churn".to_vec();
+            let block_type = 13;
+            sim.schedule(
+                20,
+                Event::Put {
+                    node: 0,
+                    key,
+                    block_type,
+                    data,
+                },
+            );
+        }
+        // churn only depends on the seed, so this is the same every time
+        for _ in 0..100 {
+            sim.step();
+        }
+        assert!(sim.online() < sim.len());
+
+        // the odd block sits only on peers that are away
+        let found = keys.filter(|&key| sim.run_get(1, key, 13, 200).is_some());
+        assert!(found.count() >= 6);
+    }
+
+    #[test]
+    fn deterministic() {
+        let run = || {
+            let mut sim = Simulation::new(SimConfig {
+                nodes: 30,
+                latency: Latency::Exponential { min: 1, mean: 1.0 },
+                loss: 0.02,
+                seed: 7,
+                ..Default::default()
+            });
+            for i in 1..=5 {
+                let key = BlockKey::from([i; 64]);
+                sim.put(usize::from(i), key, 13, &[i]);
+                sim.run_until_quiet(50);
+                sim.run_get(29, key, 13, 50);
+            }
+            (sim.round(), sim.results().to_vec())
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,76 @@
+//! What a node keeps across restarts.
+//!
+//! [`DhtNode::state`](crate::DhtNode::state) copies the routing table, the
+//! datacache and the HELLO cache, which can be saved to a directory, one
+//! file each, and loaded again on the next start.
+
+use std::{fs, io, path::Path};
+
+const ROUTING: &str = "routing";
+const DATACACHE: &str = "datacache";
+const HELLOS: &str = "hellos";
+
+/// Snapshots of a node's state. Each is in the format of its own
+/// `snapshot` method, eg [`RoutingTable::snapshot`](crate::RoutingTable::snapshot).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeState {
+    pub routing: Vec<u8>,
+    pub datacache: Vec<u8>,
+    pub hellos: Vec<u8>,
+}
+
+impl NodeState {
+    /// Write the snapshots to `dir`, creating it if needed. Each file is
+    /// replaced whole, so a crash part way leaves the old one.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for (name, snapshot) in self.files() {
+            let tmp = dir.join(format!("{name}.tmp"));
+            fs::write(&tmp, snapshot)?;
+            fs::rename(tmp, dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Read the snapshots [`save`](Self::save)d to `dir`. Missing files
+    /// are left empty, which restores nothing.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let read = |name| match fs::read(dir.join(name)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            read => read,
+        };
+        Ok(Self {
+            routing: read(ROUTING)?,
+            datacache: read(DATACACHE)?,
+            hellos: read(HELLOS)?,
+        })
+    }
+
+    fn files(&self) -> [(&str, &[u8]); 3] {
+        [
+            (ROUTING, &self.routing),
+            (DATACACHE, &self.datacache),
+            (HELLOS, &self.hellos),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeState;
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("r6n-state-{}", std::process::id()));
+        let state = NodeState {
+            routing: b"routing".to_vec(),
+            datacache: b"datacache".to_vec(),
+            hellos: vec![],
+        };
+        state.save(&dir).unwrap();
+        assert_eq!(NodeState::load(&dir).unwrap(), state);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(NodeState::load(&dir).unwrap(), NodeState::default());
+    }
+}
@@ -0,0 +1,7 @@
+//! Helpers for writing tests against this crate.
+
+pub mod identities;
+#[cfg(any(test, feature = "proptest"))]
+pub mod proptest;
+#[cfg(feature = "std")]
+pub mod random;
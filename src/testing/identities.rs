@@ -0,0 +1,66 @@
+//! A fixed set of identities, derived at build time from a constant seed.
+//!
+//! Every fixture records its k-bucket relative to the first identity, so
+//! tests can pick peers that land in the same or in different buckets
+//! without searching for keys at runtime.
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::SigningKey;
+
+use crate::{Peer, PeerId};
+
+pub struct Fixture {
+    pub secret: [u8; 32],
+    pub public: [u8; 32],
+    pub id: [u8; 64],
+    /// log2 XOR distance to [`host`]
+    pub bucket: u16,
+}
+
+impl Fixture {
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.secret)
+    }
+
+    pub fn peer(&self) -> Peer {
+        Peer(CompressedEdwardsY(self.public))
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId(self.id)
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/identities.rs"));
+
+/// The identity that all [`Fixture::bucket`]s are relative to.
+pub fn host() -> &'static Fixture {
+    &IDENTITIES[0]
+}
+
+/// All identities except the [`host`].
+pub fn peers() -> &'static [Fixture] {
+    &IDENTITIES[1..]
+}
+
+/// The identities in the host's k-bucket at this distance.
+pub fn in_bucket(bucket: u16) -> impl Iterator<Item = &'static Fixture> {
+    peers().iter().filter(move |f| f.bucket == bucket)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log2_xor_dist;
+
+    use super::{host, peers};
+
+    #[test]
+    fn fixtures_are_consistent() {
+        assert_eq!(host().bucket, 0);
+        for f in peers() {
+            assert_eq!(f.signing_key().verifying_key().to_bytes(), f.public);
+            assert_eq!(f.peer().id().0, f.id);
+            assert_eq!(log2_xor_dist(&host().peer_id(), &f.peer_id()), f.bucket);
+        }
+    }
+}
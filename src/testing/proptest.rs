@@ -0,0 +1,43 @@
+//! [proptest] strategies for peers and keys, for property tests here and
+//! downstream.
+//!
+//! ```
+//! # use proptest::prelude::*;
+//! # use r6n::{testing::proptest::{block_key, peer_id}, RoutingTable};
+//! proptest!(|(host in peer_id(), key in block_key())| {
+//!     let table = RoutingTable::new(host, Default::default());
+//!     prop_assert!(table.closest_peers(&key, 5).is_empty());
+//! });
+//! ```
+
+use ::proptest::prelude::*;
+use ed25519_dalek::SigningKey;
+
+use super::random::set_distance;
+use crate::{block::BlockKey, Peer, PeerId};
+
+/// Peers with valid public keys. Shrinks towards the key of the all zero
+/// secret.
+pub fn peer() -> impl Strategy<Value = Peer> {
+    any::<[u8; 32]>().prop_map(|secret| SigningKey::from_bytes(&secret).verifying_key().into())
+}
+
+/// The IDs of peers from [`peer`]
+pub fn peer_id() -> impl Strategy<Value = PeerId> {
+    peer().prop_map(|peer| peer.id())
+}
+
+pub fn block_key() -> impl Strategy<Value = BlockKey> {
+    any::<[u8; 64]>().prop_map(BlockKey::from)
+}
+
+/// Keys at log2 XOR distance `dist` from `from`, as with
+/// [`random::key_at_distance`](super::random::key_at_distance)
+pub fn key_at_distance(from: &[u8; 64], dist: u16) -> impl Strategy<Value = BlockKey> {
+    assert!(dist <= 512, "distances are at most 512");
+    let from = *from;
+    any::<[u8; 64]>().prop_map(move |mut key| {
+        set_distance(&from, dist, &mut key);
+        BlockKey::from(key)
+    })
+}
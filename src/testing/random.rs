@@ -0,0 +1,74 @@
+//! Random peers and keys, for property tests here and downstream.
+//!
+//! Each takes the RNG to use, so tests can seed one, eg with
+//! `StdRng::seed_from_u64`, and replay a failure.
+
+use ed25519_dalek::SigningKey;
+use rand::Rng;
+
+use crate::{block::BlockKey, Peer, PeerId};
+
+/// A peer with a valid public key
+pub fn peer(rng: &mut impl Rng) -> Peer {
+    SigningKey::from_bytes(&rng.gen()).verifying_key().into()
+}
+
+/// The ID of a peer from [`peer`]. IDs are hashes, so random bytes would
+/// do just as well.
+pub fn peer_id(rng: &mut impl Rng) -> PeerId {
+    peer(rng).id()
+}
+
+pub fn block_key(rng: &mut impl Rng) -> BlockKey {
+    let mut key = [0; 64];
+    rng.fill(&mut key[..]);
+    BlockKey::from(key)
+}
+
+/// A key at log2 XOR distance `dist` from `from`, eg to aim at one of a
+/// routing table's buckets. A `dist` of 0 gives `from` itself.
+pub fn key_at_distance(rng: &mut impl Rng, from: &[u8; 64], dist: u16) -> BlockKey {
+    assert!(dist <= 512, "distances are at most 512");
+    let mut key = [0; 64];
+    rng.fill(&mut key[..]);
+    set_distance(from, dist, &mut key);
+    BlockKey::from(key)
+}
+
+/// Turn the random bytes in `key` into a key at `dist` from `from`: the
+/// bits above the highest differing one are copied from `from`, and the
+/// ones below it stay random.
+pub(super) fn set_distance(from: &[u8; 64], dist: u16, key: &mut [u8; 64]) {
+    if dist == 0 {
+        *key = *from;
+        return;
+    }
+    // the highest differing bit is bit dist - 1, counting from the end
+    let bit = 512 - dist as usize;
+    let (byte, shift) = (bit / 8, 7 - bit % 8);
+    let below = (1u8 << shift) - 1;
+    key[..byte].copy_from_slice(&from[..byte]);
+    key[byte] = (from[byte] ^ 1 << shift) & !below | key[byte] & below;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{log2_xor_dist, PeerId};
+
+    use super::{block_key, key_at_distance};
+
+    #[test]
+    fn distances() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let from = block_key(&mut rng);
+            for dist in [0, 1, 7, 8, 9, 256, 511, 512] {
+                let key = key_at_distance(&mut rng, &from.0, dist);
+                let [a, b] = [from, key].map(|k| PeerId::from_bytes(k.0));
+                assert_eq!(log2_xor_dist(&a, &b), dist);
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::block::Timestamp;
+
+mod driver;
+
+#[cfg(feature = "tokio")]
+pub use driver::TokioTimer;
+pub use driver::{ManualTimer, TimerDriver};
+
+/// A source of time for the routing table and the engine. Swapping it out
+/// makes tests and simulations deterministic.
+pub trait Clock: Send + Sync {
+    /// Monotonic time since some fixed point, eg when the clock was created.
+    fn now(&self) -> Duration;
+
+    /// The current wall clock time, used to check block expiration.
+    fn timestamp(&self) -> Timestamp;
+}
+
+/// The real clock. In browsers this uses `performance.now()` and `Date.now()`.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn timestamp(&self) -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp::from_micros(since_epoch.as_micros() as u64)
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock {
+    /// microseconds since the clock was created
+    now: AtomicU64,
+    /// the wall clock time when the clock was created, in microseconds
+    unix_start: u64,
+}
+
+impl MockClock {
+    /// A clock starting at 0 whose wall clock time starts at `unix_start`.
+    pub fn new(unix_start: Timestamp) -> Self {
+        Self {
+            now: AtomicU64::new(0),
+            unix_start: unix_start.as_micros(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Move the clock to `now`. Like a real monotonic clock, it never goes
+    /// backwards.
+    pub fn set(&self, now: Duration) {
+        self.now
+            .fetch_max(now.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(Timestamp::from_micros(0))
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.now.load(Ordering::Relaxed))
+    }
+
+    fn timestamp(&self) -> Timestamp {
+        Timestamp::from_micros(self.unix_start + self.now.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::block::Timestamp;
+
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn mock() {
+        let clock = MockClock::new(Timestamp::from_micros(1_000_000));
+        assert_eq!(clock.now(), Duration::ZERO);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now(), Duration::from_secs(2));
+        assert_eq!(clock.timestamp().as_micros(), 3_000_000);
+
+        clock.set(Duration::from_secs(1));
+        assert_eq!(clock.now(), Duration::from_secs(2));
+
+        let expires = Timestamp::from_micros(2_500_000);
+        assert!(expires.is_expired(clock.timestamp()));
+        assert!(!Timestamp::FOREVER.is_expired(clock.timestamp()));
+    }
+}
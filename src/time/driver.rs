@@ -0,0 +1,240 @@
+//! What drivers wait on between [`tick`](crate::DhtNode::tick)s.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use super::{Clock, MockClock};
+
+/// A source of timers for a driver like [`Dht`](crate::client::Dht).
+/// Deadlines are in the time of [`clock`](Self::clock), which the node
+/// should run on too, so a driver with a virtual clock can skip ahead
+/// instead of sleeping.
+pub trait TimerDriver: Send + Sync + 'static {
+    fn clock(&self) -> Arc<dyn Clock>;
+
+    /// Wait until the clock reaches `deadline`
+    fn sleep_until(&self, deadline: Duration) -> impl Future<Output = ()> + Send;
+}
+
+/// Real timers, on the tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct TokioTimer {
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioTimer {
+    /// Sleep until `clock` reaches each deadline. It should move with real
+    /// time, like [`SystemClock`](super::SystemClock).
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
+    }
+
+    /// A timer on a clock that follows tokio's, so that with
+    /// `tokio::time::pause` the node skips ahead whenever it would sleep.
+    pub fn tokio_time() -> Self {
+        Self::new(Arc::new(TokioClock::new()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl TimerDriver for TokioTimer {
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(deadline.saturating_sub(self.clock.now()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+struct TokioClock {
+    start: tokio::time::Instant,
+    unix_start: crate::block::Timestamp,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioClock {
+    fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            unix_start: super::SystemClock::new().timestamp(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // wall clock time moves with tokio's too, so paused tests expire blocks
+    fn timestamp(&self) -> crate::block::Timestamp {
+        let elapsed = self.now().as_micros() as u64;
+        self.unix_start
+            .saturating_add(Duration::from_micros(elapsed))
+    }
+}
+
+/// Timers for tests and simulations: time only moves when told to, and
+/// sleepers wake once it passes their deadline.
+#[derive(Clone, Default)]
+pub struct ManualTimer {
+    clock: Arc<MockClock>,
+    sleepers: Arc<Mutex<Sleepers>>,
+}
+
+#[derive(Default)]
+struct Sleepers {
+    next_id: u64,
+    waiting: BTreeMap<u64, (Duration, Waker)>,
+}
+
+impl ManualTimer {
+    pub fn new(clock: Arc<MockClock>) -> Self {
+        Self {
+            clock,
+            sleepers: Default::default(),
+        }
+    }
+
+    pub fn mock_clock(&self) -> &Arc<MockClock> {
+        &self.clock
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.clock.advance(by);
+        self.wake();
+    }
+
+    /// The earliest deadline anyone is waiting for
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let sleepers = self.lock();
+        sleepers.waiting.values().map(|&(at, _)| at).min()
+    }
+
+    /// Jump to the earliest deadline anyone is waiting for and wake them,
+    /// returning it. Does nothing if nobody is waiting.
+    pub fn fast_forward(&self) -> Option<Duration> {
+        let deadline = self.next_deadline()?;
+        self.clock.set(deadline);
+        self.wake();
+        Some(deadline)
+    }
+
+    fn wake(&self) {
+        let now = self.clock.now();
+        let mut sleepers = self.lock();
+        sleepers.waiting.retain(|_, (at, waker)| {
+            if *at > now {
+                return true;
+            }
+            waker.wake_by_ref();
+            false
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Sleepers> {
+        self.sleepers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl TimerDriver for ManualTimer {
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> impl Future<Output = ()> + Send {
+        let id = {
+            let mut sleepers = self.lock();
+            sleepers.next_id += 1;
+            sleepers.next_id
+        };
+        ManualSleep {
+            timer: self.clone(),
+            deadline,
+            id,
+        }
+    }
+}
+
+struct ManualSleep {
+    timer: ManualTimer,
+    deadline: Duration,
+    id: u64,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.timer.clock.now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let entry = (self.deadline, cx.waker().clone());
+        self.timer.lock().waiting.insert(self.id, entry);
+        Poll::Pending
+    }
+}
+
+// dropped sleeps shouldn't hold up fast_forward
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        self.timer.lock().waiting.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        time::Duration,
+    };
+
+    use super::{ManualTimer, TimerDriver};
+
+    struct Flag(std::sync::atomic::AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn manual() {
+        let timer = ManualTimer::default();
+        let flag = Arc::new(Flag(Default::default()));
+        let waker = flag.clone().into();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sleep = pin!(timer.sleep_until(Duration::from_secs(60)));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(timer.next_deadline(), Some(Duration::from_secs(60)));
+
+        timer.advance(Duration::from_secs(1));
+        assert!(!flag.0.load(std::sync::atomic::Ordering::Relaxed));
+        assert_eq!(timer.fast_forward(), Some(Duration::from_secs(60)));
+        assert!(flag.0.load(std::sync::atomic::Ordering::Relaxed));
+        assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Ready(()));
+        assert_eq!(timer.fast_forward(), None);
+
+        // nobody waits on a dropped sleep
+        let mut sleep = Box::pin(timer.sleep_until(Duration::from_secs(90)));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        drop(sleep);
+        assert_eq!(timer.next_deadline(), None);
+    }
+}
@@ -1,23 +1,54 @@
+use std::{fmt, task::Poll};
+
 use crate::{Message, Peer};
 
+pub mod composite;
+pub mod hold;
+#[cfg(feature = "libp2p")]
+pub mod libp2p;
+pub mod memory;
+#[cfg(feature = "tokio")]
+mod queue;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod scheme;
+#[cfg(feature = "tokio")]
+pub mod tcp;
+#[cfg(feature = "tokio")]
+pub mod udp;
+#[cfg(all(feature = "websocket", target_arch = "wasm32", target_os = "unknown"))]
+pub mod websocket;
+
+pub use hold::HoldTracker;
+pub use scheme::{AddressSchemes, SchemePreference};
+
 /// R5N does not specify an underlay network. This is the application's
 /// responsibility to provide.
+///
+/// The DHT calls these methods while processing signals, so they must not
+/// block. Implementations that do IO asynchronously, eg on a tokio runtime,
+/// should queue the request for their own tasks and report the outcome later
+/// through an [`UnderlaySignal`].
 pub trait Underlay {
-    type Address;
-    type NetworkSizeEstimate;
+    /// Addresses are advertised in HELLOs as their [`Display`](fmt::Display)
+    /// string, which should be of the form `scheme://rest`.
+    type Address: Clone + PartialEq + fmt::Display;
+    type NetworkSizeEstimate: Into<u64>;
+    /// Why a connection attempt or send could not be started
+    type Error;
 
     /// This call allows the DHT implementation to signal to the underlay that
     /// the DHT wants to establish a connection to the target peer using the
     /// given address. If the connection attempt is successful, information
     /// on the new peer connection will be offered through the `peer_connected`
     /// signal.
-    fn try_connect(peer: Peer, addr: Self::Address);
+    fn try_connect(&self, peer: Peer, addr: Self::Address) -> Result<(), Self::Error>;
 
     /// This call tells the underlay to hold on to a connection to a peer.
     /// Underlays are usually limited in the number of active connections.
     /// With this function the DHT can indicate to the underlay which
     /// connections should preferably be preserved.
-    fn hold(peer: Peer);
+    fn hold(&self, peer: Peer);
 
     /// This call tells the underlay to drop the connection to a peer. This
     /// call is only there for symmetry and used during the peer's shutdown to
@@ -28,14 +59,24 @@ pub trait Underlay {
     /// being true. A call to [`drop`] also does not imply that the underlay
     /// must close the connection: it merely removes the preference to preserve
     /// the connection that was established by [`hold`](Underlay::hold).
-    fn drop(peer: Peer);
+    fn drop(&self, peer: Peer);
 
     /// This call allows the local peer to send a protocol message to a peer.
     /// Sending messages is expected to be done on a best-effort basis, thus
     /// the underlay does not have to guarantee delivery or message ordering.
     /// If the underlay implements flow- or congestion-control, it may discard
     /// messages to limit its queue size.
-    fn send(peer: Peer, message: Message);
+    fn send(&self, peer: Peer, message: Message) -> Result<(), Self::Error>;
+
+    /// Whether the connection to `peer` can take another message now.
+    /// Underlays with bounded send queues return [`Poll::Pending`] while
+    /// theirs is full, and signal [`SendReady`](UnderlaySignal::SendReady)
+    /// once it has room again. Until then the node keeps the peer's
+    /// messages in its own [queue](crate::outbound), rather than having
+    /// them dropped here. By default connections are always ready.
+    fn poll_ready(&self, _peer: Peer) -> Poll<()> {
+        Poll::Ready(())
+    }
 
     /// This call must return an estimate of the network size. The resulting
     /// [`NetworkSizeEstimate`](Underlay::NetworkSizeEstimate) value must be
@@ -53,7 +94,7 @@ pub enum UnderlaySignal<U: Underlay> {
     /// connection, for example to indicate that the connection is from a
     /// resource-constrained host that does not intend to function as a full
     /// peer and thus should not be considered for routing.
-    PeerConnected(Peer),
+    PeerConnected(Peer, ConnectionInfo),
     /// This signal allows the DHT to react to a recently disconnected peer.
     /// Such an event primarily triggers updates in the routing table.
     PeerDisconnected(Peer),
@@ -70,4 +111,34 @@ pub enum UnderlaySignal<U: Underlay> {
     /// This signal informs the local peer that a protocol message was received
     /// from a peer.
     Receive(Peer, Message),
+    /// The connection to a peer that wasn't [ready](Underlay::poll_ready)
+    /// has room for messages again.
+    SendReady(Peer),
+}
+
+/// What the underlay knows about a new connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionInfo {
+    /// The peer is a resource constrained host, eg a browser or phone, that
+    /// doesn't want to be routed through. It is not added to the routing
+    /// table.
+    pub constrained: bool,
+    /// How expensive the connection is to use relative to others, eg for
+    /// metered links. 0 is the cheapest.
+    pub cost: u32,
+    /// The largest message the connection can carry, if it is smaller than
+    /// the protocol's own limit
+    pub mtu: Option<u16>,
 }
+
+/// An address string was not in the format of the underlay's address scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseAddressError;
+
+impl fmt::Display for ParseAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid address for this underlay")
+    }
+}
+
+impl std::error::Error for ParseAddressError {}
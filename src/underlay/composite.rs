@@ -0,0 +1,358 @@
+//! Run the DHT over several transports at once.
+//!
+//! A [`CompositeUnderlay`] combines two underlays, and more can be combined
+//! by nesting them. The first underlay is preferred: messages go over it
+//! whenever the peer is connected on it, and only fall back to the second
+//! otherwise. A peer is connected as long as either transport is.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+    task::Poll,
+};
+
+use crate::{Message, Peer};
+
+use super::{ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal};
+
+/// An address of one of the underlays. HELLOs carry both kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompositeAddress<A, B> {
+    First(A),
+    Second(B),
+}
+
+impl<A: fmt::Display, B: fmt::Display> fmt::Display for CompositeAddress<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompositeAddress::First(a) => a.fmt(f),
+            CompositeAddress::Second(b) => b.fmt(f),
+        }
+    }
+}
+
+/// Parses as the first address type if possible, since the schemes of the
+/// underlays are expected to be distinct.
+impl<A: FromStr, B: FromStr> FromStr for CompositeAddress<A, B> {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(a) = s.parse() {
+            return Ok(CompositeAddress::First(a));
+        }
+        s.parse()
+            .map(CompositeAddress::Second)
+            .map_err(|_| ParseAddressError)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositeError<A, B> {
+    First(A),
+    Second(B),
+    /// The peer is not connected on either underlay
+    NotConnected,
+}
+
+impl<A: fmt::Display, B: fmt::Display> fmt::Display for CompositeError<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompositeError::First(a) => a.fmt(f),
+            CompositeError::Second(b) => b.fmt(f),
+            CompositeError::NotConnected => f.write_str("peer is not connected"),
+        }
+    }
+}
+
+impl<A: fmt::Debug + fmt::Display, B: fmt::Debug + fmt::Display> std::error::Error
+    for CompositeError<A, B>
+{
+}
+
+/// Two underlays acting as one.
+///
+/// Signals from each underlay have to be passed through
+/// [`from_first`](Self::from_first) or [`from_second`](Self::from_second)
+/// before giving them to the DHT, so that connections are tracked across
+/// both.
+pub struct CompositeUnderlay<A, B> {
+    first: A,
+    second: B,
+    peers: Mutex<HashMap<Peer, Transports>>,
+}
+
+#[derive(Default)]
+struct Transports {
+    first: bool,
+    second: bool,
+    // applied to transports that connect later, too
+    held: bool,
+}
+
+impl<A: Underlay, B: Underlay> CompositeUnderlay<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            peers: Mutex::default(),
+        }
+    }
+
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    /// Translate a signal from the first underlay. Connection changes that
+    /// are hidden by the other underlay are swallowed.
+    pub fn from_first(&self, signal: UnderlaySignal<A>) -> Option<UnderlaySignal<Self>> {
+        match signal {
+            UnderlaySignal::PeerConnected(peer, info) => {
+                self.connected(peer, info, |t| &mut t.first)
+            }
+            UnderlaySignal::PeerDisconnected(peer) => self.disconnected(peer, |t| &mut t.first),
+            UnderlaySignal::AddressAdded(a) => {
+                Some(UnderlaySignal::AddressAdded(CompositeAddress::First(a)))
+            }
+            UnderlaySignal::AddressDeleted(a) => {
+                Some(UnderlaySignal::AddressDeleted(CompositeAddress::First(a)))
+            }
+            UnderlaySignal::Receive(peer, message) => Some(UnderlaySignal::Receive(peer, message)),
+            UnderlaySignal::SendReady(peer) => Some(UnderlaySignal::SendReady(peer)),
+        }
+    }
+
+    /// Translate a signal from the second underlay.
+    pub fn from_second(&self, signal: UnderlaySignal<B>) -> Option<UnderlaySignal<Self>> {
+        match signal {
+            UnderlaySignal::PeerConnected(peer, info) => {
+                self.connected(peer, info, |t| &mut t.second)
+            }
+            UnderlaySignal::PeerDisconnected(peer) => self.disconnected(peer, |t| &mut t.second),
+            UnderlaySignal::AddressAdded(b) => {
+                Some(UnderlaySignal::AddressAdded(CompositeAddress::Second(b)))
+            }
+            UnderlaySignal::AddressDeleted(b) => {
+                Some(UnderlaySignal::AddressDeleted(CompositeAddress::Second(b)))
+            }
+            UnderlaySignal::Receive(peer, message) => Some(UnderlaySignal::Receive(peer, message)),
+            UnderlaySignal::SendReady(peer) => Some(UnderlaySignal::SendReady(peer)),
+        }
+    }
+
+    /// Only the first transport's connection info is passed on.
+    fn connected(
+        &self,
+        peer: Peer,
+        info: ConnectionInfo,
+        transport: fn(&mut Transports) -> &mut bool,
+    ) -> Option<UnderlaySignal<Self>> {
+        let mut peers = self.peers();
+        let t = peers.entry(peer).or_default();
+        let new = !t.first && !t.second;
+        *transport(t) = true;
+        let held = t.held;
+        drop(peers);
+
+        if held {
+            self.hold(peer);
+        }
+        new.then_some(UnderlaySignal::PeerConnected(peer, info))
+    }
+
+    fn disconnected(
+        &self,
+        peer: Peer,
+        transport: fn(&mut Transports) -> &mut bool,
+    ) -> Option<UnderlaySignal<Self>> {
+        let mut peers = self.peers();
+        let t = peers.get_mut(&peer)?;
+        if !std::mem::take(transport(t)) {
+            return None;
+        }
+        if t.first || t.second {
+            return None;
+        }
+        peers.remove(&peer);
+        Some(UnderlaySignal::PeerDisconnected(peer))
+    }
+
+    fn peers(&self) -> MutexGuard<'_, HashMap<Peer, Transports>> {
+        self.peers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<A: Underlay, B: Underlay> Underlay for CompositeUnderlay<A, B> {
+    type Address = CompositeAddress<A::Address, B::Address>;
+    type NetworkSizeEstimate = u64;
+    type Error = CompositeError<A::Error, B::Error>;
+
+    fn try_connect(&self, peer: Peer, addr: Self::Address) -> Result<(), Self::Error> {
+        match addr {
+            CompositeAddress::First(a) => self
+                .first
+                .try_connect(peer, a)
+                .map_err(CompositeError::First),
+            CompositeAddress::Second(b) => self
+                .second
+                .try_connect(peer, b)
+                .map_err(CompositeError::Second),
+        }
+    }
+
+    fn hold(&self, peer: Peer) {
+        let (first, second) = match self.peers().get_mut(&peer) {
+            Some(t) => {
+                t.held = true;
+                (t.first, t.second)
+            }
+            None => return,
+        };
+        if first {
+            self.first.hold(peer);
+        }
+        if second {
+            self.second.hold(peer);
+        }
+    }
+
+    fn drop(&self, peer: Peer) {
+        if let Some(t) = self.peers().get_mut(&peer) {
+            t.held = false;
+        }
+        self.first.drop(peer);
+        self.second.drop(peer);
+    }
+
+    /// Sends over the first underlay, or the second if that fails.
+    fn send(&self, peer: Peer, message: Message) -> Result<(), Self::Error> {
+        let (first, second) = match self.peers().get(&peer) {
+            Some(t) => (t.first, t.second),
+            None => return Err(CompositeError::NotConnected),
+        };
+        if first {
+            match self.first.send(peer, message.clone()) {
+                Ok(()) => return Ok(()),
+                Err(e) if !second => return Err(CompositeError::First(e)),
+                Err(_) => {}
+            }
+        }
+        self.second
+            .send(peer, message)
+            .map_err(CompositeError::Second)
+    }
+
+    /// Ready if either transport the peer is connected on is, as sends
+    /// fall back to the second.
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        let (first, second) = match self.peers().get(&peer) {
+            Some(t) => (t.first, t.second),
+            None => return Poll::Ready(()),
+        };
+        if first && self.first.poll_ready(peer).is_ready() {
+            return Poll::Ready(());
+        }
+        if second && self.second.poll_ready(peer).is_ready() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    /// The larger of the two estimates, since either transport may only see
+    /// part of the network.
+    fn estimate_network_size(&self) -> u64 {
+        let first = self.first.estimate_network_size().into();
+        let second = self.second.estimate_network_size().into();
+        first.max(second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::identities,
+        underlay::{
+            memory::{MemoryAddress, MemoryNetwork},
+            Underlay, UnderlaySignal,
+        },
+        Message,
+    };
+
+    use super::{CompositeAddress, CompositeError, CompositeUnderlay};
+
+    #[test]
+    fn prefers_first() {
+        let [fast, slow] = [MemoryNetwork::new(), MemoryNetwork::new()];
+        let [a, b] = [identities::peers()[0].peer(), identities::peers()[1].peer()];
+        let (fast_a, rx_fast_a) = fast.join(a);
+        let (slow_a, rx_slow_a) = slow.join(a);
+        let (fast_b, rx_fast_b) = fast.join(b);
+        let (slow_b, rx_slow_b) = slow.join(b);
+        let ua = CompositeUnderlay::new(fast_a, slow_a);
+
+        let addresses: Vec<_> = rx_fast_a
+            .try_iter()
+            .filter_map(|s| ua.from_first(s))
+            .chain(rx_slow_a.try_iter().filter_map(|s| ua.from_second(s)))
+            .map(|s| match s {
+                UnderlaySignal::AddressAdded(addr) => addr,
+                _ => panic!("unexpected signal"),
+            })
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                CompositeAddress::First(MemoryAddress(0)),
+                CompositeAddress::Second(MemoryAddress(0))
+            ]
+        );
+
+        ua.try_connect(b, CompositeAddress::Second(slow_b.address()))
+            .unwrap();
+        assert!(matches!(
+            ua.from_second(rx_slow_a.try_recv().unwrap()),
+            Some(UnderlaySignal::PeerConnected(p, _)) if p == b
+        ));
+        ua.hold(b);
+        assert!(ua.second().is_held(&b));
+
+        // a second transport to the same peer isn't a new connection, but
+        // it is held too
+        ua.try_connect(b, CompositeAddress::First(fast_b.address()))
+            .unwrap();
+        assert!(ua.from_first(rx_fast_a.try_recv().unwrap()).is_none());
+        assert!(ua.first().is_held(&b));
+
+        let message = Message::from_bytes(b"fast".to_vec());
+        ua.send(b, message.clone()).unwrap();
+        rx_fast_b.try_recv().unwrap(); // address
+        rx_fast_b.try_recv().unwrap(); // connected
+        assert!(
+            matches!(rx_fast_b.try_recv(), Ok(UnderlaySignal::Receive(p, m)) if p == a && m == message)
+        );
+        assert_eq!(rx_slow_b.try_iter().count(), 2);
+
+        // only disconnected once neither transport is left
+        fast.disconnect(MemoryAddress(0), fast_b.address());
+        assert!(ua.from_first(rx_fast_a.try_recv().unwrap()).is_none());
+        ua.send(b, message).unwrap();
+        assert!(matches!(
+            rx_slow_b.try_recv(),
+            Ok(UnderlaySignal::Receive(..))
+        ));
+
+        slow.disconnect(MemoryAddress(0), slow_b.address());
+        assert!(matches!(
+            ua.from_second(rx_slow_a.try_recv().unwrap()),
+            Some(UnderlaySignal::PeerDisconnected(p)) if p == b
+        ));
+        assert!(matches!(
+            ua.send(b, Message::from_bytes(vec![])),
+            Err(CompositeError::NotConnected)
+        ));
+    }
+}
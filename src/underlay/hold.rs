@@ -0,0 +1,109 @@
+//! Reference counted holds.
+
+use std::collections::HashMap;
+
+use crate::Peer;
+
+use super::Underlay;
+
+/// Counts [`hold`](Underlay::hold)s per peer, so that several parts of the
+/// engine can hold the same connection independently. The underlay only
+/// sees the first hold and the last release.
+#[derive(Debug, Default)]
+pub struct HoldTracker {
+    counts: HashMap<Peer, usize>,
+}
+
+impl HoldTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a hold on the peer, holding it in the underlay if it is the
+    /// first.
+    pub fn hold<U: Underlay>(&mut self, underlay: &U, peer: Peer) {
+        let count = self.counts.entry(peer).or_default();
+        *count += 1;
+        if *count == 1 {
+            underlay.hold(peer);
+        }
+    }
+
+    /// Give back a hold on the peer, dropping it in the underlay if it was
+    /// the last. Returns whether the underlay was told to drop. Releasing a
+    /// peer that isn't held does nothing.
+    pub fn release<U: Underlay>(&mut self, underlay: &U, peer: Peer) -> bool {
+        let Some(count) = self.counts.get_mut(&peer) else {
+            return false;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return false;
+        }
+        self.counts.remove(&peer);
+        underlay.drop(peer);
+        true
+    }
+
+    /// Forget every hold on a peer without telling the underlay, eg because
+    /// it disconnected.
+    pub fn forget(&mut self, peer: &Peer) {
+        self.counts.remove(peer);
+    }
+
+    /// Drop every held peer in the underlay, eg on shutdown.
+    pub fn release_all<U: Underlay>(&mut self, underlay: &U) {
+        for (peer, _) in self.counts.drain() {
+            underlay.drop(peer);
+        }
+    }
+
+    /// How many holds there are on the peer
+    pub fn count(&self, peer: &Peer) -> usize {
+        self.counts.get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn is_held(&self, peer: &Peer) -> bool {
+        self.counts.contains_key(peer)
+    }
+
+    /// The number of held peers
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing::identities, underlay::memory::MemoryNetwork};
+
+    use super::HoldTracker;
+
+    #[test]
+    fn refcount() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [identities::peers()[0].peer(), identities::peers()[1].peer()];
+        let (underlay, _rx) = network.join(a);
+        let mut holds = HoldTracker::new();
+
+        holds.hold(&underlay, b);
+        holds.hold(&underlay, b);
+        assert_eq!(holds.count(&b), 2);
+        assert!(underlay.is_held(&b));
+
+        assert!(!holds.release(&underlay, b));
+        assert!(underlay.is_held(&b));
+        assert!(holds.release(&underlay, b));
+        assert!(!underlay.is_held(&b));
+        assert!(!holds.release(&underlay, b));
+
+        holds.hold(&underlay, b);
+        holds.release_all(&underlay);
+        assert!(holds.is_empty());
+        assert!(!underlay.is_held(&b));
+    }
+}
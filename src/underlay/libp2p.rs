@@ -0,0 +1,371 @@
+//! An underlay on top of a libp2p [`Swarm`](::libp2p::swarm::Swarm), so that
+//! r6n can run alongside an existing libp2p stack.
+//!
+//! Add a [`Behaviour`] to the swarm, and give the [`Libp2pUnderlay`] to the
+//! DHT. Messages are sent on their own `/r6n/1` stream each. Only peers with
+//! Ed25519 identities can take part, since their libp2p key is their R5N
+//! identity.
+//!
+//! libp2p decides how long connections stay open, so [`hold`](Underlay::hold)
+//! and [`drop`](Underlay::drop) have no effect. Configure the swarm's
+//! idle connection timeout instead.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+use ::libp2p::{
+    core::{transport::PortUse, Endpoint},
+    identity,
+    swarm::{
+        behaviour::ConnectionEstablished, dial_opts::DialOpts, ConnectionClosed, ConnectionDenied,
+        ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent,
+        ToSwarm,
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{Message, Peer};
+
+use super::{
+    queue::{send_queue, SendQueue},
+    ConnectionInfo, Underlay, UnderlaySignal,
+};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/r6n/1");
+
+/// The libp2p id of a peer, if its key is a valid Ed25519 point.
+pub fn to_peer_id(peer: &Peer) -> Option<PeerId> {
+    let key = identity::ed25519::PublicKey::try_from_bytes(peer.as_bytes()).ok()?;
+    Some(identity::PublicKey::from(key).to_peer_id())
+}
+
+/// The R5N peer with this libp2p id, if it is an Ed25519 identity.
+pub fn from_peer_id(id: &PeerId) -> Option<Peer> {
+    // Ed25519 peer ids inline the public key rather than hashing it
+    let key = identity::PublicKey::try_decode_protobuf(id.as_ref().digest()).ok()?;
+    Some(Peer::from_bytes(key.try_into_ed25519().ok()?.to_bytes()))
+}
+
+pub struct Libp2pConfig {
+    /// libp2p doesn't estimate the network size, so it is configured instead
+    pub network_size: u64,
+    /// Messages queued per peer before it stops being
+    /// [ready](Underlay::poll_ready) and sends start failing
+    pub send_queue: usize,
+}
+
+impl Default for Libp2pConfig {
+    fn default() -> Self {
+        Self {
+            network_size: 1000,
+            send_queue: 64,
+        }
+    }
+}
+
+struct Shared {
+    signals: mpsc::UnboundedSender<UnderlaySignal<Libp2pUnderlay>>,
+    dials: VecDeque<(PeerId, Multiaddr)>,
+    waker: Option<Waker>,
+    /// peers with at least one open connection, and the queue of messages
+    /// to them
+    peers: HashMap<PeerId, SendQueue>,
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The swarm side of the underlay. It dials on behalf of
+/// [`try_connect`](Underlay::try_connect) and reports connections.
+pub struct Behaviour {
+    stream: libp2p_stream::Behaviour,
+    shared: Arc<Mutex<Shared>>,
+    runtime: Handle,
+    send_queue: usize,
+}
+
+/// The DHT side of the underlay.
+pub struct Libp2pUnderlay {
+    shared: Arc<Mutex<Shared>>,
+    network_size: u64,
+}
+
+impl Behaviour {
+    /// Create the behaviour and its underlay. This must be called from within
+    /// a tokio runtime.
+    pub fn new(
+        config: Libp2pConfig,
+    ) -> io::Result<(
+        Self,
+        Libp2pUnderlay,
+        mpsc::UnboundedReceiver<UnderlaySignal<Libp2pUnderlay>>,
+    )> {
+        let stream = libp2p_stream::Behaviour::new();
+        let incoming = stream
+            .new_control()
+            .accept(PROTOCOL)
+            .map_err(io::Error::other)?;
+
+        let (signals, rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Mutex::new(Shared {
+            signals: signals.clone(),
+            dials: VecDeque::new(),
+            waker: None,
+            peers: HashMap::new(),
+        }));
+
+        let runtime = Handle::current();
+        runtime.spawn(receive(incoming, signals));
+
+        let behaviour = Self {
+            stream,
+            shared: shared.clone(),
+            runtime,
+            send_queue: config.send_queue,
+        };
+        let underlay = Libp2pUnderlay {
+            shared,
+            network_size: config.network_size,
+        };
+        Ok((behaviour, underlay, rx))
+    }
+
+    fn connected(&mut self, id: PeerId) {
+        let Some(peer) = from_peer_id(&id) else {
+            return;
+        };
+        let mut shared = lock(&self.shared);
+        if shared.peers.contains_key(&id) {
+            return;
+        }
+
+        let (outgoing, mut queue) = send_queue(self.send_queue);
+        let mut control = self.stream.new_control();
+        let signals = shared.signals.clone();
+        // ends once the peer disconnects and the sender is dropped
+        self.runtime.spawn(async move {
+            while let Some((message, ready)) = queue.recv().await {
+                if ready {
+                    let _ = signals.send(UnderlaySignal::SendReady(peer));
+                }
+                let Ok(mut stream) = control.open_stream(id, PROTOCOL).await else {
+                    continue;
+                };
+                let _ = stream.write_all(message.as_bytes()).await;
+                let _ = stream.close().await;
+            }
+        });
+        shared.peers.insert(id, outgoing);
+        let _ = shared.signals.send(UnderlaySignal::PeerConnected(
+            peer,
+            ConnectionInfo::default(),
+        ));
+    }
+
+    fn disconnected(&mut self, id: PeerId) {
+        let mut shared = lock(&self.shared);
+        if shared.peers.remove(&id).is_some() {
+            if let Some(peer) = from_peer_id(&id) {
+                let _ = shared.signals.send(UnderlaySignal::PeerDisconnected(peer));
+            }
+        }
+    }
+}
+
+async fn receive(
+    mut incoming: libp2p_stream::IncomingStreams,
+    signals: mpsc::UnboundedSender<UnderlaySignal<Libp2pUnderlay>>,
+) {
+    while let Some((id, mut stream)) = incoming.next().await {
+        let Some(peer) = from_peer_id(&id) else {
+            continue;
+        };
+        let signals = signals.clone();
+        tokio::spawn(async move {
+            let mut message = vec![];
+            let mut limited = (&mut stream).take(u16::MAX as u64);
+            if limited.read_to_end(&mut message).await.is_ok() {
+                let message = Message::from_bytes(message);
+                let _ = signals.send(UnderlaySignal::Receive(peer, message));
+            }
+        });
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = <libp2p_stream::Behaviour as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = ();
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.stream.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.stream.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+            port_use,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished { peer_id, .. }) => {
+                self.connected(peer_id)
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => self.disconnected(peer_id),
+            _ => {}
+        }
+        self.stream.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.stream
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<(), THandlerInEvent<Self>>> {
+        let mut shared = lock(&self.shared);
+        if let Some((peer, addr)) = shared.dials.pop_front() {
+            let opts = DialOpts::peer_id(peer).addresses(vec![addr]).build();
+            return Poll::Ready(ToSwarm::Dial { opts });
+        }
+        shared.waker = Some(cx.waker().clone());
+        drop(shared);
+
+        self.stream.poll(cx)
+    }
+}
+
+impl Underlay for Libp2pUnderlay {
+    type Address = Multiaddr;
+    type NetworkSizeEstimate = u64;
+    type Error = io::Error;
+
+    /// Asks the swarm to dial. Fails if the peer can't have a libp2p id.
+    fn try_connect(&self, peer: Peer, addr: Multiaddr) -> io::Result<()> {
+        let id = to_peer_id(&peer).ok_or(io::ErrorKind::InvalidInput)?;
+        let mut shared = lock(&self.shared);
+        shared.dials.push_back((id, addr));
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn hold(&self, _peer: Peer) {}
+
+    fn drop(&self, _peer: Peer) {}
+
+    fn send(&self, peer: Peer, message: Message) -> io::Result<()> {
+        let id = to_peer_id(&peer).ok_or(io::ErrorKind::InvalidInput)?;
+        let shared = lock(&self.shared);
+        let outgoing = shared.peers.get(&id).ok_or(io::ErrorKind::NotConnected)?;
+        outgoing.try_send(message)
+    }
+
+    /// Pending while the peer's [`send_queue`](Libp2pConfig::send_queue)
+    /// is full
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        let Some(id) = to_peer_id(&peer) else {
+            return Poll::Ready(());
+        };
+        match lock(&self.shared).peers.get(&id) {
+            Some(outgoing) => outgoing.poll_ready(),
+            None => Poll::Ready(()),
+        }
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.network_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p_swarm_test::SwarmExt;
+
+    use crate::{
+        testing::identities,
+        underlay::{Underlay, UnderlaySignal},
+        Message,
+    };
+
+    use super::{from_peer_id, to_peer_id, Behaviour, Libp2pConfig};
+
+    #[test]
+    fn identities() {
+        let peer = identities::peers()[0].peer();
+        let id = to_peer_id(&peer).unwrap();
+        assert_eq!(from_peer_id(&id), Some(peer));
+    }
+
+    #[tokio::test]
+    async fn send_over_swarm() {
+        let new = || {
+            let mut parts = None;
+            let swarm = ::libp2p::Swarm::new_ephemeral_tokio(|_| {
+                let (behaviour, underlay, rx) = Behaviour::new(Libp2pConfig::default()).unwrap();
+                parts = Some((underlay, rx));
+                behaviour
+            });
+            let (underlay, rx) = parts.unwrap();
+            (swarm, underlay, rx)
+        };
+        let (mut swarm_a, ua, mut rxa) = new();
+        let (mut swarm_b, _ub, mut rxb) = new();
+        let a = from_peer_id(swarm_a.local_peer_id()).unwrap();
+        let b = from_peer_id(swarm_b.local_peer_id()).unwrap();
+
+        swarm_b.listen().with_memory_addr_external().await;
+        swarm_a.connect(&mut swarm_b).await;
+        tokio::spawn(swarm_a.loop_on_next());
+        tokio::spawn(swarm_b.loop_on_next());
+
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == b));
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == a));
+
+        let message = Message::from_bytes(b"over libp2p".to_vec());
+        ua.send(b, message.clone()).unwrap();
+        assert!(matches!(
+            rxb.recv().await,
+            Some(UnderlaySignal::Receive(p, m)) if p == a && m == message
+        ));
+    }
+}
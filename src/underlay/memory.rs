@@ -0,0 +1,365 @@
+//! An underlay that connects DHT instances in the same process, for tests.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{Message, Peer};
+
+use super::{ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal};
+
+/// The address of a member of a [`MemoryNetwork`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryAddress(pub usize);
+
+impl fmt::Display for MemoryAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory://{}", self.0)
+    }
+}
+
+impl FromStr for MemoryAddress {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let n = s.strip_prefix("memory://").ok_or(ParseAddressError)?;
+        n.parse().map(Self).map_err(|_| ParseAddressError)
+    }
+}
+
+/// What to do with a message, as decided by a [`MemoryNetwork::set_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Deliver,
+    Drop,
+    /// Keep the message until [`MemoryNetwork::release_held`], so that it
+    /// arrives after messages sent later.
+    Hold,
+    /// Like [`Hold`](Self::Hold), but the message stays held through this
+    /// many calls to [`MemoryNetwork::release_held`] first.
+    Delay(u32),
+}
+
+type Hook = Box<dyn FnMut(&Peer, &Peer) -> Delivery + Send>;
+
+/// A set of peers that can connect to and send messages to each other
+/// through channels.
+///
+/// Each member gets a [`MemoryUnderlay`] to give to its DHT, and a channel
+/// of the [`UnderlaySignal`]s the DHT should process.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    members: Vec<Member>,
+    /// connected pairs, smallest address first
+    links: HashSet<(usize, usize)>,
+    hook: Option<Hook>,
+    /// messages to deliver, with how many releases they still wait for
+    held: VecDeque<(usize, Peer, Message, u32)>,
+}
+
+struct Member {
+    peer: Peer,
+    signals: mpsc::Sender<UnderlaySignal<MemoryUnderlay>>,
+    holds: HashSet<Peer>,
+}
+
+impl Inner {
+    fn signal(&self, to: usize, signal: UnderlaySignal<MemoryUnderlay>) {
+        // the receiver is allowed to go away
+        let _ = self.members[to].signals.send(signal);
+    }
+
+    fn disconnect(&mut self, a: usize, b: usize) -> bool {
+        if !self.links.remove(&link(a, b)) {
+            return false;
+        }
+        self.signal(a, UnderlaySignal::PeerDisconnected(self.members[b].peer));
+        self.signal(b, UnderlaySignal::PeerDisconnected(self.members[a].peer));
+        true
+    }
+}
+
+fn link(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a peer to the network. Its address is announced on the returned
+    /// channel straight away.
+    pub fn join(
+        &self,
+        peer: Peer,
+    ) -> (
+        MemoryUnderlay,
+        mpsc::Receiver<UnderlaySignal<MemoryUnderlay>>,
+    ) {
+        let (signals, rx) = mpsc::channel();
+        let mut inner = self.lock();
+        let address = inner.members.len();
+        inner.members.push(Member {
+            peer,
+            signals,
+            holds: HashSet::new(),
+        });
+        inner.signal(
+            address,
+            UnderlaySignal::AddressAdded(MemoryAddress(address)),
+        );
+
+        let underlay = MemoryUnderlay {
+            network: self.clone(),
+            address,
+        };
+        (underlay, rx)
+    }
+
+    /// Decide the fate of every message sent from now on. The hook is given
+    /// the sender and the receiver.
+    pub fn set_hook(&self, hook: impl FnMut(&Peer, &Peer) -> Delivery + Send + 'static) {
+        self.lock().hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_hook(&self) {
+        self.lock().hook = None;
+    }
+
+    /// Deliver every message that was [held](Delivery::Hold) and isn't
+    /// [delayed](Delivery::Delay) any longer, returning how many there were.
+    /// Messages to peers that have since disconnected are lost.
+    pub fn release_held(&self) -> usize {
+        let mut inner = self.lock();
+        let held = std::mem::take(&mut inner.held);
+        let mut count = 0;
+        for (to, from, message, delay) in held {
+            match delay.checked_sub(1) {
+                Some(delay) => inner.held.push_back((to, from, message, delay)),
+                None => {
+                    inner.signal(to, UnderlaySignal::Receive(from, message));
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// The number of messages waiting for [`release_held`](Self::release_held)
+    pub fn held(&self) -> usize {
+        self.lock().held.len()
+    }
+
+    /// Break the connection between two members, eg to simulate a network
+    /// failure. Both are told that the other disconnected.
+    pub fn disconnect(&self, a: MemoryAddress, b: MemoryAddress) -> bool {
+        self.lock().disconnect(a.0, b.0)
+    }
+
+    /// The number of peers that joined the network
+    pub fn len(&self) -> usize {
+        self.lock().members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // a panicking test thread shouldn't take the rest of the network down
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// One member's view of a [`MemoryNetwork`]
+pub struct MemoryUnderlay {
+    network: MemoryNetwork,
+    address: usize,
+}
+
+impl MemoryUnderlay {
+    pub fn address(&self) -> MemoryAddress {
+        MemoryAddress(self.address)
+    }
+
+    pub fn is_held(&self, peer: &Peer) -> bool {
+        self.network.lock().members[self.address]
+            .holds
+            .contains(peer)
+    }
+
+    fn connected_to(&self, inner: &Inner, peer: &Peer) -> Option<usize> {
+        inner
+            .links
+            .iter()
+            .filter_map(|&(a, b)| match self.address {
+                x if x == a => Some(b),
+                x if x == b => Some(a),
+                _ => None,
+            })
+            .find(|&other| inner.members[other].peer == *peer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// No member with that peer has that address
+    Unreachable,
+    /// The peer is not connected
+    NotConnected,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::Unreachable => f.write_str("no such peer at that address"),
+            MemoryError::NotConnected => f.write_str("peer is not connected"),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+impl Underlay for MemoryUnderlay {
+    type Address = MemoryAddress;
+    type NetworkSizeEstimate = u64;
+    type Error = MemoryError;
+
+    fn try_connect(&self, peer: Peer, addr: MemoryAddress) -> Result<(), MemoryError> {
+        let mut inner = self.network.lock();
+        let to = addr.0;
+        if inner.members.get(to).is_none_or(|m| m.peer != peer) || to == self.address {
+            return Err(MemoryError::Unreachable);
+        }
+        if inner.links.insert(link(self.address, to)) {
+            let us = inner.members[self.address].peer;
+            inner.signal(
+                self.address,
+                UnderlaySignal::PeerConnected(peer, ConnectionInfo::default()),
+            );
+            inner.signal(
+                to,
+                UnderlaySignal::PeerConnected(us, ConnectionInfo::default()),
+            );
+        }
+        Ok(())
+    }
+
+    fn hold(&self, peer: Peer) {
+        self.network.lock().members[self.address].holds.insert(peer);
+    }
+
+    fn drop(&self, peer: Peer) {
+        self.network.lock().members[self.address]
+            .holds
+            .remove(&peer);
+    }
+
+    fn send(&self, peer: Peer, message: Message) -> Result<(), MemoryError> {
+        let mut inner = self.network.lock();
+        let to = self
+            .connected_to(&inner, &peer)
+            .ok_or(MemoryError::NotConnected)?;
+        let from = inner.members[self.address].peer;
+
+        let delivery = inner
+            .hook
+            .as_mut()
+            .map_or(Delivery::Deliver, |h| h(&from, &peer));
+        match delivery {
+            Delivery::Deliver => inner.signal(to, UnderlaySignal::Receive(from, message)),
+            // sends are best-effort, so a lost message is still a success
+            Delivery::Drop => {}
+            Delivery::Hold => inner.held.push_back((to, from, message, 0)),
+            Delivery::Delay(n) => inner.held.push_back((to, from, message, n)),
+        }
+        Ok(())
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.network.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use crate::{
+        testing::identities,
+        underlay::{Underlay, UnderlaySignal},
+        DhtNode, Message,
+    };
+
+    use super::{Delivery, MemoryError, MemoryNetwork, MemoryUnderlay};
+
+    fn pump(node: &mut DhtNode<MemoryUnderlay>, rx: &Receiver<UnderlaySignal<MemoryUnderlay>>) {
+        while let Ok(signal) = rx.try_recv() {
+            node.handle_signal(signal);
+        }
+    }
+
+    #[test]
+    fn connect_and_send() {
+        let network = MemoryNetwork::new();
+        let [a, b] = [&identities::peers()[0], &identities::peers()[1]];
+        let (ua, rxa) = network.join(a.peer());
+        let (ub, rxb) = network.join(b.peer());
+        let addr_b = ub.address();
+        let mut na = DhtNode::new(a.peer_id(), ua);
+        let mut nb = DhtNode::new(b.peer_id(), ub);
+        assert_eq!(na.network_size(), 2);
+
+        assert_eq!(
+            na.underlay().try_connect(a.peer(), addr_b),
+            Err(MemoryError::Unreachable)
+        );
+        na.underlay().try_connect(b.peer(), addr_b).unwrap();
+        pump(&mut na, &rxa);
+        pump(&mut nb, &rxb);
+        assert_eq!(nb.addresses(), [addr_b]);
+        assert!(na.routing_table().contains(&b.peer()));
+        assert!(nb.routing_table().contains(&a.peer()));
+        assert!(na.underlay().is_held(&b.peer()));
+
+        // hold one message back so it arrives after the next
+        let mut hold = true;
+        network.set_hook(move |_, _| match std::mem::take(&mut hold) {
+            true => Delivery::Hold,
+            false => Delivery::Deliver,
+        });
+        let [m1, m2] = [1, 2].map(|i| Message::from_bytes(vec![i]));
+        na.underlay().send(b.peer(), m1.clone()).unwrap();
+        na.underlay().send(b.peer(), m2.clone()).unwrap();
+        assert!(
+            matches!(rxb.try_recv(), Ok(UnderlaySignal::Receive(p, m)) if p == a.peer() && m == m2)
+        );
+        assert!(rxb.try_recv().is_err());
+        assert_eq!(network.release_held(), 1);
+        assert!(matches!(rxb.try_recv(), Ok(UnderlaySignal::Receive(_, m)) if m == m1));
+
+        network.set_hook(|_, _| Delivery::Delay(1));
+        na.underlay().send(b.peer(), m2.clone()).unwrap();
+        assert_eq!(network.release_held(), 0);
+        assert_eq!(network.held(), 1);
+        assert_eq!(network.release_held(), 1);
+        assert!(matches!(rxb.try_recv(), Ok(UnderlaySignal::Receive(_, m)) if m == m2));
+        network.clear_hook();
+
+        assert!(network.disconnect(na.underlay().address(), addr_b));
+        pump(&mut na, &rxa);
+        assert!(!na.routing_table().contains(&b.peer()));
+        assert_eq!(
+            na.underlay().send(b.peer(), m1),
+            Err(MemoryError::NotConnected)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! The bounded send queue of a connection that is written to from its own
+//! task, with the readiness [`Underlay::poll_ready`](super::Underlay::poll_ready)
+//! reports.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+
+use tokio::sync::mpsc;
+
+use crate::Message;
+
+/// The sending half, kept with the connection
+pub(crate) struct SendQueue {
+    sender: mpsc::Sender<Message>,
+    /// set when the queue was found full, until the writer takes a message
+    stalled: Arc<AtomicBool>,
+}
+
+/// The receiving half, for the writer task
+pub(crate) struct WriteQueue {
+    receiver: mpsc::Receiver<Message>,
+    stalled: Arc<AtomicBool>,
+}
+
+pub(crate) fn send_queue(capacity: usize) -> (SendQueue, WriteQueue) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let stalled = Arc::new(AtomicBool::new(false));
+    let send = SendQueue {
+        sender,
+        stalled: stalled.clone(),
+    };
+    (send, WriteQueue { receiver, stalled })
+}
+
+impl SendQueue {
+    pub(crate) fn poll_ready(&self) -> Poll<()> {
+        if self.sender.capacity() > 0 {
+            return Poll::Ready(());
+        }
+        self.stalled.store(true, Ordering::Release);
+        // the writer may have taken a message before it could see the flag,
+        // in which case it signals readiness that isn't needed
+        match self.sender.capacity() {
+            0 => Poll::Pending,
+            _ => Poll::Ready(()),
+        }
+    }
+
+    pub(crate) fn try_send(&self, message: Message) -> io::Result<()> {
+        self.sender.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                self.stalled.store(true, Ordering::Release);
+                io::ErrorKind::WouldBlock.into()
+            }
+            mpsc::error::TrySendError::Closed(_) => io::ErrorKind::NotConnected.into(),
+        })
+    }
+}
+
+impl WriteQueue {
+    /// The next message to write, and whether the queue was full before it
+    /// was taken, so the underlay should signal
+    /// [`SendReady`](super::UnderlaySignal::SendReady).
+    pub(crate) async fn recv(&mut self) -> Option<(Message, bool)> {
+        let message = self.receiver.recv().await?;
+        Some((message, self.stalled.swap(false, Ordering::AcqRel)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use crate::Message;
+
+    use super::send_queue;
+
+    #[tokio::test]
+    async fn stalls() {
+        let (send, mut write) = send_queue(1);
+        assert_eq!(send.poll_ready(), Poll::Ready(()));
+        send.try_send(Message::from_bytes(vec![1])).unwrap();
+        assert_eq!(send.poll_ready(), Poll::Pending);
+        let full = send.try_send(Message::from_bytes(vec![2])).unwrap_err();
+        assert_eq!(full.kind(), std::io::ErrorKind::WouldBlock);
+
+        // taking a message from a full queue makes room to signal
+        let (_, ready) = write.recv().await.unwrap();
+        assert!(ready);
+        assert_eq!(send.poll_ready(), Poll::Ready(()));
+        send.try_send(Message::from_bytes(vec![3])).unwrap();
+        let (_, ready) = write.recv().await.unwrap();
+        assert!(!ready);
+    }
+}
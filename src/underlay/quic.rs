@@ -0,0 +1,601 @@
+//! An underlay over QUIC, using quinn.
+//!
+//! Each peer presents a self-signed certificate for its Ed25519 identity key,
+//! and both sides of a connection must present one. TLS proves that the
+//! other side holds the key, so unlike [`tcp`](super::tcp) and
+//! [`udp`](super::udp), connections are authenticated and encrypted. Every
+//! message is sent on its own unidirectional stream.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::Poll,
+};
+
+use ed25519_dalek::{pkcs8::EncodePrivateKey, SigningKey, Verifier};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Endpoint,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    DigitallySignedStruct, DistinguishedName, SignatureScheme,
+};
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{Message, Peer};
+
+use super::{
+    queue::{send_queue, SendQueue},
+    ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal,
+};
+
+const ALPN: &[u8] = b"r6n";
+/// Certificates aren't issued for names, but TLS needs one anyway
+const SERVER_NAME: &str = "r6n";
+
+/// A socket address, written as `quic://host:port` in HELLOs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuicAddress(pub SocketAddr);
+
+impl QuicAddress {
+    const SCHEME: &'static str = "quic://";
+}
+
+impl fmt::Display for QuicAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Self::SCHEME, self.0)
+    }
+}
+
+impl FromStr for QuicAddress {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = s.strip_prefix(Self::SCHEME).ok_or(ParseAddressError)?;
+        addr.parse().map(Self).map_err(|_| ParseAddressError)
+    }
+}
+
+pub struct QuicConfig {
+    /// QUIC can't estimate the network size, so it is configured instead
+    pub network_size: u64,
+    /// Once there are more connections than this, the oldest connection that
+    /// isn't held is closed.
+    pub max_connections: usize,
+    /// Messages queued per connection before it stops being
+    /// [ready](Underlay::poll_ready) and sends start failing
+    pub send_queue: usize,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            network_size: 1000,
+            max_connections: 64,
+            send_queue: 64,
+        }
+    }
+}
+
+pub struct QuicUnderlay {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    local: Peer,
+    config: QuicConfig,
+    endpoint: Endpoint,
+    /// the certificate and key, for creating client configs
+    identity: Identity,
+    runtime: Handle,
+    signals: mpsc::UnboundedSender<UnderlaySignal<QuicUnderlay>>,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    by_peer: HashMap<Peer, Link>,
+    next_id: u64,
+}
+
+struct Link {
+    id: u64,
+    pinned: bool,
+    outgoing: SendQueue,
+    connection: Connection,
+}
+
+struct Identity {
+    certificate: CertificateDer<'static>,
+    pkcs8: Vec<u8>,
+}
+
+impl Identity {
+    fn new(key: &SigningKey) -> io::Result<Self> {
+        let pkcs8 = key.to_pkcs8_der().map_err(io::Error::other)?;
+        let pkcs8 = pkcs8.as_bytes().to_vec();
+        let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(
+            &PrivatePkcs8KeyDer::from(pkcs8.as_slice()),
+            &rcgen::PKCS_ED25519,
+        )
+        .map_err(io::Error::other)?;
+        let certificate = rcgen::CertificateParams::new(vec![SERVER_NAME.to_owned()])
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            certificate: certificate.der().clone(),
+            pkcs8,
+        })
+    }
+
+    fn key(&self) -> rustls::pki_types::PrivateKeyDer<'static> {
+        PrivatePkcs8KeyDer::from(self.pkcs8.clone()).into()
+    }
+
+    fn server_config(&self) -> io::Result<quinn::ServerConfig> {
+        let tls = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .with_client_cert_verifier(Arc::new(PeerVerifier { expected: None }))
+            .with_single_cert(vec![self.certificate.clone()], self.key());
+        let mut tls = tls.map_err(io::Error::other)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let quic = QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+        Ok(quinn::ServerConfig::with_crypto(Arc::new(quic)))
+    }
+
+    /// A client config that only accepts `peer`
+    fn client_config(&self, peer: Peer) -> io::Result<quinn::ClientConfig> {
+        let tls = rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PeerVerifier {
+                expected: Some(peer),
+            }))
+            .with_client_auth_cert(vec![self.certificate.clone()], self.key());
+        let mut tls = tls.map_err(io::Error::other)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let quic = QuicClientConfig::try_from(tls).map_err(io::Error::other)?;
+        Ok(quinn::ClientConfig::new(Arc::new(quic)))
+    }
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// The peer whose Ed25519 key is in this certificate
+fn certificate_peer(certificate: &CertificateDer<'_>) -> Option<Peer> {
+    // An Ed25519 SubjectPublicKeyInfo is this fixed prefix and the key
+    const SPKI_PREFIX: [u8; 12] = [
+        0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+    ];
+    let certificate = webpki::EndEntityCert::try_from(certificate).ok()?;
+    let spki = certificate.subject_public_key_info();
+    let key = spki.as_ref().strip_prefix(&SPKI_PREFIX)?;
+    Some(Peer::from_bytes(key.try_into().ok()?))
+}
+
+/// Accepts certificates for any Ed25519 key, or only for the `expected`
+/// peer. The certificate itself is not checked: the handshake signature
+/// already proves the other side holds the key.
+#[derive(Debug)]
+struct PeerVerifier {
+    expected: Option<Peer>,
+}
+
+impl PeerVerifier {
+    fn check(&self, certificate: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        let peer = certificate_peer(certificate).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+        match self.expected {
+            Some(expected) if expected != peer => Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn verify_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let invalid = rustls::Error::InvalidCertificate(rustls::CertificateError::BadSignature);
+        if dss.scheme != SignatureScheme::ED25519 {
+            return Err(invalid);
+        }
+        let peer = certificate_peer(certificate).ok_or(invalid.clone())?;
+        let key = ed25519_dalek::VerifyingKey::from_bytes(peer.as_bytes())
+            .map_err(|_| invalid.clone())?;
+        let signature =
+            ed25519_dalek::Signature::from_slice(dss.signature()).map_err(|_| invalid.clone())?;
+        key.verify(message, &signature).map_err(|_| invalid)?;
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _: &[u8],
+        _: &CertificateDer<'_>,
+        _: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        // QUIC is TLS 1.3 only
+        Err(rustls::Error::PeerIncompatible(
+            rustls::PeerIncompatible::Tls12NotOffered,
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, certificate, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _: &[u8],
+        _: &CertificateDer<'_>,
+        _: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::PeerIncompatible(
+            rustls::PeerIncompatible::Tls12NotOffered,
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, certificate, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl QuicUnderlay {
+    /// Start accepting connections, identifying as the owner of `key`. This
+    /// must be called from within a tokio runtime. Accepting stops once the
+    /// returned channel is dropped.
+    pub fn bind(
+        key: &SigningKey,
+        addr: SocketAddr,
+        config: QuicConfig,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<UnderlaySignal<Self>>)> {
+        let identity = Identity::new(key)?;
+        let endpoint = Endpoint::server(identity.server_config()?, addr)?;
+        let local_addr = endpoint.local_addr()?;
+
+        let (signals, rx) = mpsc::unbounded_channel();
+        if !local_addr.ip().is_unspecified() {
+            let _ = signals.send(UnderlaySignal::AddressAdded(QuicAddress(local_addr)));
+        }
+
+        let shared = Arc::new(Shared {
+            local: key.verifying_key().into(),
+            config,
+            endpoint,
+            identity,
+            runtime: Handle::current(),
+            signals,
+            connections: Default::default(),
+        });
+        tokio::spawn(shared.clone().accept());
+
+        Ok((Self { shared }, rx))
+    }
+
+    pub fn local_addr(&self) -> io::Result<QuicAddress> {
+        self.shared.endpoint.local_addr().map(QuicAddress)
+    }
+
+    /// The number of open connections
+    pub fn connections(&self) -> usize {
+        self.shared.lock().by_peer.len()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Connections> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn accept(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                incoming = self.endpoint.accept() => {
+                    let Some(incoming) = incoming else { return };
+                    let shared = self.clone();
+                    tokio::spawn(async move {
+                        if let Ok(connection) = incoming.await {
+                            shared.register(connection);
+                        }
+                    });
+                }
+                _ = self.signals.closed() => {
+                    self.endpoint.close(0u32.into(), b"shutdown");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Start reading and writing on an established connection.
+    fn register(self: &Arc<Self>, connection: Connection) {
+        let peer = connection
+            .peer_identity()
+            .and_then(|id| id.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| certs.first().and_then(certificate_peer));
+        let Some(peer) = peer else {
+            connection.close(1u32.into(), b"no identity");
+            return;
+        };
+
+        let (outgoing, mut queue) = send_queue(self.config.send_queue);
+        let mut connections = self.lock();
+        let id = connections.next_id;
+        connections.next_id += 1;
+
+        let shared = self.clone();
+        let reader = connection.clone();
+        tokio::spawn(async move {
+            while let Ok(mut stream) = reader.accept_uni().await {
+                let Ok(message) = stream.read_to_end(u16::MAX as usize).await else {
+                    continue;
+                };
+                let message = Message::from_bytes(message);
+                let _ = shared.signals.send(UnderlaySignal::Receive(peer, message));
+            }
+            shared.close(peer, id);
+        });
+        let writer = connection.clone();
+        let signals = self.signals.clone();
+        tokio::spawn(async move {
+            while let Some((message, ready)) = queue.recv().await {
+                if ready {
+                    let _ = signals.send(UnderlaySignal::SendReady(peer));
+                }
+                let Ok(mut stream) = writer.open_uni().await else {
+                    break;
+                };
+                if stream.write_all(message.as_bytes()).await.is_err() || stream.finish().is_err() {
+                    break;
+                }
+            }
+        });
+
+        let link = Link {
+            id,
+            pinned: false,
+            outgoing,
+            connection,
+        };
+        if let Some(old) = connections.by_peer.insert(peer, link) {
+            // the newer connection wins, the routing table sees a replacement
+            old.connection.close(0u32.into(), b"replaced");
+        }
+        let evicted = self.evict(&mut connections);
+        drop(connections);
+
+        let _ = self.signals.send(UnderlaySignal::PeerConnected(
+            peer,
+            ConnectionInfo::default(),
+        ));
+        for peer in evicted {
+            let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+        }
+    }
+
+    /// Close unpinned connections, oldest first, until we are within
+    /// `max_connections`.
+    fn evict(&self, connections: &mut Connections) -> Vec<Peer> {
+        let excess = connections
+            .by_peer
+            .len()
+            .saturating_sub(self.config.max_connections);
+        let mut unpinned: Vec<(u64, Peer)> = connections
+            .by_peer
+            .iter()
+            .filter(|(_, l)| !l.pinned)
+            .map(|(&p, l)| (l.id, p))
+            .collect();
+        unpinned.sort_unstable();
+        unpinned.truncate(excess);
+
+        for (_, peer) in &unpinned {
+            if let Some(l) = connections.by_peer.remove(peer) {
+                l.connection.close(0u32.into(), b"too many connections");
+            }
+        }
+        unpinned.into_iter().map(|(_, p)| p).collect()
+    }
+
+    fn close(&self, peer: Peer, id: u64) {
+        let mut connections = self.lock();
+        if connections.by_peer.get(&peer).is_some_and(|l| l.id == id) {
+            connections.by_peer.remove(&peer);
+            drop(connections);
+            let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+        }
+    }
+}
+
+impl Underlay for QuicUnderlay {
+    type Address = QuicAddress;
+    type NetworkSizeEstimate = u64;
+    type Error = io::Error;
+
+    /// Connects in the background. [`PeerConnected`](UnderlaySignal::PeerConnected)
+    /// is signalled once the handshake proves the other side is `peer`, and
+    /// nothing happens if it fails.
+    fn try_connect(&self, peer: Peer, addr: QuicAddress) -> io::Result<()> {
+        if peer == self.shared.local {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let config = self.shared.identity.client_config(peer)?;
+        let connecting = self
+            .shared
+            .endpoint
+            .connect_with(config, addr.0, SERVER_NAME)
+            .map_err(io::Error::other)?;
+        let shared = self.shared.clone();
+        self.shared.runtime.spawn(async move {
+            if let Ok(connection) = connecting.await {
+                shared.register(connection);
+            }
+        });
+        Ok(())
+    }
+
+    fn hold(&self, peer: Peer) {
+        if let Some(l) = self.shared.lock().by_peer.get_mut(&peer) {
+            l.pinned = true;
+        }
+    }
+
+    fn drop(&self, peer: Peer) {
+        if let Some(l) = self.shared.lock().by_peer.get_mut(&peer) {
+            l.pinned = false;
+        }
+    }
+
+    fn send(&self, peer: Peer, message: Message) -> io::Result<()> {
+        let connections = self.shared.lock();
+        let link = connections
+            .by_peer
+            .get(&peer)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        link.outgoing.try_send(message)
+    }
+
+    /// Pending while the connection's
+    /// [`send_queue`](QuicConfig::send_queue) is full
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        match self.shared.lock().by_peer.get(&peer) {
+            Some(l) => l.outgoing.poll_ready(),
+            None => Poll::Ready(()),
+        }
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.shared.config.network_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::identities,
+        underlay::{Underlay, UnderlaySignal},
+        Message,
+    };
+
+    use super::{certificate_peer, Identity, QuicAddress, QuicConfig, QuicUnderlay};
+
+    #[test]
+    fn certificate_key() {
+        let [a, b] = [0, 1].map(|i| &identities::peers()[i]);
+        let identity = Identity::new(&a.signing_key()).unwrap();
+        assert_eq!(certificate_peer(&identity.certificate), Some(a.peer()));
+
+        // an Ed25519 key elsewhere in the certificate is not its key
+        let mut spki = vec![
+            0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+        ];
+        spki.extend_from_slice(b.peer().as_bytes());
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.custom_extensions = vec![rcgen::CustomExtension::from_oid_content(
+            &[1, 3, 6, 1, 4, 1, 99999, 1],
+            spki,
+        )];
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let certificate = params.self_signed(&key_pair).unwrap();
+        assert_eq!(certificate_peer(certificate.der()), None);
+    }
+
+    #[tokio::test]
+    async fn authenticated_connection() {
+        let [a, b, c] = [0, 1, 2].map(|i| &identities::peers()[i]);
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let (ua, mut rxa) =
+            QuicUnderlay::bind(&a.signing_key(), localhost, QuicConfig::default()).unwrap();
+        let (ub, mut rxb) =
+            QuicUnderlay::bind(&b.signing_key(), localhost, QuicConfig::default()).unwrap();
+        let addr_b = ub.local_addr().unwrap();
+        assert!(matches!(
+            rxa.recv().await,
+            Some(UnderlaySignal::AddressAdded(_))
+        ));
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::AddressAdded(x)) if x == addr_b));
+        assert_eq!(addr_b.to_string().parse::<QuicAddress>(), Ok(addr_b));
+
+        // b can't prove it is c, so the connection fails and nothing happens
+        ua.try_connect(c.peer(), addr_b).unwrap();
+        ua.try_connect(b.peer(), addr_b).unwrap();
+        assert!(
+            matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == b.peer())
+        );
+        assert!(
+            matches!(rxb.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == a.peer())
+        );
+        assert_eq!(ua.connections(), 1);
+
+        let message = Message::from_bytes(b"over quic".to_vec());
+        ub.send(a.peer(), message.clone()).unwrap();
+        assert!(matches!(
+            rxa.recv().await,
+            Some(UnderlaySignal::Receive(p, m)) if p == b.peer() && m == message
+        ));
+    }
+}
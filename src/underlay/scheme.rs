@@ -0,0 +1,144 @@
+//! Which HELLO addresses we understand.
+//!
+//! HELLO addresses are strings of the form `scheme://rest`. An
+//! [`AddressSchemes`] maps each scheme we support to a validator, so that
+//! addresses we could never connect to are dropped as soon as they are seen,
+//! both in HELLOs from other peers and in our own
+//! [`AddressAdded`](super::UnderlaySignal::AddressAdded) signals.
+
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr};
+
+type Validator = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A registry of address schemes and their validators.
+#[derive(Default)]
+pub struct AddressSchemes {
+    validators: HashMap<String, Validator>,
+}
+
+/// The scheme of an address, eg `ip+udp` for `ip+udp://127.0.0.1:2086`
+pub fn scheme(addr: &str) -> Option<&str> {
+    let (scheme, _) = addr.split_once("://")?;
+    (!scheme.is_empty()).then_some(scheme)
+}
+
+fn socket_addr(addr: &str) -> bool {
+    addr.split_once("://")
+        .is_some_and(|(_, rest)| rest.parse::<SocketAddr>().is_ok())
+}
+
+impl AddressSchemes {
+    /// A registry that accepts nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The schemes of the underlays in this crate: `ip+udp`, `ip+tcp` and
+    /// `quic`, each followed by a socket address.
+    pub fn builtin() -> Self {
+        let mut schemes = Self::new();
+        for scheme in ["ip+udp", "ip+tcp", "quic"] {
+            schemes.register(scheme, socket_addr);
+        }
+        schemes
+    }
+
+    /// Accept addresses with this scheme if `validator` returns true for
+    /// them. The validator is given the whole address, including the scheme.
+    /// This replaces any validator the scheme had before.
+    pub fn register(
+        &mut self,
+        scheme: &str,
+        validator: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) {
+        self.validators
+            .insert(scheme.to_owned(), Box::new(validator));
+    }
+
+    /// Accept addresses with this scheme if they parse as an `A`.
+    pub fn register_parser<A: FromStr>(&mut self, scheme: &str) {
+        self.register(scheme, |addr| addr.parse::<A>().is_ok());
+    }
+
+    pub fn unregister(&mut self, scheme: &str) -> bool {
+        self.validators.remove(scheme).is_some()
+    }
+
+    pub fn supports(&self, scheme: &str) -> bool {
+        self.validators.contains_key(scheme)
+    }
+
+    /// Whether the address has a registered scheme and is valid for it.
+    pub fn is_valid(&self, addr: &str) -> bool {
+        scheme(addr)
+            .and_then(|s| self.validators.get(s))
+            .is_some_and(|validate| validate(addr))
+    }
+}
+
+/// The order to list or try addresses in by their scheme, most preferred
+/// first, eg `quic`, then `ip+tcp`, then `ip+udp`. Schemes not listed come
+/// last.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemePreference(Vec<String>);
+
+impl SchemePreference {
+    pub fn new<'a>(schemes: impl IntoIterator<Item = &'a str>) -> Self {
+        Self(schemes.into_iter().map(str::to_owned).collect())
+    }
+
+    /// Where the address's scheme is in the order. Lower is preferred.
+    pub fn rank(&self, addr: &str) -> usize {
+        let scheme = scheme(addr).unwrap_or_default();
+        let rank = self.0.iter().position(|s| s == scheme);
+        rank.unwrap_or(self.0.len())
+    }
+
+    pub fn schemes(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl fmt::Debug for AddressSchemes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.validators.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::underlay::memory::MemoryAddress;
+
+    use super::{scheme, AddressSchemes, SchemePreference};
+
+    #[test]
+    fn validate() {
+        let mut schemes = AddressSchemes::builtin();
+        assert_eq!(scheme("ip+udp://127.0.0.1:2086"), Some("ip+udp"));
+        assert_eq!(scheme("://x"), None);
+
+        assert!(schemes.is_valid("ip+udp://127.0.0.1:2086"));
+        assert!(schemes.is_valid("quic://[::1]:443"));
+        assert!(!schemes.is_valid("ip+tcp://localhost:2086"));
+        assert!(!schemes.is_valid("ip+udp:127.0.0.1:2086"));
+        assert!(!schemes.is_valid("tor://example.onion"));
+
+        schemes.register("tor", |addr| addr.ends_with(".onion"));
+        assert!(schemes.is_valid("tor://example.onion"));
+        assert!(schemes.unregister("quic"));
+        assert!(!schemes.is_valid("quic://[::1]:443"));
+
+        schemes.register_parser::<MemoryAddress>("memory");
+        assert!(schemes.is_valid("memory://1"));
+        assert!(!schemes.is_valid("memory://x"));
+    }
+
+    #[test]
+    fn preference() {
+        let preference = SchemePreference::new(["quic", "ip+tcp"]);
+        assert_eq!(preference.rank("quic://[::1]:443"), 0);
+        assert_eq!(preference.rank("ip+tcp://127.0.0.1:2086"), 1);
+        assert_eq!(preference.rank("ip+udp://127.0.0.1:2086"), 2);
+        assert_eq!(preference.rank("nonsense"), 2);
+    }
+}
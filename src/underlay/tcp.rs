@@ -0,0 +1,405 @@
+//! An underlay over persistent TCP connections, using tokio.
+//!
+//! A connection starts with both sides sending their public key. After that,
+//! messages are sent back to back and split up again using the
+//! [`MessageHeader`]'s size.
+//!
+//! Like [`udp`](super::udp), nothing is authenticated or encrypted.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::Poll,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::mpsc,
+    task::AbortHandle,
+};
+use zerocopy::FromBytes;
+
+use crate::{message::MessageHeader, Message, Peer};
+
+use super::{
+    queue::{send_queue, SendQueue},
+    ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal,
+};
+
+/// A socket address, written as `ip+tcp://host:port` in HELLOs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TcpAddress(pub SocketAddr);
+
+impl TcpAddress {
+    const SCHEME: &'static str = "ip+tcp://";
+}
+
+impl fmt::Display for TcpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Self::SCHEME, self.0)
+    }
+}
+
+impl FromStr for TcpAddress {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = s.strip_prefix(Self::SCHEME).ok_or(ParseAddressError)?;
+        addr.parse().map(Self).map_err(|_| ParseAddressError)
+    }
+}
+
+pub struct TcpConfig {
+    /// TCP can't estimate the network size, so it is configured instead
+    pub network_size: u64,
+    /// Once there are more connections than this, the oldest connection that
+    /// isn't held is closed.
+    pub max_connections: usize,
+    /// Messages queued per connection before it stops being
+    /// [ready](Underlay::poll_ready) and sends start failing
+    pub send_queue: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            network_size: 1000,
+            max_connections: 64,
+            send_queue: 64,
+        }
+    }
+}
+
+pub struct TcpUnderlay {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+}
+
+struct Shared {
+    local: Peer,
+    config: TcpConfig,
+    runtime: Handle,
+    signals: mpsc::UnboundedSender<UnderlaySignal<TcpUnderlay>>,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    by_peer: HashMap<Peer, Connection>,
+    /// incremented for every connection, so that a closing connection can
+    /// tell whether it was already replaced
+    next_id: u64,
+}
+
+struct Connection {
+    id: u64,
+    pinned: bool,
+    outgoing: SendQueue,
+    reader: AbortHandle,
+}
+
+impl TcpUnderlay {
+    /// Start listening for connections. This must be called from within a
+    /// tokio runtime. Listening stops once the returned channel is dropped.
+    pub async fn bind(
+        local: Peer,
+        addr: SocketAddr,
+        config: TcpConfig,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<UnderlaySignal<Self>>)> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (signals, rx) = mpsc::unbounded_channel();
+        if !local_addr.ip().is_unspecified() {
+            let _ = signals.send(UnderlaySignal::AddressAdded(TcpAddress(local_addr)));
+        }
+
+        let shared = Arc::new(Shared {
+            local,
+            config,
+            runtime: Handle::current(),
+            signals,
+            connections: Default::default(),
+        });
+        tokio::spawn(shared.clone().accept(listener));
+
+        Ok((Self { shared, local_addr }, rx))
+    }
+
+    pub fn local_addr(&self) -> TcpAddress {
+        TcpAddress(self.local_addr)
+    }
+
+    /// The number of open connections
+    pub fn connections(&self) -> usize {
+        self.shared.lock().by_peer.len()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Connections> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, _)) = accepted else { continue };
+                    tokio::spawn(self.clone().handshake(stream, None));
+                }
+                _ = self.signals.closed() => return,
+            }
+        }
+    }
+
+    /// Exchange keys, then hand the connection to its reader and writer.
+    async fn handshake(self: Arc<Self>, mut stream: TcpStream, expected: Option<Peer>) {
+        let mut key = [0; 32];
+        let exchanged = async {
+            stream.write_all(self.local.as_bytes()).await?;
+            stream.read_exact(&mut key).await
+        };
+        if exchanged.await.is_err() {
+            return;
+        }
+        let peer = Peer::from_bytes(key);
+        if peer == self.local || expected.is_some_and(|e| e != peer) {
+            return;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let (mut read, mut write) = stream.into_split();
+        let (outgoing, mut queue) = send_queue(self.config.send_queue);
+
+        let mut connections = self.lock();
+        let id = connections.next_id;
+        connections.next_id += 1;
+
+        let reader = {
+            let shared = self.clone();
+            tokio::spawn(async move {
+                while let Ok(message) = read_message(&mut read).await {
+                    let _ = shared.signals.send(UnderlaySignal::Receive(peer, message));
+                }
+                shared.close(peer, id);
+            })
+        };
+        // ends once the connection is removed and the sender is dropped
+        let signals = self.signals.clone();
+        tokio::spawn(async move {
+            while let Some((message, ready)) = queue.recv().await {
+                if ready {
+                    let _ = signals.send(UnderlaySignal::SendReady(peer));
+                }
+                if write.write_all(message.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let new = Connection {
+            id,
+            pinned: false,
+            outgoing,
+            reader: reader.abort_handle(),
+        };
+        if let Some(old) = connections.by_peer.insert(peer, new) {
+            // the newer connection wins, the routing table sees a replacement
+            old.reader.abort();
+        }
+        let evicted = self.evict(&mut connections);
+        drop(connections);
+
+        let _ = self.signals.send(UnderlaySignal::PeerConnected(
+            peer,
+            ConnectionInfo::default(),
+        ));
+        for peer in evicted {
+            let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+        }
+    }
+
+    /// Close unpinned connections, oldest first, until we are within
+    /// `max_connections`.
+    fn evict(&self, connections: &mut Connections) -> Vec<Peer> {
+        let excess = connections
+            .by_peer
+            .len()
+            .saturating_sub(self.config.max_connections);
+        let mut unpinned: Vec<(u64, Peer)> = connections
+            .by_peer
+            .iter()
+            .filter(|(_, c)| !c.pinned)
+            .map(|(&p, c)| (c.id, p))
+            .collect();
+        unpinned.sort_unstable();
+        unpinned.truncate(excess);
+
+        for (_, peer) in &unpinned {
+            if let Some(c) = connections.by_peer.remove(peer) {
+                c.reader.abort();
+            }
+        }
+        unpinned.into_iter().map(|(_, p)| p).collect()
+    }
+
+    fn close(&self, peer: Peer, id: u64) {
+        let mut connections = self.lock();
+        if connections.by_peer.get(&peer).is_some_and(|c| c.id == id) {
+            connections.by_peer.remove(&peer);
+            drop(connections);
+            let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+        }
+    }
+}
+
+async fn read_message(read: &mut (impl AsyncRead + Unpin)) -> io::Result<Message> {
+    let mut header = [0; size_of::<MessageHeader>()];
+    read.read_exact(&mut header).await?;
+    let size = MessageHeader::ref_from(&header[..]).unwrap().message_size() as usize;
+    if size < header.len() {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    let mut message = vec![0; size];
+    message[..header.len()].copy_from_slice(&header);
+    read.read_exact(&mut message[header.len()..]).await?;
+    Ok(Message::from_bytes(message))
+}
+
+impl Underlay for TcpUnderlay {
+    type Address = TcpAddress;
+    type NetworkSizeEstimate = u64;
+    type Error = io::Error;
+
+    /// Connects in the background. [`PeerConnected`](UnderlaySignal::PeerConnected)
+    /// is signalled once the peer has sent its key, and nothing happens if
+    /// it fails.
+    fn try_connect(&self, peer: Peer, addr: TcpAddress) -> io::Result<()> {
+        let shared = self.shared.clone();
+        self.shared.runtime.spawn(async move {
+            if let Ok(stream) = TcpStream::connect(addr.0).await {
+                shared.handshake(stream, Some(peer)).await;
+            }
+        });
+        Ok(())
+    }
+
+    fn hold(&self, peer: Peer) {
+        if let Some(c) = self.shared.lock().by_peer.get_mut(&peer) {
+            c.pinned = true;
+        }
+    }
+
+    fn drop(&self, peer: Peer) {
+        if let Some(c) = self.shared.lock().by_peer.get_mut(&peer) {
+            c.pinned = false;
+        }
+    }
+
+    /// Queues the message on the peer's connection. The message must start
+    /// with a [`MessageHeader`] that has its correct size.
+    fn send(&self, peer: Peer, message: Message) -> io::Result<()> {
+        if message.header().map(|h| h.message_size() as usize) != Some(message.as_bytes().len()) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let connections = self.shared.lock();
+        let c = connections
+            .by_peer
+            .get(&peer)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        c.outgoing.try_send(message)
+    }
+
+    /// Pending while the connection's
+    /// [`send_queue`](TcpConfig::send_queue) is full
+    fn poll_ready(&self, peer: Peer) -> Poll<()> {
+        match self.shared.lock().by_peer.get(&peer) {
+            Some(c) => c.outgoing.poll_ready(),
+            None => Poll::Ready(()),
+        }
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.shared.config.network_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::identities,
+        underlay::{Underlay, UnderlaySignal},
+        Message,
+    };
+
+    use super::{TcpConfig, TcpUnderlay};
+
+    fn message(body: &[u8]) -> Message {
+        let size = 4 + body.len() as u16;
+        let mut bytes = [size.to_be_bytes(), 146u16.to_be_bytes()].concat();
+        bytes.extend_from_slice(body);
+        Message::from_bytes(bytes)
+    }
+
+    #[tokio::test]
+    async fn framing_and_eviction() {
+        let [a, b, c] = [0, 1, 2].map(|i| identities::peers()[i].peer());
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let config = TcpConfig {
+            max_connections: 1,
+            ..TcpConfig::default()
+        };
+        let (ua, mut rxa) = TcpUnderlay::bind(a, localhost, config).await.unwrap();
+        let (ub, mut rxb) = TcpUnderlay::bind(b, localhost, TcpConfig::default())
+            .await
+            .unwrap();
+        let (uc, mut rxc) = TcpUnderlay::bind(c, localhost, TcpConfig::default())
+            .await
+            .unwrap();
+        for rx in [&mut rxa, &mut rxb, &mut rxc] {
+            assert!(matches!(
+                rx.recv().await,
+                Some(UnderlaySignal::AddressAdded(_))
+            ));
+        }
+
+        ub.try_connect(a, ua.local_addr()).unwrap();
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == b));
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == a));
+
+        // several messages in one stream are split up again
+        let messages = [message(b"first"), message(b""), message(&[7; 1000])];
+        for m in &messages {
+            ub.send(a, m.clone()).unwrap();
+        }
+        for m in &messages {
+            assert!(
+                matches!(rxa.recv().await, Some(UnderlaySignal::Receive(p, r)) if p == b && r == *m)
+            );
+        }
+        assert!(ub.send(a, Message::from_bytes(vec![0, 9, 0, 0])).is_err());
+
+        // a only has room for one connection, and b isn't held
+        uc.try_connect(a, ua.local_addr()).unwrap();
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == c));
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerDisconnected(p)) if p == b));
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::PeerDisconnected(p)) if p == a));
+        assert_eq!(ua.connections(), 1);
+
+        // now c is held, so the newest connection goes instead
+        ua.hold(c);
+        ub.try_connect(a, ua.local_addr()).unwrap();
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == b));
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerDisconnected(p)) if p == b));
+        assert_eq!(ua.connections(), 1);
+        assert!(ua.send(c, message(b"still here")).is_ok());
+    }
+}
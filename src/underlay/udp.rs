@@ -0,0 +1,341 @@
+//! An underlay over UDP, using tokio.
+//!
+//! Every datagram starts with the sender's public key and a frame type. A
+//! peer is considered connected from the first datagram it sends us, and
+//! disconnected once it has been silent for [`UdpConfig::idle_timeout`]. Held
+//! peers are sent keep-alives so that they don't time us out.
+//!
+//! Nothing is authenticated or encrypted: anyone can claim to be any peer.
+//! This is meant for testing and trusted networks.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::mpsc, time::Instant};
+
+use crate::{Message, Peer};
+
+use super::{ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal};
+
+/// The largest datagram we read. Larger messages are truncated by the OS.
+const MAX_DATAGRAM: usize = 65507;
+
+const CONNECTION_INFO: ConnectionInfo = ConnectionInfo {
+    constrained: false,
+    cost: 0,
+    // less the frame header
+    mtu: Some((MAX_DATAGRAM - 33) as u16),
+};
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_MESSAGE: u8 = 1;
+
+/// A socket address, written as `ip+udp://host:port` in HELLOs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UdpAddress(pub SocketAddr);
+
+impl UdpAddress {
+    const SCHEME: &'static str = "ip+udp://";
+}
+
+impl fmt::Display for UdpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Self::SCHEME, self.0)
+    }
+}
+
+impl FromStr for UdpAddress {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = s.strip_prefix(Self::SCHEME).ok_or(ParseAddressError)?;
+        addr.parse().map(Self).map_err(|_| ParseAddressError)
+    }
+}
+
+pub struct UdpConfig {
+    /// UDP can't estimate the network size, so it is configured instead
+    pub network_size: u64,
+    /// Peers that we haven't heard from for this long are disconnected
+    pub idle_timeout: Duration,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            network_size: 1000,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+pub struct UdpUnderlay {
+    /// sends go straight to the non-blocking socket, tokio only wakes the
+    /// receiver
+    socket: std::net::UdpSocket,
+    local: Peer,
+    network_size: u64,
+    peers: Arc<Mutex<HashMap<Peer, Connection>>>,
+}
+
+struct Connection {
+    addr: SocketAddr,
+    last_seen: Instant,
+    held: bool,
+}
+
+impl UdpUnderlay {
+    /// Bind a socket and start receiving on it. This must be called from
+    /// within a tokio runtime. The returned channel closes if the socket
+    /// fails, and receiving stops once the channel is dropped.
+    pub fn bind(
+        local: Peer,
+        addr: SocketAddr,
+        config: UdpConfig,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<UnderlaySignal<Self>>)> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+        let underlay = Self {
+            socket: socket.try_clone()?,
+            local,
+            network_size: config.network_size,
+            peers: Default::default(),
+        };
+
+        let (signals, rx) = mpsc::unbounded_channel();
+        if !local_addr.ip().is_unspecified() {
+            let _ = signals.send(UnderlaySignal::AddressAdded(UdpAddress(local_addr)));
+        }
+
+        let receiver = Receiver {
+            send: socket.try_clone()?,
+            socket: UdpSocket::from_std(socket)?,
+            local,
+            peers: underlay.peers.clone(),
+            signals,
+            idle_timeout: config.idle_timeout,
+        };
+        tokio::spawn(receiver.run());
+
+        Ok((underlay, rx))
+    }
+
+    pub fn local_addr(&self) -> io::Result<UdpAddress> {
+        self.socket.local_addr().map(UdpAddress)
+    }
+
+    fn peers(&self) -> MutexGuard<'_, HashMap<Peer, Connection>> {
+        lock(&self.peers)
+    }
+}
+
+fn lock(peers: &Mutex<HashMap<Peer, Connection>>) -> MutexGuard<'_, HashMap<Peer, Connection>> {
+    peers.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn frame(local: &Peer, kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(33 + payload.len());
+    frame.extend_from_slice(local.as_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+impl Underlay for UdpUnderlay {
+    type Address = UdpAddress;
+    type NetworkSizeEstimate = u64;
+    type Error = io::Error;
+
+    /// Sends a hello. The peer is connected once it replies.
+    fn try_connect(&self, _peer: Peer, addr: UdpAddress) -> io::Result<()> {
+        let hello = frame(&self.local, FRAME_HELLO, &[]);
+        self.socket.send_to(&hello, addr.0).map(|_| ())
+    }
+
+    fn hold(&self, peer: Peer) {
+        if let Some(c) = self.peers().get_mut(&peer) {
+            c.held = true;
+        }
+    }
+
+    fn drop(&self, peer: Peer) {
+        if let Some(c) = self.peers().get_mut(&peer) {
+            c.held = false;
+        }
+    }
+
+    /// Sends without waiting. If the socket buffer is full the message is
+    /// lost and an error is returned.
+    fn send(&self, peer: Peer, message: Message) -> io::Result<()> {
+        let addr = self
+            .peers()
+            .get(&peer)
+            .map(|c| c.addr)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let frame = frame(&self.local, FRAME_MESSAGE, message.as_bytes());
+        self.socket.send_to(&frame, addr).map(|_| ())
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.network_size
+    }
+}
+
+struct Receiver {
+    socket: UdpSocket,
+    send: std::net::UdpSocket,
+    local: Peer,
+    peers: Arc<Mutex<HashMap<Peer, Connection>>>,
+    signals: mpsc::UnboundedSender<UnderlaySignal<UdpUnderlay>>,
+    idle_timeout: Duration,
+}
+
+impl Receiver {
+    async fn run(self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut expire = tokio::time::interval(self.idle_timeout / 4);
+        loop {
+            tokio::select! {
+                recv = self.socket.recv_from(&mut buf) => {
+                    let Ok((n, from)) = recv else { return };
+                    self.receive(&buf[..n], from);
+                }
+                _ = expire.tick() => self.expire(),
+                _ = self.signals.closed() => return,
+            }
+        }
+    }
+
+    fn receive(&self, datagram: &[u8], from: SocketAddr) {
+        let Some((key, rest)) = datagram.split_first_chunk::<32>() else {
+            return;
+        };
+        let Some((&kind, payload)) = rest.split_first() else {
+            return;
+        };
+        let peer = Peer::from_bytes(*key);
+        if peer == self.local {
+            return;
+        }
+
+        let now = Instant::now();
+        let connected = match lock(&self.peers).entry(peer) {
+            Entry::Occupied(mut c) => {
+                let c = c.get_mut();
+                c.addr = from;
+                c.last_seen = now;
+                false
+            }
+            Entry::Vacant(v) => {
+                v.insert(Connection {
+                    addr: from,
+                    last_seen: now,
+                    held: false,
+                });
+                true
+            }
+        };
+
+        if connected {
+            // let the peer know about us too, in case it initiated
+            let _ = self
+                .send
+                .send_to(&frame(&self.local, FRAME_HELLO, &[]), from);
+            let _ = self
+                .signals
+                .send(UnderlaySignal::PeerConnected(peer, CONNECTION_INFO));
+        }
+        if kind == FRAME_MESSAGE {
+            let message = Message::from_bytes(payload.to_vec());
+            let _ = self.signals.send(UnderlaySignal::Receive(peer, message));
+        }
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut expired = vec![];
+        let mut keep_alive = vec![];
+        lock(&self.peers).retain(|&peer, c| {
+            if now - c.last_seen >= self.idle_timeout {
+                expired.push(peer);
+                return false;
+            }
+            if c.held {
+                keep_alive.push(c.addr);
+            }
+            true
+        });
+
+        let hello = frame(&self.local, FRAME_HELLO, &[]);
+        for addr in keep_alive {
+            let _ = self.send.send_to(&hello, addr);
+        }
+        for peer in expired {
+            let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        testing::identities,
+        underlay::{Underlay, UnderlaySignal},
+        Message,
+    };
+
+    use super::{UdpAddress, UdpConfig, UdpUnderlay};
+
+    #[test]
+    fn address() {
+        let addr: UdpAddress = "ip+udp://[::1]:2086".parse().unwrap();
+        assert_eq!(addr.to_string(), "ip+udp://[::1]:2086");
+        assert_eq!(addr.0.port(), 2086);
+        assert!("ip+tcp://127.0.0.1:2086".parse::<UdpAddress>().is_err());
+        assert!("ip+udp://localhost:2086".parse::<UdpAddress>().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_send_timeout() {
+        let [a, b] = [identities::peers()[0].peer(), identities::peers()[1].peer()];
+        let config = || UdpConfig {
+            idle_timeout: Duration::from_millis(200),
+            ..UdpConfig::default()
+        };
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let (ua, mut rxa) = UdpUnderlay::bind(a, localhost, config()).unwrap();
+        let (ub, mut rxb) = UdpUnderlay::bind(b, localhost, config()).unwrap();
+
+        let addr_b = ub.local_addr().unwrap();
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::AddressAdded(x)) if x == addr_b));
+        assert!(matches!(
+            rxa.recv().await,
+            Some(UnderlaySignal::AddressAdded(_))
+        ));
+
+        ua.try_connect(b, addr_b).unwrap();
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == a));
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerConnected(p, _)) if p == b));
+
+        let message = Message::from_bytes(b"hello".to_vec());
+        ua.send(b, message.clone()).unwrap();
+        assert!(
+            matches!(rxb.recv().await, Some(UnderlaySignal::Receive(p, m)) if p == a && m == message)
+        );
+
+        // b sends a keep-alives, but a never sends anything back
+        ub.hold(a);
+        assert!(matches!(rxb.recv().await, Some(UnderlaySignal::PeerDisconnected(p)) if p == a));
+        assert!(rxa.try_recv().is_err());
+        // b stopped sending keep-alives once it timed a out
+        assert!(matches!(rxa.recv().await, Some(UnderlaySignal::PeerDisconnected(p)) if p == b));
+    }
+}
@@ -0,0 +1,306 @@
+//! An underlay over browser WebSockets, for light nodes running under
+//! `wasm32-unknown-unknown`.
+//!
+//! Browsers can only dial out, so this connects to `ws://` or `wss://`
+//! addresses of other peers. Once the socket is open both sides send their
+//! 32-byte public key as the first binary frame, and the peer counts as
+//! connected when the key matches the one that was dialed. After that every
+//! binary frame is one message.
+//!
+//! Everything happens on the browser's event loop. Signals are queued on the
+//! returned channel, which should be drained with `try_recv` from a timer or
+//! after each callback.
+
+use std::{cell::RefCell, collections::HashMap, fmt, io, rc::Rc, str::FromStr, sync::mpsc};
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+use crate::{Message, Peer};
+
+use super::{ConnectionInfo, ParseAddressError, Underlay, UnderlaySignal};
+
+/// A WebSocket URL, written as is in HELLOs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WebSocketAddress(String);
+
+impl WebSocketAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for WebSocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for WebSocketAddress {
+    type Err = ParseAddressError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("ws://")
+            .or_else(|| s.strip_prefix("wss://"))
+            .ok_or(ParseAddressError)?;
+        if rest.is_empty() {
+            return Err(ParseAddressError);
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+pub struct WebSocketConfig {
+    /// Browsers can't estimate the network size, so it is configured instead
+    pub network_size: u64,
+    /// Once there are more connections than this, the oldest ones that
+    /// aren't held are closed
+    pub max_connections: usize,
+    /// Sends fail once this many bytes are waiting in a socket's buffer
+    pub send_buffer: u32,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            network_size: 1000,
+            max_connections: 16,
+            send_buffer: 1 << 20,
+        }
+    }
+}
+
+pub struct WebSocketUnderlay {
+    local: Peer,
+    config: WebSocketConfig,
+    signals: mpsc::Sender<UnderlaySignal<Self>>,
+    connections: Rc<RefCell<Connections>>,
+}
+
+#[derive(Default)]
+struct Connections {
+    by_peer: HashMap<Peer, Connection>,
+    next_id: u64,
+    /// connections removed from their own callbacks, which can't free the
+    /// callbacks while they are running
+    closed: Vec<Connection>,
+}
+
+impl Connections {
+    fn remove(&mut self, peer: &Peer) -> Option<Connection> {
+        let c = self.by_peer.remove(peer)?;
+        c.close();
+        Some(c)
+    }
+}
+
+impl Drop for Connections {
+    fn drop(&mut self) {
+        for c in self.by_peer.values() {
+            c.close();
+        }
+    }
+}
+
+struct Connection {
+    id: u64,
+    socket: WebSocket,
+    /// whether the peer sent its key yet
+    open: bool,
+    held: bool,
+    // the browser only keeps weak references to these
+    _onopen: Closure<dyn FnMut()>,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+    _onclose: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Connection {
+    fn close(&self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+fn js_error(e: JsValue) -> io::Error {
+    io::Error::other(e.as_string().unwrap_or_else(|| format!("{e:?}")))
+}
+
+impl WebSocketUnderlay {
+    pub fn new(
+        local: Peer,
+        config: WebSocketConfig,
+    ) -> (Self, mpsc::Receiver<UnderlaySignal<Self>>) {
+        let (signals, rx) = mpsc::channel();
+        let underlay = Self {
+            local,
+            config,
+            signals,
+            connections: Default::default(),
+        };
+        (underlay, rx)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.borrow().by_peer.len()
+    }
+
+    /// Close the oldest connections that aren't held, until we are within
+    /// the limit again.
+    fn evict(&self) {
+        let mut connections = self.connections.borrow_mut();
+        while connections.by_peer.len() > self.config.max_connections {
+            let oldest = connections
+                .by_peer
+                .iter()
+                .filter(|(_, c)| !c.held)
+                .min_by_key(|(_, c)| c.id)
+                .map(|(&peer, _)| peer);
+            let Some(peer) = oldest else { break };
+            let c = connections.remove(&peer).unwrap();
+            if c.open {
+                let _ = self.signals.send(UnderlaySignal::PeerDisconnected(peer));
+            }
+        }
+    }
+}
+
+impl Underlay for WebSocketUnderlay {
+    type Address = WebSocketAddress;
+    type NetworkSizeEstimate = u64;
+    type Error = io::Error;
+
+    /// Opens a socket. The peer is connected once it has sent its key.
+    fn try_connect(&self, peer: Peer, addr: WebSocketAddress) -> io::Result<()> {
+        {
+            let mut connections = self.connections.borrow_mut();
+            connections.closed.clear();
+            if connections.by_peer.contains_key(&peer) {
+                return Ok(());
+            }
+        }
+
+        let socket = WebSocket::new(addr.as_str()).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let id = {
+            let mut connections = self.connections.borrow_mut();
+            connections.next_id += 1;
+            connections.next_id
+        };
+
+        let onopen = {
+            let socket = socket.clone();
+            let local = self.local;
+            Closure::<dyn FnMut()>::new(move || {
+                let _ = socket.send_with_u8_array(local.as_bytes());
+            })
+        };
+
+        let onmessage = {
+            let connections = Rc::downgrade(&self.connections);
+            let signals = self.signals.clone();
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                let Some(connections) = connections.upgrade() else {
+                    return;
+                };
+                let Ok(buf) = event.data().dyn_into::<ArrayBuffer>() else {
+                    return;
+                };
+                let bytes = Uint8Array::new(&buf).to_vec();
+
+                let mut connections = connections.borrow_mut();
+                let Some(c) = connections.by_peer.get_mut(&peer).filter(|c| c.id == id) else {
+                    return;
+                };
+                if c.open {
+                    let message = Message::from_bytes(bytes);
+                    let _ = signals.send(UnderlaySignal::Receive(peer, message));
+                } else if bytes == peer.as_bytes() {
+                    c.open = true;
+                    let _ = signals.send(UnderlaySignal::PeerConnected(
+                        peer,
+                        ConnectionInfo::default(),
+                    ));
+                } else {
+                    // not who we dialed
+                    let c = connections.remove(&peer).unwrap();
+                    connections.closed.push(c);
+                }
+            })
+        };
+
+        let onclose = {
+            let connections = Rc::downgrade(&self.connections);
+            let signals = self.signals.clone();
+            Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| {
+                let Some(connections) = connections.upgrade() else {
+                    return;
+                };
+                let mut connections = connections.borrow_mut();
+                if connections.by_peer.get(&peer).is_some_and(|c| c.id == id) {
+                    let c = connections.remove(&peer).unwrap();
+                    if c.open {
+                        let _ = signals.send(UnderlaySignal::PeerDisconnected(peer));
+                    }
+                    connections.closed.push(c);
+                }
+            })
+        };
+
+        socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+        let connection = Connection {
+            id,
+            socket,
+            open: false,
+            held: false,
+            _onopen: onopen,
+            _onmessage: onmessage,
+            _onclose: onclose,
+        };
+        self.connections
+            .borrow_mut()
+            .by_peer
+            .insert(peer, connection);
+        self.evict();
+        Ok(())
+    }
+
+    fn hold(&self, peer: Peer) {
+        if let Some(c) = self.connections.borrow_mut().by_peer.get_mut(&peer) {
+            c.held = true;
+        }
+    }
+
+    fn drop(&self, peer: Peer) {
+        if let Some(c) = self.connections.borrow_mut().by_peer.get_mut(&peer) {
+            c.held = false;
+        }
+    }
+
+    /// Fails with [`WouldBlock`](io::ErrorKind::WouldBlock) if too much is
+    /// already waiting to be sent.
+    fn send(&self, peer: Peer, message: Message) -> io::Result<()> {
+        let connections = self.connections.borrow();
+        let c = connections
+            .by_peer
+            .get(&peer)
+            .filter(|c| c.open)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        if c.socket.buffered_amount() > self.config.send_buffer {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        c.socket
+            .send_with_u8_array(message.as_bytes())
+            .map_err(js_error)
+    }
+
+    fn estimate_network_size(&self) -> u64 {
+        self.config.network_size
+    }
+}